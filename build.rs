@@ -0,0 +1,67 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+// Directory to embed when the embed feature is enabled, relative to the crate root
+const EMBED_DIR_ENV: &str = "EMBED_DIR";
+const EMBED_DIR_DEFAULT: &str = "res";
+const EMBED_OUT_FILE: &str = "embedded.rs";
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.is_dir() {
+            true => collect_files(&path, files)?,
+            false => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-env-changed={}", EMBED_DIR_ENV);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Only generate the table when the embed feature is enabled
+    if env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return Ok(());
+    }
+
+    // Resolve the embed dir, a missing dir results in an empty table
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let embed_dir = manifest_dir.join(env::var(EMBED_DIR_ENV).unwrap_or(EMBED_DIR_DEFAULT.into()));
+    let mut files = Vec::new();
+    if embed_dir.is_dir() {
+        println!("cargo:rerun-if-changed={}", embed_dir.display());
+        collect_files(&embed_dir, &mut files)?;
+    }
+
+    // Build the entry names relative to the embed dir, sorted for binary search
+    let mut entries = files
+        .iter()
+        .map(|f| {
+            let name = f
+                .strip_prefix(&embed_dir)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            (name, f.canonicalize().unwrap())
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    // Write the table
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join(EMBED_OUT_FILE);
+    let mut out = fs::File::create(out_path)?;
+    writeln!(out, "pub static EMBEDDED_FILES: &[(&str, &[u8])] = &[")?;
+    for (name, path) in &entries {
+        println!("cargo:rerun-if-changed={}", path.display());
+        writeln!(out, "    ({:?}, include_bytes!({:?})),", name, path)?;
+    }
+    writeln!(out, "];")?;
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::{net::SocketAddr, path::Path, time::SystemTime};
+
+use tokio::{
+    fs::OpenOptions,
+    io::{self, AsyncWriteExt, BufWriter},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    task,
+};
+
+use crate::{error, log_ctx, util::fmt_clf_date};
+
+// Combined log format, the common log format followed by the referer and the user agent
+// Sample: 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326 "http://example.com/" "curl/8.0"
+const ACCESS_LOG_BUFF_SIZE: usize = crate::BUFF_INIT_SIZE * 64;
+const ACCESS_LOG_EMPTY_FIELD: &str = "-";
+
+log_ctx!("AccessLog");
+
+pub struct AccessLogEntry<'a> {
+    pub sockaddr: &'a SocketAddr,
+    pub request_line: &'a str,
+    pub status: u16,
+    pub bytes: usize,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+// Lines are handed to a writer task so that requests never wait for the disk
+pub struct AccessLog(UnboundedSender<String>);
+
+// Quote a field, quotes and backslashes inside are escaped
+fn quote(s: Option<&str>) -> String {
+    let s = s.unwrap_or(ACCESS_LOG_EMPTY_FIELD);
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl AccessLogEntry<'_> {
+    fn format(&self, time: SystemTime) -> String {
+        let bytes = match self.bytes {
+            0 => ACCESS_LOG_EMPTY_FIELD.to_string(),
+            b => b.to_string(),
+        };
+        format!(
+            "{} - - [{}] {} {} {} {} {}\n",
+            self.sockaddr.ip(),
+            fmt_clf_date(time),
+            quote(Some(self.request_line)),
+            self.status,
+            bytes,
+            quote(self.referer),
+            quote(self.user_agent)
+        )
+    }
+}
+
+impl AccessLog {
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (tx, mut rx) = unbounded_channel::<String>();
+        task::spawn(async move {
+            let mut writer = BufWriter::with_capacity(ACCESS_LOG_BUFF_SIZE, file);
+            while let Some(line) = rx.recv().await {
+                // Write all pending lines before flushing
+                let mut res = writer.write_all(line.as_bytes()).await;
+                while res.is_ok() {
+                    match rx.try_recv() {
+                        Ok(line) => res = writer.write_all(line.as_bytes()).await,
+                        Err(_) => break,
+                    }
+                }
+                if let Err(e) = res.and(writer.flush().await) {
+                    error!("Failed to write access log: {}", e);
+                }
+            }
+        });
+        Ok(Self(tx))
+    }
+
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let _ = self.0.send(entry.format(SystemTime::now()));
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    fmt::Write,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use tokio::{
+    io::{self, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task,
+};
+
+use crate::{
+    debug, error,
+    http::{read_headers_buff, read_timeout, status_reason, HttpRequest, HttpResponse},
+    info,
+    log::{self, LogFilter},
+    log_ctx,
+    requestlog::StatusFilter,
+    state::{ServerState, Site},
+    util::{fmt_rfc3339, fmt_size, json_escape},
+};
+
+// Endpoints of the admin listener, all answered with json
+// Sample: curl -X POST '127.0.0.1:9000/cache/purge?site=example.com&path=/index.html' or curl -X PURGE 127.0.0.1:9000/assets/*
+const ADMIN_CONFIG: &str = "/config";
+const ADMIN_CACHE: &str = "/cache";
+const ADMIN_CACHE_PURGE: &str = "/cache/purge";
+const ADMIN_MAP_RELOAD: &str = "/map/reload";
+const ADMIN_LOG_LEVEL: &str = "/log/level";
+const ADMIN_LOG_REQUESTS: &str = "/log/requests";
+const ADMIN_CONNECTIONS: &str = "/connections";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const ADMIN_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 4;
+
+log_ctx!("Admin");
+
+// Port of the loopback interface or a full address, e.g. 9000 or 0.0.0.0:9000
+// The admin API mutates the server, so it is only reachable from other hosts when asked for
+pub fn parse_admin_addr(s: &str) -> Option<SocketAddr> {
+    match s.parse::<u16>() {
+        Ok(port) => Some(SocketAddr::from((Ipv4Addr::LOCALHOST, port))),
+        Err(_) => s.parse().ok(),
+    }
+}
+
+fn json_response(status: u16, json: String) -> HttpResponse {
+    HttpResponse::new(status).with_text(JSON_CONTENT_TYPE, json)
+}
+
+fn json_error(status: u16, msg: &str) -> HttpResponse {
+    json_response(status, format!("{{\"error\":{}}}", json_escape(msg)))
+}
+
+// Sites selected by the site parameter, every site if not given
+fn select_sites<'a>(state: &'a ServerState, req: &HttpRequest<'_>) -> Option<Vec<&'a Site>> {
+    match req.param("site") {
+        Some(name) => state
+            .sites()
+            .find(|s| s.display_name().eq_ignore_ascii_case(name))
+            .map(|s| vec![s]),
+        None => Some(state.sites().collect()),
+    }
+}
+
+async fn config_json(state: &ServerState) -> String {
+    let mut json = String::with_capacity(ADMIN_BUFF_INIT_SIZE);
+    let _ = write!(
+        json,
+        "{{\"log_level\":{},\"log_format\":{},\"debug_endpoints\":{},\"dir_listing\":{},\"idle_timeout_ms\":{},\"header_timeout_ms\":{},\"body_timeout_ms\":{},\"request_timeout_ms\":{},\"max_header_size\":{},\"max_header_count\":{},\"max_body_size\":{},\"metrics_path\":{},\"sites\":[",
+        json_escape(&log::get_log_filter().to_string()),
+        json_escape(&log::get_log_format().to_string()),
+        state.debug_endpoints,
+        state.dir_listing,
+        state.idle_timeout.as_millis(),
+        state.header_timeout.as_millis(),
+        state.body_timeout.as_millis(),
+        state.request_timeout.as_millis(),
+        state.max_header_size,
+        state.max_header_count,
+        state.max_body_size,
+        match &state.metrics_path {
+            Some(p) => json_escape(p),
+            None => "null".to_string(),
+        }
+    );
+    for (i, site) in state.sites().enumerate() {
+        let stats = site.file_cache.stats().await;
+        let _ = write!(
+            json,
+            "{}{{\"name\":{},\"res_root\":{},\"map_file\":{},\"file_cache_size\":{}}}",
+            if i > 0 { "," } else { "" },
+            json_escape(site.display_name()),
+            json_escape(&site.res_root().path.display().to_string()),
+            json_escape(&site.map_path().display().to_string()),
+            stats
+                .size_limit
+                .map_or("null".to_string(), |l| l.to_string())
+        );
+    }
+    json.push_str("]}");
+    json
+}
+
+async fn cache_json(sites: &[&Site]) -> String {
+    let mut json = String::with_capacity(ADMIN_BUFF_INIT_SIZE);
+    json.push('[');
+    for (i, site) in sites.iter().enumerate() {
+        let stats = site.file_cache.stats().await;
+        let _ = write!(
+            json,
+            "{}{{\"site\":{},\"hits\":{},\"misses\":{},\"evictions\":{},\"rejections\":{},\"size\":{},\"size_limit\":{},\"entries\":[",
+            if i > 0 { "," } else { "" },
+            json_escape(site.display_name()),
+            stats.hits,
+            stats.misses,
+            stats.evictions,
+            stats.rejections,
+            stats.size,
+            stats.size_limit.map_or("null".to_string(), |l| l.to_string())
+        );
+        for (j, (path, size)) in site.file_cache.entries().await.iter().enumerate() {
+            let _ = write!(
+                json,
+                "{}{{\"path\":{},\"size\":{}}}",
+                if j > 0 { "," } else { "" },
+                json_escape(&path.display().to_string()),
+                size
+            );
+        }
+        json.push_str("]}");
+    }
+    json.push(']');
+    json
+}
+
+// Entries to remove from the caches
+enum Purge<'a> {
+    All,
+    Path(&'a str),   // A file, or every file under a folder
+    Prefix(&'a str), // Every file whose path starts with the string
+}
+
+// Remove the entries from the caches, paths are cache keys or paths relative to the res root of each site
+// Returns the number of entries and bytes freed
+async fn purge(sites: &[&Site], target: &Purge<'_>) -> (usize, usize) {
+    let mut freed = (0, 0);
+    for site in sites {
+        let root = site.res_root().path.clone();
+        let keys = |p: &str| [PathBuf::from(p), root.join(p.trim_start_matches('/'))];
+        let (entries, bytes) = match target {
+            Purge::All => site.file_cache.clear().await,
+            Purge::Path(p) => {
+                let keys = keys(p);
+                let matches = |k: &Path| keys.iter().any(|key| k.starts_with(key));
+                site.file_cache.remove_where(matches).await
+            }
+            Purge::Prefix(p) => {
+                let keys = keys(p).map(|k| k.to_string_lossy().into_owned());
+                let matches = |k: &Path| {
+                    let k = k.to_string_lossy();
+                    keys.iter().any(|key| k.starts_with(key.as_str()))
+                };
+                site.file_cache.remove_where(matches).await
+            }
+        };
+        freed.0 += entries;
+        freed.1 += bytes;
+    }
+    freed
+}
+
+async fn purge_response(
+    state: &ServerState,
+    req: &HttpRequest<'_>,
+    target: Purge<'_>,
+) -> HttpResponse {
+    match select_sites(state, req) {
+        Some(sites) => {
+            let (purged, freed) = purge(&sites, &target).await;
+            info!("Purged {} cache entries ({})", purged, fmt_size(freed));
+            let json = format!("{{\"purged\":{},\"freed\":{}}}", purged, freed);
+            json_response(200, json)
+        }
+        None => json_error(404, "Unknown site"),
+    }
+}
+
+fn connections_json(state: &ServerState) -> String {
+    let now = SystemTime::now();
+    let conns = state
+        .metrics
+        .connections()
+        .iter()
+        .map(|c| {
+            format!(
+                "{{\"id\":{},\"remote_addr\":{},\"opened\":{},\"age_ms\":{}}}",
+                c.id,
+                json_escape(&c.remote_addr.to_string()),
+                json_escape(&fmt_rfc3339(c.opened)),
+                now.duration_since(c.opened).unwrap_or_default().as_millis()
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", conns.join(","))
+}
+
+fn request_log_json(state: &ServerState) -> String {
+    let statuses = state.request_log.statuses();
+    format!(
+        "{{\"sample\":{},\"status\":{}}}",
+        state.request_log.sample(),
+        statuses.map_or("null".to_string(), |s| json_escape(&s.to_string()))
+    )
+}
+
+// Both are optional, an empty status logs every status again
+// Sample: curl -X POST '127.0.0.1:9000/log/requests?sample=100&status=4xx,5xx'
+fn update_request_log(state: &ServerState, req: &HttpRequest<'_>) -> HttpResponse {
+    let sample = match req.param("sample").map(|s| s.parse::<u64>()) {
+        Some(Ok(n)) if n > 0 => Some(n),
+        Some(_) => return json_error(400, "Invalid sample"),
+        None => None,
+    };
+    let statuses = match req.param("status") {
+        Some("") => Some(None),
+        Some(s) => match StatusFilter::parse(s) {
+            Some(f) => Some(Some(f)),
+            None => return json_error(400, "Invalid status"),
+        },
+        None => None,
+    };
+    if let Some(n) = sample {
+        state.request_log.set_sample(n);
+    }
+    if let Some(f) = statuses {
+        state.request_log.set_statuses(f);
+    }
+    let json = request_log_json(state);
+    info!("Request log changed to {}", json);
+    json_response(200, json)
+}
+
+async fn respond(req: &HttpRequest<'_>, state: &ServerState) -> HttpResponse {
+    let path = req.path.as_ref();
+    match (req.method, path) {
+        ("GET", ADMIN_CONFIG) => json_response(200, config_json(state).await),
+        ("GET", ADMIN_CACHE) => match select_sites(state, req) {
+            Some(sites) => json_response(200, cache_json(&sites).await),
+            None => json_error(404, "Unknown site"),
+        },
+        ("POST", ADMIN_CACHE_PURGE) => {
+            let target = match (req.param("path"), req.param("prefix")) {
+                (Some(""), _) | (_, Some("")) => return json_error(400, "Empty path"),
+                (Some(_), Some(_)) => return json_error(400, "Both path and prefix given"),
+                (Some(p), None) => Purge::Path(p),
+                (None, Some(p)) => Purge::Prefix(p),
+                (None, None) => Purge::All,
+            };
+            purge_response(state, req, target).await
+        }
+
+        // Purge by the path of the request, e.g. PURGE /index.html, or by prefix with a trailing *
+        ("PURGE", p) => {
+            let target = match p.strip_suffix('*') {
+                Some(prefix) => Purge::Prefix(prefix),
+                None => Purge::Path(p),
+            };
+            purge_response(state, req, target).await
+        }
+        ("POST", ADMIN_MAP_RELOAD) => match select_sites(state, req) {
+            Some(sites) => {
+                for site in &sites {
+                    info!("Reloading map of {}...", site.display_name());
+                    site.reload_request_map().await;
+                }
+                json_response(200, format!("{{\"reloaded\":{}}}", sites.len()))
+            }
+            None => json_error(404, "Unknown site"),
+        },
+        ("GET", ADMIN_LOG_LEVEL) => json_response(
+            200,
+            format!(
+                "{{\"level\":{}}}",
+                json_escape(&log::get_log_filter().to_string())
+            ),
+        ),
+        ("POST", ADMIN_LOG_LEVEL) => match req.param("level").and_then(LogFilter::parse) {
+            Some(filter) => {
+                info!("Log level changed to {}", &filter);
+                let json = format!("{{\"level\":{}}}", json_escape(&filter.to_string()));
+                log::update_log_filter(filter);
+                json_response(200, json)
+            }
+            None => json_error(400, "Invalid level"),
+        },
+        ("GET", ADMIN_LOG_REQUESTS) => json_response(200, request_log_json(state)),
+        ("POST", ADMIN_LOG_REQUESTS) => update_request_log(state, req),
+        ("GET", ADMIN_CONNECTIONS) => json_response(200, connections_json(state)),
+        (
+            _,
+            ADMIN_CONFIG | ADMIN_CACHE | ADMIN_CACHE_PURGE | ADMIN_MAP_RELOAD | ADMIN_LOG_LEVEL
+            | ADMIN_LOG_REQUESTS | ADMIN_CONNECTIONS,
+        ) => json_error(405, status_reason(405)),
+        _ => json_error(404, status_reason(404)),
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &ServerState) -> io::Result<()> {
+    let (r_stream, mut w_stream) = stream.split();
+    let mut r_stream = BufReader::new(r_stream);
+
+    // Parameters are passed in the query, so the body is never read and the connection is closed after the response
+    let header_buff = read_timeout(
+        state.header_timeout,
+        None,
+        read_headers_buff(&mut r_stream, state.max_header_size),
+    )
+    .await?;
+    let header = String::from_utf8_lossy(&header_buff);
+    let mut res = match HttpRequest::parse(&header) {
+        Ok(req) => {
+            let res = respond(&req, state).await;
+            debug!("{} {} -> {}", req.method, req.path, res.status_line());
+            res
+        }
+        Err(e) => json_error(400, &e.to_string()),
+    };
+    res.write_to(&mut w_stream, false).await?;
+    stream.shutdown().await
+}
+
+// Serve the admin API on a separate listener, apart from the public accept loop
+pub async fn listen(addr: SocketAddr, state: Arc<ServerState>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("admin socket binded @{}", listener.local_addr()?);
+    task::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Admin connection error: {}", e);
+                    continue;
+                }
+            };
+            debug!("admin connection from: {}", &addr);
+            let state = state.clone();
+            task::spawn(async move {
+                if let Err(e) = handle_connection(stream, &state).await {
+                    error!("Error: {}, {}", &addr, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
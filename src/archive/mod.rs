@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    io::{Read, SeekFrom},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use flate2::read::DeflateDecoder;
+use tokio::{
+    fs::File,
+    io::{self, AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{debug, filecache::Validator, log_ctx};
+
+// Tar format constants
+const TAR_EXT: &str = "tar";
+const TAR_BLOCK_SIZE: u64 = 512;
+const TAR_TYPE_FILE: u8 = b'0';
+const TAR_TYPE_FILE_OLD: u8 = 0;
+const TAR_TYPE_GNU_LONG_NAME: u8 = b'L';
+const TAR_USTAR_MAGIC: &[u8] = b"ustar";
+
+// Zip format constants
+const ZIP_EXT: &str = "zip";
+const ZIP_EOCD_SIG: u32 = 0x06054b50;
+const ZIP_EOCD_SIZE: usize = 22;
+const ZIP_EOCD_MAX_SEARCH: u64 = 0xFFFF + ZIP_EOCD_SIZE as u64;
+const ZIP_CENTRAL_SIG: u32 = 0x02014b50;
+const ZIP_CENTRAL_SIZE: usize = 46;
+const ZIP_LOCAL_SIG: u32 = 0x04034b50;
+const ZIP_LOCAL_SIZE: usize = 30;
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+
+log_ctx!("Archive");
+
+#[derive(Debug, Clone, Copy)]
+enum Compression {
+    Stored,
+    Deflate(u64), // Contains the uncompressed size
+}
+
+#[derive(Debug)]
+struct ArchiveEntry {
+    offset: u64,
+    size: u64,
+    compression: Compression,
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    entries: HashMap<String, ArchiveEntry>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn le_u16(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn le_u32(b: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+}
+
+// Convert a path to the entry name used in the index, e.g. ./a/b.txt -> a/b.txt
+pub fn entry_name(path: &Path) -> String {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Parse a nul or space terminated octal number of a tar header
+fn tar_octal(field: &[u8]) -> io::Result<u64> {
+    let s = field
+        .iter()
+        .take_while(|&&b| b != 0 && b != b' ')
+        .map(|&b| b as char)
+        .collect::<String>();
+    match s.trim().is_empty() {
+        true => Ok(0),
+        false => u64::from_str_radix(s.trim(), 8).map_err(|_| invalid_data("Invalid tar number")),
+    }
+}
+
+// Read a nul terminated string of a tar header
+fn tar_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+impl Archive {
+    pub fn is_archive(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some(TAR_EXT | ZIP_EXT)
+        ) && path.is_file()
+    }
+
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path).await?;
+        let entries = match path.extension().and_then(|e| e.to_str()) {
+            Some(TAR_EXT) => Self::index_tar(&mut file).await?,
+            Some(ZIP_EXT) => Self::index_zip(&mut file).await?,
+            _ => return Err(invalid_data("Unsupported archive format")),
+        };
+        debug!("Indexed {} entries in {}", entries.len(), path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            modified: file.metadata().await?.modified().ok(),
+            entries,
+        })
+    }
+
+    async fn index_tar(file: &mut File) -> io::Result<HashMap<String, ArchiveEntry>> {
+        let mut entries = HashMap::new();
+        let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+        let mut offset = 0u64;
+        let mut long_name = None;
+        loop {
+            // Read header, an empty block marks the end of archive
+            file.seek(SeekFrom::Start(offset)).await?;
+            match file.read_exact(&mut header).await {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            // Parse name, size and type
+            let size = tar_octal(&header[124..136])?;
+            let data_offset = offset + TAR_BLOCK_SIZE;
+            let type_flag = header[156];
+            let name = match long_name.take() {
+                Some(n) => n,
+                None => match &header[257..262] == TAR_USTAR_MAGIC && header[345] != 0 {
+                    true => format!(
+                        "{}/{}",
+                        tar_str(&header[345..500]),
+                        tar_str(&header[0..100])
+                    ),
+                    false => tar_str(&header[0..100]),
+                },
+            };
+
+            // Add regular files to index
+            match type_flag {
+                TAR_TYPE_FILE | TAR_TYPE_FILE_OLD => {
+                    let entry = ArchiveEntry {
+                        offset: data_offset,
+                        size,
+                        compression: Compression::Stored,
+                    };
+                    entries.insert(entry_name(Path::new(&name)), entry);
+                }
+                TAR_TYPE_GNU_LONG_NAME => {
+                    let mut buf = vec![0u8; size as usize];
+                    file.read_exact(&mut buf).await?;
+                    long_name = Some(tar_str(&buf));
+                }
+                _ => (), // Skip directories, links and extended headers
+            }
+
+            // Move to next header, data is padded to block size
+            offset = data_offset + size.div_ceil(TAR_BLOCK_SIZE) * TAR_BLOCK_SIZE;
+        }
+        Ok(entries)
+    }
+
+    async fn index_zip(file: &mut File) -> io::Result<HashMap<String, ArchiveEntry>> {
+        // Find end of central directory from the end of file
+        let file_len = file.metadata().await?.len();
+        let search_len = file_len.min(ZIP_EOCD_MAX_SEARCH);
+        let mut tail = vec![0u8; search_len as usize];
+        file.seek(SeekFrom::Start(file_len - search_len)).await?;
+        file.read_exact(&mut tail).await?;
+        if tail.len() < ZIP_EOCD_SIZE {
+            return Err(invalid_data("Invalid zip file"));
+        }
+        let eocd = (0..=tail.len() - ZIP_EOCD_SIZE)
+            .rev()
+            .find(|&i| le_u32(&tail, i) == ZIP_EOCD_SIG)
+            .ok_or_else(|| invalid_data("Cannot find zip end of central directory"))?;
+        let entry_count = le_u16(&tail, eocd + 10) as usize;
+        let cd_size = le_u32(&tail, eocd + 12) as usize;
+        let cd_offset = le_u32(&tail, eocd + 16) as u64;
+
+        // Read central directory
+        let mut cd = vec![0u8; cd_size];
+        file.seek(SeekFrom::Start(cd_offset)).await?;
+        file.read_exact(&mut cd).await?;
+
+        let mut entries = HashMap::new();
+        let mut i = 0;
+        for _ in 0..entry_count {
+            if i + ZIP_CENTRAL_SIZE > cd.len() || le_u32(&cd, i) != ZIP_CENTRAL_SIG {
+                return Err(invalid_data("Invalid zip central directory"));
+            }
+            let method = le_u16(&cd, i + 10);
+            let compressed_size = le_u32(&cd, i + 20) as u64;
+            let uncompressed_size = le_u32(&cd, i + 24) as u64;
+            let name_len = le_u16(&cd, i + 28) as usize;
+            let extra_len = le_u16(&cd, i + 30) as usize;
+            let comment_len = le_u16(&cd, i + 32) as usize;
+            let local_offset = le_u32(&cd, i + 42) as u64;
+            let name_end = (i + ZIP_CENTRAL_SIZE + name_len).min(cd.len());
+            let name = String::from_utf8_lossy(&cd[i + ZIP_CENTRAL_SIZE..name_end]).into_owned();
+            i += ZIP_CENTRAL_SIZE + name_len + extra_len + comment_len;
+
+            // Skip directories
+            if name.ends_with('/') {
+                continue;
+            }
+
+            // Resolve data offset from local header as its extra field may differ
+            let mut local = [0u8; ZIP_LOCAL_SIZE];
+            file.seek(SeekFrom::Start(local_offset)).await?;
+            file.read_exact(&mut local).await?;
+            if le_u32(&local, 0) != ZIP_LOCAL_SIG {
+                return Err(invalid_data("Invalid zip local header"));
+            }
+            let data_offset = local_offset
+                + ZIP_LOCAL_SIZE as u64
+                + le_u16(&local, 26) as u64
+                + le_u16(&local, 28) as u64;
+
+            // Add to index
+            let compression = match method {
+                ZIP_METHOD_STORED => Compression::Stored,
+                ZIP_METHOD_DEFLATE => Compression::Deflate(uncompressed_size),
+                _ => {
+                    debug!("Skipping {} with unsupported compression {}", name, method);
+                    continue;
+                }
+            };
+            let entry = ArchiveEntry {
+                offset: data_offset,
+                size: compressed_size,
+                compression,
+            };
+            entries.insert(entry_name(Path::new(&name)), entry);
+        }
+        Ok(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Resolve the entry name of a path, directories are resolved to their index file
+    pub fn resolve(&self, path: &Path, indexes: &[String]) -> Option<String> {
+        let name = entry_name(path);
+        if self.entries.contains_key(&name) {
+            return Some(name);
+        }
+        indexes
+            .iter()
+            .map(|index| match name.is_empty() {
+                true => index.to_string(),
+                false => format!("{}/{}", name, index),
+            })
+            .find(|index| self.entries.contains_key(index))
+    }
+
+    // Whether entries are stored under the path, folders have no entries of their own
+    pub fn is_dir(&self, path: &Path) -> bool {
+        let name = entry_name(path);
+        if name.is_empty() {
+            return true;
+        }
+        let prefix = format!("{}/", name);
+        !self.entries.contains_key(&name) && self.entries.keys().any(|n| n.starts_with(&prefix))
+    }
+
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    // Entry names with their uncompressed sizes
+    pub fn entries(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.entries.iter().map(|(n, e)| {
+            let size = match e.compression {
+                Compression::Stored => e.size,
+                Compression::Deflate(size) => size,
+            };
+            (n.as_str(), size)
+        })
+    }
+
+    // Entries share the modified time of the archive
+    pub fn validator(&self, name: &str) -> Option<Validator> {
+        let entry = self.entries.get(name)?;
+        let size = match entry.compression {
+            Compression::Stored => entry.size,
+            Compression::Deflate(size) => size,
+        };
+        Some(Validator {
+            modified: self.modified?,
+            size: size as usize,
+        })
+    }
+
+    pub async fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        let entry = self
+            .entries
+            .get(name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name.to_string()))?;
+
+        // Read raw data of the entry
+        let mut file = File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(entry.offset)).await?;
+        let mut buf = vec![0u8; entry.size as usize];
+        file.read_exact(&mut buf).await?;
+
+        // Decompress if required
+        match entry.compression {
+            Compression::Stored => Ok(buf),
+            Compression::Deflate(size) => {
+                let mut res = Vec::with_capacity(size as usize);
+                DeflateDecoder::new(buf.as_slice()).read_to_end(&mut res)?;
+                Ok(res)
+            }
+        }
+    }
+}
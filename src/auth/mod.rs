@@ -0,0 +1,89 @@
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+use tokio::{fs::read_to_string, io, task::spawn_blocking};
+
+use crate::{
+    http::{HttpRequest, HttpResponse},
+    log_ctx, trace,
+    util::base64_decode,
+    warn,
+};
+
+// Credential files are in the htpasswd format, one user per line
+// Sample of bcrypt entry: alice:$2y$10$...
+// Sample of sha1 entry: bob:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=
+const AUTH_SCHEME: &str = "Basic";
+const AUTH_USER_DELIM: char = ':';
+const AUTH_COMMENT_PREFIX: char = '#';
+const AUTH_BCRYPT_PREFIXES: &[&str] = &["$2y$", "$2b$", "$2a$"];
+const AUTH_SHA1_PREFIX: &str = "{SHA}";
+
+log_ctx!("Auth");
+
+// Decode the user and password of the Basic authorization header
+fn credentials(http_request: &HttpRequest<'_>) -> Option<(String, String)> {
+    let (scheme, token) = http_request
+        .header("Authorization")?
+        .trim()
+        .split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case(AUTH_SCHEME) {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(token.trim())?).ok()?;
+    let (user, password) = decoded.split_once(AUTH_USER_DELIM)?;
+    Some((user.to_string(), password.to_string()))
+}
+
+// Compare without short circuiting so that the time taken does not reveal the matching prefix
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn verify(user: &str, password: String, hash: &str) -> bool {
+    if AUTH_BCRYPT_PREFIXES.iter().any(|p| hash.starts_with(p)) {
+        // bcrypt is slow by design, keep it off the async workers
+        let hash = hash.to_string();
+        return spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+    }
+    if let Some(expected) = hash.strip_prefix(AUTH_SHA1_PREFIX).and_then(base64_decode) {
+        let digest = Sha1::digest(password.as_bytes());
+        return constant_time_eq(&digest, &expected);
+    }
+    warn!("Unsupported password hash format for user {}", user);
+    false
+}
+
+// Whether the request carries credentials of a user in the credential file
+pub async fn is_authorized(http_request: &HttpRequest<'_>, users_file: &Path) -> io::Result<bool> {
+    let (user, password) = match credentials(http_request) {
+        Some(c) => c,
+        None => return Ok(false),
+    };
+    let users = read_to_string(users_file).await?;
+    let hash = users
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with(AUTH_COMMENT_PREFIX))
+        .filter_map(|l| l.split_once(AUTH_USER_DELIM))
+        .find(|(u, _)| *u == user)
+        .map(|(_, h)| h);
+    let authorized = match hash {
+        Some(h) => verify(&user, password, h).await,
+        None => false,
+    };
+    trace!("User {} authorized: {}", user, authorized);
+    Ok(authorized)
+}
+
+pub fn unauthorized(realm: &str) -> HttpResponse {
+    let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+    HttpResponse::new(401)
+        .with_header(
+            "WWW-Authenticate",
+            format!("{} realm=\"{}\", charset=\"UTF-8\"", AUTH_SCHEME, realm),
+        )
+        .with_text("text/plain", crate::http::status_reason(401))
+}
@@ -0,0 +1,73 @@
+use std::{
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+};
+
+// New buffers start with the size of a typical request header
+const POOLED_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+
+// Buffers grown beyond this size, e.g. by a large body, are freed instead of pooled
+const POOLED_BUFF_MAX_SIZE: usize = crate::BUFF_INIT_SIZE * 64;
+
+// Buffers kept for reuse by every worker thread
+const POOL_MAX_COUNT: usize = 256;
+
+// Every worker thread keeps its own pool so that taking a buffer never waits on a lock
+// Tasks may move between threads, buffers are then returned to the pool of the thread dropping them
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+// A buffer which is cleared and returned to the pool once dropped
+#[derive(Debug, Default)]
+pub struct PooledBuf(Vec<u8>);
+
+// Take an empty buffer from the pool of the thread, or allocate one if the pool is empty
+pub fn take() -> PooledBuf {
+    let buf = POOL.try_with(|p| p.borrow_mut().pop()).ok().flatten();
+    PooledBuf(buf.unwrap_or_else(|| Vec::with_capacity(POOLED_BUFF_INIT_SIZE)))
+}
+
+impl From<Vec<u8>> for PooledBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        Self(buf)
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let capacity = self.0.capacity();
+        if capacity == 0 || capacity > POOLED_BUFF_MAX_SIZE {
+            return;
+        }
+        // The pool is gone while the thread exits
+        let _ = POOL.try_with(|p| {
+            let mut pool = p.borrow_mut();
+            if pool.len() < POOL_MAX_COUNT {
+                let mut buf = std::mem::take(&mut self.0);
+                buf.clear();
+                pool.push(buf);
+            }
+        });
+    }
+}
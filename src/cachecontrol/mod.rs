@@ -0,0 +1,135 @@
+use std::{fmt::Display, time::Duration};
+
+use crate::{http::HttpResponse, util::parse_duration};
+
+// Define rule delimiters
+// Sample of rules: /assets/*.js=immutable, *.html=no-cache and /docs/**=10m
+const RULE_DELIM: char = '=';
+const PATH_DELIM: char = '/';
+const IMMUTABLE_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+const CACHE_CONTROL_HEADER: &str = "Cache-Control";
+
+// How long clients may reuse a response without asking again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    NoCache,          // Revalidated with the validators on every use
+    NoStore,          // Never kept by the client
+    Immutable,        // Kept for a year, for files with a hash in the name
+    MaxAge(Duration), // Sample: 10m, 1h or 7d
+}
+
+impl CachePolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "no-cache" => Some(CachePolicy::NoCache),
+            "no-store" => Some(CachePolicy::NoStore),
+            "immutable" => Some(CachePolicy::Immutable),
+            s => parse_duration(s).map(CachePolicy::MaxAge),
+        }
+    }
+
+    fn header_value(&self) -> String {
+        match self {
+            CachePolicy::NoCache => "no-cache".to_string(),
+            CachePolicy::NoStore => "no-store".to_string(),
+            CachePolicy::Immutable => {
+                format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE.as_secs())
+            }
+            CachePolicy::MaxAge(d) => format!("public, max-age={}", d.as_secs()),
+        }
+    }
+
+    // Successful responses which do not set the header themselves are given the policy
+    pub fn apply(&self, res: HttpResponse) -> HttpResponse {
+        let is_success = matches!(res.status(), 200 | 203 | 206 | 304);
+        match is_success && res.header(CACHE_CONTROL_HEADER).is_none() {
+            true => res.with_header(CACHE_CONTROL_HEADER, self.header_value()),
+            false => res,
+        }
+    }
+}
+
+impl Display for CachePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CachePolicy::NoCache => write!(f, "no-cache"),
+            CachePolicy::NoStore => write!(f, "no-store"),
+            CachePolicy::Immutable => write!(f, "immutable"),
+            CachePolicy::MaxAge(d) => write!(f, "{}s", d.as_secs()),
+        }
+    }
+}
+
+// Policy of the paths matching a pattern
+// * matches within a segment, ** across segments and ? a single character
+// Patterns without a slash are matched against the file name, wherever it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheRule {
+    pattern: String,
+    policy: CachePolicy,
+}
+
+impl CacheRule {
+    // Sample: /assets/*.js=immutable
+    pub fn parse(s: &str) -> Option<Self> {
+        let (pattern, policy) = s.split_once(RULE_DELIM)?;
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(Self {
+            pattern: pattern.to_string(),
+            policy: CachePolicy::parse(policy.trim())?,
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        pattern_matches(&self.pattern, path)
+    }
+}
+
+// Patterns without a slash are matched against the file name, wherever it is
+pub fn pattern_matches(pattern: &str, path: &str) -> bool {
+    match pattern.contains(PATH_DELIM) {
+        true => glob_match(pattern.as_bytes(), path.as_bytes()),
+        false => {
+            let name = path.rsplit(PATH_DELIM).next().unwrap_or_default();
+            glob_match(pattern.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+impl Display for CacheRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.pattern, RULE_DELIM, self.policy)
+    }
+}
+
+// Rules in the order given, the first matching the path is used
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheRules {
+    pub rules: Vec<CacheRule>,
+}
+
+impl CacheRules {
+    pub fn policy_for(&self, path: &str) -> Option<&CachePolicy> {
+        self.rules
+            .iter()
+            .find(|r| r.matches(path))
+            .map(|r| &r.policy)
+    }
+}
+
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern {
+        [] => s.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        [b'*', rest @ ..] => {
+            // Stop at the end of the segment
+            let end = s.iter().position(|&c| c == b'/').unwrap_or(s.len());
+            (0..=end).any(|i| glob_match(rest, &s[i..]))
+        }
+        [b'?', rest @ ..] => matches!(s, [c, ..] if *c != b'/') && glob_match(rest, &s[1..]),
+        [c, rest @ ..] => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
@@ -0,0 +1,198 @@
+use std::{net::SocketAddr, path::Path, process::Stdio};
+
+use tokio::{
+    io::{self, AsyncWriteExt},
+    process::Command,
+    task,
+};
+
+use crate::{
+    debug,
+    http::{status_reason, HttpRequest, HttpResponse, ResponseBody},
+    log_ctx, warn,
+};
+
+// Sample of script output:
+// Status: 404 Not Found
+// Content-Type: text/plain
+//
+// Not here
+const CGI_VERSION: &str = "CGI/1.1";
+const CGI_STATUS_HEADER: &str = "Status";
+const CGI_LOCATION_HEADER: &str = "Location";
+const SERVER_SOFTWARE: &str = concat!("http-server/", env!("CARGO_PKG_VERSION"));
+
+// Headers which are passed in their own variables, or must not reach the script, e.g. httpoxy
+const SKIPPED_ENV_HEADERS: &[&str] = &["Content-Length", "Content-Type", "Proxy"];
+
+// Headers of the output which are set by the server for the connection
+const SKIPPED_OUTPUT_HEADERS: &[&str] = &[
+    "Content-Length",
+    "Connection",
+    "Keep-Alive",
+    "Transfer-Encoding",
+];
+
+log_ctx!("CGI");
+
+pub fn bad_gateway() -> HttpResponse {
+    HttpResponse::new(502).with_text("text/plain", status_reason(502))
+}
+
+// Meta variables of the request, shared with FastCGI
+pub fn variables(
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+) -> Vec<(String, String)> {
+    let host = req.header("Host").unwrap_or_default();
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((n, p)) if p.parse::<u16>().is_ok() && (!n.contains(':') || n.ends_with(']')) => {
+            (n, p)
+        }
+        _ => (host, ""),
+    };
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE", CGI_VERSION.to_string()),
+        ("SERVER_SOFTWARE", SERVER_SOFTWARE.to_string()),
+        ("SERVER_PROTOCOL", req.protocol.to_string()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", req.method.to_string()),
+        ("SCRIPT_NAME", req.path.to_string()),
+        ("PATH_INFO", String::new()),
+        ("QUERY_STRING", req.query.unwrap_or_default().to_string()),
+        ("REMOTE_ADDR", sockaddr.ip().to_string()),
+        ("REMOTE_PORT", sockaddr.port().to_string()),
+        ("CONTENT_LENGTH", body.map_or(0, |b| b.len()).to_string()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect::<Vec<_>>();
+    if let Some(t) = req.header("Content-Type") {
+        vars.push(("CONTENT_TYPE".to_string(), t.to_string()));
+    }
+    for (k, v) in &req.headers {
+        if SKIPPED_ENV_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(k))
+        {
+            continue;
+        }
+        let k = format!("HTTP_{}", k.to_ascii_uppercase().replace('-', "_"));
+        vars.push((k, v.to_string()));
+    }
+    vars
+}
+
+// Run the program with the request in the CGI environment and the body on its stdin
+pub async fn execute(
+    program: &Path,
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+) -> HttpResponse {
+    let mut cmd = Command::new(program);
+    if let Some(dir) = program.parent() {
+        cmd.current_dir(dir);
+    }
+    cmd.env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .envs(variables(sockaddr, req, body));
+
+    // The child is killed if the request is dropped, e.g. by the request timeout
+    cmd.stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Failed to execute {}: {}", program.display(), e);
+            return bad_gateway();
+        }
+    };
+
+    // Write the body while the output is read, so that neither side blocks on a full pipe
+    let stdin = child.stdin.take();
+    let body = body.map(|b| b.to_vec()).unwrap_or_default();
+    let writer = task::spawn(async move {
+        if let Some(mut stdin) = stdin {
+            stdin.write_all(&body).await?;
+        }
+        io::Result::Ok(())
+    });
+    let output = match child.wait_with_output().await {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("Failed to read the output of {}: {}", program.display(), e);
+            return bad_gateway();
+        }
+    };
+    if let Ok(Err(e)) = writer.await {
+        debug!("Script {} did not read its input: {}", program.display(), e);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("{}: {}", program.display(), line);
+    }
+    if !output.status.success() {
+        warn!("Script {} exited with {}", program.display(), output.status);
+    }
+    match parse_output(output.stdout) {
+        Some(res) => res,
+        None => {
+            warn!("Invalid output of {}", program.display());
+            bad_gateway()
+        }
+    }
+}
+
+// Split the output into the headers and the body, None if it has no header
+pub fn parse_output(mut out: Vec<u8>) -> Option<HttpResponse> {
+    // The headers end at the first empty line, with either line ending, or at the end of the output
+    let (end, body_start) = out
+        .windows(2)
+        .enumerate()
+        .find_map(|(i, w)| match w {
+            b"\n\n" => Some((i, i + 2)),
+            b"\n\r" if out.get(i + 2) == Some(&b'\n') => Some((i, i + 3)),
+            _ => None,
+        })
+        .unwrap_or((out.len(), out.len()));
+    let header = String::from_utf8_lossy(&out[..end]).into_owned();
+    let body = out.split_off(body_start);
+
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in header.lines() {
+        let (k, v) = line.split_once(':')?;
+        let (k, v) = (k.trim(), v.trim());
+        if k.eq_ignore_ascii_case(CGI_STATUS_HEADER) {
+            let code = v.split_whitespace().next()?;
+            status = Some(
+                code.parse::<u16>()
+                    .ok()
+                    .filter(|s| (100..=599).contains(s))?,
+            );
+        } else if !SKIPPED_OUTPUT_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(k))
+        {
+            headers.push((k.to_string(), v.to_string()));
+        }
+    }
+    if headers.is_empty() && status.is_none() {
+        return None;
+    }
+
+    // Scripts answering with a location only are redirects
+    let is_redirect = headers
+        .iter()
+        .any(|(k, _)| k.eq_ignore_ascii_case(CGI_LOCATION_HEADER));
+    let status = status.unwrap_or(if is_redirect { 302 } else { 200 });
+    let mut res = HttpResponse::new(status);
+    for (k, v) in headers {
+        res = res.with_header(k, v);
+    }
+    Some(res.with_body(ResponseBody::Bytes(body)))
+}
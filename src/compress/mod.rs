@@ -0,0 +1,170 @@
+use std::{io::Write, path::Path};
+
+use flate2::{write::GzEncoder, Compression};
+use tokio::io;
+
+use crate::{
+    filecache::AbstractFile,
+    http::{HttpRequest, HttpResponse, ResponseBody},
+    log_ctx, trace,
+};
+
+// Small bodies do not benefit from compression, large ones would have to be buffered in memory
+const COMPRESS_MIN_BODY_SIZE: usize = 256;
+const COMPRESS_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+const COMPRESSIBLE_EXTS: &[&str] = &[
+    "html", "htm", "css", "js", "mjs", "json", "txt", "xml", "svg", "md", "csv", "map",
+];
+const COMPRESSIBLE_TYPES: &[&str] = &["json", "javascript", "xml", "svg"];
+const PRECOMPRESSED_EXTS: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+log_ctx!("Compress");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder =
+                    GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                let mut res = Vec::with_capacity(data.len() / 2);
+                let params = brotli::enc::BrotliEncoderParams::default();
+                brotli::BrotliCompress(&mut &data[..], &mut res, &params)?;
+                Ok(res)
+            }
+        }
+    }
+}
+
+// Whether the Accept-Encoding header accepts the encoding, encodings with q=0 are refused
+fn accepts(accept: &str, name: &str) -> bool {
+    accept.split(',').any(|token| {
+        let mut params = token.split(';').map(|p| p.trim());
+        let coding = params.next().unwrap_or_default();
+        let refused = params
+            .filter_map(|p| p.strip_prefix("q="))
+            .any(|q| q.parse::<f32>().is_ok_and(|q| q == 0.));
+        (coding.eq_ignore_ascii_case(name) || coding == "*") && !refused
+    })
+}
+
+// Choose the preferred supported encoding of the Accept-Encoding header
+fn negotiate(accept: &str) -> Option<Encoding> {
+    #[cfg(feature = "brotli")]
+    if accepts(accept, Encoding::Brotli.name()) {
+        return Some(Encoding::Brotli);
+    }
+    accepts(accept, Encoding::Gzip.name()).then_some(Encoding::Gzip)
+}
+
+// Encodings of precompressed sibling files accepted by the client with the extension of the sibling, preferred first
+// Siblings are served as they are, so brotli does not need the brotli feature
+pub fn precompressed(http_request: &HttpRequest<'_>) -> Vec<(&'static str, &'static str)> {
+    let accept = http_request.header("Accept-Encoding").unwrap_or_default();
+    PRECOMPRESSED_EXTS
+        .iter()
+        .filter(|(name, _)| accepts(accept, name))
+        .copied()
+        .collect()
+}
+
+// Mark the ETag of an encoded representation as weak, as its bytes differ from the file
+pub fn weaken_etag(res: HttpResponse) -> HttpResponse {
+    match res.header("ETag").filter(|e| !e.starts_with("W/")) {
+        Some(etag) => {
+            let weak = format!("W/{}", etag);
+            res.without_header("ETag").with_header("ETag", weak)
+        }
+        None => res,
+    }
+}
+
+// Text-like content is detected by the content type if given, otherwise by the file extension
+fn is_compressible(res: &HttpResponse, file_path: Option<&Path>) -> bool {
+    match res.header("Content-Type") {
+        Some(t) => {
+            let t = t.to_ascii_lowercase();
+            t.starts_with("text/") || COMPRESSIBLE_TYPES.iter().any(|c| t.contains(c))
+        }
+        None => file_path
+            .and_then(|p| p.extension())
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| COMPRESSIBLE_EXTS.contains(&e.to_ascii_lowercase().as_str())),
+    }
+}
+
+pub async fn apply(
+    res: HttpResponse,
+    http_request: &HttpRequest<'_>,
+    file_path: Option<&Path>,
+) -> io::Result<HttpResponse> {
+    // Only full responses of text-like content are compressed
+    if res.status() != 200 || res.header("Content-Encoding").is_some() {
+        return Ok(res);
+    }
+    if !is_compressible(&res, file_path) {
+        return Ok(res);
+    }
+    let mut res = res.with_header("Vary", "Accept-Encoding");
+    let len = res.body_len();
+    let encoding = match http_request.header("Accept-Encoding").and_then(negotiate) {
+        Some(e) if (COMPRESS_MIN_BODY_SIZE..=COMPRESS_MAX_BODY_SIZE).contains(&len) => e,
+        _ => return Ok(res),
+    };
+
+    // Reuse the compressed variant stored alongside the cached file
+    let body = res.take_body();
+    let variants = match &body {
+        ResponseBody::File(f) => f.variants(),
+        _ => None,
+    };
+    let cached = variants.as_ref().and_then(|v| v.get(encoding.name()));
+    let compressed = match cached {
+        Some(c) => {
+            trace!("Using cached {} variant", encoding.name());
+            c
+        }
+        None => {
+            // Keep the original if compression does not help
+            let data = body.into_bytes().await?;
+            let compressed = encoding.compress(&data)?;
+            if compressed.len() >= data.len() {
+                return Ok(res.with_body(ResponseBody::Bytes(data)));
+            }
+            trace!(
+                "Compressed {} bytes to {} bytes with {}",
+                data.len(),
+                compressed.len(),
+                encoding.name()
+            );
+            let compressed = compressed.into();
+            if let Some(v) = &variants {
+                v.insert(encoding.name(), &compressed);
+            }
+            compressed
+        }
+    };
+
+    Ok(weaken_etag(res)
+        .with_header("Content-Encoding", encoding.name())
+        .with_body(ResponseBody::File(AbstractFile::from(compressed))))
+}
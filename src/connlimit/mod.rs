@@ -0,0 +1,100 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep_until, Instant},
+};
+
+use crate::{info, log_ctx, metrics::Metrics, warn};
+
+log_ctx!("ConnLimit");
+
+// Caps the connections served at once and the rate they are accepted at
+// Accept loops wait before accepting, so that pending connections queue in the listen backlog instead of as tasks
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    slots: Option<Arc<Semaphore>>,
+    saturated: AtomicBool, // Whether the limit was reached since the last slot was free right away
+    rate: Option<AcceptRate>,
+}
+
+// Spaces out accepts evenly, bursts of up to a second of accepts pass without waiting
+#[derive(Debug)]
+struct AcceptRate {
+    interval: Duration,
+    burst: Duration,
+    next: Mutex<Instant>, // Time the next accept is due at if accepts were evenly spaced
+}
+
+impl ConnectionLimit {
+    // Zero is unlimited for both the connections and the accepts per second
+    pub fn new(max: usize, rate: u32) -> Self {
+        let rate = (rate > 0).then(|| {
+            let interval = Duration::from_secs(1) / rate;
+            AcceptRate {
+                interval,
+                burst: Duration::from_secs(1) - interval,
+                next: Mutex::new(Instant::now()),
+            }
+        });
+        Self {
+            max,
+            slots: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            saturated: AtomicBool::new(false),
+            rate,
+        }
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.slots.as_ref().map(|_| self.max)
+    }
+
+    // Wait until the next connection may be accepted, the slot is taken until the permit is dropped
+    pub async fn admit(&self, metrics: &Metrics) -> Option<OwnedSemaphorePermit> {
+        if let Some(rate) = &self.rate {
+            rate.wait().await;
+        }
+        let slots = self.slots.as_ref()?;
+        if let Ok(permit) = slots.clone().try_acquire_owned() {
+            if self.saturated.swap(false, Ordering::Relaxed) {
+                info!(
+                    "Below the limit of {} connections, accepting right away again",
+                    self.max
+                );
+            }
+            return Some(permit);
+        }
+
+        // Warn once until a slot is free right away again
+        if !self.saturated.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Limit of {} connections reached, new connections wait in the backlog",
+                self.max
+            );
+        }
+        metrics.record_connection_limit();
+        slots.clone().acquire_owned().await.ok()
+    }
+}
+
+impl AcceptRate {
+    async fn wait(&self) {
+        let now = Instant::now();
+        let due = {
+            let mut next = self.next.lock().unwrap();
+            let due = (*next).max(now);
+            *next = due + self.interval;
+            due
+        };
+        if let Some(at) = due.checked_sub(self.burst).filter(|at| *at > now) {
+            sleep_until(at).await;
+        }
+    }
+}
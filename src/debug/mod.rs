@@ -0,0 +1,82 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{
+    http::{HttpRequest, HttpResponse},
+    log_ctx, trace,
+    util::{json_escape, parse_duration},
+};
+
+// Built-in endpoints for using the server as a test target
+// Sample: /__debug/echo, /__debug/ip, /__debug/delay/500ms
+pub const DEBUG_PATH_PREFIX: &str = "/__debug/";
+const DEBUG_ECHO: &str = "echo";
+const DEBUG_IP: &str = "ip";
+const DEBUG_DELAY: &str = "delay/";
+const DEBUG_MAX_DELAY: Duration = Duration::from_secs(60);
+const JSON_CONTENT_TYPE: &str = "application/json";
+const STRING_INIT_SIZE: usize = crate::BUFF_INIT_SIZE;
+
+log_ctx!("Debug");
+
+pub async fn handle(
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+) -> HttpResponse {
+    let endpoint = &req.path[DEBUG_PATH_PREFIX.len()..];
+    trace!("Debug endpoint requested: {}", endpoint);
+
+    // Echo the request back as json
+    if endpoint == DEBUG_ECHO {
+        return HttpResponse::new(200).with_text(JSON_CONTENT_TYPE, echo_json(req, body));
+    }
+
+    // Return the address of the client
+    if endpoint == DEBUG_IP {
+        let json = format!(
+            "{{\"ip\":{},\"port\":{}}}",
+            json_escape(&sockaddr.ip().to_string()),
+            sockaddr.port()
+        );
+        return HttpResponse::new(200).with_text(JSON_CONTENT_TYPE, json);
+    }
+
+    // Sleep for the given duration before responding
+    if let Some(delay) = endpoint.strip_prefix(DEBUG_DELAY) {
+        let delay = match parse_duration(delay) {
+            Some(d) if d <= DEBUG_MAX_DELAY => d,
+            _ => {
+                return HttpResponse::new(400)
+                    .with_text("text/plain", format!("Invalid delay: {}", delay))
+            }
+        };
+        tokio::time::sleep(delay).await;
+        let json = format!("{{\"delay_ms\":{}}}", delay.as_millis());
+        return HttpResponse::new(200).with_text(JSON_CONTENT_TYPE, json);
+    }
+
+    HttpResponse::not_found()
+}
+
+fn echo_json(req: &HttpRequest<'_>, body: Option<&[u8]>) -> String {
+    let mut json = String::with_capacity(STRING_INIT_SIZE);
+    json.push_str(&format!(
+        "{{\"method\":{},\"path\":{},\"protocol\":{},\"headers\":{{",
+        json_escape(req.method),
+        json_escape(&req.path),
+        json_escape(req.protocol)
+    ));
+    for (i, (key, val)) in req.headers.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(&format!("{}:{}", json_escape(key), json_escape(val)));
+    }
+    json.push_str("},\"body\":");
+    match body {
+        Some(b) => json.push_str(&json_escape(&String::from_utf8_lossy(b))),
+        None => json.push_str("null"),
+    }
+    json.push('}');
+    json
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use crate::archive::entry_name;
+
+// Table of (entry name, content) sorted by entry name, generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+
+fn get(name: &str) -> Option<&'static [u8]> {
+    EMBEDDED_FILES
+        .binary_search_by(|(n, _)| (*n).cmp(name))
+        .ok()
+        .map(|i| EMBEDDED_FILES[i].1)
+}
+
+pub fn len() -> usize {
+    EMBEDDED_FILES.len()
+}
+
+// Entry names with their sizes
+pub fn entries() -> impl Iterator<Item = (&'static str, u64)> {
+    EMBEDDED_FILES.iter().map(|(n, d)| (*n, d.len() as u64))
+}
+
+// Resolve the content of a path, directories are resolved to their index file
+pub fn resolve(path: &Path, indexes: &[String]) -> Option<&'static [u8]> {
+    let name = entry_name(path);
+    if let Some(data) = get(&name) {
+        return Some(data);
+    }
+    indexes.iter().find_map(|index| match name.is_empty() {
+        true => get(index),
+        false => get(&format!("{}/{}", name, index)),
+    })
+}
+
+// Whether files are embedded under the path, the first name after the prefix is found by the sort order
+pub fn is_dir(path: &Path) -> bool {
+    let name = entry_name(path);
+    if name.is_empty() {
+        return true;
+    }
+    let prefix = format!("{}/", name);
+    let i = EMBEDDED_FILES.partition_point(|(n, _)| *n < prefix.as_str());
+    EMBEDDED_FILES
+        .get(i)
+        .is_some_and(|(n, _)| n.starts_with(&prefix))
+}
@@ -0,0 +1,191 @@
+use std::{fmt::Display, net::SocketAddr, path::Path};
+
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+};
+
+use crate::{
+    cgi::{self, bad_gateway},
+    http::{HttpRequest, HttpResponse},
+    log_ctx, warn,
+};
+
+// Sample of FastCGI upstream: fastcgi://127.0.0.1:9000
+const FCGI_SCHEME: &str = "fastcgi://";
+
+// Records of the protocol, see the FastCGI specification 1.0
+const FCGI_VERSION: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_REQUEST_ID: u16 = 1; // A single request per connection
+const FCGI_MAX_CONTENT_LEN: usize = u16::MAX as usize;
+const FCGI_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+
+log_ctx!("FastCGI");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FastCgiUpstream {
+    host: String,
+    port: u16,
+}
+
+impl FastCgiUpstream {
+    pub fn is_upstream(s: &str) -> bool {
+        s.starts_with(FCGI_SCHEME)
+    }
+
+    pub fn parse(url: &str) -> Option<Self> {
+        let authority = url.strip_prefix(FCGI_SCHEME)?.trim_end_matches('/');
+        let (host, port) = authority.rsplit_once(':')?;
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_string(),
+            port: port.parse().ok()?,
+        })
+    }
+}
+
+impl Display for FastCgiUpstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}:{}", FCGI_SCHEME, self.host, self.port)
+    }
+}
+
+// Write a record, contents longer than a record are split over several
+async fn write_record<W: AsyncWriteExt + Unpin>(
+    w: &mut W,
+    kind: u8,
+    content: &[u8],
+) -> io::Result<()> {
+    let mut chunks = content.chunks(FCGI_MAX_CONTENT_LEN).peekable();
+    if chunks.peek().is_none() {
+        return w.write_all(&record_header(kind, 0)).await;
+    }
+    for chunk in chunks {
+        w.write_all(&record_header(kind, chunk.len() as u16))
+            .await?;
+        w.write_all(chunk).await?;
+    }
+    Ok(())
+}
+
+fn record_header(kind: u8, len: u16) -> [u8; 8] {
+    let id = FCGI_REQUEST_ID.to_be_bytes();
+    let len = len.to_be_bytes();
+    [FCGI_VERSION, kind, id[0], id[1], len[0], len[1], 0, 0]
+}
+
+// Lengths below 128 take a byte, longer ones four bytes with the high bit set
+fn push_len(buff: &mut Vec<u8>, len: usize) {
+    match len < 0x80 {
+        true => buff.push(len as u8),
+        false => buff.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes()),
+    }
+}
+
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    let mut buff = Vec::with_capacity(FCGI_BUFF_INIT_SIZE);
+    for (k, v) in params {
+        push_len(&mut buff, k.len());
+        push_len(&mut buff, v.len());
+        buff.extend_from_slice(k.as_bytes());
+        buff.extend_from_slice(v.as_bytes());
+    }
+    buff
+}
+
+// Send the request and collect the stdout of the application until it ends the request
+async fn exchange(
+    upstream: &FastCgiUpstream,
+    params: &[(String, String)],
+    body: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect((upstream.host.as_str(), upstream.port)).await?;
+    let (r_stream, w_stream) = stream.split();
+
+    // Begin the request as responder, the application closes the connection when done
+    let mut w_stream = BufWriter::new(w_stream);
+    let role = FCGI_RESPONDER.to_be_bytes();
+    write_record(
+        &mut w_stream,
+        FCGI_BEGIN_REQUEST,
+        &[role[0], role[1], 0, 0, 0, 0, 0, 0],
+    )
+    .await?;
+    write_record(&mut w_stream, FCGI_PARAMS, &encode_params(params)).await?;
+    write_record(&mut w_stream, FCGI_PARAMS, &[]).await?;
+    write_record(&mut w_stream, FCGI_STDIN, body).await?;
+    if !body.is_empty() {
+        write_record(&mut w_stream, FCGI_STDIN, &[]).await?;
+    }
+    w_stream.flush().await?;
+
+    let mut r_stream = BufReader::new(r_stream);
+    let mut stdout = Vec::with_capacity(FCGI_BUFF_INIT_SIZE);
+    loop {
+        let mut header = [0; 8];
+        r_stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0; len + header[6] as usize];
+        r_stream.read_exact(&mut content).await?;
+        content.truncate(len);
+        match header[1] {
+            FCGI_STDOUT => stdout.extend_from_slice(&content),
+            FCGI_STDERR => {
+                for line in String::from_utf8_lossy(&content).lines() {
+                    warn!("{}: {}", upstream, line);
+                }
+            }
+            FCGI_END_REQUEST => return Ok(stdout),
+            _ => {}
+        }
+    }
+}
+
+// Forward the request to the application with the script under the document root
+pub async fn forward(
+    upstream: &FastCgiUpstream,
+    document_root: &Path,
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+) -> HttpResponse {
+    let script = document_root.join(req.path.trim_start_matches('/'));
+    let request_uri = match req.query {
+        Some(q) => format!("{}?{}", req.path, q),
+        None => req.path.to_string(),
+    };
+    let mut params = cgi::variables(sockaddr, req, body);
+    params.extend([
+        ("SCRIPT_FILENAME".to_string(), script.display().to_string()),
+        (
+            "DOCUMENT_ROOT".to_string(),
+            document_root.display().to_string(),
+        ),
+        ("REQUEST_URI".to_string(), request_uri),
+        ("REDIRECT_STATUS".to_string(), "200".to_string()), // Required by php-cgi
+    ]);
+
+    let stdout = match exchange(upstream, &params, body.unwrap_or_default()).await {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("FastCGI request to {} failed: {}", upstream, e);
+            return bad_gateway();
+        }
+    };
+    match cgi::parse_output(stdout) {
+        Some(res) => res,
+        None => {
+            warn!("Invalid output of {} for {}", upstream, req.path);
+            bad_gateway()
+        }
+    }
+}
@@ -1,24 +1,228 @@
 use std::{
     collections::HashMap,
-    io::Cursor,
+    fmt::{Display, Formatter},
+    future::Future,
+    io::{Cursor, SeekFrom},
     path::{Path, PathBuf},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     fs::File,
-    io::{self, AsyncRead, AsyncReadExt},
-    sync::{RwLock, RwLockWriteGuard},
+    io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, Take},
+    sync::{watch, RwLock, RwLockWriteGuard},
 };
 
-use crate::{debug, log_ctx, timer};
+#[cfg(target_os = "linux")]
+use crate::mmap::Mmap;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use crate::uring::{self, UringFile};
+use crate::{
+    cachecontrol::pattern_matches,
+    debug, log_ctx, slowlog, timer,
+    util::{fnv1a, parse_duration},
+};
 
 const FILE_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+
+// Paths are spread over shards with their own lock, size limit and eviction, so that writes to one do not stall the others
+const SHARD_COUNT: usize = 16;
+
+// Missing files are remembered for a while, so that hot 404 paths (favicons, probes) do not hit the disk every time
+const MISSING_TTL: Duration = Duration::from_secs(10);
+const MAX_MISSING_ENTRIES: usize = 4096;
+
+// Request counts of the frequency sketch, kept in rows of saturating counters and halved once enough are added
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH_BITS: usize = 14;
+const SKETCH_WIDTH: usize = 1 << SKETCH_WIDTH_BITS;
+const SKETCH_MAX_COUNT: u8 = 15;
+const SKETCH_RESET_AFTER: usize = SKETCH_WIDTH * 10;
+
+const TTL_RULE_DELIM: char = '=';
 log_ctx!("FileCache");
 
+// Time after which cache entries are checked against their files, per path pattern
+// Sample: 5m for every path, or /live/**=10s for the paths matching the pattern
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheTtl {
+    pub default: Option<Duration>,
+    pub rules: Vec<(String, Duration)>, // The first pattern matching the path wins over the default
+}
+
+impl CacheTtl {
+    pub fn add(&mut self, s: &str) -> Option<()> {
+        match s.split_once(TTL_RULE_DELIM) {
+            Some((pattern, ttl)) => {
+                let pattern = pattern.trim();
+                if pattern.is_empty() {
+                    return None;
+                }
+                let ttl = parse_duration(ttl)?;
+                self.rules.push((pattern.to_string(), ttl));
+            }
+            None => self.default = Some(parse_duration(s)?),
+        }
+        Some(())
+    }
+
+    // TTL of a path relative to the res root, None if its entry never expires
+    pub fn ttl_for(&self, path: &str) -> Option<Duration> {
+        let ttl = self
+            .rules
+            .iter()
+            .find(|(p, _)| pattern_matches(p, path))
+            .map(|(_, t)| *t)
+            .or(self.default)?;
+        (!ttl.is_zero()).then_some(ttl)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.rules.is_empty()
+    }
+
+    // The default then the rules in the format they are given in
+    pub fn entries(&self) -> Vec<String> {
+        let default = self.default.map(|t| format!("{}ms", t.as_millis()));
+        let rules = self
+            .rules
+            .iter()
+            .map(|(p, t)| format!("{}{}{}ms", p, TTL_RULE_DELIM, t.as_millis()));
+        default.into_iter().chain(rules).collect()
+    }
+}
+
+impl Display for CacheTtl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.entries().join(", "))
+    }
+}
+
+// Which files enter a full shard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Admission {
+    #[default]
+    Lru, // Every file, evicting the least recently used ones
+    TinyLfu, // Only files requested more often than each of the ones they would evict
+}
+
+impl Admission {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "lru" => Some(Admission::Lru),
+            "tinylfu" => Some(Admission::TinyLfu),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Admission {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Admission::Lru => write!(f, "lru"),
+            Admission::TinyLfu => write!(f, "tinylfu"),
+        }
+    }
+}
+
+// Count-min sketch estimating how often each path is requested, old counts fade as they are halved
+struct FrequencySketch {
+    counters: Box<[u8]>, // SKETCH_DEPTH rows of SKETCH_WIDTH counters
+    additions: usize,
+}
+
+impl FrequencySketch {
+    fn new() -> Self {
+        Self {
+            counters: vec![0; SKETCH_DEPTH * SKETCH_WIDTH].into(),
+            additions: 0,
+        }
+    }
+
+    // Each row takes other high bits of the spread hash, the low bits also pick the shard of the path
+    fn indexes(path: &Path) -> impl Iterator<Item = usize> {
+        let hash =
+            fnv1a(&[path.as_os_str().as_encoded_bytes()]).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        (0..SKETCH_DEPTH).map(move |row| {
+            let shift = 64 - SKETCH_WIDTH_BITS * (row + 1);
+            row * SKETCH_WIDTH + ((hash >> shift) as usize & (SKETCH_WIDTH - 1))
+        })
+    }
+
+    fn increment(&mut self, path: &Path) {
+        for i in Self::indexes(path) {
+            self.counters[i] = (self.counters[i] + 1).min(SKETCH_MAX_COUNT);
+        }
+        self.additions += 1;
+        if self.additions >= SKETCH_RESET_AFTER {
+            self.counters.iter_mut().for_each(|c| *c /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, path: &Path) -> u8 {
+        Self::indexes(path)
+            .map(|i| self.counters[i])
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+// Identifies a version of a file by its modified time and size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Validator {
+    pub modified: SystemTime,
+    pub size: usize,
+}
+
+impl Validator {
+    pub async fn from_file(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata().await?;
+        Ok(Self {
+            modified: metadata.modified()?,
+            size: metadata.len() as usize,
+        })
+    }
+
+    pub fn etag(&self) -> String {
+        let modified = self.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!(
+            "\"{:x}-{:x}-{:x}\"",
+            modified.as_secs(),
+            modified.subsec_nanos(),
+            self.size
+        )
+    }
+}
+
+// Alternative representations of a cached file (e.g. compressed), which are dropped together with the entry
+// They are not counted towards the cache size as they are only kept when smaller than the file
+type VariantMap = HashMap<&'static str, Arc<[u8]>>;
+
+#[derive(Clone, Debug, Default)]
+pub struct Variants(Arc<std::sync::Mutex<VariantMap>>);
+
+impl Variants {
+    pub fn get(&self, name: &str) -> Option<Arc<[u8]>> {
+        self.0.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn insert(&self, name: &'static str, data: &Arc<[u8]>) {
+        self.0.lock().unwrap().insert(name, data.clone());
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CacheEntry {
     data: Arc<[u8]>,
+    validator: Option<Validator>,
+    variants: Variants,
+    last_accessed: Arc<AtomicU64>, // Tick of the cache clock, shared by clones of the entry
+    checked_at: Arc<AtomicU64>, // Last time the entry was known to match the file, in ms since the cache was created
 }
 
 struct FileCacheInner {
@@ -27,46 +231,231 @@ struct FileCacheInner {
     cur_size: usize,
 }
 
-pub struct FileCache(RwLock<FileCacheInner>);
+// Receives the entry once the task filling the cache for a path is done, None if it was not cached
+type FillReceiver = watch::Receiver<Option<CacheEntry>>;
 
-struct FileCacheInsertOk {
-    new_entry: CacheEntry,
+pub struct FileCache {
+    shards: Box<[RwLock<FileCacheInner>]>,
+    in_flight: std::sync::Mutex<HashMap<PathBuf, FillReceiver>>, // Paths being read into the cache
+    missing: std::sync::Mutex<HashMap<PathBuf, Instant>>, // Paths found missing, with the time they expire
+    max_file_size: AtomicUsize, // Larger files are streamed from disk instead of cached, usize::MAX if unlimited
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    rejections: AtomicU64,
+    clock: AtomicU64, // Ticks on every access to order entries by recency
+    sketch: Option<std::sync::Mutex<FrequencySketch>>, // Request counts of the paths under TinyLFU admission
+    mmap_min_size: usize, // Files of at least this size are memory mapped instead of cached, usize::MAX if never
+    epoch: Instant,
 }
 
-enum FileCacheInsertError {
-    CacheFull, // Cache is full. The option contains the removed entry on insert attempt
-    IoError(io::Error), // IO Error
+// Held by the only task filling the cache for a path, waiters are released when it is dropped
+struct FillGuard<'a> {
+    cache: &'a FileCache,
+    path: PathBuf,
+    tx: watch::Sender<Option<CacheEntry>>,
 }
 
-impl From<io::Error> for FileCacheInsertError {
-    fn from(e: io::Error) -> Self {
-        Self::IoError(e)
+impl FillGuard<'_> {
+    fn complete(self, entry: &CacheEntry) {
+        self.tx.send_replace(Some(entry.clone()));
     }
 }
 
+impl Drop for FillGuard<'_> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().unwrap().remove(&self.path);
+    }
+}
+
+enum Fill<'a> {
+    Leader(FillGuard<'a>),
+    Done(Option<CacheEntry>),
+}
+
+// Snapshot of the counters and the occupancy of a cache
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub rejections: u64, // Files not admitted as they are requested less often than the ones they would evict
+    pub size: usize,
+    pub size_limit: Option<usize>,
+    pub entries: usize,
+}
+
+impl CacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+// File on disk of a cache entry being revalidated
+enum Current {
+    Unchanged,
+    Changed(Validator, Vec<u8>),
+    Gone, // Removed, unreadable or no longer cacheable
+}
+
+// Outcome of revalidating a cache entry against the file on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revalidation {
+    NotCached,
+    Unchanged, // File not modified, or modified with identical content
+    Refreshed, // Entry replaced in place with the new content
+    Removed,   // File is gone or no longer cacheable
+}
+
 #[derive(Debug)]
-pub enum AbstractFile {
-    File(File, usize),
-    CacheEntry(Cursor<Arc<[u8]>>, usize),
+enum FileSource {
+    File(File),
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    Uring(UringFile),
+    #[cfg(target_os = "linux")]
+    Mapped(Cursor<Mmap>),
+    CacheEntry(Cursor<Arc<[u8]>>),
+    Static(Cursor<&'static [u8]>),
+    Partial(Box<Take<AbstractFile>>),
+}
+
+#[derive(Debug)]
+pub struct AbstractFile {
+    source: FileSource,
+    len: usize,
+    validator: Option<Validator>,
+    variants: Option<Variants>,
 }
 
 impl AbstractFile {
-    pub fn from_file(file: File, size: usize) -> Self {
-        Self::File(file, size)
+    // Files are read through io_uring on the threads of the io_uring runtimes
+    pub async fn from_file(file: File, size: usize) -> Self {
+        #[cfg(all(target_os = "linux", feature = "uring"))]
+        let file = match uring::is_active() {
+            true => FileSource::Uring(UringFile::new(file.into_std().await)),
+            false => FileSource::File(file),
+        };
+        #[cfg(not(all(target_os = "linux", feature = "uring")))]
+        let file = FileSource::File(file);
+        Self {
+            source: file,
+            len: size,
+            validator: None,
+            variants: None,
+        }
+    }
+
+    pub fn with_validator(mut self, validator: Option<Validator>) -> Self {
+        self.validator = validator;
+        self
     }
 
     pub fn len(&self) -> usize {
-        match self {
-            Self::File(_, s) => *s,
-            Self::CacheEntry(_, s) => *s,
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn validator(&self) -> Option<Validator> {
+        self.validator
+    }
+
+    // The file on disk if the whole of it is read from there, e.g. to be sent by the kernel
+    pub fn disk_file(&self) -> Option<&File> {
+        match &self.source {
+            FileSource::File(f) => Some(f),
+            _ => None,
         }
     }
+
+    // The rest of the mapping if the whole file is read from memory mapped pages
+    #[cfg(target_os = "linux")]
+    pub fn mapped(&self) -> Option<&[u8]> {
+        match &self.source {
+            FileSource::Mapped(c) => Some(&c.get_ref().as_ref()[c.position() as usize..]),
+            _ => None,
+        }
+    }
+
+    pub fn variants(&self) -> Option<Variants> {
+        self.variants.clone()
+    }
+
+    // Restrict reading to the given byte range
+    pub async fn into_range(mut self, start: usize, len: usize) -> io::Result<Self> {
+        match &mut self.source {
+            FileSource::File(f) => {
+                f.seek(SeekFrom::Start(start as u64)).await?;
+            }
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            FileSource::Uring(f) => f.seek(start as u64),
+            #[cfg(target_os = "linux")]
+            FileSource::Mapped(c) => c.set_position(start as u64),
+            FileSource::CacheEntry(c) => c.set_position(start as u64),
+            FileSource::Static(c) => c.set_position(start as u64),
+            FileSource::Partial(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Range of a partial file",
+                ))
+            }
+        }
+        let validator = self.validator;
+        Ok(Self {
+            source: FileSource::Partial(Box::new(self.take(len as u64))),
+            len,
+            validator,
+            variants: None,
+        })
+    }
 }
 
 impl From<Arc<[u8]>> for AbstractFile {
     fn from(data: Arc<[u8]>) -> Self {
         let len = data.len();
-        Self::CacheEntry(Cursor::new(data), len)
+        Self {
+            source: FileSource::CacheEntry(Cursor::new(data)),
+            len,
+            validator: None,
+            variants: None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<Mmap> for AbstractFile {
+    fn from(map: Mmap) -> Self {
+        let len = map.as_ref().len();
+        Self {
+            source: FileSource::Mapped(Cursor::new(map)),
+            len,
+            validator: None,
+            variants: None,
+        }
+    }
+}
+
+impl From<&'static [u8]> for AbstractFile {
+    fn from(data: &'static [u8]) -> Self {
+        Self {
+            source: FileSource::Static(Cursor::new(data)),
+            len: data.len(),
+            validator: None,
+            variants: None,
+        }
+    }
+}
+
+impl From<CacheEntry> for AbstractFile {
+    fn from(entry: CacheEntry) -> Self {
+        let mut file = AbstractFile::from(entry.data).with_validator(entry.validator);
+        file.variants = Some(entry.variants);
+        file
     }
 }
 
@@ -76,25 +465,205 @@ impl AsyncRead for AbstractFile {
         cx: &mut std::task::Context<'_>,
         buf: &mut io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        match self.get_mut() {
-            Self::File(f, _) => Pin::new(f).poll_read(cx, buf),
-            Self::CacheEntry(c, _) => Pin::new(c).poll_read(cx, buf),
+        match &mut self.get_mut().source {
+            FileSource::File(f) => Pin::new(f).poll_read(cx, buf),
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            FileSource::Uring(f) => Pin::new(f).poll_read(cx, buf),
+            #[cfg(target_os = "linux")]
+            FileSource::Mapped(c) => Pin::new(c).poll_read(cx, buf),
+            FileSource::CacheEntry(c) => Pin::new(c).poll_read(cx, buf),
+            FileSource::Static(c) => Pin::new(c).poll_read(cx, buf),
+            FileSource::Partial(t) => Pin::new(t.as_mut()).poll_read(cx, buf),
         }
     }
 }
 
 impl FileCache {
-    pub fn new(size_limit: Option<usize>) -> Self {
-        let inner = FileCacheInner {
-            cache: HashMap::new(),
-            size_limit,
-            cur_size: 0,
+    pub fn new(size_limit: Option<usize>, max_file_size: Option<usize>) -> Self {
+        // Every shard must fit the largest cacheable file, so small caches get fewer shards
+        let shard_count = match (size_limit, max_file_size) {
+            (Some(limit), Some(max)) => (limit / max.max(1)).clamp(1, SHARD_COUNT),
+            (Some(_), None) => 1,
+            (None, _) => SHARD_COUNT,
         };
-        Self(RwLock::new(inner))
+        let shards = (0..shard_count)
+            .map(|i| {
+                RwLock::new(FileCacheInner {
+                    cache: HashMap::new(),
+                    size_limit: size_limit
+                        .map(|l| l / shard_count + usize::from(i < l % shard_count)),
+                    cur_size: 0,
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+            missing: std::sync::Mutex::new(HashMap::new()),
+            max_file_size: AtomicUsize::new(max_file_size.unwrap_or(usize::MAX)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            rejections: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            sketch: None,
+            mmap_min_size: usize::MAX,
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn with_admission(mut self, admission: Admission) -> Self {
+        self.sketch = match admission {
+            Admission::Lru => None,
+            Admission::TinyLfu => Some(std::sync::Mutex::new(FrequencySketch::new())),
+        };
+        self
+    }
+
+    // Serve files of at least the given size from a memory mapping, sharing the page cache between requests
+    pub fn with_mmap(mut self, min_size: Option<usize>) -> Self {
+        self.mmap_min_size = min_size.map_or(usize::MAX, |s| s.max(1));
+        self
+    }
+
+    pub fn admission(&self) -> Admission {
+        match self.sketch {
+            Some(_) => Admission::TinyLfu,
+            None => Admission::Lru,
+        }
+    }
+
+    // Count the request of the path for admission, hits and misses alike
+    fn record_access(&self, path: &Path) {
+        if let Some(sketch) = &self.sketch {
+            sketch.lock().unwrap().increment(path);
+        }
+    }
+
+    fn shard(&self, path: &Path) -> &RwLock<FileCacheInner> {
+        &self.shards[self.shard_index(path)]
+    }
+
+    fn shard_index(&self, path: &Path) -> usize {
+        let hash = fnv1a(&[path.as_os_str().as_encoded_bytes()]);
+        (hash % self.shards.len() as u64) as usize
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
     }
 
     async fn get(&self, path: &Path) -> Option<CacheEntry> {
-        self.0.read().await.cache.get(path).cloned()
+        let entry = self.shard(path).read().await.cache.get(path).cloned()?;
+        entry.last_accessed.store(self.tick(), Ordering::Relaxed);
+        Some(entry)
+    }
+
+    // Either become the task filling the cache for the path, or wait for the one already doing it
+    async fn join_fill(&self, path: &Path) -> Fill<'_> {
+        let mut rx = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(path) {
+                Some(rx) => rx.clone(),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    in_flight.insert(path.into(), rx);
+                    return Fill::Leader(FillGuard {
+                        cache: self,
+                        path: path.into(),
+                        tx,
+                    });
+                }
+            }
+        };
+        // None if the filling task failed or did not cache the file, the waiter then reads it on its own
+        debug!("Waiting for the in-flight read of {}...", path.display());
+        let entry = rx
+            .wait_for(|e| e.is_some())
+            .await
+            .ok()
+            .and_then(|e| e.clone());
+        Fill::Done(entry)
+    }
+
+    fn is_cacheable(&self, size: usize) -> bool {
+        size <= self.max_file_size.load(Ordering::Relaxed)
+    }
+
+    // Whether an entry of the size fits into the empty shard of the path
+    async fn fits_shard(&self, path: &Path, size: usize) -> bool {
+        let read_guard = self.shard(path).read().await;
+        read_guard.size_limit.is_none_or(|limit| size <= limit)
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            rejections: self.rejections.load(Ordering::Relaxed),
+            size: 0,
+            size_limit: None,
+            entries: 0,
+        };
+        for shard in self.shards.iter() {
+            let read_guard = shard.read().await;
+            stats.size += read_guard.cur_size;
+            stats.entries += read_guard.cache.len();
+            stats.size_limit = read_guard
+                .size_limit
+                .map(|l| stats.size_limit.unwrap_or(0) + l);
+        }
+        stats
+    }
+
+    // Whether a file of the size can be cached at the path without evicting others
+    pub async fn has_room(&self, path: &Path, size: usize) -> bool {
+        let read_guard = self.shard(path).read().await;
+        self.is_cacheable(size)
+            && read_guard
+                .size_limit
+                .is_none_or(|limit| read_guard.cur_size + size <= limit)
+    }
+
+    // Paths and sizes of the cached files, sorted by path
+    pub async fn entries(&self) -> Vec<(PathBuf, usize)> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let read_guard = shard.read().await;
+            entries.extend(
+                read_guard
+                    .cache
+                    .iter()
+                    .map(|(p, e)| (p.clone(), e.data.len())),
+            );
+        }
+        entries.sort();
+        entries
+    }
+
+    // Paths of the cached files, most recently used first
+    pub async fn hot_set(&self) -> Vec<PathBuf> {
+        let mut entries = Vec::new();
+        for shard in self.shards.iter() {
+            let read_guard = shard.read().await;
+            entries.extend(
+                read_guard
+                    .cache
+                    .iter()
+                    .map(|(p, e)| (e.last_accessed.load(Ordering::Relaxed), p.clone())),
+            );
+        }
+        entries.sort_unstable_by_key(|(tick, _)| std::cmp::Reverse(*tick));
+        entries.into_iter().map(|(_, p)| p).collect()
+    }
+
+    pub async fn contains(&self, path: &Path) -> bool {
+        self.shard(path).read().await.cache.contains_key(path)
     }
 
     fn _remove(
@@ -115,47 +684,389 @@ impl FileCache {
     }
 
     pub async fn remove(&self, path: &Path) -> Option<CacheEntry> {
-        let mut write_guard = self.0.write().await;
+        let mut write_guard = self.shard(path).write().await;
         self._remove(path, &mut write_guard)
     }
 
-    async fn insert(
+    // Remove the entries of the paths matching the predicate, returns the number of entries and bytes freed
+    pub async fn remove_where(&self, pred: impl Fn(&Path) -> bool) -> (usize, usize) {
+        let mut freed = (0, 0);
+        for shard in self.shards.iter() {
+            let mut write_guard = shard.write().await;
+            let paths = write_guard
+                .cache
+                .keys()
+                .filter(|p| pred(p))
+                .cloned()
+                .collect::<Vec<_>>();
+            for path in paths {
+                if let Some(e) = self._remove(&path, &mut write_guard) {
+                    freed.0 += 1;
+                    freed.1 += e.data.len();
+                }
+            }
+        }
+        freed
+    }
+
+    // Remove the entries of a file or of every file under a folder, e.g. one renamed or moved away
+    // Returns the paths of the entries removed
+    pub async fn remove_under(&self, path: &Path) -> Vec<PathBuf> {
+        let mut removed = Vec::new();
+        for shard in self.shards.iter() {
+            let mut write_guard = shard.write().await;
+            let paths = write_guard
+                .cache
+                .keys()
+                .filter(|p| p.starts_with(path))
+                .cloned()
+                .collect::<Vec<_>>();
+            for path in paths {
+                if self._remove(&path, &mut write_guard).is_some() {
+                    removed.push(path);
+                }
+            }
+        }
+        removed
+    }
+
+    // Bring the entry of a changed file up to date, the file is only read again if its validator changed
+    // Entries whose content turns out identical keep their variants, so compressed copies are not redone
+    pub async fn revalidate(&self, path: &Path) -> Revalidation {
+        let entry = match self.shard(path).read().await.cache.get(path).cloned() {
+            Some(e) => e,
+            None => return Revalidation::NotCached,
+        };
+        match self.check_current(path, &entry).await {
+            Current::Unchanged => Revalidation::Unchanged,
+            current => {
+                let mut write_guard = self.shard(path).write().await;
+                self._apply_current(path, &entry, current, &mut write_guard)
+            }
+        }
+    }
+
+    // Revalidate the entries of many changed files, e.g. a burst of watcher events
+    // The files are read first and the lock of each shard is then taken once for all of its paths
+    // Returns the outcome of the cached paths only
+    pub async fn revalidate_all<'a>(&self, paths: &'a [PathBuf]) -> Vec<(&'a Path, Revalidation)> {
+        let mut results = Vec::new();
+        let mut changed = Vec::new();
+        for path in paths {
+            let entry = match self.shard(path).read().await.cache.get(path).cloned() {
+                Some(e) => e,
+                None => continue,
+            };
+            match self.check_current(path, &entry).await {
+                Current::Unchanged => results.push((path.as_path(), Revalidation::Unchanged)),
+                current => changed.push((path.as_path(), entry, current)),
+            }
+        }
+        changed.sort_by_key(|(p, _, _)| self.shard_index(p));
+        let mut changed = changed.into_iter().peekable();
+        while let Some((first, _, _)) = changed.peek() {
+            let i = self.shard_index(first);
+            let mut write_guard = self.shards[i].write().await;
+            while let Some((path, entry, current)) =
+                changed.next_if(|(p, _, _)| self.shard_index(p) == i)
+            {
+                let r = self._apply_current(path, &entry, current, &mut write_guard);
+                results.push((path, r));
+            }
+        }
+        results
+    }
+
+    // Compare the entry with its file on disk, reading the file only if its validator changed
+    async fn check_current(&self, path: &Path, entry: &CacheEntry) -> Current {
+        match Self::read_current(path, entry.validator).await {
+            Ok(Some((_, None))) => Current::Unchanged,
+            Ok(Some((v, Some(d)))) if self.is_cacheable(v.size) => Current::Changed(v, d),
+            Ok(_) => Current::Gone,
+            Err(e) => {
+                debug!("Cannot revalidate {}: {}", path.display(), e);
+                Current::Gone
+            }
+        }
+    }
+
+    // Update the entry with what was found on disk, unless it was replaced or removed while the file was read
+    fn _apply_current(
         &self,
         path: &Path,
-        file: &mut File,
-        f_size: usize,
-    ) -> Result<FileCacheInsertOk, FileCacheInsertError> {
-        // Obtain write guard
-        // Write guard is held until the end of the function to ensure cache size limit is enforced
-        let mut write_guard = self.0.write().await;
+        entry: &CacheEntry,
+        current: Current,
+        write_guard: &mut RwLockWriteGuard<FileCacheInner>,
+    ) -> Revalidation {
+        let (validator, data) = match current {
+            Current::Unchanged => return Revalidation::Unchanged,
+            Current::Gone => {
+                self._remove(path, write_guard);
+                return Revalidation::Removed;
+            }
+            Current::Changed(v, d) => (v, d),
+        };
+        let current = match write_guard.cache.get_mut(path) {
+            Some(c) if Arc::ptr_eq(&c.data, &entry.data) => c,
+            _ => return Revalidation::Unchanged,
+        };
+        if *current.data == *data {
+            current.validator = Some(validator);
+            debug!(
+                "Content of {} unchanged, validator updated.",
+                path.display()
+            );
+            return Revalidation::Unchanged;
+        }
+        let last_accessed = current.last_accessed.load(Ordering::Relaxed);
+        if !self._prepare_insert(path, data.len(), write_guard) {
+            return Revalidation::Removed;
+        }
+        let new_entry = self._insert(path, data.into(), Some(validator), write_guard);
+        new_entry
+            .last_accessed
+            .store(last_accessed, Ordering::Relaxed);
+        Revalidation::Refreshed
+    }
 
-        // try remove old entry
-        let _ = self._remove(path, &mut write_guard);
+    // Validator and content of a file on disk, None if it is not a regular file
+    // The content is only read if the validator differs from the known one
+    async fn read_current(
+        path: &Path,
+        known: Option<Validator>,
+    ) -> io::Result<Option<(Validator, Option<Vec<u8>>)>> {
+        let mut file = match File::open(path).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if !file.metadata().await?.is_file() {
+            return Ok(None);
+        }
+        let validator = Validator::from_file(&file).await?;
+        if known == Some(validator) {
+            return Ok(Some((validator, None)));
+        }
+        let mut buf = Vec::with_capacity(validator.size);
+        file.read_to_end(&mut buf).await?;
+        Ok(Some((validator, Some(buf))))
+    }
 
-        // check if new entry can be inserted
-        let can_insert = match &write_guard.size_limit {
-            Some(limit) => write_guard.cur_size + f_size <= *limit,
-            None => true,
+    // Revalidate the entry of the path once it was last checked longer than the TTL ago
+    // A safety net for changes the watcher misses, only one of concurrent requests checks while the others use the entry
+    pub async fn expire(&self, path: &Path, ttl: Duration) {
+        let checked_at = match self.shard(path).read().await.cache.get(path) {
+            Some(e) => e.checked_at.clone(),
+            None => return,
         };
+        let (checked, now) = (checked_at.load(Ordering::Relaxed), self.now_ms());
+        if now.saturating_sub(checked) < ttl.as_millis() as u64 {
+            return;
+        }
+        if checked_at
+            .compare_exchange(checked, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        let res = self.revalidate(path).await;
+        debug!(
+            "Cache entry of {} checked after {}ms: {:?}",
+            path.display(),
+            ttl.as_millis(),
+            res
+        );
+    }
 
-        // Return Err if new entry cannot be inserted
-        if !can_insert {
+    pub fn is_missing(&self, path: &Path) -> bool {
+        let mut missing = self.missing.lock().unwrap();
+        match missing.get(path) {
+            Some(expires) if *expires > Instant::now() => true,
+            Some(_) => {
+                missing.remove(path);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn mark_missing(&self, path: &Path) {
+        let mut missing = self.missing.lock().unwrap();
+        let now = Instant::now();
+
+        // Drop the expired entries once full, then the one closest to expiring
+        if missing.len() >= MAX_MISSING_ENTRIES {
+            missing.retain(|_, expires| *expires > now);
+        }
+        if missing.len() >= MAX_MISSING_ENTRIES {
+            let oldest = missing
+                .iter()
+                .min_by_key(|(_, expires)| **expires)
+                .map(|(p, _)| p.clone());
+            if let Some(p) = oldest {
+                missing.remove(&p);
+            }
+        }
+        missing.insert(path.into(), now + MISSING_TTL);
+    }
+
+    // Forget the path and its parent folder were missing, the folder is missing its index until a file is created in it
+    pub fn unmark_missing(&self, path: &Path) {
+        let mut missing = self.missing.lock().unwrap();
+        missing.remove(path);
+        if let Some(parent) = path.parent() {
+            missing.remove(parent);
+        }
+    }
+
+    // Forget the paths under a folder moved into place were missing, along with the folder and its parent
+    pub fn unmark_missing_under(&self, path: &Path) {
+        let mut missing = self.missing.lock().unwrap();
+        missing.retain(|p, _| !p.starts_with(path));
+        if let Some(parent) = path.parent() {
+            missing.remove(parent);
+        }
+    }
+
+    // Returns the number of entries and bytes freed
+    pub async fn clear(&self) -> (usize, usize) {
+        let mut freed = (0, 0);
+        for shard in self.shards.iter() {
+            let mut write_guard = shard.write().await;
+            freed.0 += write_guard.cache.len();
+            freed.1 += write_guard.cur_size;
+            write_guard.cache.clear();
+            write_guard.cur_size = 0;
+        }
+        self.missing.lock().unwrap().clear();
+        debug!("Cache cleared, {} shards emptied.", self.shards.len());
+        freed
+    }
+
+    // Make room for the new entry in its shard, false if it cannot fit even into the empty shard
+    fn _prepare_insert(
+        &self,
+        path: &Path,
+        f_size: usize,
+        write_guard: &mut RwLockWriteGuard<FileCacheInner>,
+    ) -> bool {
+        // try remove old entry
+        let _ = self._remove(path, write_guard);
+
+        let limit = match write_guard.size_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        if f_size > limit {
             debug!(
-                "Cache entry cannot be inserted for {}, cache size limit reached. Current cache size: {}. New entry size: {}.",
+                "Cache entry cannot be inserted for {}, larger than the cache size limit. Cache size limit: {}. New entry size: {}.",
                 path.display(),
-                write_guard.cur_size,
+                limit,
                 f_size
             );
-            return Err(FileCacheInsertError::CacheFull);
+            return false;
         }
 
-        // Read file to buffer
-        let mut buf = Vec::with_capacity(FILE_BUFF_INIT_SIZE);
-        file.read_to_end(&mut buf).await?;
+        // Keep the victims if the new entry is not requested more often than each of them
+        let victims = Self::_victims(limit - f_size, write_guard);
+        if let (Some(sketch), false) = (&self.sketch, victims.is_empty()) {
+            let sketch = sketch.lock().unwrap();
+            let freq = sketch.estimate(path);
+            if let Some(v) = victims.iter().find(|v| sketch.estimate(v) >= freq) {
+                debug!(
+                    "Cache entry not admitted for {}, requested no more often than {}.",
+                    path.display(),
+                    v.display()
+                );
+                self.rejections.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
 
+        // Evict the least recently used entries until the new entry fits
+        self._evict(victims, write_guard);
+        true
+    }
+
+    // Least recently used entries to evict for the shard not to be larger than the size, oldest first
+    fn _victims(size: usize, write_guard: &RwLockWriteGuard<FileCacheInner>) -> Vec<PathBuf> {
+        if write_guard.cur_size <= size {
+            return Vec::new();
+        }
+        let mut entries = write_guard
+            .cache
+            .iter()
+            .map(|(p, e)| (e.last_accessed.load(Ordering::Relaxed), p, e.data.len()))
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(tick, _, _)| *tick);
+        let mut excess = write_guard.cur_size - size;
+        let mut victims = Vec::new();
+        for (_, path, len) in entries {
+            if excess == 0 {
+                break;
+            }
+            excess = excess.saturating_sub(len);
+            victims.push(path.clone());
+        }
+        victims
+    }
+
+    fn _evict(&self, victims: Vec<PathBuf>, write_guard: &mut RwLockWriteGuard<FileCacheInner>) {
+        for lru in victims {
+            debug!(
+                "Evicting least recently used cache entry {}.",
+                lru.display()
+            );
+            self._remove(&lru, write_guard);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Change the limits in place, entries over the new limits are evicted
+    // The number of shards is kept, so each shard gets an equal share of the new size limit
+    pub async fn resize(&self, size_limit: Option<usize>, max_file_size: Option<usize>) {
+        self.max_file_size
+            .store(max_file_size.unwrap_or(usize::MAX), Ordering::Relaxed);
+        let shard_count = self.shards.len();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let mut write_guard = shard.write().await;
+            write_guard.size_limit =
+                size_limit.map(|l| l / shard_count + usize::from(i < l % shard_count));
+
+            // Entries which are no longer cacheable are streamed from disk from now on
+            let too_large = write_guard
+                .cache
+                .iter()
+                .filter(|(_, e)| !self.is_cacheable(e.data.len()))
+                .map(|(p, _)| p.clone())
+                .collect::<Vec<_>>();
+            for path in too_large {
+                self._remove(&path, &mut write_guard);
+            }
+            if let Some(limit) = write_guard.size_limit {
+                let victims = Self::_victims(limit, &write_guard);
+                self._evict(victims, &mut write_guard);
+            }
+        }
+        debug!("Cache resized, {} shards updated.", shard_count);
+    }
+
+    fn _insert(
+        &self,
+        path: &Path,
+        data: Arc<[u8]>,
+        validator: Option<Validator>,
+        write_guard: &mut RwLockWriteGuard<FileCacheInner>,
+    ) -> CacheEntry {
         // insert new entry
-        write_guard.cur_size += buf.len();
-        let new_entry = CacheEntry { data: buf.into() };
+        write_guard.cur_size += data.len();
+        let new_entry = CacheEntry {
+            data,
+            validator,
+            variants: Variants::default(),
+            last_accessed: Arc::new(AtomicU64::new(self.tick())),
+            checked_at: Arc::new(AtomicU64::new(self.now_ms())),
+        };
         write_guard.cache.insert(path.into(), new_entry.clone());
 
         debug!(
@@ -163,33 +1074,160 @@ impl FileCache {
             path.display(),
             write_guard.cur_size
         );
+        new_entry
+    }
+
+    // Insert the data unless it does not fit, the data is read beforehand so that the shard is only locked briefly
+    async fn insert(
+        &self,
+        path: &Path,
+        data: Arc<[u8]>,
+        validator: Option<Validator>,
+    ) -> Option<CacheEntry> {
+        // Write guard is held until the end of the function to ensure cache size limit is enforced
+        let mut write_guard = self.shard(path).write().await;
+        if !self._prepare_insert(path, data.len(), &mut write_guard) {
+            return None;
+        }
+        Some(self._insert(path, data, validator, &mut write_guard))
+    }
+
+    pub async fn open_with<F: Future<Output = io::Result<Vec<u8>>>>(
+        &self,
+        path: &Path,
+        validator: Option<Validator>,
+        load: F,
+    ) -> io::Result<AbstractFile> {
+        timer!("FileCache::open_with");
+        self.record_access(path);
 
-        // return ok
-        Ok(FileCacheInsertOk { new_entry })
+        // Return the cached data if it exists
+        if let Some(e) = self.get(path).await {
+            debug!("Cache valid for {}, using cached data...", path.display());
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            slowlog::record_cache(true);
+            return Ok(AbstractFile::from(e));
+        }
+
+        // Load the data and insert into cache if it fits, concurrent misses wait for the first one
+        debug!("Cache miss for {}, loading data...", path.display());
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        slowlog::record_cache(false);
+        let fill = match self.join_fill(path).await {
+            Fill::Done(Some(e)) => return Ok(AbstractFile::from(e)),
+            Fill::Done(None) => None,
+            Fill::Leader(guard) => match self.get(path).await {
+                Some(e) => return Ok(AbstractFile::from(e)),
+                None => Some(guard),
+            },
+        };
+        let data: Arc<[u8]> = load.await?.into();
+        if !self.is_cacheable(data.len()) {
+            debug!(
+                "Not caching {}, larger than the max cacheable file size.",
+                path.display()
+            );
+            return Ok(AbstractFile::from(data).with_validator(validator));
+        }
+        if let (Some(entry), Some(fill)) = (self.insert(path, data.clone(), validator).await, fill)
+        {
+            fill.complete(&entry);
+        }
+        Ok(AbstractFile::from(data).with_validator(validator))
+    }
+
+    // Read the file from disk without caching it, an entry cached before is dropped so that it stops taking room
+    pub async fn open_uncached(&self, path: &Path) -> io::Result<AbstractFile> {
+        timer!("FileCache::open_uncached");
+        if self.contains(path).await && self.remove(path).await.is_some() {
+            debug!(
+                "Dropped cached file {}, excluded from the cache.",
+                path.display()
+            );
+        }
+        let file = File::open(path).await?;
+        let validator = Validator::from_file(&file).await?;
+        let f_size = validator.size;
+        #[cfg(target_os = "linux")]
+        if f_size >= self.mmap_min_size {
+            let map = Mmap::map(&file, f_size)?;
+            return Ok(AbstractFile::from(map).with_validator(Some(validator)));
+        }
+        let file = AbstractFile::from_file(file, f_size).await;
+        Ok(file.with_validator(Some(validator)))
     }
 
     pub async fn open(&self, path: &Path) -> io::Result<AbstractFile> {
         timer!("FileCache::open");
+        self.record_access(path);
         let cached = self.get(path).await;
         let path_str = path.display(); // for logging
 
         // Return the cached file if it exists and is valid
         if let Some(e) = cached {
             debug!("Cache valid for {}, using cached file...", &path_str);
-            return Ok(AbstractFile::from(e.data));
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            slowlog::record_cache(true);
+            return Ok(AbstractFile::from(e));
         }
 
-        // Read the file into cache
+        // Read the file into cache, concurrent misses wait for the first one instead of reading it again
         debug!("Cache miss for {}, reading file...", &path_str);
-        let mut file = File::open(path).await?;
-        let f_size = file.metadata().await?.len() as usize;
-        let retval = match self.insert(path, &mut file, f_size).await {
-            Ok(cached) => Ok(AbstractFile::from(cached.new_entry.data)),
-            Err(e) => match e {
-                FileCacheInsertError::IoError(e) => Err(e),
-                FileCacheInsertError::CacheFull => Ok(AbstractFile::from_file(file, f_size)),
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        slowlog::record_cache(false);
+        let fill = match self.join_fill(path).await {
+            Fill::Done(Some(e)) => return Ok(AbstractFile::from(e)),
+            Fill::Done(None) => None,
+            Fill::Leader(guard) => match self.get(path).await {
+                // Filled by a task which finished right before this one joined
+                Some(e) => return Ok(AbstractFile::from(e)),
+                None => Some(guard),
             },
-        }?;
-        Ok(retval)
+        };
+        let file = File::open(path).await?;
+        let validator = Validator::from_file(&file).await?;
+        let f_size = validator.size;
+
+        // Map large files instead of reading them, the kernel keeps their pages for every request
+        #[cfg(target_os = "linux")]
+        if f_size >= self.mmap_min_size {
+            debug!("Mapping {} into memory...", &path_str);
+            let map = Mmap::map(&file, f_size)?;
+            return Ok(AbstractFile::from(map).with_validator(Some(validator)));
+        }
+
+        let mut file = AbstractFile::from_file(file, f_size)
+            .await
+            .with_validator(Some(validator));
+
+        // Stream files which cannot be cached from disk without reading them into memory
+        if !self.is_cacheable(f_size) {
+            debug!(
+                "Streaming {} from disk, larger than the max cacheable file size.",
+                &path_str
+            );
+            return Ok(file);
+        }
+        if !self.fits_shard(path, f_size).await {
+            debug!(
+                "Streaming {} from disk, larger than the cache size limit.",
+                &path_str
+            );
+            return Ok(file);
+        }
+
+        // Read file to buffer
+        let mut buf = Vec::with_capacity(FILE_BUFF_INIT_SIZE);
+        file.read_to_end(&mut buf).await?;
+        let data: Arc<[u8]> = buf.into();
+        match self.insert(path, data.clone(), Some(validator)).await {
+            Some(entry) => {
+                if let Some(fill) = fill {
+                    fill.complete(&entry);
+                }
+                Ok(AbstractFile::from(entry))
+            }
+            None => Ok(AbstractFile::from(data).with_validator(Some(validator))),
+        }
     }
 }
@@ -0,0 +1,70 @@
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{http::HttpRequest, ipfilter::Cidr};
+
+// Sample of Forwarded: for=203.0.113.7;proto=https, for="[2001:db8::1]:4711"
+// Sample of X-Forwarded-For: 203.0.113.7, 10.0.0.2
+const FORWARDED_HEADER: &str = "Forwarded";
+const X_FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+const FORWARDED_FOR_PARAM: &str = "for";
+const HOP_DELIM: char = ',';
+const PARAM_DELIM: char = ';';
+const PARAM_KEY_VAL_DELIM: char = '=';
+
+// Address of the client behind trusted proxies, preferring Forwarded over X-Forwarded-For
+// Hops are walked from the nearest, the first which is not a trusted proxy is the client
+// None if the peer is not trusted or the headers name no address
+pub fn client_ip(peer: IpAddr, request: &HttpRequest, trusted: &[Cidr]) -> Option<IpAddr> {
+    if !is_trusted(peer, trusted) {
+        return None;
+    }
+    let hops = match (
+        request.header(FORWARDED_HEADER),
+        request.header(X_FORWARDED_FOR_HEADER),
+    ) {
+        (Some(f), _) => f.split(HOP_DELIM).map(forwarded_for).collect::<Vec<_>>(),
+        (None, Some(x)) => x.split(HOP_DELIM).map(parse_node).collect(),
+        (None, None) => return None,
+    };
+
+    // Hops which are obfuscated or unknown end the walk, as the ones before cannot be verified
+    let mut client = None;
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = Some(ip);
+                if !is_trusted(ip, trusted) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    client
+}
+
+pub fn is_trusted(ip: IpAddr, trusted: &[Cidr]) -> bool {
+    trusted.iter().any(|c| c.contains(ip))
+}
+
+// Address of the for parameter of a hop of the Forwarded header
+fn forwarded_for(hop: &str) -> Option<IpAddr> {
+    hop.split(PARAM_DELIM)
+        .filter_map(|p| p.split_once(PARAM_KEY_VAL_DELIM))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(FORWARDED_FOR_PARAM))
+        .and_then(|(_, v)| parse_node(v.trim().trim_matches('"')))
+}
+
+// Sample: 203.0.113.7, 203.0.113.7:4711, 2001:db8::1 or [2001:db8::1]:4711
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|a| a.ip()))
+        .or_else(|_| {
+            node.strip_prefix('[')
+                .and_then(|n| n.strip_suffix(']'))
+                .unwrap_or_default()
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
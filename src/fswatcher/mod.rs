@@ -1,15 +1,64 @@
-use notify::{EventKind, RecursiveMode, Watcher};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, WatcherKind,
+};
 use std::{
+    collections::HashSet,
     error::Error,
     fmt::{self, Display, Formatter},
-    path::PathBuf,
+    future,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs::metadata,
+    select,
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
 };
-use tokio::{sync::mpsc, task::JoinHandle};
 
-use crate::{error, filecache::FileCache, log_ctx, requestmap::RequestMap, trace, BUFF_INIT_SIZE};
+use crate::{
+    cachecontrol::pattern_matches,
+    debug, error, info, log_ctx,
+    pollwatcher::PollWatcher,
+    server::DEFAULT_WATCH_POLL_INTERVAL,
+    state::{ServerState, Site},
+    trace, warn, BUFF_INIT_SIZE,
+};
 log_ctx!("FSWatcher");
 
+// Changes are applied in one batch once no event was seen for the debounce window of the server
+// Editors and build tools write files in several steps, each firing its own event
+// Under constant changes, a batch waits at most this many windows after its first event
+const MAX_DEBOUNCE_WINDOWS: u32 = 10;
+
+// Failures of the native watcher before falling back to polling, and in total before giving up
+const FALLBACK_AFTER_FAILURES: u32 = 2;
+const MAX_WATCHER_FAILURES: u32 = 5;
+
+// Wait before re-creating a failed watcher, doubled on every consecutive failure
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(30);
+
+// A watcher running this long is healthy again, its earlier failures are forgotten
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+// File systems whose changes made by other machines are not reported to the native watcher
+// NFS, SMB, CIFS, SMB2, 9P (WSL and VM shares), FUSE (sshfs and Docker Desktop volumes), Ceph, AFS and Coda
+#[cfg(target_os = "linux")]
+const NETWORK_FS_MAGICS: [u32; 9] = [
+    0x6969, 0x517b, 0xff534d42, 0xfe534d42, 0x01021997, 0x65735546, 0x00c36400, 0x5346414f,
+    0x73757245,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatcherMode {
+    Native,
+    Polling,
+}
+
 #[derive(Debug)]
 pub enum WatcherError {
     InitError,
@@ -41,52 +90,438 @@ impl Display for WatcherError {
     }
 }
 
-pub fn setup_fs_watcher(
-    ctx: Arc<(FileCache, Option<RequestMap>, PathBuf)>,
-) -> notify::Result<JoinHandle<Result<(), WatcherError>>> {
+// Paths changed since the last batch, with the time of the first and last events
+#[derive(Default)]
+struct PendingChanges {
+    paths: HashSet<PathBuf>,
+    maps: HashSet<PathBuf>,           // Map files to reload
+    renames: Vec<(PathBuf, PathBuf)>, // Old and new paths of the renames reported as pairs
+    moved: HashSet<PathBuf>,          // Paths renamed without their other half, from or to
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl PendingChanges {
+    fn touch(&mut self) {
+        let now = Instant::now();
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    // Time the batch is applied at, None if nothing is pending
+    fn due(&self, debounce: Duration) -> Option<Instant> {
+        let (first, last) = (self.first?, self.last?);
+        Some((last + debounce).min(first + debounce * MAX_DEBOUNCE_WINDOWS))
+    }
+
+    fn take(&mut self) -> Self {
+        std::mem::take(self)
+    }
+}
+
+// Keep the res roots watched for the lifetime of the server, re-creating the watcher when it fails
+// The task only ends with the last error once the watcher failed too often in a row
+pub fn setup_fs_watcher(ctx: Arc<ServerState>) -> JoinHandle<WatcherError> {
+    tokio::spawn(async move {
+        let mut mode = start_mode(&ctx);
+        let mut failures = 0;
+
+        // Changes waiting for the next batch, kept when the watcher is re-created
+        let mut pending = PendingChanges::default();
+        loop {
+            let started = Instant::now();
+            let err = run_watcher(&ctx, mode, &mut pending).await;
+            if started.elapsed() >= HEALTHY_AFTER {
+                failures = 0;
+            }
+            failures += 1;
+            if failures >= MAX_WATCHER_FAILURES {
+                return err;
+            }
+            let backoff = (RESTART_BACKOFF * 2u32.pow(failures - 1)).min(MAX_RESTART_BACKOFF);
+            error!(
+                "File watcher failed ({}/{}): {}, restarting in {}ms...",
+                failures,
+                MAX_WATCHER_FAILURES,
+                err,
+                backoff.as_millis()
+            );
+            if mode == WatcherMode::Native && failures >= FALLBACK_AFTER_FAILURES {
+                warn!(
+                    "Native file watcher keeps failing, polling every {}ms instead",
+                    poll_interval(&ctx).as_millis()
+                );
+                mode = WatcherMode::Polling;
+            }
+            time::sleep(backoff).await;
+
+            // Changes made while nothing was watched are unknown, start over from the disk
+            for site in ctx.sites() {
+                site.file_cache.clear().await;
+                site.reload_request_map().await;
+            }
+        }
+    })
+}
+
+// Poll when told to, when the platform has no native watcher, or when a res root is on a network file system
+fn start_mode(ctx: &ServerState) -> WatcherMode {
+    if ctx.watch_poll.is_some() {
+        info!(
+            "Polling the res roots every {}ms",
+            poll_interval(ctx).as_millis()
+        );
+        return WatcherMode::Polling;
+    }
+    if RecommendedWatcher::kind() == WatcherKind::PollWatcher {
+        info!(
+            "No native file watcher on this platform, polling every {}ms",
+            poll_interval(ctx).as_millis()
+        );
+        return WatcherMode::Polling;
+    }
+    if let Some(root) = watchable_roots(ctx).iter().find(|r| is_network_fs(r)) {
+        info!(
+            "{} is on a network file system, polling every {}ms",
+            root.display(),
+            poll_interval(ctx).as_millis()
+        );
+        return WatcherMode::Polling;
+    }
+    WatcherMode::Native
+}
+
+fn poll_interval(ctx: &ServerState) -> Duration {
+    ctx.watch_poll.unwrap_or(DEFAULT_WATCH_POLL_INTERVAL)
+}
+
+#[cfg(target_os = "linux")]
+fn is_network_fs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+    let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    // Safety: the path is nul terminated and stat is only read when the call succeeded
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    let f_type = unsafe { stat.assume_init() }.f_type as u32;
+    NETWORK_FS_MAGICS.contains(&f_type)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_network_fs(_path: &Path) -> bool {
+    false
+}
+
+fn create_watcher(
+    ctx: &ServerState,
+    mode: WatcherMode,
+    tx: mpsc::Sender<notify::Result<Event>>,
+) -> notify::Result<Box<dyn Watcher + Send>> {
+    // Events sent after the receiver of a failed watcher is gone are dropped
+    let handler = move |res| {
+        let _ = tx.blocking_send(res);
+    };
+    Ok(match mode {
+        WatcherMode::Native => Box::new(notify::recommended_watcher(handler)?),
+        WatcherMode::Polling => {
+            let patterns = ctx.watch_ignore.clone();
+            let watcher =
+                PollWatcher::with_budget(handler, poll_interval(ctx), ctx.watch_poll_budget);
+            match patterns.is_empty() {
+                true => Box::new(watcher),
+                false => {
+                    Box::new(watcher.ignore(move |rel, is_dir| is_ignored(&patterns, rel, is_dir)))
+                }
+            }
+        }
+    })
+}
+
+// Watch the roots until the watcher fails
+async fn run_watcher(
+    ctx: &ServerState,
+    mode: WatcherMode,
+    pending: &mut PendingChanges,
+) -> WatcherError {
     // create watcher and event channel
     let (tx, mut rx) = mpsc::channel(BUFF_INIT_SIZE);
-    let mut watcher = notify::recommended_watcher(move |res| tx.blocking_send(res).unwrap())?;
+    let mut watcher = match create_watcher(ctx, mode, tx) {
+        Ok(w) => w,
+        Err(err) => {
+            error!("Error creating watcher: {}", err);
+            return WatcherError::InitError;
+        }
+    };
 
-    // spawn watcher task
-    let t: JoinHandle<Result<(), WatcherError>> = tokio::spawn(async move {
-        let (file_cache, _, res_root) = ctx.as_ref();
+    // watch res folder of every site
+    let mut watched = Vec::new();
+    for root in watchable_roots(ctx) {
+        if let Err(err) = watcher.watch(&root, RecursiveMode::Recursive) {
+            error!("Error watching directory: {}", err);
+            return WatcherError::InitError;
+        }
+        watched.push(root);
+    }
 
-        // watch res folder
-        if let Err(err) = watcher.watch(res_root, RecursiveMode::Recursive) {
+    // watch the folder of the map file of every site, editors often replace the file instead of modifying it
+    for dir in map_dirs(ctx) {
+        if let Err(err) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
             error!("Error watching directory: {}", err);
-            return Err(WatcherError::InitError);
+            return WatcherError::InitError;
         }
+    }
+    debug!("Watching {} res roots ({:?})", watched.len(), mode);
 
-        // event loop
-        while let Some(e) = rx.recv().await {
-            let event = match e {
-                Ok(event) => {
-                    trace!("Folder event: {:?}", event);
-                    match event.kind {
-                        EventKind::Modify(_) => Some(event),
-                        EventKind::Remove(_) => Some(event),
-                        _ => None,
+    // event loop
+    loop {
+        let next_batch = pending.due(ctx.watch_debounce);
+
+        // Select between folder event, res root change and the batch of pending changes
+        let e = select! {
+            e = rx.recv() => match e {
+                Some(e) => e,
+                None => return WatcherError::ChannelClosed,
+            },
+            _ = ctx.res_root_changed.notified() => {
+                watched = match rewatch(watcher.as_mut(), watched, ctx) {
+                    Ok(w) => w,
+                    Err(err) => return err,
+                };
+                continue;
+            }
+            _ = async {
+                match next_batch {
+                    Some(t) => time::sleep_until(t).await,
+                    None => future::pending().await,
+                }
+            } => {
+                apply_changes(ctx, pending.take()).await;
+                continue;
+            }
+        };
+
+        let event = match e {
+            Ok(mut event) => {
+                // Paths matching the ignore patterns are dropped before anything looks at the event
+                if !ctx.watch_ignore.is_empty() && !event.paths.is_empty() {
+                    event.paths.retain(|p| !is_ignored_path(ctx, p));
+                    if event.paths.is_empty() {
+                        continue;
                     }
                 }
-                Err(err) => return Err(WatcherError::EventError(err)),
-            };
-
-            // remove file from cache
-            if let Some(event) = event {
-                for path in event.paths {
-                    let removed = file_cache.remove(&path).await;
-                    if removed.is_some() {
-                        trace!("Removed {} from file cache", path.display());
+                trace!("Folder event: {:?}", event);
+
+                // reload the map of the sites whose map file changed with the next batch
+                if !matches!(event.kind, EventKind::Access(_)) {
+                    for site in ctx
+                        .sites()
+                        .filter(|s| event.paths.iter().any(|p| p == s.map_path()))
+                    {
+                        pending.maps.insert(site.map_path().to_path_buf());
+                        pending.touch();
+                    }
+                }
+
+                event
+            }
+            Err(err) => return WatcherError::EventError(err),
+        };
+
+        let roots = watchable_roots(ctx);
+        let paths = event
+            .paths
+            .into_iter()
+            .filter(|p| roots.iter().any(|r| p.starts_with(r)))
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            continue;
+        }
+
+        // Created paths may have been found missing before, and so may the files of a folder moved into place
+        for path in &paths {
+            for site in ctx.sites().filter(|s| path.starts_with(&s.res_root().path)) {
+                match event.kind {
+                    EventKind::Create(_) => site.file_cache.unmark_missing(path),
+                    EventKind::Modify(ModifyKind::Name(_)) => {
+                        site.file_cache.unmark_missing_under(path)
                     }
+                    _ => {}
                 }
             }
         }
 
-        // return error if channel closed
-        Err(WatcherError::ChannelClosed)
-    });
+        // Entries are keyed by path, so the old paths of renamed files and folders are dropped with the next batch
+        if let EventKind::Modify(ModifyKind::Name(mode)) = event.kind {
+            match (mode, paths.as_slice()) {
+                (RenameMode::Both, [from, to]) => pending.renames.push((from.clone(), to.clone())),
+                _ => pending.moved.extend(paths.iter().cloned()),
+            }
+        }
 
-    // return watcher task
-    Ok(t)
+        // Refresh modified and removed files of the res roots once events settle, the events of a path are coalesced
+        if matches!(event.kind, EventKind::Modify(_) | EventKind::Remove(_)) {
+            pending.paths.extend(paths);
+            pending.touch();
+        }
+    }
+}
+
+// Bring the changed files up to date in the caches of the sites they belong to, files not cached are left alone
+// Pages of the dev mode are told about the changes, whether the files were cached or not
+async fn apply_changes(ctx: &ServerState, changes: PendingChanges) {
+    for site in ctx.sites().filter(|s| changes.maps.contains(s.map_path())) {
+        info!("Map file of {} changed, reloading...", site.display_name());
+        site.reload_request_map().await;
+    }
+    for site in ctx.sites() {
+        apply_renames(site, &changes).await;
+    }
+    let paths = changes.paths.into_iter().collect::<Vec<_>>();
+    if paths.is_empty() {
+        return;
+    }
+    debug!("Applying changes of {} paths", paths.len());
+    if let Some(live_reload) = &ctx.live_reload {
+        for path in &paths {
+            live_reload.notify(path);
+        }
+    }
+    for site in ctx.sites() {
+        let root = site.res_root();
+        let site_paths = paths
+            .iter()
+            .filter(|p| p.starts_with(&root.path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for (path, r) in site.file_cache.revalidate_all(&site_paths).await {
+            trace!("Revalidated {} in file cache: {:?}", path.display(), r);
+        }
+    }
+}
+
+// Drop the entries under the old paths of renamed files and folders, those moved away included
+// The files cached under a renamed path are loaded again under the new one, so that they stay warm
+async fn apply_renames(site: &Site, changes: &PendingChanges) {
+    let root = site.res_root();
+    for (from, to) in changes
+        .renames
+        .iter()
+        .filter(|(f, _)| f.starts_with(&root.path))
+    {
+        let removed = site.file_cache.remove_under(from).await;
+        if removed.is_empty() || !to.starts_with(&root.path) {
+            continue;
+        }
+        let list = removed
+            .iter()
+            .filter_map(|p| p.strip_prefix(from).ok())
+            .map(|rel| match rel.as_os_str().is_empty() {
+                true => to.clone(),
+                false => to.join(rel),
+            })
+            .filter_map(|p| p.strip_prefix(&root.path).ok().map(Path::to_path_buf))
+            .collect::<Vec<_>>();
+        let (files, _) = site.preload(Some(&list)).await;
+        debug!(
+            "Renamed {} to {}, {} of {} cached files loaded again",
+            from.display(),
+            to.display(),
+            files,
+            removed.len()
+        );
+    }
+
+    // Paths renamed without their other half are gone if they were the old ones
+    for path in changes.moved.iter().filter(|p| p.starts_with(&root.path)) {
+        if metadata(path).await.is_err() {
+            let removed = site.file_cache.remove_under(path).await;
+            if !removed.is_empty() {
+                debug!(
+                    "Moved {} away, {} cached files dropped",
+                    path.display(),
+                    removed.len()
+                );
+            }
+        }
+    }
+}
+
+// Patterns are matched like those of the cache control against the path from the res root, e.g. /build/app.js
+// Folders are matched with a trailing slash, so that ignored folders are not walked when polling
+fn is_ignored(patterns: &[String], rel_path: &Path, is_dir: bool) -> bool {
+    let mut path = format!("/{}", rel_path.to_string_lossy().replace('\\', "/"));
+    if is_dir {
+        path.push('/');
+    }
+    patterns.iter().any(|p| pattern_matches(p, &path))
+}
+
+fn is_ignored_path(ctx: &ServerState, path: &Path) -> bool {
+    ctx.sites().any(|s| {
+        let root = s.res_root();
+        root.is_folder()
+            && path
+                .strip_prefix(&root.path)
+                .is_ok_and(|rel| is_ignored(&ctx.watch_ignore, rel, false))
+    })
+}
+
+// Patterns with a slash but not starting with one, e.g. node_modules/**, match below any folder
+pub fn watch_ignore_pattern(pattern: &str) -> String {
+    match pattern.contains('/') && !pattern.starts_with('/') {
+        true => format!("**/{}", pattern),
+        false => pattern.to_string(),
+    }
+}
+
+// Only folders are watched, archives and embedded files are reloaded explicitly
+fn watchable_roots(ctx: &ServerState) -> Vec<PathBuf> {
+    ctx.sites()
+        .map(|s| s.res_root())
+        .filter(|r| r.is_folder())
+        .map(|r| r.path.clone())
+        .collect()
+}
+
+fn map_dirs(ctx: &ServerState) -> Vec<PathBuf> {
+    let mut dirs = ctx
+        .sites()
+        .filter_map(|s| s.map_path().parent())
+        .map(|p| match p.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => p.to_path_buf(),
+        })
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+fn rewatch(
+    watcher: &mut dyn Watcher,
+    watched: Vec<PathBuf>,
+    ctx: &ServerState,
+) -> Result<Vec<PathBuf>, WatcherError> {
+    let roots = watchable_roots(ctx);
+
+    // unwatch roots no longer in use
+    for root in watched.iter().filter(|w| !roots.contains(w)) {
+        if let Err(err) = watcher.unwatch(root) {
+            error!("Error unwatching directory: {}", err);
+        }
+    }
+
+    // watch new roots
+    for root in roots.iter().filter(|r| !watched.contains(r)) {
+        if let Err(err) = watcher.watch(root, RecursiveMode::Recursive) {
+            error!("Error watching directory: {}", err);
+            return Err(WatcherError::InitError);
+        }
+        info!("Watching {}", root.display());
+    }
+    Ok(roots)
 }
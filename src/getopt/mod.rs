@@ -1,37 +1,97 @@
-use std::{borrow::Cow, collections::HashMap, env, ffi::OsStr};
+use std::{collections::HashMap, env, ffi::OsString};
 
-const ENV_ARG_FLAG_PREFIX: &str = "-";
+const SHORT_PREFIX: &str = "-";
+const LONG_PREFIX: &str = "--";
+const LONG_VALUE_DELIM: char = '=';
+const HELP_COLUMN: usize = 34; // Of the descriptions on the help screen
 
-enum ArgType<'a> {
-    Flag(Cow<'a, str>),
-    Value(Cow<'a, str>),
+// Whether an option takes a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    None,
+    Required,
+    Optional, // Taken from the next argument unless it is an option
 }
 
-impl<'a> ArgType<'a> {
-    fn parse(s: &'a OsStr) -> Self {
-        let s = s.to_string_lossy();
-        if s.starts_with(ENV_ARG_FLAG_PREFIX) {
-            match s {
-                Cow::Borrowed(s) => ArgType::Flag(Cow::Borrowed(&s[1..])),
-                Cow::Owned(mut s) => {
-                    s.drain(..1); // Remove flag prefix
-                    ArgType::Flag(Cow::Owned(s))
-                }
-            }
-        } else {
-            ArgType::Value(s)
+// Option keyed by its long name, which can also be given by a single letter
+// Sample: -p 8080, -p8080, --port 8080 or --port=8080
+#[derive(Debug)]
+pub struct Opt {
+    pub short: Option<char>,
+    pub long: &'static str,
+    pub value: ValueKind,
+    pub value_name: &'static str,
+    pub help: &'static str,
+}
+
+impl Opt {
+    pub const fn flag(short: Option<char>, long: &'static str, help: &'static str) -> Self {
+        Self {
+            short,
+            long,
+            value: ValueKind::None,
+            value_name: "",
+            help,
+        }
+    }
+
+    pub const fn value(
+        short: Option<char>,
+        long: &'static str,
+        value_name: &'static str,
+        help: &'static str,
+    ) -> Self {
+        Self {
+            short,
+            long,
+            value: ValueKind::Required,
+            value_name,
+            help,
+        }
+    }
+
+    pub const fn optional(
+        short: Option<char>,
+        long: &'static str,
+        value_name: &'static str,
+        help: &'static str,
+    ) -> Self {
+        Self {
+            short,
+            long,
+            value: ValueKind::Optional,
+            value_name,
+            help,
         }
     }
 }
 
+#[derive(Debug)]
+enum ErrorKind {
+    Unexpected,      // Value without option
+    Unknown,         // Option not in the list
+    MissingValue,    // Option requiring a value given without
+    UnexpectedValue, // Flag given with a value
+}
+
 #[derive(Debug)]
 pub struct GetOptError {
     invalid_arg: String,
+    kind: ErrorKind,
 }
 
 impl std::fmt::Display for GetOptError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Invalid argument: {}", self.invalid_arg)
+        match self.kind {
+            ErrorKind::Unexpected => write!(f, "Invalid argument: {}", self.invalid_arg),
+            ErrorKind::Unknown => write!(
+                f,
+                "Unknown option: {}, see {}help",
+                self.invalid_arg, LONG_PREFIX
+            ),
+            ErrorKind::MissingValue => write!(f, "Option {} requires a value", self.invalid_arg),
+            ErrorKind::UnexpectedValue => write!(f, "Option {} takes no value", self.invalid_arg),
+        }
     }
 }
 
@@ -49,36 +109,144 @@ impl std::error::Error for GetOptError {
     }
 }
 
-pub fn getopt() -> Result<HashMap<String, Option<String>>, GetOptError> {
-    let args = env::args_os().skip(1).collect::<Vec<_>>();
-    let mut args_map: HashMap<String, Option<String>> = HashMap::new();
-    let mut cur_flag = None;
-    for arg in &args {
-        let arg: ArgType = ArgType::parse(arg);
-        match cur_flag {
-            None => match arg {
-                ArgType::Flag(f) => cur_flag = Some(f), // Update cur flag
-                ArgType::Value(v) => {
-                    // Error if value without flag
-                    return Err(GetOptError {
-                        invalid_arg: v.to_string(),
-                    });
-                }
-            },
-            Some(f) => match arg {
-                ArgType::Flag(a) => {
-                    args_map.insert(f.to_string(), None); // Insert cur_flag if current arg is also a flag
-                    cur_flag = Some(a); // Update cur flag as arg
-                }
-                ArgType::Value(a) => {
-                    args_map.insert(f.to_string(), Some(a.to_string())); // Insert cur_flag if current arg is a value
-                    cur_flag = None; // Reset cur flag
-                }
-            },
+// Parsed arguments by long name, options given multiple times keep every value in order
+pub struct Args {
+    map: HashMap<String, Vec<Option<String>>>,
+}
+
+impl Args {
+    // Last value of the option
+    pub fn get(&self, key: &str) -> Option<&Option<String>> {
+        self.map.get(key).and_then(|v| v.last())
+    }
+
+    // Every value of the option, occurrences without value are skipped
+    pub fn get_all(&self, key: &str) -> impl Iterator<Item = &str> {
+        self.map
+            .get(key)
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_deref())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+}
+
+pub fn getopt(options: &[Opt]) -> Result<Args, GetOptError> {
+    parse(options, env::args_os().skip(1))
+}
+
+// Parse arguments given in the order of a command line, options not in the list are refused
+// Short flags can be combined, e.g. -dL, the last of them may take a value, e.g. -dp8080
+pub fn parse<I: IntoIterator<Item = OsString>>(
+    options: &[Opt],
+    args: I,
+) -> Result<Args, GetOptError> {
+    let args = args
+        .into_iter()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let mut args_map: HashMap<String, Vec<Option<String>>> = HashMap::new();
+    let mut rest = args.iter().map(String::as_str).peekable();
+    while let Some(arg) = rest.next() {
+        let error = |kind| GetOptError {
+            invalid_arg: arg.to_string(),
+            kind,
+        };
+
+        // Long option with the value after the delimiter or as the next argument
+        if let Some(long) = arg.strip_prefix(LONG_PREFIX) {
+            let (name, inline) = match long.split_once(LONG_VALUE_DELIM) {
+                Some((n, v)) => (n, Some(v)),
+                None => (long, None),
+            };
+            let opt = options
+                .iter()
+                .find(|o| o.long == name)
+                .ok_or_else(|| error(ErrorKind::Unknown))?;
+            let value = take_value(opt, inline, &mut rest).map_err(error)?;
+            args_map
+                .entry(opt.long.to_string())
+                .or_default()
+                .push(value);
+            continue;
+        }
+
+        // Short options, the one taking a value ends the group with the rest as value
+        let shorts = match arg.strip_prefix(SHORT_PREFIX) {
+            Some(s) if !s.is_empty() => s,
+            _ => return Err(error(ErrorKind::Unexpected)),
+        };
+        for (i, c) in shorts.char_indices() {
+            let opt = options
+                .iter()
+                .find(|o| o.short == Some(c))
+                .ok_or_else(|| GetOptError {
+                    invalid_arg: format!("{}{}", SHORT_PREFIX, c),
+                    kind: ErrorKind::Unknown,
+                })?;
+            let inline = &shorts[i + c.len_utf8()..];
+            let ends_group = opt.value != ValueKind::None;
+            let inline = (ends_group && !inline.is_empty()).then_some(inline);
+            let value = take_value(opt, inline, &mut rest).map_err(|kind| GetOptError {
+                invalid_arg: format!("{}{}", SHORT_PREFIX, c),
+                kind,
+            })?;
+            args_map
+                .entry(opt.long.to_string())
+                .or_default()
+                .push(value);
+            if ends_group {
+                break;
+            }
         }
     }
-    if let Some(f) = cur_flag {
-        args_map.insert(f.to_string(), None); // Insert cur_flag if last arg is a flag
+    Ok(Args { map: args_map })
+}
+
+// Value of the option given inline or as the next argument, which is not taken if it is an option
+fn take_value<'a, I: Iterator<Item = &'a str>>(
+    opt: &Opt,
+    inline: Option<&str>,
+    rest: &mut std::iter::Peekable<I>,
+) -> Result<Option<String>, ErrorKind> {
+    let next_is_value = rest
+        .peek()
+        .is_some_and(|a| !a.starts_with(SHORT_PREFIX) || *a == SHORT_PREFIX);
+    match (opt.value, inline) {
+        (ValueKind::None, Some(_)) => Err(ErrorKind::UnexpectedValue),
+        (ValueKind::None, None) => Ok(None),
+        (_, Some(v)) => Ok(Some(v.to_string())),
+        (ValueKind::Required, None) if !next_is_value => Err(ErrorKind::MissingValue),
+        (_, None) if next_is_value => Ok(rest.next().map(str::to_string)),
+        (_, None) => Ok(None),
+    }
+}
+
+// Help screen describing every option in the order given
+pub fn help(usage: &str, options: &[Opt]) -> String {
+    let mut res = format!("Usage: {}\n\nOptions:\n", usage);
+    for opt in options {
+        let short = match opt.short {
+            Some(c) => format!("{}{}, ", SHORT_PREFIX, c),
+            None => " ".repeat(SHORT_PREFIX.len() + 3),
+        };
+        let value = match opt.value {
+            ValueKind::None => String::new(),
+            ValueKind::Required => format!(" <{}>", opt.value_name),
+            ValueKind::Optional => format!(" [<{}>]", opt.value_name),
+        };
+        let name = format!("  {}{}{}{}", short, LONG_PREFIX, opt.long, value);
+
+        // Descriptions of long names start on the next line
+        match name.len() < HELP_COLUMN {
+            true => res.push_str(&format!("{:<width$}", name, width = HELP_COLUMN)),
+            false => res.push_str(&format!("{}\n{}", name, " ".repeat(HELP_COLUMN))),
+        }
+        res.push_str(opt.help);
+        res.push('\n');
     }
-    Ok(args_map)
+    res
 }
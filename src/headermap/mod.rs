@@ -0,0 +1,114 @@
+use std::borrow::Cow;
+
+// Headers whose values are comma separated lists, repeated ones are joined into one value
+const LIST_HEADERS: [&str; 16] = [
+    "Accept",
+    "Accept-Charset",
+    "Accept-Encoding",
+    "Accept-Language",
+    "Cache-Control",
+    "Connection",
+    "Forwarded",
+    "If-Match",
+    "If-None-Match",
+    "Pragma",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Via",
+    "X-Forwarded-For",
+];
+
+// Cookies are joined with semicolons instead, see RFC 6265 5.4
+const COOKIE_HEADER: &str = "Cookie";
+const COOKIE_DELIM: &str = "; ";
+const LIST_DELIM: &str = ", ";
+
+// Headers the routing and framing depend on, repeating them with another value is rejected
+const SINGLETON_HEADERS: [&str; 2] = ["Host", "Content-Length"];
+
+type Iter<'s, 'a> = std::iter::Map<
+    std::slice::Iter<'s, (&'a str, Cow<'a, str>)>,
+    fn(&'s (&'a str, Cow<'a, str>)) -> (&'a str, &'s str),
+>;
+
+// Request headers in the order received, names are matched case-insensitively
+// Repeated list headers are joined, other repeated headers are kept as separate entries
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap<'a> {
+    entries: Vec<(&'a str, Cow<'a, str>)>,
+}
+
+impl<'a> HeaderMap<'a> {
+    // Value of the first header with the name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    // Values of every header with the name in the order received
+    pub fn get_all<'s>(&'s self, name: &'s str) -> impl Iterator<Item = &'s str> + 's {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_ref())
+    }
+
+    pub fn iter(&self) -> Iter<'_, 'a> {
+        self.entries.iter().map(|(k, v)| (*k, v.as_ref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Add a header as received, false if it repeats a singleton header with another value
+    pub fn append(&mut self, name: &'a str, value: &'a str) -> bool {
+        let delim = match name {
+            n if n.eq_ignore_ascii_case(COOKIE_HEADER) => Some(COOKIE_DELIM),
+            n if LIST_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(n)) => Some(LIST_DELIM),
+            _ => None,
+        };
+        let is_singleton = SINGLETON_HEADERS
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name));
+        let existing = self
+            .entries
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name));
+        match (existing, delim) {
+            (Some((_, v)), Some(delim)) => {
+                let joined = v.to_mut();
+                joined.push_str(delim);
+                joined.push_str(value);
+            }
+            (Some((_, v)), None) if is_singleton => return v == value,
+            _ => self.entries.push((name, Cow::Borrowed(value))),
+        }
+        true
+    }
+}
+
+impl<'s, 'a> IntoIterator for &'s HeaderMap<'a> {
+    type Item = (&'a str, &'s str);
+    type IntoIter = Iter<'s, 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Whether the name is a token, i.e. no whitespace, separators or control characters, see RFC 9110 5.6.2
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
@@ -1,25 +1,71 @@
-use crate::filecache::FileCache;
-use crate::log::{get_log_level, LogLevel};
-use crate::requestmap::RequestMap;
+use crate::accesslog::AccessLogEntry;
+use crate::archive::entry_name;
+use crate::auth;
+use crate::bufpool::{self, PooledBuf};
+use crate::cgi;
+use crate::compress;
+use crate::debug::{self, DEBUG_PATH_PREFIX};
+use crate::fastcgi;
+use crate::filecache::{AbstractFile, Validator};
+use crate::forwarded;
+use crate::headermap::{self, HeaderMap};
+use crate::listing::{self, DirEntry};
+use crate::livereload;
+use crate::log::{self, Field, LogLevel};
+use crate::markdown::{self, Markdown};
+use crate::metrics;
+use crate::middleware::{self, Next};
+use crate::proxy;
+use crate::proxyproto;
+use crate::requestmap::{Access, Affinity, Client, EntryOptions, MapTarget, RequestMap};
+use crate::security::SecurityHeaders;
+#[cfg(target_os = "linux")]
+use crate::sendfile;
+use crate::ssi;
+use crate::state::{ServerState, Site};
 use crate::teewriter::tee_write;
-use crate::{info, log_ctx, trace};
+use crate::template;
+use crate::transform;
+use crate::upload;
+use crate::util::{
+    self, fmt_http_date, fmt_size, normalize_path, parse_http_date, percent_decode,
+    percent_encode_attr,
+};
+use crate::websocket;
+use crate::{error, log_ctx, slowlog, trace, warn};
+use rand::{thread_rng, Rng};
 use std::error::Error;
 use std::fmt::Display;
-use std::net::SocketAddr;
-use std::{borrow::Cow, collections::HashMap, io::Cursor, path::Path};
+use std::io::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use std::{borrow::Cow, future::Future, io::Cursor, path::Path};
 use tokio::io::AsyncBufReadExt;
 use tokio::{
-    io::{self, stdout, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{self, stdout, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::TcpStream,
+    time::{timeout, timeout_at, Instant},
 };
 
 const HEADER_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+const RETRY_AFTER_SECS: u64 = 1;
+const ERROR_PAGE_EXT: &str = "html"; // Default error pages are named by status, e.g. 404.html
+const HIDDEN_PATH_STATUS: u16 = 404; // Hidden paths are refused as if they did not exist
+const AFFINITY_COOKIE: &str = "affinity"; // Sample: Cookie: affinity=5f2a9c0e1b7d3846
+const STATIC_CONTENT_TYPE: &str = "text/plain; charset=utf-8"; // Body of static map entries
+const SSI_CONTENT_TYPE: &str = "text/html; charset=utf-8"; // Processed server side include pages
+const CHUNK_LINE_MAX_SIZE: usize = 4 * 1024; // Chunk size lines with their extensions, and each trailer
+const CHUNK_TRAILERS_MAX_SIZE: usize = 64 * 1024; // All the trailers after the last chunk
+const SLOW_REQUEST_LOG_CTX: &str = "SlowRequest"; // Context of the slow request lines, printed at any level
 log_ctx!("HTTP");
 
-async fn read_headers_buff<R: AsyncBufReadExt + Unpin>(
+// Read until the end of header, headers larger than the max size fail with InvalidData
+pub async fn read_headers_buff<R: AsyncBufReadExt + Unpin>(
     stream: &mut R,
-) -> Result<Vec<u8>, io::Error> {
-    let mut res = Vec::with_capacity(HEADER_BUFF_INIT_SIZE);
+    max_size: usize,
+) -> Result<PooledBuf, io::Error> {
+    let mut res = bufpool::take();
     const END_OF_HEADER: &[u8] = b"\r\n\r\n";
     let mut eoh_index = 0;
     loop {
@@ -51,6 +97,12 @@ async fn read_headers_buff<R: AsyncBufReadExt + Unpin>(
         }
         res.extend_from_slice(&buff[..consume_size]);
         stream.consume(consume_size);
+        if res.len() > max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Header larger than {} bytes", max_size),
+            ));
+        }
 
         // Break if the end of header is found
         if is_done {
@@ -60,18 +112,207 @@ async fn read_headers_buff<R: AsyncBufReadExt + Unpin>(
     Ok(res)
 }
 
-struct HttpRequest<'a> {
-    method: &'a str,
-    path: &'a str,
-    protocol: &'a str,
-    headers: HashMap<&'a str, &'a str>,
+// Malformed chunked framing fails with InvalidInput, so that it is told apart from the header limits
+fn bad_framing(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+// Size of the chunk of a chunk size line, ignoring chunk extensions
+fn chunk_size(line: &[u8]) -> io::Result<usize> {
+    let line = std::str::from_utf8(line).map_err(|_| bad_framing("Invalid chunk size"))?;
+    let size = line.trim().split(';').next().unwrap_or_default();
+    usize::from_str_radix(size.trim(), 16).map_err(|_| bad_framing("Invalid chunk size"))
+}
+
+// Read a line of the framing as bytes, 0 at the end of the stream
+// Lines are read up to CHUNK_LINE_MAX_SIZE, longer ones fail with InvalidInput
+async fn read_framing_line<R: AsyncBufReadExt + Unpin>(
+    stream: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<usize> {
+    line.clear();
+    let read = (&mut *stream)
+        .take(CHUNK_LINE_MAX_SIZE as u64)
+        .read_until(b'\n', line)
+        .await?;
+    match read == CHUNK_LINE_MAX_SIZE && !line.ends_with(b"\n") {
+        true => Err(bad_framing("Chunk line too long")),
+        false => Ok(read),
+    }
+}
+
+// Read the chunk size line, the body ending before it fails with UnexpectedEof
+async fn read_chunk_size<R: AsyncBufReadExt + Unpin>(
+    stream: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<usize> {
+    match read_framing_line(stream, line).await? {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        _ => chunk_size(line),
+    }
+}
+
+// The data of a chunk is followed by an empty line
+async fn read_chunk_end<R: AsyncBufReadExt + Unpin>(
+    stream: &mut R,
+    line: &mut Vec<u8>,
+) -> io::Result<()> {
+    match read_framing_line(stream, line).await? {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        _ if line.trim_ascii().is_empty() => Ok(()),
+        _ => Err(bad_framing("Invalid chunk line")),
+    }
+}
+
+// Read a trailer line, None once the trailers end with the empty line or the stream
+// Trailers over CHUNK_TRAILERS_MAX_SIZE in total fail with InvalidInput
+async fn read_trailer<'a, R: AsyncBufReadExt + Unpin>(
+    stream: &mut R,
+    line: &'a mut Vec<u8>,
+    total: &mut usize,
+) -> io::Result<Option<&'a [u8]>> {
+    let read = read_framing_line(stream, line).await?;
+    *total += read;
+    if *total > CHUNK_TRAILERS_MAX_SIZE {
+        return Err(bad_framing("Trailers too large"));
+    }
+    match read == 0 || line.trim_ascii().is_empty() {
+        true => Ok(None),
+        false => Ok(Some(line)),
+    }
+}
+
+// Decode a chunked body, bodies over the max size fail with FileTooLarge and malformed framing with InvalidInput
+pub async fn read_chunked<R: AsyncBufReadExt + Unpin>(
+    stream: &mut R,
+    max_size: usize,
+) -> Result<Vec<u8>, io::Error> {
+    let mut res = Vec::with_capacity(HEADER_BUFF_INIT_SIZE);
+    let mut line = Vec::new();
+    loop {
+        // Read chunk size, ignoring chunk extensions
+        let size = read_chunk_size(stream, &mut line).await?;
+
+        // Read trailers until empty line if last chunk
+        if size == 0 {
+            let mut trailers = 0;
+            while read_trailer(stream, &mut line, &mut trailers)
+                .await?
+                .is_some()
+            {}
+            return Ok(res);
+        }
+
+        // Read chunk data and the trailing line break
+        if size > max_size - res.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("Body larger than {} bytes", max_size),
+            ));
+        }
+        let start = res.len();
+        res.resize(start + size, 0);
+        stream.read_exact(&mut res[start..]).await?;
+        read_chunk_end(stream, &mut line).await?;
+    }
+}
+
+// Relay a chunked body chunk by chunk with its framing, so that each chunk reaches the writer once read
+// Fails like read_chunked
+pub async fn copy_chunked<R, W>(stream: &mut R, writer: &mut W, max_size: usize) -> io::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut copied = 0;
+    let mut line = Vec::new();
+    loop {
+        // Read chunk size, ignoring chunk extensions
+        let size = read_chunk_size(stream, &mut line).await?;
+        writer.write_all(&line).await?;
+
+        // Copy trailers until empty line if last chunk
+        if size == 0 {
+            let mut trailers = 0;
+            while let Some(trailer) = read_trailer(stream, &mut line, &mut trailers).await? {
+                writer.write_all(trailer).await?;
+            }
+            writer.write_all(b"\r\n").await?;
+            return writer.flush().await;
+        }
+
+        // Copy chunk data and the trailing line break
+        if size > max_size - copied {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("Body larger than {} bytes", max_size),
+            ));
+        }
+        copied += size;
+        let mut chunk = (&mut *stream).take(size as u64);
+        if io::copy_buf(&mut chunk, writer).await? < size as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        read_chunk_end(stream, &mut line).await?;
+        writer.write_all(b"\r\n").await?;
+        writer.flush().await?;
+    }
+}
+
+// How the length of a request body is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+pub struct HttpRequest<'a> {
+    pub method: &'a str,
+    pub path: Cow<'a, str>, // Percent decoded
+    pub query: Option<&'a str>,
+    pub params: Vec<(Cow<'a, str>, Cow<'a, str>)>, // Decoded query parameters in order
+    pub protocol: &'a str,
+    pub version: HttpVersion, // Version parsed from the protocol
+    pub headers: HeaderMap<'a>,
+}
+
+// Versions of HTTP/1 served, later minor versions are served as HTTP/1.1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpVersion {
+    Http10,
+    #[default]
+    Http11,
+}
+
+impl HttpVersion {
+    // None if the major version is not 1, e.g. HTTP/2.0 or HTTP/0.9
+    pub fn parse(protocol: &str) -> Option<Self> {
+        let minor = protocol.strip_prefix("HTTP/1.")?;
+        match minor {
+            "0" => Some(HttpVersion::Http10),
+            m if !m.is_empty() && m.bytes().all(|b| b.is_ascii_digit()) => {
+                Some(HttpVersion::Http11)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+        }
+    }
 }
 
 #[derive(Debug)]
-enum ParseHttpError {
+pub(crate) enum ParseHttpError {
     EmptyStartLine,
     InvalidStartLine(String),
     InvalidHeader(String),
+    PathEscape(String),
+    UnsupportedVersion(String),
 }
 
 impl Display for ParseHttpError {
@@ -86,6 +327,16 @@ impl Display for ParseHttpError {
             ParseHttpError::InvalidHeader(s) => {
                 write!(f, "Failed to parse HTTP request. Invalid header: {}", s)
             }
+            ParseHttpError::PathEscape(s) => {
+                write!(f, "Failed to parse HTTP request. Path escapes root: {}", s)
+            }
+            ParseHttpError::UnsupportedVersion(s) => {
+                write!(
+                    f,
+                    "Failed to parse HTTP request. Unsupported version: {}",
+                    s
+                )
+            }
         }
     }
 }
@@ -104,8 +355,101 @@ impl Error for ParseHttpError {
     }
 }
 
+// Failure of a request, classified by the response it should get
+#[derive(Debug)]
+enum RequestError {
+    BadRequest(Box<dyn Error + Send + Sync>), // Malformed request
+    Timeout,                                  // Client too slow to send the request
+    HeadersTooLarge,                          // Headers over the size or count limit
+    PayloadTooLarge,                          // Body over the size limit
+    NotImplemented(String),                   // Unsupported transfer coding
+    VersionNotSupported(String),              // Major version other than HTTP/1
+    Forbidden(IpAddr), // Client named by a trusted proxy refused by the IP filter
+    Internal(Box<dyn Error + Send + Sync>), // Fault of the server
+    Closed(io::Error), // No response can be written, e.g. client gone or response partially written
+}
+
+impl RequestError {
+    fn status(&self) -> Option<u16> {
+        match self {
+            RequestError::BadRequest(_) => Some(400),
+            RequestError::Timeout => Some(408),
+            RequestError::HeadersTooLarge => Some(431),
+            RequestError::PayloadTooLarge => Some(413),
+            RequestError::NotImplemented(_) => Some(501),
+            RequestError::VersionNotSupported(_) => Some(505),
+            RequestError::Forbidden(_) => Some(403),
+            RequestError::Internal(_) => Some(500),
+            RequestError::Closed(_) => None,
+        }
+    }
+
+    // Errors reading from the client, timeouts, limits and malformed bodies are answered while other errors mean the client is gone
+    fn from_read(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => RequestError::Timeout,
+            io::ErrorKind::InvalidData => RequestError::HeadersTooLarge,
+            io::ErrorKind::InvalidInput => RequestError::BadRequest(e.into()),
+            io::ErrorKind::FileTooLarge => RequestError::PayloadTooLarge,
+            _ => RequestError::Closed(e),
+        }
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::BadRequest(e) => write!(f, "Bad request: {}", e),
+            RequestError::Timeout => write!(f, "Timed out reading request"),
+            RequestError::HeadersTooLarge => write!(f, "Request headers too large"),
+            RequestError::PayloadTooLarge => write!(f, "Request body too large"),
+            RequestError::NotImplemented(t) => write!(f, "Unsupported transfer encoding: {}", t),
+            RequestError::VersionNotSupported(v) => write!(f, "Unsupported HTTP version: {}", v),
+            RequestError::Forbidden(ip) => write!(f, "Client {} refused", ip),
+            RequestError::Internal(e) => write!(f, "Internal error: {}", e),
+            RequestError::Closed(e) => write!(f, "Connection error: {}", e),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+}
+
 impl<'a> HttpRequest<'a> {
-    fn parse(raw_str: &'a str) -> Result<Self, ParseHttpError> {
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
+    }
+
+    // Value of the first cookie with the given name
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get_all("Cookie")
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| c.split_once('='))
+            .find(|(k, _)| k.trim() == name)
+            .map(|(_, v)| v.trim())
+    }
+
+    // Value of the first query parameter with the given name
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_ref())
+    }
+
+    pub(crate) fn parse(raw_str: &'a str) -> Result<Self, ParseHttpError> {
         // Construct iterator
         let mut header_lines = raw_str.lines().take_while(|l| !l.is_empty());
 
@@ -117,94 +461,573 @@ impl<'a> HttpRequest<'a> {
             .ok_or(ParseHttpError::InvalidStartLine(
                 "Missing HTTP method".into(),
             ))?;
-        let path = start_line_items
+        let target = start_line_items
             .next()
             .ok_or(ParseHttpError::InvalidStartLine("Missing HTTP path".into()))?;
+
+        // Split off the query string
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (p, Some(q)),
+            None => (target, None),
+        };
+        let params = query.map(parse_query).unwrap_or_default();
+
+        // Decode the path, backslashes and nul are rejected as they are separators or terminators on some platforms
+        let path = match path.contains('%') {
+            true => Cow::Owned(percent_decode(path).ok_or_else(|| {
+                ParseHttpError::InvalidStartLine(format!("Invalid path encoding {}", path))
+            })?),
+            false => Cow::Borrowed(path),
+        };
+        if path.contains(['\\', '\0']) {
+            return Err(ParseHttpError::InvalidStartLine(format!(
+                "Invalid path character {}",
+                path
+            )));
+        }
+
+        // Resolve dot segments so that the path cannot escape the res root
+        let is_dirty = path.contains("//") || path.contains("/.");
+        let path = match path.starts_with('/') && is_dirty {
+            true => match normalize_path(&path) {
+                Some(p) => Cow::Owned(p),
+                None => return Err(ParseHttpError::PathEscape(path.into_owned())),
+            },
+            false => path,
+        };
         let protocol = start_line_items
             .next()
             .ok_or(ParseHttpError::InvalidStartLine(
                 "Missing HTTP version".into(),
             ))?;
+        let is_digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        let version = match HttpVersion::parse(protocol) {
+            Some(v) => v,
+            None => match protocol
+                .strip_prefix("HTTP/")
+                .and_then(|v| v.split_once('.'))
+            {
+                Some((major, minor)) if is_digits(major) && is_digits(minor) => {
+                    return Err(ParseHttpError::UnsupportedVersion(protocol.to_string()))
+                }
+                _ => {
+                    return Err(ParseHttpError::InvalidStartLine(format!(
+                        "Invalid HTTP version {}",
+                        protocol
+                    )))
+                }
+            },
+        };
 
         // Parse headers
-        let mut headers = HashMap::new();
+        // Names with surrounding whitespace and folded lines are rejected, see RFC 9112 5
+        let mut headers = HeaderMap::default();
         for line in header_lines {
-            let mut key_val = line.split(':');
-            let key = key_val
-                .next()
-                .ok_or(ParseHttpError::InvalidHeader(line.to_string()))?
-                .trim();
-            let val = key_val
-                .next()
-                .ok_or(ParseHttpError::InvalidHeader(line.to_string()))?
-                .trim();
-            headers.insert(key, val);
+            // Split at the first colon only as values may contain colons, e.g. dates
+            let (key, val) = line
+                .split_once(':')
+                .filter(|(k, _)| headermap::is_valid_name(k))
+                .ok_or(ParseHttpError::InvalidHeader(line.to_string()))?;
+            if !headers.append(key, val.trim()) {
+                return Err(ParseHttpError::InvalidHeader(format!(
+                    "Conflicting {} headers",
+                    key
+                )));
+            }
         }
 
         Ok(HttpRequest {
             method,
             path,
+            query,
+            params,
             protocol,
+            version,
             headers,
         })
     }
 }
 
+// Decode a query component where '+' stands for a space, undecodable components are kept as is
+fn decode_query_component(s: &str) -> Cow<'_, str> {
+    match s.contains(['%', '+']) {
+        true => percent_decode(&s.replace('+', " "))
+            .map(Cow::Owned)
+            .unwrap_or(Cow::Borrowed(s)),
+        false => Cow::Borrowed(s),
+    }
+}
+
+// Split a query string into decoded name value pairs
+fn parse_query(query: &str) -> Vec<(Cow<'_, str>, Cow<'_, str>)> {
+    let decode = decode_query_component;
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
+pub enum ResponseBody {
+    Empty,
+    Bytes(Vec<u8>),
+    File(AbstractFile),
+}
+
+impl ResponseBody {
+    fn len(&self) -> usize {
+        match self {
+            ResponseBody::Empty => 0,
+            ResponseBody::Bytes(b) => b.len(),
+            ResponseBody::File(f) => f.len(),
+        }
+    }
+
+    pub async fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            ResponseBody::Empty => Ok(Vec::new()),
+            ResponseBody::Bytes(b) => Ok(b),
+            ResponseBody::File(mut f) => {
+                let mut buf = Vec::with_capacity(f.len());
+                f.read_to_end(&mut buf).await?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+pub struct HttpResponse {
+    status: u16,
+    version: HttpVersion, // Version of the status line, the one of the request once answered
+    headers: Vec<(Cow<'static, str>, String)>,
+    security: Option<bool>, // Whether the map entry adds the security headers, None follows the server
+    head_only: bool,        // Answer to a HEAD request, the headers of the body are sent without it
+    body: ResponseBody,
+}
+
+pub fn status_reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        418 => "I'm a teapot",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+impl HttpResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            version: HttpVersion::default(),
+            headers: Vec::new(),
+            security: None,
+            head_only: false,
+            body: ResponseBody::Empty,
+        }
+    }
+
+    pub fn with_header(
+        mut self,
+        key: impl Into<Cow<'static, str>>,
+        val: impl Into<String>,
+    ) -> Self {
+        self.headers.push((key.into(), val.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: ResponseBody) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn with_text(self, content_type: &str, text: impl Into<String>) -> Self {
+        self.with_header("Content-Type", content_type)
+            .with_body(ResponseBody::Bytes(text.into().into_bytes()))
+    }
+
+    pub fn not_found() -> Self {
+        const NOT_FOUND_MSG: &str = "NOT FOUND";
+        Self::new(404).with_body(ResponseBody::Bytes(NOT_FOUND_MSG.into()))
+    }
+
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn without_header(mut self, key: &str) -> Self {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn body_len(&self) -> usize {
+        self.body.len()
+    }
+
+    pub fn take_body(&mut self) -> ResponseBody {
+        std::mem::replace(&mut self.body, ResponseBody::Empty)
+    }
+
+    // Answer with the version of the request, so that HTTP/1.0 clients get an HTTP/1.0 response
+    pub fn set_version(&mut self, version: HttpVersion) {
+        self.version = version;
+    }
+
+    // Answer with the version of the request, and without the body if it is a HEAD request
+    // The Content-Length stays the one of the body, as for the same GET request
+    pub fn answer(&mut self, req: &HttpRequest<'_>) {
+        self.version = req.version;
+        self.head_only = req.method == "HEAD";
+    }
+
+    pub fn status_line(&self) -> String {
+        format!("{} {}", self.status, status_reason(self.status))
+    }
+
+    // Writing to the buffer never fails, so the results of write are ignored
+    fn header_buf(&self, keep_alive: bool) -> PooledBuf {
+        let mut res = bufpool::take();
+        let (version, reason) = (self.version.as_str(), status_reason(self.status));
+        let _ = write!(res, "{} {} {}\r\n", version, self.status, reason); // Write the status line
+
+        // Write the content length, responses which never have a body have none
+        if !matches!(self.status, 100..=199 | 204 | 304) {
+            let _ = write!(res, "Content-Length: {}\r\n", self.body.len());
+        }
+        for (key, val) in &self.headers {
+            let _ = write!(res, "{}: {}\r\n", key, val);
+        }
+        // Responses switching protocols carry their own connection header
+        if self.header("Connection").is_none() {
+            res.extend_from_slice(match keep_alive {
+                true => b"Connection: keep-alive\r\n",
+                false => b"Connection: close\r\n", // Close the connection
+            });
+        }
+        res.extend_from_slice(b"\r\n"); // End of header
+        res
+    }
+
+    // Write the response to the client socket, bodies of files on disk are sent by the kernel where supported
+    // and memory mapped bodies are written straight from their pages
+    // Bodies are copied through userspace if they are also traced to stdout
+    pub async fn send_to<W>(&mut self, w_stream: &mut W, keep_alive: bool) -> io::Result<()>
+    where
+        W: AsyncWrite + AsRef<TcpStream> + Unpin + Send,
+    {
+        #[cfg(target_os = "linux")]
+        if let ResponseBody::File(f) = &self.body {
+            if !self.head_only && !log::enabled(LogLevel::Trace, _LOG_CTX_JK23BN4KJ2) {
+                if let Some(file) = f.disk_file() {
+                    w_stream.write_all(&self.header_buf(keep_alive)).await?;
+                    w_stream.flush().await?;
+                    return sendfile::send(w_stream.as_ref(), file, f.len()).await;
+                }
+                if let Some(data) = f.mapped() {
+                    w_stream.write_all(&self.header_buf(keep_alive)).await?;
+                    w_stream.write_all(data).await?;
+                    return w_stream.flush().await;
+                }
+            }
+        }
+        self.write_to(w_stream, keep_alive).await
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        w_stream: &mut W,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        // convert header to stream and chain with the body
+        let header = Cursor::new(self.header_buf(keep_alive));
+        let mut empty_body = Cursor::new(&[] as &[u8]);
+        let mut bytes_body;
+        let body = match &mut self.body {
+            _ if self.head_only => &mut empty_body as &mut (dyn AsyncRead + Unpin + Send),
+            ResponseBody::Empty => &mut empty_body,
+            ResponseBody::Bytes(b) => {
+                bytes_body = Cursor::new(b.as_slice());
+                &mut bytes_body
+            }
+            ResponseBody::File(f) => f,
+        };
+        let mut res = AsyncReadExt::chain(header, body);
+
+        // declare output streams
+        let mut ostreams = vec![w_stream as &mut (dyn AsyncWrite + Unpin + Send)];
+
+        // Copy to stdout only if trace is enabled
+        let mut stdout = match log::enabled(LogLevel::Trace, _LOG_CTX_JK23BN4KJ2) {
+            true => Some(stdout()),
+            false => None,
+        };
+        if let Some(stdout) = &mut stdout {
+            // Copy to stdout only if trace is enabled
+            trace!("");
+            ostreams.push(stdout);
+        }
+        tee_write(&mut res, &mut ostreams).await?;
+
+        if let Some(stdout) = &mut stdout {
+            // Write a new line to stdout
+            stdout.write_all(b"\n").await?;
+            stdout.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+// Answer 403 to a client refused by the IP filter, the request is never read
+pub(crate) async fn refuse<W: AsyncWrite + Unpin + Send>(w_stream: &mut W) {
+    let mut res = HttpResponse::new(403).with_text("text/plain", status_reason(403));
+    let _ = res.write_to(w_stream, false).await;
+}
+
 pub async fn handle_connection(
     sockaddr: &SocketAddr,
     mut stream: TcpStream,
-    res_file_root: &Path,
-    file_cache: &FileCache,
-    request_map: Option<&RequestMap>,
+    state: &ServerState,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let start = std::time::Instant::now();
-
     // Split stream to a buffered reader and a writer
     let (r_stream, mut w_stream) = stream.split();
     let mut r_stream = BufReader::with_capacity(HEADER_BUFF_INIT_SIZE, r_stream);
 
-    // Read the header
-    let header_buff = read_headers_buff(&mut r_stream).await?;
-    let http_request = String::from_utf8(header_buff)?;
-    let http_request = HttpRequest::parse(&http_request)?;
-
-    // Read the body if request is POST
-    let body_buff = if http_request.method == "POST" {
-        // Get content length
-        let content_length = http_request.headers.get("Content-Length");
-        if content_length.is_none() {
-            return Err("Cannot find content length".into());
-        }
-        let content_length = match content_length.unwrap().parse::<usize>() {
-            Ok(l) => l,
-            Err(e) => return Err(format!("Failed read content length: {}", e).into()),
+    // Take the client from the preamble of the proxy, which speaks for itself without client
+    let client;
+    let sockaddr = match state.proxy_protocol {
+        true => {
+            if !forwarded::is_trusted(sockaddr.ip(), &state.trusted_proxies) {
+                return Err(format!("PROXY protocol preamble from untrusted {}", sockaddr).into());
+            }
+            let preamble = proxyproto::read_preamble(&mut r_stream);
+            let preamble = read_timeout(state.header_timeout, None, preamble).await?;
+            client = preamble.unwrap_or(*sockaddr);
+            if !state.ip_filter.permits(client.ip()) {
+                crate::debug!("connection refused for {} via {}", client, sockaddr);
+                state.metrics.record_refused_connection();
+                if !state.drop_refused {
+                    refuse(&mut w_stream).await;
+                }
+                return Ok(());
+            }
+            &client
+        }
+        false => sockaddr,
+    };
+    let _open = state.metrics.open_connection(sockaddr);
+
+    // Serve requests until the client closes, asks to close or stays idle for too long
+    let mut served = 0;
+    loop {
+        // Wait for the next request on a persistent connection
+        if served > 0 {
+            match timeout(state.idle_timeout, r_stream.fill_buf()).await {
+                Ok(Ok([])) => break, // Closed by client
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => {
+                    crate::debug!("Idle timeout for {}", sockaddr);
+                    break;
+                }
+            }
+        }
+
+        // Answer failed requests with the matching status before closing
+        // Gather the details of the request for the slow request log if enabled
+        let request = handle_request(sockaddr, &mut r_stream, &mut w_stream, state);
+        let res = match state.slow_request.is_zero() {
+            true => request.await,
+            false => slowlog::scope(request).await,
         };
+        let keep_alive = match res {
+            Ok(k) => k,
+            Err(e) => {
+                let status = match e.status() {
+                    Some(s) => s,
+                    None => return Err(e.into()),
+                };
+                let res = HttpResponse::new(status).with_text("text/plain", status_reason(status));
+                let res = error_page(res, state.site(None)).await?;
+                let mut res = with_security_headers(res, state);
+                if res.write_to(&mut w_stream, false).await.is_ok() {
+                    let _ = stream.shutdown().await;
+                }
 
-        // Read the body
-        let mut buff = vec![0; content_length];
-        r_stream.read_exact(&mut buff).await?;
-        Some(buff)
-    } else {
-        None
+                // Client errors are logged here, server faults are returned to the caller
+                if status >= 500 {
+                    return Err(e.into());
+                }
+                warn!("{} -> {}: {}", sockaddr, res.status_line(), e);
+                return Ok(());
+            }
+        };
+        served += 1;
+        if !keep_alive {
+            break;
+        }
+    }
+
+    // Flush and shutdown the stream
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Serve a single request, returns whether the connection should be kept alive
+async fn handle_request<R, W>(
+    sockaddr: &SocketAddr,
+    r_stream: &mut R,
+    w_stream: &mut W,
+    state: &ServerState,
+) -> Result<bool, RequestError>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWrite + AsRef<TcpStream> + Unpin + Send,
+{
+    let start = std::time::Instant::now();
+    let deadline =
+        (!state.request_timeout.is_zero()).then(|| Instant::now() + state.request_timeout);
+
+    // Read the header
+    let header_read = read_headers_buff(r_stream, state.max_header_size);
+    let header_buff = read_timeout(state.header_timeout, deadline, header_read)
+        .await
+        .map_err(RequestError::from_read)?;
+    let raw_request =
+        std::str::from_utf8(&header_buff).map_err(|e| RequestError::BadRequest(e.into()))?;
+    let request_line = raw_request.lines().next().unwrap_or_default();
+    let header_count = raw_request
+        .lines()
+        .skip(1)
+        .take_while(|l| !l.is_empty())
+        .count();
+    if header_count > state.max_header_count {
+        return Err(RequestError::HeadersTooLarge);
+    }
+    let http_request = match HttpRequest::parse(raw_request) {
+        Ok(r) => r,
+        Err(ParseHttpError::PathEscape(p)) => {
+            warn!("Rejected path escaping root from {}: {}", sockaddr, p);
+            let res = HttpResponse::new(403).with_text("text/plain", status_reason(403));
+            let mut res = error_page(res, state.site(None))
+                .await
+                .map_err(|e| RequestError::Internal(e.into()))?;
+            write_access_log(state, sockaddr, request_line, None, &res);
+            res.write_to(w_stream, false)
+                .await
+                .map_err(RequestError::Closed)?;
+            return Ok(false);
+        }
+        Err(ParseHttpError::UnsupportedVersion(v)) => {
+            return Err(RequestError::VersionNotSupported(v))
+        }
+        Err(e) => return Err(RequestError::BadRequest(e.into())),
+    };
+
+    // Take the client from the forwarding headers of trusted proxies, the port is unknown
+    let forwarded;
+    let sockaddr = match forwarded::client_ip(sockaddr.ip(), &http_request, &state.trusted_proxies)
+    {
+        Some(ip) if !state.ip_filter.permits(ip) => {
+            state.metrics.record_refused_connection();
+            return Err(RequestError::Forbidden(ip));
+        }
+        Some(ip) => {
+            forwarded = SocketAddr::new(ip, 0);
+            &forwarded
+        }
+        None => sockaddr,
     };
 
+    // Hand upgrade requests and streamed proxy requests over to the mapped entry before the body is read
+    // The connection is not reused after
+    let framing = body_framing(&http_request, state)?;
+    let site = state.site(http_request.header("Host"));
+    let upgraded = upgrade(
+        sockaddr,
+        &http_request,
+        framing,
+        site,
+        state,
+        r_stream,
+        w_stream,
+    )
+    .await
+    .map_err(RequestError::Closed)?;
+
+    // Read the body of any method so that the next request on the connection starts at its header
+    let body_buff = match upgraded {
+        Some(_) => None,
+        None => read_body(r_stream, framing, state, deadline).await?,
+    };
+
+    // Keep the connection alive unless asked otherwise, HTTP/1.0 clients must ask for it
+    let keep_alive = !state.idle_timeout.is_zero()
+        && match http_request
+            .header("Connection")
+            .map(|c| c.to_ascii_lowercase())
+        {
+            Some(c) if c.contains("close") => false,
+            Some(c) if c.contains("keep-alive") => true,
+            _ => http_request.version == HttpVersion::Http11,
+        };
+
     // Log request if trace is enabled
-    if get_log_level() <= LogLevel::Trace {
+    if log::enabled(LogLevel::Trace, _LOG_CTX_JK23BN4KJ2) {
         let mut msg = format!(
-            "\n{} {} {}\n",
-            http_request.method, http_request.path, http_request.protocol
+            "\n{} {}{} {}\n",
+            http_request.method,
+            http_request.path,
+            http_request
+                .query
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default(),
+            http_request.protocol
         );
-        for (key, val) in http_request.headers {
+        for (key, val) in &http_request.headers {
             msg.push_str(&format!("{}: {}\n", key, val));
         }
 
         // Read the body if request is POST
-        if let Some(body_buff) = body_buff {
+        if let Some(body_buff) = &body_buff {
             // Line break for body
             msg.push('\n');
 
             // // Read the body
-            let body = String::from_utf8_lossy(&body_buff);
+            let body = String::from_utf8_lossy(body_buff);
             msg.push_str(&body);
         }
 
@@ -212,106 +1035,1053 @@ pub async fn handle_connection(
         trace!("{}", msg);
     }
 
-    // Try to find the file from the map, if not exists, use the http request path as it is
-    let file_path = match request_map {
-        Some(map) => map.get(http_request.path),
+    // Stream the changes to the pages of the dev mode, the connection is not reused either
+    let upgraded = match (&state.live_reload, upgraded) {
+        (Some(l), None) if livereload::is_feed(&http_request) => Some(
+            livereload::feed(l, site, r_stream, w_stream)
+                .await
+                .map_err(RequestError::Closed)?,
+        ),
+        (_, upgraded) => upgraded,
+    };
+    let is_upgraded = upgraded.is_some();
+    let (response, keep_alive) = match upgraded {
+        Some(res) => {
+            write_access_log(state, sockaddr, request_line, Some(&http_request), &res);
+            (res, false)
+        }
+        None => {
+            // Build the response
+            let response = respond(
+                sockaddr,
+                &http_request,
+                body_buff.as_deref().map(Vec::as_slice),
+                site,
+                state,
+            )
+            .await
+            .map_err(|e| RequestError::Internal(e.into()))?;
+
+            // Write the response
+            let mut response = with_security_headers(response, state);
+            response.answer(&http_request);
+            write_access_log(
+                state,
+                sockaddr,
+                request_line,
+                Some(&http_request),
+                &response,
+            );
+            response
+                .send_to(w_stream, keep_alive)
+                .await
+                .map_err(RequestError::Closed)?;
+            (response, keep_alive)
+        }
+    };
+    state
+        .metrics
+        .record_request(response.status(), start.elapsed());
+
+    // Upgraded connections are held open by design, so they are never slow
+    let elapsed = start.elapsed();
+    let is_slow = !state.slow_request.is_zero() && elapsed >= state.slow_request && !is_upgraded;
+    if is_slow {
+        log_slow_request(sockaddr, &http_request, site, &response, elapsed);
+    }
+    if !state.request_log.should_log(response.status(), is_slow) {
+        return Ok(keep_alive);
+    }
+
+    // Log the request & response, the json format carries the parts as fields as well
+    let duration_us = elapsed.as_micros() as u64;
+    let log_line = format!(
+        "{} {} {} -> {} [{}μs]",
+        sockaddr,
+        &http_request.method,
+        &http_request.path,
+        response.status_line(),
+        duration_us
+    );
+    if log::enabled(LogLevel::Info, _LOG_CTX_JK23BN4KJ2) {
+        let remote_addr = sockaddr.to_string();
+        let mut fields = vec![
+            ("remote_addr", Field::Str(&remote_addr)),
+            ("method", Field::Str(http_request.method)),
+            ("path", Field::Str(&http_request.path)),
+            ("status", Field::Num(response.status() as u64)),
+            ("duration_us", Field::Num(duration_us)),
+        ];
+        if let Some(name) = &site.name {
+            fields.push(("site", Field::Str(name)));
+        }
+        let msg = match &site.name {
+            Some(name) => format!("[{}] {}", name, log_line),
+            None => log_line.clone(),
+        };
+        log::write(
+            LogLevel::Info,
+            _LOG_CTX_JK23BN4KJ2,
+            format_args!("{}", msg),
+            &fields,
+        );
+    }
+    if let Err(e) = site.write_log(&log_line).await {
+        error!("Failed to write log of {}: {}", site.display_name(), e);
+    }
+    Ok(keep_alive)
+}
+
+// Log the request in full at warn, regardless of the level set for the context
+fn log_slow_request(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    site: &Site,
+    response: &HttpResponse,
+    elapsed: Duration,
+) {
+    let stats = slowlog::current().unwrap_or_default();
+    let remote_addr = sockaddr.to_string();
+    let bytes = response.body_len();
+    let mut msg = format!(
+        "{} {} {}{} -> {} [{}ms] {}",
+        sockaddr,
+        http_request.method,
+        http_request.path,
+        http_request
+            .query
+            .map(|q| format!("?{}", q))
+            .unwrap_or_default(),
+        response.status_line(),
+        elapsed.as_millis(),
+        fmt_size(bytes)
+    );
+    let cache = stats.cache_summary();
+    if let Some(c) = &cache {
+        msg.push_str(&format!(", cache {}", c));
+    }
+    if let Some(u) = stats.upstream {
+        msg.push_str(&format!(", upstream {}ms", u.as_millis()));
+    }
+    if let Some(name) = &site.name {
+        msg = format!("[{}] {}", name, msg);
+    }
+
+    let mut fields = vec![
+        ("remote_addr", Field::Str(&remote_addr)),
+        ("method", Field::Str(http_request.method)),
+        ("path", Field::Str(&http_request.path)),
+        ("status", Field::Num(response.status() as u64)),
+        ("duration_us", Field::Num(elapsed.as_micros() as u64)),
+        ("bytes", Field::Num(bytes as u64)),
+    ];
+    if let Some(c) = &cache {
+        fields.push(("cache", Field::Str(c)));
+    }
+    if let Some(u) = stats.upstream {
+        fields.push(("upstream_us", Field::Num(u.as_micros() as u64)));
+    }
+    if let Some(name) = &site.name {
+        fields.push(("site", Field::Str(name)));
+    }
+    log::write(
+        LogLevel::Warn,
+        SLOW_REQUEST_LOG_CTX,
+        format_args!("{}", msg),
+        &fields,
+    );
+}
+
+// Framing of the request body, checked before any of it is read
+fn body_framing(
+    http_request: &HttpRequest<'_>,
+    state: &ServerState,
+) -> Result<BodyFraming, RequestError> {
+    let content_length = http_request.header("Content-Length");
+    match http_request.header("Transfer-Encoding") {
+        // Both headers are ambiguous and could smuggle a request past an intermediary
+        Some(_) if content_length.is_some() => Err(RequestError::BadRequest(
+            "Both Content-Length and Transfer-Encoding are set".into(),
+        )),
+        Some(t) if t.trim().eq_ignore_ascii_case("chunked") => Ok(BodyFraming::Chunked),
+        Some(t) => Err(RequestError::NotImplemented(t.to_string())),
+        None => {
+            let content_length = match content_length.map(|l| l.trim().parse::<usize>()) {
+                Some(Ok(l)) => l,
+                Some(Err(e)) => {
+                    let e = format!("Failed read content length: {}", e);
+                    return Err(RequestError::BadRequest(e.into()));
+                }
+                None if http_request.method == "POST" => {
+                    return Err(RequestError::BadRequest(
+                        "Cannot find content length".into(),
+                    ))
+                }
+                None => return Ok(BodyFraming::None),
+            };
+
+            // Refuse before reading so that the size is never allocated
+            if content_length > state.max_body_size {
+                return Err(RequestError::PayloadTooLarge);
+            }
+            Ok(BodyFraming::Length(content_length))
+        }
+    }
+}
+
+// Read the body framed by Transfer-Encoding or Content-Length, None if the request has no body
+async fn read_body<R: AsyncBufReadExt + Unpin>(
+    r_stream: &mut R,
+    framing: BodyFraming,
+    state: &ServerState,
+    deadline: Option<Instant>,
+) -> Result<Option<PooledBuf>, RequestError> {
+    let body = match framing {
+        BodyFraming::None => return Ok(None),
+        BodyFraming::Chunked => {
+            let read = read_chunked(r_stream, state.max_body_size);
+            read_timeout(state.body_timeout, deadline, read)
+                .await
+                .map(PooledBuf::from)
+        }
+        BodyFraming::Length(len) => {
+            let mut buff = bufpool::take();
+            buff.resize(len, 0);
+            let read = r_stream.read_exact(&mut buff);
+            read_timeout(state.body_timeout, deadline, read)
+                .await
+                .map(|_| buff)
+        }
+    };
+    body.map(Some).map_err(RequestError::from_read)
+}
+
+// Read within the timeout and the deadline of the whole request, elapsed reads fail with TimedOut
+// Zero timeouts are not limited
+pub async fn read_timeout<T>(
+    dur: Duration,
+    deadline: Option<Instant>,
+    read: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let at = (!dur.is_zero()).then(|| Instant::now() + dur);
+    let at = match (at, deadline) {
+        (Some(at), Some(deadline)) => at.min(deadline),
+        (Some(at), None) | (None, Some(at)) => at,
+        (None, None) => return read.await,
+    };
+    match timeout_at(at, read).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Read timed out")),
+    }
+}
+
+// Upgrade to the websocket handler or the upstream of the mapped entry, or stream to the upstream
+// Returns the written response, None if the entry does not take over the connection, the request is then served as usual
+async fn upgrade<R, W>(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    framing: BodyFraming,
+    site: &Site,
+    state: &ServerState,
+    r_stream: &mut R,
+    w_stream: &mut W,
+) -> io::Result<Option<HttpResponse>>
+where
+    R: AsyncBufReadExt + Unpin,
+    W: AsyncWrite + Unpin + Send,
+{
+    let is_upgrade = websocket::is_upgrade(http_request);
+    let request_map = site.request_map();
+    if !is_upgrade && !request_map.as_ref().is_some_and(|m| m.has_proxies()) {
+        return Ok(None);
+    }
+    let (cookie, _) = affinity_cookie(http_request);
+    let client = Client {
+        ip: sockaddr.ip(),
+        cookie: &cookie,
+        request: http_request,
+    };
+    let (target, options) = match request_map
+        .as_ref()
+        .and_then(|m| m.get(&http_request.path, http_request.method, &client))
+    {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+
+    // Denied paths are refused by the regular handling
+    if denied(request_map.as_deref(), &http_request.path, true).is_some() {
+        return Ok(None);
+    }
+    let streamed = options.stream || proxy::is_streamed(http_request);
+    let res = match (target, options.websocket) {
+        (MapTarget::File(_), None)
+        | (MapTarget::Static(..) | MapTarget::Cgi(_) | MapTarget::FastCgi(_), _) => {
+            return Ok(None)
+        }
+        (MapTarget::File(_), _) if !is_upgrade => return Ok(None),
+        (MapTarget::Proxy(_), _) if !is_upgrade && !streamed => return Ok(None),
+        _ if !options.ip_filter.permits(sockaddr.ip()) => {
+            let res = HttpResponse::new(403).with_text("text/plain", status_reason(403));
+            let mut res = error_page(res, site).await?;
+            res.answer(http_request);
+            res.write_to(w_stream, false).await?;
+            res
+        }
+        _ if !is_authorized(http_request, options, site).await? => {
+            let mut res = error_page(auth::unauthorized(&http_request.path), site).await?;
+            res.answer(http_request);
+            res.write_to(w_stream, false).await?;
+            res
+        }
+        (MapTarget::Proxy(u), _) if is_upgrade => {
+            let policy = &options.upstream_policy;
+            websocket::tunnel(u[0], policy, sockaddr, http_request, r_stream, w_stream).await?
+        }
+        (MapTarget::Proxy(u), _) => {
+            let (policy, max) = (&options.upstream_policy, state.max_body_size);
+            let body = (framing, max);
+            proxy::stream(&u, policy, body, sockaddr, http_request, r_stream, w_stream).await?
+        }
+        (MapTarget::File(_), Some(h)) => {
+            websocket::accept(h, http_request, r_stream, w_stream).await?
+        }
+    };
+    Ok(Some(res))
+}
+
+// Build the response of a parsed request independent of the transport it arrived on
+// The HTTP/1.1 framing (reading, keep-alive, writing) is handled by the caller
+pub async fn respond(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    site: &Site,
+    state: &ServerState,
+) -> io::Result<HttpResponse> {
+    // Walk the middleware chain, which ends with the built-in handling
+    let req = middleware::Request {
+        sockaddr,
+        http: http_request,
+        body,
+        site,
+    };
+    Next::new(&state.middleware, state).run(req).await
+}
+
+// Add the security headers of the server, or the preset for map entries asking for them
+fn with_security_headers(res: HttpResponse, state: &ServerState) -> HttpResponse {
+    match (res.security, state.security_headers()) {
+        (Some(false), _) | (None, None) => res,
+        (_, Some(h)) => h.apply(res),
+        (Some(true), None) => SecurityHeaders::default().apply(res),
+    }
+}
+
+// Built-in handling at the end of the middleware chain
+pub(crate) async fn dispatch(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    site: &Site,
+    state: &ServerState,
+) -> io::Result<HttpResponse> {
+    // Serve built-in debug and metrics endpoints if enabled, otherwise serve the file
+    let debug_endpoints = state.debug_endpoints;
+    let is_metrics = state.metrics_path.as_deref() == Some(http_request.path.as_ref());
+    match debug_endpoints && http_request.path.starts_with(DEBUG_PATH_PREFIX) {
+        true => Ok(debug::handle(sockaddr, http_request, body).await),
+        false if is_metrics => Ok(metrics::response(state).await),
+        false => match serve(sockaddr, http_request, body, site, state).await {
+            Ok(res) => Ok(res),
+            Err(e) => error_page(fs_error_response(e)?, site).await,
+        },
+    }
+}
+
+fn write_access_log(
+    state: &ServerState,
+    sockaddr: &SocketAddr,
+    request_line: &str,
+    http_request: Option<&HttpRequest<'_>>,
+    response: &HttpResponse,
+) {
+    if let Some(log) = &state.access_log {
+        log.write(&AccessLogEntry {
+            sockaddr,
+            request_line,
+            status: response.status(),
+            bytes: response.body_len(),
+            referer: http_request.and_then(|r| r.header("Referer")),
+            user_agent: http_request.and_then(|r| r.header("User-Agent")),
+        });
+    }
+}
+
+// Surface permission and transient filesystem errors to the client, other errors are returned as is
+fn fs_error_response(e: io::Error) -> io::Result<HttpResponse> {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        warn!("Permission denied: {}", e);
+        return Ok(HttpResponse::new(403).with_text("text/plain", status_reason(403)));
+    }
+    if util::is_transient(&e) {
+        warn!("Transient error: {}", e);
+        let res = HttpResponse::new(503).with_header("Retry-After", RETRY_AFTER_SECS.to_string());
+        return Ok(res.with_text("text/plain", status_reason(503)));
+    }
+    Err(e)
+}
+
+// Replace the body of an error response with the custom page of the site, upstream errors are passed through
+async fn error_page(res: HttpResponse, site: &Site) -> io::Result<HttpResponse> {
+    if res.status() < 400 {
+        return Ok(res);
+    }
+
+    // Pages configured in the map take precedence over the ones named by status in the res root
+    let request_map = site.request_map();
+    let mapped = request_map
+        .as_ref()
+        .and_then(|m| m.error_page(res.status()));
+    let path = match mapped {
+        Some(p) => Cow::Borrowed(p),
+        None => Cow::Owned(format!("{}.{}", res.status(), ERROR_PAGE_EXT).into()),
+    };
+    match site.open(&path).await {
+        Ok(f) => {
+            trace!("Serving error page {}", path.display());
+            let res = res.without_header("Content-Type");
+            let res = match util::content_type(&path) {
+                Some(t) => res.with_header("Content-Type", t),
+                None => res,
+            };
+            Ok(res.with_body(ResponseBody::File(f)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound && mapped.is_none() => Ok(res),
+        Err(e) => {
+            warn!("Failed to open error page {}: {}", path.display(), e);
+            Ok(res)
+        }
+    }
+}
+
+// Whether the request may access the entry, entries without credential file are open to all
+async fn is_authorized(
+    http_request: &HttpRequest<'_>,
+    options: &EntryOptions,
+    site: &Site,
+) -> io::Result<bool> {
+    match &options.auth {
+        Some(users) => auth::is_authorized(http_request, &site.root_path(users)).await,
+        None => Ok(true),
+    }
+}
+
+async fn inject_faults(options: &EntryOptions) -> Option<HttpResponse> {
+    // Sleep for the delay plus a random jitter
+    let jitter = match options.jitter {
+        Some(j) => thread_rng().gen_range(Duration::ZERO..=j),
+        None => Duration::ZERO,
+    };
+    let delay = options.delay.unwrap_or(Duration::ZERO) + jitter;
+    if !delay.is_zero() {
+        trace!("Delaying response by {}ms", delay.as_millis());
+        tokio::time::sleep(delay).await;
+    }
+
+    // Fail the request based on the fail rate
+    let fail = options.fail?;
+    match thread_rng().gen_range(0. ..100.) < fail.percent {
+        true => {
+            trace!("Injecting failure with status {}", fail.status);
+            let res = HttpResponse::new(fail.status);
+            let reason = status_reason(fail.status);
+            Some(res.with_text("text/plain", reason))
+        }
+        false => None,
+    }
+}
+
+async fn serve(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    site: &Site,
+    state: &ServerState,
+) -> io::Result<HttpResponse> {
+    // Try to find the target from the map, if not exists, use the http request path as it is
+    // The map is held for the whole request so that a reload does not affect it midway
+    let request_map = site.request_map();
+    let (cookie, new_cookie) = affinity_cookie(http_request);
+    let client = Client {
+        ip: sockaddr.ip(),
+        cookie: &cookie,
+        request: http_request,
+    };
+    let mapped = match &request_map {
+        Some(map) => map.get(&http_request.path, http_request.method, &client),
         None => None,
     };
-    let file_path = match file_path {
-        Some(p) => p,
+
+    // Refuse denied paths before anything else, even if the files exist
+    if let Some(status) = denied(request_map.as_deref(), &http_request.path, mapped.is_some()) {
+        let res = HttpResponse::new(status).with_text("text/plain", status_reason(status));
+        return error_page(res, site).await;
+    }
+
+    // Store uploads under the upload folder, unless the path is mapped
+    if let (Some(upload), None) = (&state.upload, &mapped) {
+        if let Some(res) = upload::handle(upload, http_request, body, site).await {
+            return Ok(res);
+        }
+    }
+
+    // The path is mapped, but only for other methods
+    if mapped.is_none() {
+        if let Some(allow) = request_map
+            .as_ref()
+            .and_then(|m| m.allowed_methods(&http_request.path, http_request.method))
+        {
+            let res = HttpResponse::new(405).with_header("Allow", allow);
+            return error_page(res, site).await;
+        }
+    }
+
+    // Refuse clients outside the networks of the entry before asking for credentials
+    if let Some((_, options)) = &mapped {
+        if !options.ip_filter.permits(sockaddr.ip()) {
+            let res = HttpResponse::new(403).with_text("text/plain", status_reason(403));
+            return error_page(res, site).await;
+        }
+    }
+
+    // Require credentials of protected entries
+    if let Some((_, options)) = &mapped {
+        if !is_authorized(http_request, options, site).await? {
+            return error_page(auth::unauthorized(&http_request.path), site).await;
+        }
+    }
+
+    // Simulate latency and failure of the mapped entry
+    if let Some((_, options)) = &mapped {
+        if let Some(res) = inject_faults(options).await {
+            return error_page(res, site).await;
+        }
+    }
+
+    let file_path = match &mapped {
+        Some((MapTarget::File(p), _)) => Some(*p),
+        Some((
+            MapTarget::Proxy(_) | MapTarget::Static(..) | MapTarget::Cgi(_) | MapTarget::FastCgi(_),
+            _,
+        )) => None,
         None => match http_request.path.starts_with('/') {
-            true => Path::new(&http_request.path[1..]), // Remove the leading slash
-            false => Path::new(&http_request.path),
+            true => Some(Path::new(&http_request.path[1..])), // Remove the leading slash
+            false => Some(Path::new(http_request.path.as_ref())),
         },
     };
+    let mut res = match (&mapped, file_path) {
+        (Some((MapTarget::Proxy(u), o)), _) => {
+            let cache = &site.upstream_cache;
+            let policy = &o.upstream_policy;
+            proxy::forward(u, cache, policy, o.ttl, sockaddr, http_request, body).await
+        }
+        (Some((MapTarget::FastCgi(u), _)), _) => {
+            let root = site.res_root();
+            fastcgi::forward(u, &root.path, sockaddr, http_request, body).await
+        }
+        (Some((MapTarget::Cgi(p), _)), _) => {
+            cgi::execute(&site.root_path(p), sockaddr, http_request, body).await
+        }
+        (Some((MapTarget::Static(status, body), _)), _) => match body.is_empty() {
+            true => HttpResponse::new(*status),
+            false => HttpResponse::new(*status).with_text(STATIC_CONTENT_TYPE, *body),
+        },
+        (_, Some(p)) => {
+            // Add the trailing slash to folders, so that relative links of their index resolve inside them
+            let is_read = matches!(http_request.method, "GET" | "HEAD");
+            if is_read
+                && mapped.is_none()
+                && !http_request.path.ends_with('/')
+                && site.is_dir(p).await
+            {
+                return Ok(redirect_to_folder(http_request));
+            }
+
+            let options = mapped.as_ref().map(|(_, o)| *o);
+            let res = serve_file(sockaddr, http_request, p, options, site, state).await?;
+
+            // List folders without index file if enabled
+            let res = match res.status() == 404 && state.dir_listing {
+                true => serve_listing(http_request, p, mapped.is_some(), site)
+                    .await?
+                    .unwrap_or(res),
+                false => res,
+            };
+
+            // Serve the fallback of unknown paths under its prefix, e.g. the shell of a single page app
+            let res = match res.status() == 404 && mapped.is_none() {
+                true => {
+                    let map = request_map.as_deref();
+                    serve_fallback(sockaddr, http_request, map, &client, site, state)
+                        .await?
+                        .unwrap_or(res)
+                }
+                false => res,
+            };
+            error_page(res, site).await?
+        }
+        (_, None) => unreachable!(),
+    };
+
+    // Apply the global transformations of the site, then the ones of the mapped entry
+    if let Some(map) = &request_map {
+        if !map.global_transforms().is_empty() {
+            res = transform::apply(res, map.global_transforms(), site).await?;
+        }
+    }
+    if let Some((_, options)) = &mapped {
+        if !options.transforms.is_empty() {
+            res = transform::apply(res, &options.transforms, site).await?;
+        }
+    }
+
+    // Let clients reuse the response by the policy of the entry, or of the first rule matching the path
+    let cache_rules = state.cache_rules();
+    let policy = match mapped.as_ref().and_then(|(_, o)| o.cache.as_ref()) {
+        Some(p) => Some(p),
+        None => cache_rules.policy_for(&http_request.path),
+    };
+    if let Some(p) = policy {
+        res = p.apply(res);
+    }
+
+    // Load the script of the dev mode into pages before ranges and compression see the body
+    if state.live_reload.is_some() {
+        res = livereload::inject(res, file_path).await?;
+    }
+
+    // Serve the requested byte range of files, upstreams handle ranges by themselves
+    // HEAD requests learn that ranges are supported but get the headers of the full response
+    if file_path.is_some() && matches!(http_request.method, "GET" | "HEAD") {
+        let range = http_request
+            .header("Range")
+            .filter(|_| http_request.method == "GET");
+        res = apply_range(res, range).await?;
+    }
+
+    // Hand the id out to clients without one so that they keep the chosen target
+    if let Some((_, options)) = &mapped {
+        if options.affinity == Some(Affinity::Cookie) && new_cookie {
+            let cookie = format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Lax",
+                AFFINITY_COOKIE, cookie
+            );
+            res = res.with_header("Set-Cookie", cookie);
+        }
+    }
+
+    // Compress full responses for clients which accept it
+    let mut res = compress::apply(res, http_request, file_path).await?;
+    res.security = mapped.and_then(|(_, o)| o.security);
+    Ok(res)
+}
+
+// Redirect to the path with a trailing slash, keeping the query
+fn redirect_to_folder(http_request: &HttpRequest<'_>) -> HttpResponse {
+    let path = http_request
+        .path
+        .split('/')
+        .map(percent_encode_attr)
+        .collect::<Vec<_>>()
+        .join("/");
+    let location = match http_request.query {
+        Some(q) => format!("{}/?{}", path, q),
+        None => format!("{}/", path),
+    };
+    HttpResponse::new(301).with_header("Location", location)
+}
+
+// Id of the client for the cookie affinity, a new one is generated if the client has none
+fn affinity_cookie<'a>(http_request: &'a HttpRequest<'_>) -> (Cow<'a, str>, bool) {
+    match http_request.cookie(AFFINITY_COOKIE) {
+        Some(c) => (Cow::Borrowed(c), false),
+        None => (
+            Cow::Owned(format!("{:016x}", thread_rng().gen::<u64>())),
+            true,
+        ),
+    }
+}
+
+enum ByteRange {
+    Full,
+    Partial(usize, usize), // Start and length
+    Unsatisfiable,
+}
+
+// Parse a single range of the Range header, invalid or multiple ranges fall back to the full content
+fn parse_range(range: &str, len: usize) -> ByteRange {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(r) => r,
+        None => return ByteRange::Full,
+    };
+    match (first.parse::<usize>(), last.parse::<usize>()) {
+        // Suffix range of the last n bytes, e.g. bytes=-500
+        (Err(_), Ok(n)) if first.is_empty() => match n > 0 && len > 0 {
+            true => ByteRange::Partial(len - n.min(len), n.min(len)),
+            false => ByteRange::Unsatisfiable,
+        },
+
+        // Open range to the end, e.g. bytes=500-
+        (Ok(start), Err(_)) if last.is_empty() => match start < len {
+            true => ByteRange::Partial(start, len - start),
+            false => ByteRange::Unsatisfiable,
+        },
+
+        // Closed range, e.g. bytes=500-999
+        (Ok(start), Ok(end)) if start <= end => match start < len {
+            true => ByteRange::Partial(start, end.min(len - 1) - start + 1),
+            false => ByteRange::Unsatisfiable,
+        },
+        _ => ByteRange::Full,
+    }
+}
+
+// Serve the requested byte range of a successful response
+async fn apply_range(res: HttpResponse, range: Option<&str>) -> io::Result<HttpResponse> {
+    let mut res = res.with_header("Accept-Ranges", "bytes");
+    let range = match range {
+        Some(r) if res.status == 200 => r,
+        _ => return Ok(res),
+    };
+    let len = res.body_len();
+    match parse_range(range, len) {
+        ByteRange::Full => Ok(res),
+        ByteRange::Unsatisfiable => {
+            trace!("Unsatisfiable range {} of {} bytes", range, len);
+            Ok(HttpResponse::new(416).with_header("Content-Range", format!("bytes */{}", len)))
+        }
+        ByteRange::Partial(start, part_len) => {
+            trace!("Serving range {} of {} bytes", range, len);
+            let body = match res.take_body() {
+                ResponseBody::Empty => ResponseBody::Empty,
+                ResponseBody::Bytes(mut b) => {
+                    b.truncate(start + part_len);
+                    b.drain(..start);
+                    ResponseBody::Bytes(b)
+                }
+                ResponseBody::File(f) => ResponseBody::File(f.into_range(start, part_len).await?),
+            };
+            res.status = 206;
+            let content_range = format!("bytes {}-{}/{}", start, start + part_len - 1, len);
+            Ok(res
+                .with_header("Content-Range", content_range)
+                .with_body(body))
+        }
+    }
+}
 
-    // Check if the path is a directory, if so, use the index file
-    let file_path = res_file_root.join(file_path);
-    let file_path = match file_path.is_dir() {
-        true => Cow::Owned(file_path.join("index")),
-        false => Cow::Borrowed(&file_path),
+async fn serve_file(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    file_path: &Path,
+    options: Option<&EntryOptions>,
+    site: &Site,
+    state: &ServerState,
+) -> io::Result<HttpResponse> {
+    let markdown = state.markdown.as_ref();
+    // Open res file, entries excluded from the file cache are read from disk
+    let opened = match options.is_some_and(|o| o.uncached) {
+        true => site.open_uncached(file_path).await,
+        false => site.open(file_path).await,
     };
+    let res = match opened {
+        Ok(mut f) => match options {
+            // Substitute placeholders if the mapped entry is a template
+            Some(options) if options.template => {
+                let validator = f.validator();
+                let cached = validator.and_then(|v| options.rendered.get(file_path, v));
+                let body = match cached {
+                    Some(b) => {
+                        trace!("Using cached rendering of {}", file_path.display());
+                        b
+                    }
+                    None => {
+                        let mut buf = Vec::with_capacity(f.len());
+                        f.read_to_end(&mut buf).await?;
+                        let ctx = template::Context {
+                            req: http_request,
+                            ip: sockaddr.ip(),
+                            variables: &options.variables,
+                        };
+                        let (body, per_request) = template::render(&buf, &ctx);
+                        let body: Arc<[u8]> = body.into();
+
+                        // Renderings with values of the request only apply to the request
+                        if let (Some(v), false) = (validator, per_request) {
+                            options.rendered.insert(file_path, v, &body);
+                        }
+                        body
+                    }
+                };
+                HttpResponse::new(200).with_body(ResponseBody::File(AbstractFile::from(body)))
+            }
+
+            // Render markdown files into pages if enabled, or if the mapped entry asks for it
+            Some(options) if options.markdown => markdown::render(f, markdown, site).await?,
+            _ if markdown.is_some() && Markdown::is_markdown(file_path) => {
+                markdown::render(f, markdown, site).await?
+            }
 
-    // Open res file
-    trace!("Opening file: {}", &file_path.as_path().display());
-    let mut file = match file_cache.open(&file_path).await {
-        Ok(f) => Some(f),
+            // Process the directives of server side include pages if enabled
+            _ if state.ssi && ssi::is_ssi(file_path) => {
+                let (body, validator) = ssi::render(f, file_path, http_request, site).await?;
+                let res = HttpResponse::new(200)
+                    .with_header("Content-Type", SSI_CONTENT_TYPE)
+                    .with_body(ResponseBody::Bytes(body));
+                match validator {
+                    Some(v) => with_validator(res, http_request, v),
+                    None => res,
+                }
+            }
+            _ => {
+                let validator = f.validator();
+                let precompressed = match state.precompressed && state.live_reload.is_none() {
+                    true => open_precompressed(http_request, file_path, site).await?,
+                    false => None,
+                };
+                let res = match precompressed {
+                    Some((p, encoding)) => {
+                        trace!("Serving {} sibling of {}", encoding, file_path.display());
+                        let res = HttpResponse::new(200)
+                            .with_header("Content-Encoding", encoding)
+                            .with_header("Vary", "Accept-Encoding")
+                            .with_body(ResponseBody::File(p));
+                        match util::content_type(file_path) {
+                            Some(t) => res.with_header("Content-Type", t),
+                            None => res,
+                        }
+                    }
+                    None => HttpResponse::new(200).with_body(ResponseBody::File(f)),
+                };
+                let res = match validator {
+                    Some(v) => with_validator(res, http_request, v),
+                    None => res,
+                };
+                match res.header("Content-Encoding") {
+                    Some(_) => compress::weaken_etag(res),
+                    None => res,
+                }
+            }
+        },
         Err(e) => match e.kind() {
             io::ErrorKind::NotFound => {
-                trace!("File not found: {}", &file_path.as_path().display());
-                None
+                trace!("File not found: {}", file_path.display());
+                return Ok(HttpResponse::not_found());
             }
-            _ => return Err(e.into()),
+            _ => return Err(e),
         },
     };
 
-    // Write the response
-    const NOT_FOUND_STATUS: &str = "404 Not Found";
-    const NOT_FOUND_MSG: &str = "NOT FOUND";
-    const OK_STATUS: &str = "200 OK";
-    let mut res = String::with_capacity(HEADER_BUFF_INIT_SIZE);
-    let res_status = match &file {
-        Some(_) => OK_STATUS,
-        None => NOT_FOUND_STATUS,
+    // Ask the client to save the file if the mapped entry is a download
+    match options {
+        Some(options) if options.download => {
+            Ok(res.with_header("Content-Disposition", content_disposition(file_path)))
+        }
+        _ => Ok(res),
+    }
+}
+
+// The precompressed sibling of the file in the preferred encoding accepted by the client, with the encoding
+// Ranges are served from the file itself
+async fn open_precompressed(
+    http_request: &HttpRequest<'_>,
+    file_path: &Path,
+    site: &Site,
+) -> io::Result<Option<(AbstractFile, &'static str)>> {
+    if http_request.header("Range").is_some() {
+        return Ok(None);
+    }
+    for (encoding, ext) in compress::precompressed(http_request) {
+        match site.open_sibling(file_path, ext).await {
+            Ok(f) => return Ok(Some((f, encoding))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+// Serve the file of the fallback entry, None if no fallback prefix contains the path
+async fn serve_fallback(
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    request_map: Option<&RequestMap>,
+    client: &Client<'_>,
+    site: &Site,
+    state: &ServerState,
+) -> io::Result<Option<HttpResponse>> {
+    if !matches!(http_request.method, "GET" | "HEAD") {
+        return Ok(None);
+    }
+    let (path, options) = match request_map
+        .and_then(|m| m.fallback(&http_request.path, http_request.method, client))
+    {
+        Some(f) => f,
+        None => return Ok(None),
     };
-    res.push_str(&format!(
-        // Write the status line
-        "HTTP/1.1 {}\r\n",
-        res_status
-    ));
-    res.push_str(&format!(
-        // Write the content length
-        "Content-Length: {}\r\n",
-        match &file {
-            Some(f) => f.len(),
-            None => NOT_FOUND_MSG.len(),
-        }
-    ));
-    res.push_str("Connection: close\r\n"); // Close the connection
-    res.push_str("\r\n"); // End of header
-
-    // convert header to stream and chain with body of either a file or a string
-    let mut not_found_body = Cursor::new(NOT_FOUND_MSG.as_bytes());
-    let mut res = AsyncReadExt::chain(
-        Cursor::new(res),
-        match &mut file {
-            Some(f) => f as &mut (dyn AsyncRead + Unpin + Send),
-            None => &mut not_found_body,
-        },
-    );
+    if !options.ip_filter.permits(client.ip) {
+        let res = HttpResponse::new(403).with_text("text/plain", status_reason(403));
+        return error_page(res, site).await.map(Some);
+    }
 
-    // declare output streams
-    let mut ostreams = vec![&mut w_stream as &mut (dyn tokio::io::AsyncWrite + Unpin + Send)];
+    // Require credentials and simulate faults as for mapped entries
+    if !is_authorized(http_request, options, site).await? {
+        return error_page(auth::unauthorized(&http_request.path), site)
+            .await
+            .map(Some);
+    }
+    if let Some(res) = inject_faults(options).await {
+        return error_page(res, site).await.map(Some);
+    }
+    trace!(
+        "Serving fallback {} for {}",
+        path.display(),
+        http_request.path
+    );
+    let res = serve_file(sockaddr, http_request, path, Some(options), site, state).await?;
 
-    // Copy to stdout only if trace is enabled
-    let mut stdout = match get_log_level() <= LogLevel::Trace {
-        true => Some(stdout()),
-        false => None,
+    // The type follows the fallback file rather than the requested path, unless it was rendered
+    let res = match util::content_type(path) {
+        Some(t) if res.status() == 200 && res.header("Content-Type").is_none() => {
+            res.with_header("Content-Type", t)
+        }
+        _ => res,
     };
-    if let Some(stdout) = &mut stdout {
-        // Copy to stdout only if trace is enabled
-        trace!("");
-        ostreams.push(stdout);
+    match options.transforms.is_empty() {
+        true => Ok(Some(res)),
+        false => transform::apply(res, &options.transforms, site)
+            .await
+            .map(Some),
     }
-    tee_write(&mut res, &mut ostreams).await?;
+}
 
-    // Flush and shutdown the stream
-    stream.shutdown().await?;
-    if let Some(stdout) = &mut stdout {
-        // Write a new line to stdout
-        stdout.write_all(b"\n").await?;
-        stdout.flush().await?;
+// Status of the refusal of the path by the deny rules of the map
+// Hidden paths, with a segment starting with a dot, are refused unless mapped or allowed by a rule
+fn denied(request_map: Option<&RequestMap>, path: &str, mapped: bool) -> Option<u16> {
+    match request_map.and_then(|m| m.access(path)) {
+        Some(Access::Deny(status)) => Some(status),
+        Some(Access::Allow) => None,
+        None if !mapped && path.split('/').any(|s| s.starts_with('.')) => Some(HIDDEN_PATH_STATUS),
+        None => None,
     }
+}
 
-    // Log the request & response
-    info!(
-        "{} {} {} -> {} [{}μs]",
-        sockaddr,
-        &http_request.method,
-        &http_request.path,
-        res_status,
-        start.elapsed().as_micros()
+// Generate the listing of a folder, None if the path is not a folder
+async fn serve_listing(
+    http_request: &HttpRequest<'_>,
+    file_path: &Path,
+    mapped: bool,
+    site: &Site,
+) -> io::Result<Option<HttpResponse>> {
+    let mut entries = site.list_dir(file_path).await?;
+
+    // Links of a mapped folder point to the folder itself as only exact keys are mapped
+    // Otherwise the keys of the map under the request path are listed as well
+    let base = match mapped {
+        true => format!("/{}", entry_name(file_path)),
+        false => {
+            if let Some(map) = site.request_map() {
+                for (name, is_dir) in map.children(&http_request.path) {
+                    let entries = entries.get_or_insert_with(Vec::new);
+                    if !entries.iter().any(|e| e.name == name) {
+                        entries.push(DirEntry {
+                            name: name.to_string(),
+                            is_dir,
+                            size: None,
+                            modified: None,
+                        });
+                    }
+                }
+            }
+            http_request.path.to_string()
+        }
+    };
+    let mut entries = match entries {
+        Some(e) => e,
+        None => return Ok(None),
+    };
+
+    // Hide the entries which would be refused
+    let request_map = site.request_map();
+    let parent = http_request.path.trim_end_matches('/');
+    entries.retain(|e| {
+        let path = format!("{}/{}", parent, e.name);
+        denied(request_map.as_deref(), &path, false).is_none()
+    });
+    trace!(
+        "Listing {} entries of {}",
+        entries.len(),
+        file_path.display()
     );
+    let html = listing::render(&http_request.path, &base, entries);
+    Ok(Some(
+        HttpResponse::new(200).with_text("text/html; charset=utf-8", html),
+    ))
+}
 
-    Ok(())
+// Emit the validator and answer with 304 if the client has the current version
+fn with_validator(res: HttpResponse, http_request: &HttpRequest<'_>, v: Validator) -> HttpResponse {
+    let etag = v.etag();
+    let last_modified = fmt_http_date(v.modified);
+
+    // If-Modified-Since is only used without If-None-Match
+    let not_modified = match http_request.header("If-None-Match") {
+        Some(tags) => tags
+            .split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == etag),
+        None => http_request
+            .header("If-Modified-Since")
+            .and_then(parse_http_date)
+            .is_some_and(|since| {
+                // Compare in whole seconds as http dates have no sub second precision
+                let modified = v.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+                modified.as_secs() <= since.as_secs()
+            }),
+    };
+    let res = match not_modified && matches!(http_request.method, "GET" | "HEAD") {
+        true => {
+            trace!("Not modified: {}", &etag);
+            HttpResponse::new(304)
+        }
+        false => res,
+    };
+    res.with_header("ETag", etag)
+        .with_header("Last-Modified", last_modified)
+}
+
+fn content_disposition(file_path: &Path) -> String {
+    // Take the last segment with both unix and windows separators
+    let path = file_path.to_string_lossy();
+    let name = path.rsplit(['/', '\\']).next().unwrap_or_default();
+
+    // Plain filename as fallback for clients without RFC 5987 support
+    let fallback = name
+        .chars()
+        .map(|c| match (c.is_ascii_graphic() && c != '"') || c == ' ' {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback,
+        percent_encode_attr(name)
+    )
 }
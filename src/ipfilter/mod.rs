@@ -0,0 +1,109 @@
+use std::{fmt::Display, net::IpAddr};
+
+const CIDR_PREFIX_DELIM: char = '/';
+const LIST_DELIM: char = ',';
+
+// Network in CIDR notation, a bare address is a network of its own
+// Sample: 10.0.0.0/8, 192.168.1.20 or fd00::/8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once(CIDR_PREFIX_DELIM) {
+            Some((a, p)) => (a.parse::<IpAddr>().ok()?, Some(p.parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+
+        // Keep the network address only, so that the host bits do not matter
+        let addr = match addr {
+            IpAddr::V4(a) => IpAddr::V4((u32::from(a) & mask_v4(prefix)).into()),
+            IpAddr::V6(a) => IpAddr::V6((u128::from(a) & mask_v6(prefix)).into()),
+        };
+        Some(Self { addr, prefix })
+    }
+
+    // IPv4 clients of dual stack sockets arrive as mapped IPv6 addresses and are matched as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(ip) & mask_v4(self.prefix) == net.into(),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask_v6(self.prefix) == net.into()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.addr, CIDR_PREFIX_DELIM, self.prefix)
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+// Networks clients are allowed from and denied from, the deny list wins and an empty allow list allows all
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    // Comma separated networks, sample: 10.0.0.0/8,192.168.1.0/24
+    pub fn parse_list(s: &str) -> Option<Vec<Cidr>> {
+        s.split(LIST_DELIM).map(|c| Cidr::parse(c.trim())).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    pub fn fmt_list(list: &[Cidr]) -> String {
+        list.iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(&LIST_DELIM.to_string())
+    }
+}
+
+impl Display for IpFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.allow.is_empty(), self.deny.is_empty()) {
+            (true, true) => write!(f, "all"),
+            (false, true) => write!(f, "allow {}", Self::fmt_list(&self.allow)),
+            (true, false) => write!(f, "deny {}", Self::fmt_list(&self.deny)),
+            (false, false) => write!(
+                f,
+                "allow {}, deny {}",
+                Self::fmt_list(&self.allow),
+                Self::fmt_list(&self.deny)
+            ),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+mod accesslog;
+pub mod admin;
+mod archive;
+mod auth;
+mod bufpool;
+pub mod cachecontrol;
+mod cgi;
+mod compress;
+mod connlimit;
+mod debug;
+#[cfg(feature = "embed")]
+mod embed;
+mod fastcgi;
+pub mod filecache;
+mod forwarded;
+mod fswatcher;
+pub mod headermap;
+pub mod http;
+pub mod ipfilter;
+mod listing;
+mod livereload;
+pub mod log;
+pub mod markdown;
+pub mod metrics;
+pub mod middleware;
+#[cfg(target_os = "linux")]
+mod mmap;
+pub mod multipart;
+mod pollwatcher;
+pub mod privdrop;
+mod proxy;
+mod proxyproto;
+pub mod redirect;
+pub mod requestlog;
+pub mod requestmap;
+pub mod sdnotify;
+pub mod security;
+#[cfg(target_os = "linux")]
+mod sendfile;
+pub mod server;
+mod signal;
+mod slowlog;
+mod ssi;
+pub mod state;
+mod teewriter;
+mod template;
+mod transform;
+pub mod upload;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
+pub mod util;
+mod websocket;
+
+pub use middleware::Middleware;
+pub use server::{Server, ServerBuilder};
+
+// Constants
+pub const BUFF_INIT_SIZE: usize = 1024; // Referencial init buffer size of all program buffers. All buffers are initialized using multiples of this value.
+pub const DEFAULT_LOG_LEVEL: log::LogLevel = log::LogLevel::Info;
+pub const RES_ROOT_FOLDER: &str = "res";
+pub const REQ_MAP_FILE: &str = "map.txt";
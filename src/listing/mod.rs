@@ -0,0 +1,118 @@
+use std::{collections::BTreeMap, path::Path, time::SystemTime};
+
+use tokio::{fs::read_dir, io};
+
+use crate::util::{fmt_http_date, fmt_size, html_escape, percent_encode_attr};
+
+const LISTING_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 4;
+
+#[derive(Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+}
+
+// List a folder of the file system, None if the path is not a folder
+pub async fn list_folder(path: &Path) -> io::Result<Option<Vec<DirEntry>>> {
+    if !path.is_dir() {
+        return Ok(None);
+    }
+    let mut entries = Vec::new();
+    let mut dir = read_dir(path).await?;
+    while let Some(e) = dir.next_entry().await? {
+        let metadata = e.metadata().await?;
+        entries.push(DirEntry {
+            name: e.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: metadata.is_file().then_some(metadata.len()),
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(Some(entries))
+}
+
+// List a folder of flat entry names (e.g. a/b.txt) as in archives, None if no entry is under the folder
+pub fn list_names<'a>(
+    names: impl Iterator<Item = (&'a str, u64)>,
+    dir: &str,
+    modified: Option<SystemTime>,
+) -> Option<Vec<DirEntry>> {
+    let prefix = match dir.is_empty() {
+        true => String::new(),
+        false => format!("{}/", dir),
+    };
+
+    // Direct children are files, deeper entries are represented by their first folder
+    let mut children = BTreeMap::new();
+    for (name, size) in names {
+        let rest = match name.strip_prefix(&prefix) {
+            Some(r) if !r.is_empty() => r,
+            _ => continue,
+        };
+        match rest.split_once('/') {
+            Some((d, _)) => children.insert(d.to_string(), None),
+            None => children.insert(rest.to_string(), Some(size)),
+        };
+    }
+    if children.is_empty() && !dir.is_empty() {
+        return None;
+    }
+    let entries = children
+        .into_iter()
+        .map(|(name, size)| DirEntry {
+            name,
+            is_dir: size.is_none(),
+            size,
+            modified,
+        })
+        .collect();
+    Some(entries)
+}
+
+// Encode each segment of a decoded path for use in links
+fn encode_path(path: &str) -> String {
+    let encoded = path
+        .split('/')
+        .map(percent_encode_attr)
+        .collect::<Vec<_>>()
+        .join("/");
+    html_escape(&encoded)
+}
+
+// Render the entries as html, links are built from the given base path
+pub fn render(title: &str, base: &str, mut entries: Vec<DirEntry>) -> String {
+    // Folders first, then by name
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    let base = base.trim_end_matches('/');
+
+    let mut html = String::with_capacity(LISTING_BUFF_INIT_SIZE);
+    let title = html_escape(title);
+    html.push_str(&format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+        title
+    ));
+    if !base.is_empty() {
+        let parent = &base[..base.rfind('/').unwrap_or(0)];
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+            encode_path(parent)
+        ));
+    }
+    for e in entries {
+        let suffix = if e.is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}/{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            encode_path(base),
+            encode_path(&e.name),
+            suffix,
+            html_escape(&e.name),
+            suffix,
+            e.size.map(|s| fmt_size(s as usize)).unwrap_or("-".into()),
+            e.modified.map(fmt_http_date).unwrap_or("-".into()),
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
@@ -0,0 +1,126 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
+    select,
+    sync::broadcast,
+    time,
+};
+
+use crate::{
+    debug,
+    http::{HttpRequest, HttpResponse, ResponseBody},
+    log_ctx,
+    state::Site,
+    trace,
+    transform::{self, TRANSFORM_MAX_BODY_SIZE},
+};
+
+// Pages served in dev mode listen to the change feed and reload once a file of their site changed
+const LIVE_RELOAD_PATH: &str = "/__livereload";
+const LIVE_RELOAD_SCRIPT: &str = concat!(
+    "<script>new EventSource(\"/__livereload\")",
+    ".addEventListener(\"reload\",function(){location.reload()})</script>"
+);
+const LIVE_RELOAD_CHANNEL_SIZE: usize = 64;
+
+// The length of the stream is unknown, so it has no Content-Length and the connection is closed after it
+const FEED_HEADER: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+
+// Comments keep idle proxies from closing the stream
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+log_ctx!("LiveReload");
+
+#[derive(Debug)]
+pub struct LiveReload {
+    changes: broadcast::Sender<PathBuf>,
+}
+
+impl Default for LiveReload {
+    fn default() -> Self {
+        Self {
+            changes: broadcast::channel(LIVE_RELOAD_CHANNEL_SIZE).0,
+        }
+    }
+}
+
+impl LiveReload {
+    // Tell the open pages that a file changed, nothing happens if none is open
+    pub fn notify(&self, path: &Path) {
+        if self.changes.send(path.to_path_buf()).is_ok() {
+            trace!("Notified pages of change of {}", path.display());
+        }
+    }
+}
+
+pub fn is_feed(http_request: &HttpRequest<'_>) -> bool {
+    http_request.method == "GET" && http_request.path == LIVE_RELOAD_PATH
+}
+
+// Stream a reload event for every change under the res root of the site until the client leaves
+pub async fn feed<R, W>(
+    live_reload: &LiveReload,
+    site: &Site,
+    r_stream: &mut R,
+    w_stream: &mut W,
+) -> io::Result<HttpResponse>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut changes = live_reload.changes.subscribe();
+    w_stream.write_all(FEED_HEADER).await?;
+    w_stream.flush().await?;
+    let mut ticker = time::interval(KEEP_ALIVE_INTERVAL);
+    ticker.tick().await;
+    debug!("Page subscribed to changes of {}", site.display_name());
+    loop {
+        let msg = select! {
+            c = changes.recv() => match c {
+                Ok(p) if !p.starts_with(&site.res_root().path) => continue,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => "event: reload\ndata:\n\n",
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = ticker.tick() => ": keep-alive\n\n",
+
+            // Nothing is expected from the client, reading only tells when it is gone
+            r = r_stream.fill_buf() => match r {
+                Ok([]) | Err(_) => break,
+                Ok(b) => {
+                    let len = b.len();
+                    r_stream.consume(len);
+                    continue;
+                }
+            },
+        };
+        w_stream.write_all(msg.as_bytes()).await?;
+        w_stream.flush().await?;
+    }
+    debug!("Page unsubscribed from changes of {}", site.display_name());
+    Ok(HttpResponse::new(200))
+}
+
+// Add the script before the closing body tag of HTML pages, or at the end if the page has none
+// Pages are detected by the content type if given, otherwise by the file extension
+pub async fn inject(mut res: HttpResponse, file_path: Option<&Path>) -> io::Result<HttpResponse> {
+    let is_html = match res.header("Content-Type") {
+        Some(t) => t.to_ascii_lowercase().starts_with("text/html"),
+        None => file_path
+            .and_then(|p| p.extension())
+            .is_some_and(|e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm")),
+    };
+    if !is_html
+        || res.body_len() > TRANSFORM_MAX_BODY_SIZE
+        || res.header("Content-Encoding").is_some()
+    {
+        return Ok(res);
+    }
+    let mut body = res.take_body().into_bytes().await?;
+    let i = transform::rfind_tag(&body, transform::INJECT_BEFORE_TAG).unwrap_or(body.len());
+    body.splice(i..i, LIVE_RELOAD_SCRIPT.bytes());
+    Ok(res.with_body(ResponseBody::Bytes(body)))
+}
@@ -1,7 +1,22 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    ffi::OsString,
+    fmt::{Arguments, Display, Formatter, Write},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, IsTerminal, Stderr, Stdout, Write as _},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        RwLock,
+    },
+    thread,
+    time::SystemTime,
+};
 
 use tokio::sync::{OnceCell, SetError};
 
+use crate::util::{fmt_rfc3339, json_escape};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
     Error = 4,
@@ -30,6 +45,35 @@ impl Display for LogLevel {
     }
 }
 
+impl LogLevel {
+    // Warnings and errors go to stderr, the rest to stdout
+    fn is_err(self) -> bool {
+        self >= LogLevel::Warn
+    }
+
+    // ANSI color of the level tag on a terminal
+    fn color(self) -> &'static str {
+        match self {
+            LogLevel::Error => "\x1b[1;31m",
+            LogLevel::Warn => "\x1b[33m",
+            LogLevel::Info => "\x1b[32m",
+            LogLevel::Debug => "\x1b[34m",
+            LogLevel::Trace => "\x1b[2m",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
 impl From<&str> for LogLevel {
     fn from(s: &str) -> Self {
         let s = s.to_lowercase();
@@ -50,12 +94,469 @@ impl From<&String> for LogLevel {
     }
 }
 
-pub static LOG_LEVEL: OnceCell<LogLevel> = OnceCell::const_new();
-pub fn set_log_level(level: LogLevel) -> Result<(), SetError<LogLevel>> {
-    LOG_LEVEL.set(level)
+// Level of every context with overrides of single contexts
+// Sample: info,FileCache=debug
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    level: LogLevel,
+    overrides: Vec<(String, LogLevel)>,
+}
+
+impl LogFilter {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut filter = Self::default();
+        for part in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((ctx, level)) => {
+                    let ctx = ctx.trim();
+                    if ctx.is_empty() {
+                        return None;
+                    }
+                    filter
+                        .overrides
+                        .push((ctx.to_string(), LogLevel::parse(level)?));
+                }
+                None => filter.level = LogLevel::parse(part)?,
+            }
+        }
+        Some(filter)
+    }
+
+    // Contexts are matched case-insensitively, e.g. filecache=debug applies to FileCache
+    pub fn level_of(&self, ctx: &str) -> LogLevel {
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(c, _)| c.eq_ignore_ascii_case(ctx))
+            .map_or(self.level, |(_, l)| *l)
+    }
+
+    // Lowest level printed by any context
+    fn min_level(&self) -> LogLevel {
+        self.overrides
+            .iter()
+            .map(|(_, l)| *l)
+            .fold(self.level, LogLevel::min)
+    }
+}
+
+impl From<LogLevel> for LogFilter {
+    fn from(level: LogLevel) -> Self {
+        Self {
+            level,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl Display for LogFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.level)?;
+        for (ctx, level) in &self.overrides {
+            write!(f, ",{}={}", ctx, level)?;
+        }
+        Ok(())
+    }
+}
+
+// The level defaults to Info until set, e.g. when the server is embedded as a library
+// Lines below the lowest level of the filter are skipped without taking the lock
+static LOG_FILTER: RwLock<Option<LogFilter>> = RwLock::new(None);
+static MIN_LOG_LEVEL: AtomicU8 = AtomicU8::new(crate::DEFAULT_LOG_LEVEL as u8);
+pub fn set_log_level(level: LogLevel) -> Result<(), SetError<LogFilter>> {
+    set_log_filter(level.into())
+}
+
+// Set the filter once at startup, see update_log_filter to change it later on
+pub fn set_log_filter(filter: LogFilter) -> Result<(), SetError<LogFilter>> {
+    if LOG_FILTER.read().unwrap().is_some() {
+        return Err(SetError::AlreadyInitializedError(filter));
+    }
+    update_log_filter(filter);
+    Ok(())
+}
+pub fn update_log_filter(filter: LogFilter) {
+    let mut guard = LOG_FILTER.write().unwrap();
+    MIN_LOG_LEVEL.store(filter.min_level() as u8, Ordering::Relaxed);
+    *guard = Some(filter);
+}
+pub fn get_log_filter() -> LogFilter {
+    LOG_FILTER.read().unwrap().clone().unwrap_or_default()
 }
 pub fn get_log_level() -> LogLevel {
-    LOG_LEVEL.get().copied().unwrap()
+    get_log_filter().level
+}
+
+// Whether lines of the level are printed for the context, see the macros below
+pub fn enabled(level: LogLevel, ctx: &str) -> bool {
+    if (level as u8) < MIN_LOG_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    match &*LOG_FILTER.read().unwrap() {
+        Some(f) => f.level_of(ctx) <= level,
+        None => LogLevel::default() <= level,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text, // Sample: 2000-10-10T13:55:36.123Z [Server][INFO] socket binded @0.0.0.0:3006
+    Json, // Sample: {"timestamp":"2000-10-10T13:55:36.123Z","level":"info","context":"Server","message":"socket binded @0.0.0.0:3006"}
+}
+
+impl LogFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+// Structured value attached to a log line, only emitted in the json format
+pub enum Field<'a> {
+    Str(&'a str),
+    Num(u64),
+}
+
+pub static LOG_FORMAT: OnceCell<LogFormat> = OnceCell::const_new();
+pub fn set_log_format(format: LogFormat) -> Result<(), SetError<LogFormat>> {
+    LOG_FORMAT.set(format)
+}
+pub fn get_log_format() -> LogFormat {
+    LOG_FORMAT.get().copied().unwrap_or_default()
+}
+
+// Colors are only used for text lines printed to a terminal, and never in the log file
+static LOG_COLOR: AtomicBool = AtomicBool::new(true);
+pub fn set_log_color(enabled: bool) {
+    LOG_COLOR.store(enabled, Ordering::Relaxed);
+}
+
+fn use_color(terminal: &impl IsTerminal) -> bool {
+    LOG_COLOR.load(Ordering::Relaxed)
+        && get_log_format() == LogFormat::Text
+        && terminal.is_terminal()
+}
+
+// Color the level tag of a text line, e.g. [ERROR]
+fn colorize(level: LogLevel, line: &str) -> String {
+    let tag = format!("[{}]", level.to_string().to_uppercase());
+    let colored = format!("[{}{}\x1b[0m]", level.color(), &tag[1..tag.len() - 1]);
+    line.replacen(&tag, &colored, 1)
+}
+
+pub const DEFAULT_LOG_QUEUE_SIZE: usize = 8192;
+
+// What a full queue does to new lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogOverflow {
+    #[default]
+    Block, // Wait for the logger to catch up
+    Drop, // Discard the line, the number of discarded lines is logged once there is room again
+}
+
+impl Display for LogOverflow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogOverflow::Block => write!(f, "block"),
+            LogOverflow::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+// File receiving the lines instead of the console
+// Rotated files are numbered from the newest, e.g. server.log.1 is the previous server.log
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub max_size: u64, // Size to rotate at, zero never rotates, e.g. when rotated by logrotate
+    pub keep: usize,   // Number of rotated files to keep
+}
+
+enum LogMsg {
+    Line(LogLevel, String),
+    Flush(SyncSender<()>),
+    Reopen,
+}
+
+struct FileOutput {
+    config: LogFile,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+impl FileOutput {
+    fn open(config: LogFile) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.config.max_size > 0 && self.size > 0 && self.size + len > self.config.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    // Shift the rotated files by one, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let rotated = |i: usize| {
+            let mut p = OsString::from(self.config.path.as_os_str());
+            p.push(format!(".{}", i));
+            PathBuf::from(p)
+        };
+        let ignore_missing = |r: io::Result<()>| match r {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        ignore_missing(fs::remove_file(rotated(self.config.keep)))?;
+        for i in (1..self.config.keep).rev() {
+            ignore_missing(fs::rename(rotated(i), rotated(i + 1)))?;
+        }
+        match self.config.keep {
+            0 => fs::remove_file(&self.config.path)?,
+            _ => fs::rename(&self.config.path, rotated(1))?,
+        }
+        self.reopen()
+    }
+
+    // Open the path again, e.g. after the file is moved away by logrotate
+    fn reopen(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        *self = Self::open(self.config.clone())?;
+        Ok(())
+    }
+}
+
+struct Output {
+    stdout: BufWriter<Stdout>,
+    stderr: BufWriter<Stderr>,
+    color: (bool, bool), // Whether stdout and stderr are colored
+    file: Option<FileOutput>,
+}
+
+impl Output {
+    fn write_line(&mut self, level: LogLevel, line: &str) {
+        let res = match (&mut self.file, level.is_err()) {
+            (Some(f), _) => f.write_line(line),
+            (None, true) if self.color.1 => writeln!(self.stderr, "{}", colorize(level, line)),
+            (None, true) => writeln!(self.stderr, "{}", line),
+            (None, false) if self.color.0 => writeln!(self.stdout, "{}", colorize(level, line)),
+            (None, false) => writeln!(self.stdout, "{}", line),
+        };
+
+        // Fall back to the console when the file fails, the logger has nowhere else to report to
+        if let Err(e) = res {
+            let _ = writeln!(self.stderr, "Failed to write log file: {}", e);
+            let _ = writeln!(self.stderr, "{}", line);
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.stdout.flush();
+        let _ = self.stderr.flush();
+        if let Some(f) = &mut self.file {
+            let _ = f.writer.flush();
+        }
+    }
+
+    fn reopen(&mut self) {
+        if let Some(f) = &mut self.file {
+            if let Err(e) = f.reopen() {
+                let _ = writeln!(self.stderr, "Failed to reopen log file: {}", e);
+            }
+        }
+    }
+}
+
+struct Logger {
+    sender: SyncSender<LogMsg>,
+    overflow: LogOverflow,
+    dropped: AtomicU64,
+}
+
+// Lines are written by the calling task until the logger is started
+static LOGGER: OnceCell<Logger> = OnceCell::const_new();
+
+// Hand the lines over to a dedicated thread through a bounded queue, so that tasks never wait on the stdout lock
+// The log file is opened here so that an unwritable path fails the start
+pub fn start_logger(
+    queue_size: usize,
+    overflow: LogOverflow,
+    file: Option<LogFile>,
+) -> io::Result<()> {
+    let file = match file {
+        Some(f) => {
+            let path = f.path.clone();
+            let f = FileOutput::open(f).map_err(|e| {
+                let msg = format!("Failed to open log file {}: {}", path.display(), e);
+                io::Error::new(e.kind(), msg)
+            })?;
+            Some(f)
+        }
+        None => None,
+    };
+    let output = Output {
+        stdout: BufWriter::new(io::stdout()),
+        stderr: BufWriter::new(io::stderr()),
+        color: (use_color(&io::stdout()), use_color(&io::stderr())),
+        file,
+    };
+    let (sender, receiver) = mpsc::sync_channel(queue_size);
+    let logger = Logger {
+        sender,
+        overflow,
+        dropped: AtomicU64::new(0),
+    };
+    if LOGGER.set(logger).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Logger already started",
+        ));
+    }
+    thread::Builder::new()
+        .name("logger".to_string())
+        .spawn(move || run_logger(receiver, output))?;
+    Ok(())
+}
+
+// Wait until every queued line is written, e.g. before the process exits
+pub fn flush() {
+    if let Some(logger) = LOGGER.get() {
+        let (ack, done) = mpsc::sync_channel(0);
+        if logger.sender.send(LogMsg::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+}
+
+// Open the log file again, e.g. on SIGHUP after logrotate moved it away
+pub fn reopen() {
+    if let Some(logger) = LOGGER.get() {
+        let _ = logger.sender.send(LogMsg::Reopen);
+    }
+}
+
+fn run_logger(receiver: Receiver<LogMsg>, mut output: Output) {
+    let mut next = receiver.recv().ok();
+    while let Some(msg) = next {
+        // Report the lines dropped since the last report before the next line
+        if let Some(dropped) = LOGGER.get().map(|l| l.dropped.swap(0, Ordering::Relaxed)) {
+            if dropped > 0 {
+                let line = format_line(
+                    LogLevel::Warn,
+                    "Log",
+                    format_args!("{} log lines dropped", dropped),
+                    &[],
+                );
+                output.write_line(LogLevel::Warn, &line);
+            }
+        }
+        match msg {
+            LogMsg::Line(level, line) => output.write_line(level, &line),
+            LogMsg::Flush(ack) => {
+                output.flush();
+                let _ = ack.send(());
+            }
+            LogMsg::Reopen => output.reopen(),
+        }
+
+        // Write out the buffers once the queue is drained
+        next = match receiver.try_recv() {
+            Ok(m) => Some(m),
+            Err(_) => {
+                output.flush();
+                receiver.recv().ok()
+            }
+        };
+    }
+}
+
+// Print a line of the level, warnings and errors go to stderr and the rest to stdout
+// Callers check the level, see the macros below
+pub fn write(level: LogLevel, ctx: &str, msg: Arguments<'_>, fields: &[(&str, Field<'_>)]) {
+    let line = format_line(level, ctx, msg, fields);
+    let logger = match LOGGER.get() {
+        Some(l) => l,
+        None => {
+            match level.is_err() {
+                true if use_color(&io::stderr()) => eprintln!("{}", colorize(level, &line)),
+                true => eprintln!("{}", line),
+                false if use_color(&io::stdout()) => println!("{}", colorize(level, &line)),
+                false => println!("{}", line),
+            }
+            return;
+        }
+    };
+    let msg = LogMsg::Line(level, line);
+    match logger.overflow {
+        LogOverflow::Block => {
+            let _ = logger.sender.send(msg);
+        }
+        LogOverflow::Drop => {
+            if let Err(TrySendError::Full(_)) = logger.sender.try_send(msg) {
+                logger.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn format_line(
+    level: LogLevel,
+    ctx: &str,
+    msg: Arguments<'_>,
+    fields: &[(&str, Field<'_>)],
+) -> String {
+    match get_log_format() {
+        LogFormat::Text => format!(
+            "{} [{}][{}] {}",
+            fmt_rfc3339(SystemTime::now()),
+            ctx,
+            level.to_string().to_uppercase(),
+            msg
+        ),
+        LogFormat::Json => {
+            let mut line = format!(
+                "{{\"timestamp\":\"{}\",\"level\":\"{}\",\"context\":{},\"message\":{}",
+                fmt_rfc3339(SystemTime::now()),
+                level.to_string().to_lowercase(),
+                json_escape(ctx),
+                json_escape(&msg.to_string())
+            );
+            for (k, v) in fields {
+                let _ = match v {
+                    Field::Str(s) => write!(line, ",{}:{}", json_escape(k), json_escape(s)),
+                    Field::Num(n) => write!(line, ",{}:{}", json_escape(k), n),
+                };
+            }
+            line.push('}');
+            line
+        }
+    }
 }
 
 #[macro_export]
@@ -68,15 +569,15 @@ macro_rules! log_ctx {
 #[macro_export]
 macro_rules! error {
     ($arg0: tt, $($arg:tt)*) => {
-        eprintln!(concat!("[{}][ERROR] ",$arg0), _LOG_CTX_JK23BN4KJ2, $($arg)*);
+        $crate::log::write($crate::log::LogLevel::Error, _LOG_CTX_JK23BN4KJ2, format_args!($arg0, $($arg)*), &[]);
     };
 }
 
 #[macro_export]
 macro_rules! warn {
     ($arg0: tt, $($arg:tt)*) => {
-        if ($crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Warn) {
-            println!(concat!("[{}][WARN] ",$arg0), _LOG_CTX_JK23BN4KJ2, $($arg)*);
+        if $crate::log::enabled($crate::log::LogLevel::Warn, _LOG_CTX_JK23BN4KJ2) {
+            $crate::log::write($crate::log::LogLevel::Warn, _LOG_CTX_JK23BN4KJ2, format_args!($arg0, $($arg)*), &[]);
         }
     };
 }
@@ -84,13 +585,13 @@ macro_rules! warn {
 #[macro_export]
 macro_rules! info {
     ($arg0: tt) => {
-    if ($crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Info) {
-            println!(concat!("[{}][INFO] ",$arg0), _LOG_CTX_JK23BN4KJ2);
+    if $crate::log::enabled($crate::log::LogLevel::Info, _LOG_CTX_JK23BN4KJ2) {
+            $crate::log::write($crate::log::LogLevel::Info, _LOG_CTX_JK23BN4KJ2, format_args!($arg0), &[]);
         }
     };
     ($arg0: tt, $($arg:tt)*) => {
-        if ($crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Info) {
-            println!(concat!("[{}][INFO] ",$arg0), _LOG_CTX_JK23BN4KJ2, $($arg)*);
+        if $crate::log::enabled($crate::log::LogLevel::Info, _LOG_CTX_JK23BN4KJ2) {
+            $crate::log::write($crate::log::LogLevel::Info, _LOG_CTX_JK23BN4KJ2, format_args!($arg0, $($arg)*), &[]);
         }
     };
 }
@@ -98,8 +599,8 @@ macro_rules! info {
 #[macro_export]
 macro_rules! debug {
     ($arg0: tt, $($arg:tt)*) => {
-        if ($crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Debug) {
-            println!(concat!("[{}][DEBUG] ",$arg0), _LOG_CTX_JK23BN4KJ2, $($arg)*);
+        if $crate::log::enabled($crate::log::LogLevel::Debug, _LOG_CTX_JK23BN4KJ2) {
+            $crate::log::write($crate::log::LogLevel::Debug, _LOG_CTX_JK23BN4KJ2, format_args!($arg0, $($arg)*), &[]);
         }
     };
 }
@@ -107,13 +608,13 @@ macro_rules! debug {
 #[macro_export]
 macro_rules! trace {
     ($arg0: tt) => {
-        if ($crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Trace) {
-            println!(concat!("[{}][TRACE] ",$arg0), _LOG_CTX_JK23BN4KJ2);
+        if $crate::log::enabled($crate::log::LogLevel::Trace, _LOG_CTX_JK23BN4KJ2) {
+            $crate::log::write($crate::log::LogLevel::Trace, _LOG_CTX_JK23BN4KJ2, format_args!($arg0), &[]);
         }
     };
     ($arg0: tt, $($arg:tt)*) => {
-        if ($crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Trace) {
-            println!(concat!("[{}][TRACE] ",$arg0), _LOG_CTX_JK23BN4KJ2, $($arg)*);
+        if $crate::log::enabled($crate::log::LogLevel::Trace, _LOG_CTX_JK23BN4KJ2) {
+            $crate::log::write($crate::log::LogLevel::Trace, _LOG_CTX_JK23BN4KJ2, format_args!($arg0, $($arg)*), &[]);
         }
     };
 }
@@ -122,7 +623,7 @@ macro_rules! trace {
 macro_rules! timer {
     ($ctx:expr) => {
         let _timer_jk23_bn4_kj2 =
-            if $crate::log::LOG_LEVEL.get().copied().unwrap() <= $crate::log::LogLevel::Debug {
+            if $crate::log::enabled($crate::log::LogLevel::Debug, _LOG_CTX_JK23BN4KJ2) {
                 Some($crate::log::Timer::new(_LOG_CTX_JK23BN4KJ2, $ctx))
             } else {
                 None
@@ -148,12 +649,16 @@ impl<'a> Timer<'a> {
 
 impl Drop for Timer<'_> {
     fn drop(&mut self) {
-        let elapsed = self.start.elapsed();
-        println!(
-            "[{}][DEBUG][{}] elipsed {}μs",
+        let elapsed = self.start.elapsed().as_micros() as u64;
+        let fields = [
+            ("timer", Field::Str(self.timer_ctx)),
+            ("duration_us", Field::Num(elapsed)),
+        ];
+        write(
+            LogLevel::Debug,
             self.log_ctx,
-            self.timer_ctx,
-            elapsed.as_micros()
+            format_args!("[{}] elipsed {}μs", self.timer_ctx, elapsed),
+            &fields,
         );
     }
 }
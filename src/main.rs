@@ -1,65 +1,1073 @@
-mod filecache;
-mod fswatcher;
 mod getopt;
-mod http;
-mod log;
-mod requestmap;
-mod teewriter;
-mod util;
-
-use filecache::FileCache;
-use fswatcher::setup_fs_watcher;
-use getopt::getopt;
-use http::handle_connection;
-use log::LogLevel;
-use requestmap::RequestMap;
-use std::{env, path::PathBuf, sync::Arc};
+
+use getopt::{getopt, Args, Opt};
+use http_server::{
+    admin::parse_admin_addr,
+    cachecontrol::{CacheRule, CacheRules},
+    error,
+    filecache::{Admission, CacheTtl},
+    info,
+    ipfilter::{Cidr, IpFilter},
+    log::{self, LogFile, LogFilter, LogFormat, LogOverflow, DEFAULT_LOG_QUEUE_SIZE},
+    log_ctx,
+    markdown::Markdown,
+    metrics::MetricsEndpoint,
+    privdrop::{self, Ids},
+    redirect::Redirect,
+    requestlog::StatusFilter,
+    requestmap::RequestMap,
+    sdnotify,
+    security::SecurityHeaders,
+    server::{
+        parse_listen_addr, DEFAULT_BODY_TIMEOUT, DEFAULT_FILE_CACHE_SIZE, DEFAULT_HEADER_TIMEOUT,
+        DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_CACHEABLE_SIZE,
+        DEFAULT_MAX_HEADER_COUNT, DEFAULT_MAX_HEADER_SIZE, DEFAULT_PORT, DEFAULT_REQUEST_TIMEOUT,
+        DEFAULT_WATCH_DEBOUNCE, DEFAULT_WATCH_POLL_BUDGET, DEFAULT_WATCH_POLL_INTERVAL,
+    },
+    state::{parse_tenants, Preload, ServerState, DEFAULT_INDEX_FILES},
+    upload::Upload,
+    util::{fmt_size, json_escape, parse_duration},
+    warn, Server, ServerBuilder, DEFAULT_LOG_LEVEL, REQ_MAP_FILE, RES_ROOT_FOLDER,
+};
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tokio::{
-    fs::read_to_string,
-    net::TcpListener,
+    fs::{read_to_string, write},
     select,
-    task::{self},
 };
-use util::fmt_size;
 
 // Constants
-const BUFF_INIT_SIZE: usize = 1024; // Referencial init buffer size of all program buffers. All buffers are initialized using multiples of this value.
-const DEFAULT_PORT: u16 = 3006;
-const DEFAULT_FILE_CACHE_SIZE: usize = 100 * 1024 * 1024;
-const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::Info;
-const RES_ROOT_FOLDER: &str = "res";
-const REQ_MAP_FILE: &str = "map.txt";
-const ENV_ARG_PORT_KEY: &str = "p";
-const ENV_ARG_FILE_ROOT_KEY: &str = "f";
-const ENV_ARG_FILE_CACHE_SIZE_KEY: &str = "c";
-const ENV_ARG_LOG_LEVEL_KEY: &str = "l";
+const ENV_ARG_PORT_KEY: &str = "port";
+const ENV_ARG_ACCEPT_WORKERS_KEY: &str = "accept-workers";
+const ENV_ARG_BIND_KEY: &str = "bind";
+const ENV_ARG_FILE_ROOT_KEY: &str = "root";
+const ENV_ARG_FILE_CACHE_SIZE_KEY: &str = "file-cache-size";
+const ENV_ARG_MAX_CACHEABLE_SIZE_KEY: &str = "max-cacheable-size";
+const ENV_ARG_CACHE_ADMISSION_KEY: &str = "cache-admission";
+const ENV_ARG_MMAP_KEY: &str = "mmap";
+const ENV_ARG_CACHE_TTL_KEY: &str = "cache-ttl";
+const ENV_ARG_CACHE_EXCLUDE_KEY: &str = "cache-exclude";
+const ENV_ARG_WATCH_DEBOUNCE_KEY: &str = "watch-debounce";
+const ENV_ARG_WATCH_POLL_KEY: &str = "watch-poll";
+const ENV_ARG_WATCH_POLL_BUDGET_KEY: &str = "watch-poll-budget";
+const ENV_ARG_WATCH_IGNORE_KEY: &str = "watch-ignore";
+const ENV_ARG_LOG_LEVEL_KEY: &str = "log-level";
+const ENV_ARG_DEBUG_ENDPOINTS_KEY: &str = "debug-endpoints";
+const ENV_ARG_TENANTS_FILE_KEY: &str = "tenants";
+const ENV_ARG_RES_DIR_KEY: &str = "res-dir";
+const ENV_ARG_READY_FILE_KEY: &str = "ready-file";
+const ENV_ARG_IDLE_TIMEOUT_KEY: &str = "idle-timeout";
+const ENV_ARG_HEADER_TIMEOUT_KEY: &str = "header-timeout";
+const ENV_ARG_BODY_TIMEOUT_KEY: &str = "body-timeout";
+const ENV_ARG_REQUEST_TIMEOUT_KEY: &str = "request-timeout";
+const ENV_ARG_MAX_HEADER_SIZE_KEY: &str = "max-header-size";
+const ENV_ARG_MAX_HEADER_COUNT_KEY: &str = "max-header-count";
+const ENV_ARG_MAX_BODY_SIZE_KEY: &str = "max-body-size";
+const ENV_ARG_DIR_LISTING_KEY: &str = "dir-listing";
+const ENV_ARG_METRICS_KEY: &str = "metrics";
+const ENV_ARG_REDIRECT_KEY: &str = "redirect";
+const ENV_ARG_ACME_WEBROOT_KEY: &str = "acme-webroot";
+const ENV_ARG_ACCESS_LOG_KEY: &str = "access-log";
+const ENV_ARG_ADMIN_KEY: &str = "admin";
+const ENV_ARG_CACHE_STATS_INTERVAL_KEY: &str = "cache-stats-interval";
+const ENV_ARG_UPLOAD_KEY: &str = "upload";
+const ENV_ARG_DUMP_MAP_KEY: &str = "dump-map";
+const ENV_ARG_CHECK_KEY: &str = "check";
+const ENV_ARG_LOG_FORMAT_KEY: &str = "log-format";
+const ENV_ARG_LOG_QUEUE_KEY: &str = "log-queue";
+const ENV_ARG_LOG_DROP_KEY: &str = "log-drop";
+const ENV_ARG_NO_COLOR_KEY: &str = "no-color";
+const ENV_ARG_LOG_FILE_KEY: &str = "log-file";
+const ENV_ARG_LOG_MAX_SIZE_KEY: &str = "log-max-size";
+const ENV_ARG_LOG_KEEP_KEY: &str = "log-keep";
+const ENV_ARG_PRELOAD_KEY: &str = "preload";
+const ENV_ARG_CACHE_PERSIST_KEY: &str = "cache-persist";
+const ENV_ARG_UPLOAD_MAX_SIZE_KEY: &str = "upload-max-size";
+const ENV_ARG_UPLOAD_EXT_KEY: &str = "upload-ext";
+const ENV_ARG_INDEX_KEY: &str = "index";
+const ENV_ARG_SLOW_REQUEST_KEY: &str = "slow-request";
+const ENV_ARG_LOG_SAMPLE_KEY: &str = "log-sample";
+const ENV_ARG_LOG_STATUS_KEY: &str = "log-status";
+const ENV_ARG_DEV_KEY: &str = "dev";
+const ENV_ARG_MARKDOWN_KEY: &str = "markdown";
+const ENV_ARG_MARKDOWN_CSS_KEY: &str = "markdown-css";
+const ENV_ARG_SSI_KEY: &str = "ssi";
+const ENV_ARG_PRECOMPRESSED_KEY: &str = "precompressed";
+const ENV_ARG_SECURITY_HEADERS_KEY: &str = "security-headers";
+const ENV_ARG_SECURITY_HEADER_KEY: &str = "security-header";
+const ENV_ARG_CACHE_CONTROL_KEY: &str = "cache-control";
+const ENV_ARG_IO_URING_KEY: &str = "io-uring";
+const ENV_ARG_CONFIG_KEY: &str = "config";
+const ENV_ARG_MAX_CONNECTIONS_KEY: &str = "max-connections";
+const ENV_ARG_ACCEPT_RATE_KEY: &str = "accept-rate";
+const ENV_ARG_USER_KEY: &str = "user";
+const ENV_ARG_GROUP_KEY: &str = "group";
+const ENV_ARG_CHROOT_KEY: &str = "chroot";
+const ENV_ARG_ALLOW_KEY: &str = "allow";
+const ENV_ARG_DENY_KEY: &str = "deny";
+const ENV_ARG_DROP_REFUSED_KEY: &str = "drop-refused";
+const ENV_ARG_TRUSTED_PROXIES_KEY: &str = "trusted-proxies";
+const ENV_ARG_PROXY_PROTOCOL_KEY: &str = "proxy-protocol";
+const ENV_ARG_HELP_KEY: &str = "help";
+const ENV_ARG_VERSION_KEY: &str = "version";
+const CONFIG_COMMENT_PREFIX: char = '#';
+const DEFAULT_LOG_KEEP: usize = 5;
+const USAGE: &str = "http-server [options]";
+
+// Options of the command line and the config file, in the order of the help screen
+const OPTIONS: &[Opt] = &[
+    Opt::value(
+        Some('p'),
+        ENV_ARG_PORT_KEY,
+        "port",
+        "Port or address to listen on, can be repeated (Default to 3006)",
+    ),
+    Opt::value(
+        Some('b'),
+        ENV_ARG_BIND_KEY,
+        "ip",
+        "Address of the ports given without one, e.g. 127.0.0.1 or :: (Default to 0.0.0.0)",
+    ),
+    Opt::value(
+        Some('W'),
+        ENV_ARG_ACCEPT_WORKERS_KEY,
+        "workers",
+        "Sockets bound to every address with SO_REUSEPORT (Default to 1)",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_IO_URING_KEY,
+        "Serve connections on io_uring runtimes",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_USER_KEY,
+        "user",
+        "User to switch to once the sockets are bound",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_GROUP_KEY,
+        "group",
+        "Group to switch to once the sockets are bound",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_CHROOT_KEY,
+        "Make the root folder the root of the file system",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_MAX_CONNECTIONS_KEY,
+        "count",
+        "Connections served at once (Default to 0, unlimited)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_ACCEPT_RATE_KEY,
+        "rate",
+        "Connections accepted per second (Default to 0, unlimited)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_ALLOW_KEY,
+        "networks",
+        "Networks clients may connect from, can be repeated",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_DENY_KEY,
+        "networks",
+        "Networks clients are refused from, can be repeated",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_DROP_REFUSED_KEY,
+        "Close refused connections without answering 403",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_TRUSTED_PROXIES_KEY,
+        "networks",
+        "Proxies naming the client in forwarding headers, can be repeated",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_PROXY_PROTOCOL_KEY,
+        "Expect a PROXY protocol preamble on every connection",
+    ),
+    Opt::value(
+        Some('f'),
+        ENV_ARG_FILE_ROOT_KEY,
+        "folder",
+        "Root folder of the map file and the res folder (Default to the current folder)",
+    ),
+    Opt::value(
+        Some('r'),
+        ENV_ARG_RES_DIR_KEY,
+        "folder",
+        "Folder or archive to serve responses from (Default to res)",
+    ),
+    Opt::value(
+        Some('c'),
+        ENV_ARG_FILE_CACHE_SIZE_KEY,
+        "kb",
+        "Size limit of the file cache in KB",
+    ),
+    Opt::value(
+        Some('C'),
+        ENV_ARG_MAX_CACHEABLE_SIZE_KEY,
+        "kb",
+        "Size of the largest cached file in KB",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_CACHE_ADMISSION_KEY,
+        "policy",
+        "Files let into a full file cache, lru or tinylfu (Default to lru)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_CACHE_TTL_KEY,
+        "[pattern=]ttl",
+        "Time after which cached files are checked against the disk, can be repeated",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_CACHE_EXCLUDE_KEY,
+        "pattern",
+        "Paths read from disk on every request instead of cached, can be repeated",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_WATCH_DEBOUNCE_KEY,
+        "duration",
+        "Quiet time before file changes are applied to the cache in one batch (Default to 200ms)",
+    ),
+    Opt::optional(
+        None,
+        ENV_ARG_WATCH_POLL_KEY,
+        "interval",
+        "Poll the res files for changes at this interval instead of watching them (Default to 2s)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_WATCH_POLL_BUDGET_KEY,
+        "files",
+        "Files looked at per scan when polling (Default to 100000)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_WATCH_IGNORE_KEY,
+        "pattern",
+        "Paths whose changes the file watcher ignores, can be repeated",
+    ),
+    Opt::optional(
+        None,
+        ENV_ARG_MMAP_KEY,
+        "min_kb",
+        "Serve files of at least this size in KB from memory mappings (Default to files larger than the max cacheable size)",
+    ),
+    Opt::optional(
+        None,
+        ENV_ARG_PRELOAD_KEY,
+        "list",
+        "Load the files, or the listed ones, into the cache at startup",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_CACHE_PERSIST_KEY,
+        "dir",
+        "Folder the cached paths are saved to on shutdown and loaded back from at startup",
+    ),
+    Opt::value(
+        Some('l'),
+        ENV_ARG_LOG_LEVEL_KEY,
+        "level",
+        "Log level, optionally per context, e.g. warn,FileCache=debug (Default to info)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_FORMAT_KEY,
+        "format",
+        "Format of the log lines, text or json (Default to text)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_QUEUE_KEY,
+        "lines",
+        "Log lines queued for the logger thread (Default to 8192)",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_LOG_DROP_KEY,
+        "Drop log lines when the queue is full instead of waiting",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_NO_COLOR_KEY,
+        "Never color the log levels, even on a terminal",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_FILE_KEY,
+        "file",
+        "File to append the logs to instead of the console",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_MAX_SIZE_KEY,
+        "kb",
+        "Size in KB at which the log file is rotated",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_KEEP_KEY,
+        "count",
+        "Rotated log files kept (Default to 5)",
+    ),
+    Opt::flag(
+        Some('d'),
+        ENV_ARG_DEBUG_ENDPOINTS_KEY,
+        "Enable the built-in debug endpoints",
+    ),
+    Opt::flag(
+        Some('L'),
+        ENV_ARG_DIR_LISTING_KEY,
+        "List folders without index file",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_INDEX_KEY,
+        "files",
+        "File names served for a folder (Default to index.html,index.htm,index)",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_DEV_KEY,
+        "Reload pages in the browser once a file changes",
+    ),
+    Opt::value(
+        Some('k'),
+        ENV_ARG_IDLE_TIMEOUT_KEY,
+        "duration",
+        "Idle time before a persistent connection is closed (Default to 5s)",
+    ),
+    Opt::value(
+        Some('H'),
+        ENV_ARG_HEADER_TIMEOUT_KEY,
+        "duration",
+        "Time limit to read the request headers (Default to 10s)",
+    ),
+    Opt::value(
+        Some('B'),
+        ENV_ARG_BODY_TIMEOUT_KEY,
+        "duration",
+        "Time limit to read the request body (Default to 30s)",
+    ),
+    Opt::value(
+        Some('T'),
+        ENV_ARG_REQUEST_TIMEOUT_KEY,
+        "duration",
+        "Time limit to read the whole request (Default to 60s)",
+    ),
+    Opt::value(
+        Some('x'),
+        ENV_ARG_MAX_HEADER_SIZE_KEY,
+        "kb",
+        "Size limit of the request headers in KB (Default to 64)",
+    ),
+    Opt::value(
+        Some('X'),
+        ENV_ARG_MAX_HEADER_COUNT_KEY,
+        "count",
+        "Limit of the number of request headers (Default to 100)",
+    ),
+    Opt::value(
+        Some('z'),
+        ENV_ARG_MAX_BODY_SIZE_KEY,
+        "kb",
+        "Size limit of the request body in KB (Default to 10240)",
+    ),
+    Opt::value(
+        Some('m'),
+        ENV_ARG_METRICS_KEY,
+        "path|port",
+        "Serve Prometheus metrics on a path or a separate port",
+    ),
+    Opt::value(
+        Some('P'),
+        ENV_ARG_REDIRECT_KEY,
+        "port[:https_port]",
+        "Redirect plain HTTP on the port to https",
+    ),
+    Opt::value(
+        Some('w'),
+        ENV_ARG_ACME_WEBROOT_KEY,
+        "folder",
+        "Serve ACME challenges on the redirect port from the folder",
+    ),
+    Opt::value(
+        Some('A'),
+        ENV_ARG_ADMIN_KEY,
+        "port|address",
+        "Serve the admin API on the port of the loopback or the address",
+    ),
+    Opt::value(
+        Some('u'),
+        ENV_ARG_UPLOAD_KEY,
+        "folder",
+        "Accept PUT uploads to the folder of the res folder",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_UPLOAD_MAX_SIZE_KEY,
+        "kb",
+        "Size limit of uploads in KB (Default to the max body size)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_UPLOAD_EXT_KEY,
+        "extensions",
+        "Extensions allowed for uploads (Default to any)",
+    ),
+    Opt::optional(
+        None,
+        ENV_ARG_MARKDOWN_KEY,
+        "template",
+        "Render markdown files into pages, optionally with a template",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_MARKDOWN_CSS_KEY,
+        "url",
+        "Stylesheet linked from the rendered markdown pages",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_SSI_KEY,
+        "Process server side includes of .shtml files",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_PRECOMPRESSED_KEY,
+        "Serve .br and .gz siblings of files to clients accepting them",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_SECURITY_HEADERS_KEY,
+        "Add the preset of security headers to every response",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_SECURITY_HEADER_KEY,
+        "name: value",
+        "Replace, add or remove a header of the preset, can be repeated",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_CACHE_CONTROL_KEY,
+        "pattern=policy",
+        "Cache-Control of the paths matching the pattern, can be repeated",
+    ),
+    Opt::value(
+        Some('t'),
+        ENV_ARG_TENANTS_FILE_KEY,
+        "file",
+        "Tenants file for serving multiple hosts",
+    ),
+    Opt::value(
+        Some('a'),
+        ENV_ARG_ACCESS_LOG_KEY,
+        "file",
+        "File to append the access log in the Combined Log Format to",
+    ),
+    Opt::value(
+        Some('s'),
+        ENV_ARG_CACHE_STATS_INTERVAL_KEY,
+        "duration",
+        "Interval to log the file cache statistics at",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_SLOW_REQUEST_KEY,
+        "duration",
+        "Log requests taking longer in full",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_SAMPLE_KEY,
+        "n",
+        "Log 1 in n successful requests, failed and slow requests are always logged (Default to 1)",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_LOG_STATUS_KEY,
+        "codes",
+        "Only log the requests answered with these status codes or classes, e.g. 4xx,5xx,304",
+    ),
+    Opt::value(
+        Some('R'),
+        ENV_ARG_READY_FILE_KEY,
+        "file",
+        "File to write the startup record to once ready",
+    ),
+    Opt::value(
+        None,
+        ENV_ARG_CONFIG_KEY,
+        "file",
+        "File of options, one per line, overridden by the command line",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_DUMP_MAP_KEY,
+        "Print the request map in the canonical format and exit",
+    ),
+    Opt::flag(
+        None,
+        ENV_ARG_CHECK_KEY,
+        "Check the config, the sites and the files they name and exit",
+    ),
+    Opt::flag(Some('h'), ENV_ARG_HELP_KEY, "Print this help and exit"),
+    Opt::flag(Some('V'), ENV_ARG_VERSION_KEY, "Print the version and exit"),
+];
 log_ctx!("Main");
 
 struct Config {
     file_root: PathBuf,
-    port: u16,
+    res_dir: Option<PathBuf>,
+    listen: Vec<SocketAddr>,
+    bind_ip: IpAddr,
+    accept_workers: usize,
+    io_uring: bool,
+    user: Option<String>,
+    group: Option<String>,
+    chroot: bool,
+    max_connections: usize,
+    accept_rate: u32,
+    ip_filter: IpFilter,
+    drop_refused: bool,
+    trusted_proxies: Vec<Cidr>,
+    proxy_protocol: bool,
     file_cache_size: usize,
-    log_level: LogLevel,
+    max_cacheable_size: usize,
+    cache_admission: Admission,
+    cache_ttl: CacheTtl,
+    cache_exclude: Vec<String>,
+    watch_debounce: Duration,
+    watch_poll: Option<Duration>,
+    watch_poll_budget: usize,
+    watch_ignore: Vec<String>,
+    mmap: Option<usize>,
+    log_level: LogFilter,
+    log_format: LogFormat,
+    log_queue_size: usize,
+    log_overflow: LogOverflow,
+    log_color: bool,
+    log_file: Option<LogFile>,
+    debug_endpoints: bool,
+    dir_listing: bool,
+    index_files: Vec<String>,
+    dev: bool,
+    idle_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    request_timeout: Duration,
+    max_header_size: usize,
+    max_header_count: usize,
+    max_body_size: usize,
+    metrics: Option<MetricsEndpoint>,
+    redirect: Option<Redirect>,
+    admin: Option<SocketAddr>,
+    upload: Option<Upload>,
+    markdown: Option<Markdown>,
+    ssi: bool,
+    precompressed: bool,
+    security_headers: Option<SecurityHeaders>,
+    cache_rules: CacheRules,
+    tenants_file: Option<PathBuf>,
+    access_log: Option<PathBuf>,
+    cache_stats_interval: Duration,
+    slow_request: Duration,
+    log_sample: u64,
+    log_status: Option<StatusFilter>,
+    preload: Option<Preload>,
+    cache_persist: Option<PathBuf>,
+    ready_file: Option<PathBuf>,
+    config_file: Option<PathBuf>,
+    dump_map: bool,
+    check: bool,
+}
+
+// Options applied to the running server on SIGHUP, others take effect on restart
+const RELOADABLE_OPTIONS: [&str; 5] = [
+    "log_level",
+    "file_cache_size",
+    "max_cacheable_size",
+    "security_headers",
+    "cache_control",
+];
+
+impl Config {
+    // Options by JSON key with JSON values, in the order of the startup record
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let opt_path = |p: &Option<PathBuf>| match p {
+            Some(p) => json_escape(&p.display().to_string()),
+            None => "null".to_string(),
+        };
+        vec![
+            (
+                "listen",
+                format!(
+                    "[{}]",
+                    self.listen
+                        .iter()
+                        .map(|a| json_escape(&a.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            ("bind", json_escape(&self.bind_ip.to_string())),
+            ("accept_workers", self.accept_workers.to_string()),
+            ("io_uring", self.io_uring.to_string()),
+            (
+                "user",
+                self.user.as_deref().map_or("null".to_string(), json_escape),
+            ),
+            (
+                "group",
+                self.group
+                    .as_deref()
+                    .map_or("null".to_string(), json_escape),
+            ),
+            ("chroot", self.chroot.to_string()),
+            ("max_connections", self.max_connections.to_string()),
+            ("accept_rate", self.accept_rate.to_string()),
+            (
+                "allow",
+                format!(
+                    "[{}]",
+                    self.ip_filter
+                        .allow
+                        .iter()
+                        .map(|c| json_escape(&c.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            (
+                "deny",
+                format!(
+                    "[{}]",
+                    self.ip_filter
+                        .deny
+                        .iter()
+                        .map(|c| json_escape(&c.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            ("drop_refused", self.drop_refused.to_string()),
+            (
+                "trusted_proxies",
+                format!(
+                    "[{}]",
+                    self.trusted_proxies
+                        .iter()
+                        .map(|c| json_escape(&c.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            ("proxy_protocol", self.proxy_protocol.to_string()),
+            (
+                "file_root",
+                json_escape(&self.file_root.display().to_string()),
+            ),
+            ("res_dir", opt_path(&self.res_dir)),
+            ("file_cache_size", self.file_cache_size.to_string()),
+            ("max_cacheable_size", self.max_cacheable_size.to_string()),
+            (
+                "cache_admission",
+                json_escape(&self.cache_admission.to_string()),
+            ),
+            (
+                "cache_ttl",
+                format!(
+                    "[{}]",
+                    self.cache_ttl
+                        .entries()
+                        .iter()
+                        .map(|e| json_escape(e))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            (
+                "cache_exclude",
+                format!(
+                    "[{}]",
+                    self.cache_exclude
+                        .iter()
+                        .map(|p| json_escape(p))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            (
+                "watch_debounce_ms",
+                self.watch_debounce.as_millis().to_string(),
+            ),
+            (
+                "watch_poll_ms",
+                self.watch_poll
+                    .map_or("null".to_string(), |p| p.as_millis().to_string()),
+            ),
+            ("watch_poll_budget", self.watch_poll_budget.to_string()),
+            (
+                "watch_ignore",
+                format!(
+                    "[{}]",
+                    self.watch_ignore
+                        .iter()
+                        .map(|p| json_escape(p))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            (
+                "mmap",
+                self.mmap.map_or("null".to_string(), |m| m.to_string()),
+            ),
+            ("log_level", json_escape(&self.log_level.to_string())),
+            ("log_format", json_escape(&self.log_format.to_string())),
+            ("log_queue_size", self.log_queue_size.to_string()),
+            ("log_overflow", json_escape(&self.log_overflow.to_string())),
+            ("log_color", self.log_color.to_string()),
+            (
+                "log_file",
+                opt_path(&self.log_file.as_ref().map(|f| f.path.clone())),
+            ),
+            (
+                "log_max_size",
+                self.log_file.as_ref().map_or(0, |f| f.max_size).to_string(),
+            ),
+            (
+                "log_keep",
+                self.log_file.as_ref().map_or(0, |f| f.keep).to_string(),
+            ),
+            ("debug_endpoints", self.debug_endpoints.to_string()),
+            ("dir_listing", self.dir_listing.to_string()),
+            (
+                "index_files",
+                format!(
+                    "[{}]",
+                    self.index_files
+                        .iter()
+                        .map(|f| json_escape(f))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            ("dev", self.dev.to_string()),
+            ("idle_timeout_ms", self.idle_timeout.as_millis().to_string()),
+            (
+                "header_timeout_ms",
+                self.header_timeout.as_millis().to_string(),
+            ),
+            ("body_timeout_ms", self.body_timeout.as_millis().to_string()),
+            (
+                "request_timeout_ms",
+                self.request_timeout.as_millis().to_string(),
+            ),
+            ("max_header_size", self.max_header_size.to_string()),
+            ("max_header_count", self.max_header_count.to_string()),
+            ("max_body_size", self.max_body_size.to_string()),
+            (
+                "metrics",
+                match &self.metrics {
+                    Some(m) => json_escape(&m.to_string()),
+                    None => "null".to_string(),
+                },
+            ),
+            (
+                "redirect",
+                match &self.redirect {
+                    Some(r) => json_escape(&r.to_string()),
+                    None => "null".to_string(),
+                },
+            ),
+            (
+                "acme_webroot",
+                opt_path(&self.redirect.as_ref().and_then(|r| r.acme_webroot.clone())),
+            ),
+            (
+                "admin",
+                match &self.admin {
+                    Some(a) => json_escape(&a.to_string()),
+                    None => "null".to_string(),
+                },
+            ),
+            (
+                "upload",
+                match &self.upload {
+                    Some(u) => json_escape(&u.to_string()),
+                    None => "null".to_string(),
+                },
+            ),
+            (
+                "upload_max_size",
+                match self.upload.as_ref().and_then(|u| u.max_size) {
+                    Some(m) => m.to_string(),
+                    None => "null".to_string(),
+                },
+            ),
+            (
+                "upload_ext",
+                format!(
+                    "[{}]",
+                    self.upload
+                        .iter()
+                        .flat_map(|u| u.extensions.iter().map(|e| json_escape(e)))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            ("markdown", self.markdown.is_some().to_string()),
+            (
+                "markdown_template",
+                opt_path(&self.markdown.as_ref().and_then(|m| m.template.clone())),
+            ),
+            (
+                "markdown_css",
+                match self.markdown.as_ref().and_then(|m| m.stylesheet.as_ref()) {
+                    Some(s) => json_escape(s),
+                    None => "null".to_string(),
+                },
+            ),
+            ("ssi", self.ssi.to_string()),
+            ("precompressed", self.precompressed.to_string()),
+            (
+                "security_headers",
+                match &self.security_headers {
+                    Some(h) => format!(
+                        "[{}]",
+                        h.headers()
+                            .iter()
+                            .map(|(k, v)| json_escape(&format!("{}: {}", k, v)))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    ),
+                    None => "null".to_string(),
+                },
+            ),
+            (
+                "cache_control",
+                format!(
+                    "[{}]",
+                    self.cache_rules
+                        .rules
+                        .iter()
+                        .map(|r| json_escape(&r.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ),
+            ),
+            ("tenants_file", opt_path(&self.tenants_file)),
+            ("access_log", opt_path(&self.access_log)),
+            (
+                "cache_stats_interval_ms",
+                self.cache_stats_interval.as_millis().to_string(),
+            ),
+            ("slow_request_ms", self.slow_request.as_millis().to_string()),
+            ("log_sample", self.log_sample.to_string()),
+            (
+                "log_status",
+                self.log_status
+                    .as_ref()
+                    .map_or("null".to_string(), |s| json_escape(&s.to_string())),
+            ),
+            (
+                "preload",
+                match &self.preload {
+                    Some(p) => json_escape(&p.to_string()),
+                    None => "null".to_string(),
+                },
+            ),
+            ("cache_persist", opt_path(&self.cache_persist)),
+            ("ready_file", opt_path(&self.ready_file)),
+            ("config_file", opt_path(&self.config_file)),
+        ]
+    }
+
+    fn to_json(&self) -> String {
+        let fields = self
+            .fields()
+            .into_iter()
+            .map(|(k, v)| format!("{}:{}", json_escape(k), v))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+fn get_duration(
+    args: &Args,
+    key: &str,
+    name: &str,
+) -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+    match args.get(key) {
+        Some(Some(v)) => match parse_duration(v) {
+            Some(d) => Ok(Some(d)),
+            None => Err(format!("Invalid {}: {}", name, v).into()),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Options of the config file are put before the command line, so that the command line overrides them
+// The file holds one option per line as given on the command line, e.g. "-p 8080" or "--ssi"
+fn read_args() -> Result<(Args, Option<PathBuf>), Box<dyn std::error::Error>> {
+    let args = getopt(OPTIONS)?;
+    let path = match args.get(ENV_ARG_CONFIG_KEY) {
+        Some(Some(p)) => PathBuf::from(p),
+        Some(None) => return Err("Config file requires a path".into()),
+        None => return Ok((args, None)),
+    };
+    let file = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    let mut file_args = Vec::new();
+    for line in file
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with(CONFIG_COMMENT_PREFIX))
+    {
+        match line.split_once(char::is_whitespace) {
+            Some((flag, val)) => {
+                file_args.push(flag.into());
+                file_args.push(val.trim().into());
+            }
+            None => file_args.push(line.into()),
+        }
+    }
+    let args = getopt::parse(OPTIONS, file_args.into_iter().chain(env::args_os().skip(1)))?;
+    Ok((args, Some(path)))
 }
 
 fn get_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let args = getopt()?;
+    let (args, config_file) = read_args()?;
+
+    // get bind address of the ports given without one
+    let bind_ip = match args.get(ENV_ARG_BIND_KEY) {
+        Some(Some(b)) => match b.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return Err(format!("Invalid bind address: {}", b).into()),
+        },
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    // get listen addresses
+    let mut listen = Vec::new();
+    for p in args.get_all(ENV_ARG_PORT_KEY) {
+        match parse_listen_addr(p, bind_ip) {
+            Some(a) => listen.push(a),
+            None => return Err(format!("Invalid port or address: {}", p).into()),
+        }
+    }
+    if listen.is_empty() {
+        listen.push(SocketAddr::new(bind_ip, DEFAULT_PORT));
+    }
+
+    // get accept workers per address
+    let accept_workers = match args.get(ENV_ARG_ACCEPT_WORKERS_KEY) {
+        Some(Some(w)) => match w.parse::<usize>() {
+            Ok(w) if w > 0 => w,
+            _ => return Err(format!("Invalid accept workers: {}", w).into()),
+        },
+        _ => 1,
+    };
 
-    // get port
-    let port = match args.get(ENV_ARG_PORT_KEY) {
-        Some(Some(p)) => match p.parse::<u16>() {
-            Ok(p) => p,
-            Err(e) => return Err(format!("Invalid port: {}", e).into()),
+    // get whether connections are served on io_uring runtimes
+    let io_uring = args.contains_key(ENV_ARG_IO_URING_KEY);
+    if io_uring && !cfg!(all(target_os = "linux", feature = "uring")) {
+        return Err("--io-uring requires building with the uring feature on Linux".into());
+    }
+
+    // get the user and group switched to after binding, and whether the file root becomes the root
+    let user = match args.get(ENV_ARG_USER_KEY) {
+        Some(Some(u)) => Some(u.clone()),
+        Some(None) => return Err("--user requires a user name or id".into()),
+        None => None,
+    };
+    let group = match args.get(ENV_ARG_GROUP_KEY) {
+        Some(Some(g)) => Some(g.clone()),
+        Some(None) => return Err("--group requires a group name or id".into()),
+        None => None,
+    };
+    let chroot = args.contains_key(ENV_ARG_CHROOT_KEY);
+    if (user.is_some() || group.is_some() || chroot) && !cfg!(target_os = "linux") {
+        return Err("--user, --group and --chroot are only supported on Linux".into());
+    }
+
+    // get limits of the connections served at once and accepted per second, zero is unlimited
+    let max_connections = match args.get(ENV_ARG_MAX_CONNECTIONS_KEY) {
+        Some(Some(m)) => match m.parse::<usize>() {
+            Ok(m) => m,
+            Err(e) => return Err(format!("Invalid max connections: {}", e).into()),
+        },
+        _ => 0,
+    };
+    let accept_rate = match args.get(ENV_ARG_ACCEPT_RATE_KEY) {
+        Some(Some(r)) => match r.parse::<u32>() {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Invalid accept rate: {}", e).into()),
         },
-        _ => DEFAULT_PORT,
+        _ => 0,
     };
 
+    // get networks clients may connect from, every flag takes a comma separated list
+    let mut ip_filter = IpFilter::default();
+    for (key, list) in [
+        (ENV_ARG_ALLOW_KEY, &mut ip_filter.allow),
+        (ENV_ARG_DENY_KEY, &mut ip_filter.deny),
+    ] {
+        for l in args.get_all(key) {
+            match IpFilter::parse_list(l) {
+                Some(l) => list.extend(l),
+                None => return Err(format!("Invalid network list: {}", l).into()),
+            }
+        }
+    }
+    let drop_refused = args.contains_key(ENV_ARG_DROP_REFUSED_KEY);
+
+    // get proxies trusted to name the client, with the headers or the PROXY protocol
+    let mut trusted_proxies = Vec::new();
+    for l in args.get_all(ENV_ARG_TRUSTED_PROXIES_KEY) {
+        match IpFilter::parse_list(l) {
+            Some(l) => trusted_proxies.extend(l),
+            None => return Err(format!("Invalid network list: {}", l).into()),
+        }
+    }
+    let proxy_protocol = args.contains_key(ENV_ARG_PROXY_PROTOCOL_KEY);
+    if proxy_protocol && trusted_proxies.is_empty() {
+        return Err("--proxy-protocol requires --trusted-proxies".into());
+    }
+
     // get file root
     let file_root = match args.get(ENV_ARG_FILE_ROOT_KEY) {
         Some(Some(f)) => PathBuf::from(f),
         _ => env::current_dir()?,
     };
 
+    // get res dir
+    let res_dir = match args.get(ENV_ARG_RES_DIR_KEY) {
+        Some(Some(r)) => Some(PathBuf::from(r)),
+        _ => None,
+    };
+
     // get file cache size
     let file_cache_size = match args.get(ENV_ARG_FILE_CACHE_SIZE_KEY) {
         Some(Some(c)) => match c.parse::<usize>() {
@@ -69,96 +1077,946 @@ fn get_config() -> Result<Config, Box<dyn std::error::Error>> {
         _ => DEFAULT_FILE_CACHE_SIZE,
     };
 
+    // get max cacheable file size
+    let max_cacheable_size = match args.get(ENV_ARG_MAX_CACHEABLE_SIZE_KEY) {
+        Some(Some(c)) => match c.parse::<usize>() {
+            Ok(c) => c * 1024,
+            Err(e) => return Err(format!("Invalid max cacheable file size: {}", e).into()),
+        },
+        _ => DEFAULT_MAX_CACHEABLE_SIZE,
+    };
+
+    // get file cache admission policy
+    let cache_admission = match args.get(ENV_ARG_CACHE_ADMISSION_KEY) {
+        Some(Some(a)) => match Admission::parse(a) {
+            Some(a) => a,
+            None => return Err(format!("Invalid cache admission policy: {}", a).into()),
+        },
+        _ => Admission::default(),
+    };
+
+    // get file cache ttl, the default and the rules of the patterns in the order given
+    let mut cache_ttl = CacheTtl::default();
+    for t in args.get_all(ENV_ARG_CACHE_TTL_KEY) {
+        if cache_ttl.add(t).is_none() {
+            return Err(format!("Invalid cache ttl: {}", t).into());
+        }
+    }
+
+    // get the patterns of the paths excluded from the file cache
+    let mut cache_exclude = Vec::new();
+    for p in args.get_all(ENV_ARG_CACHE_EXCLUDE_KEY) {
+        match p.trim() {
+            "" => return Err(format!("Invalid cache exclude pattern: {}", p).into()),
+            p => cache_exclude.push(p.to_string()),
+        }
+    }
+
+    // get the debounce window of the file watcher
+    let watch_debounce = get_duration(&args, ENV_ARG_WATCH_DEBOUNCE_KEY, "watch debounce")?
+        .unwrap_or(DEFAULT_WATCH_DEBOUNCE);
+
+    // get the polling interval of the file watcher, polling is otherwise chosen by the file system of the res roots
+    let watch_poll = match args.get(ENV_ARG_WATCH_POLL_KEY) {
+        Some(Some(_)) => {
+            match get_duration(&args, ENV_ARG_WATCH_POLL_KEY, "watch poll interval")? {
+                Some(d) if d.is_zero() => return Err("Invalid watch poll interval: 0".into()),
+                d => d,
+            }
+        }
+        Some(None) => Some(DEFAULT_WATCH_POLL_INTERVAL),
+        None => None,
+    };
+    let watch_poll_budget = match args.get(ENV_ARG_WATCH_POLL_BUDGET_KEY) {
+        Some(Some(b)) => match b.parse::<usize>() {
+            Ok(b) if b > 0 => b,
+            _ => return Err(format!("Invalid watch poll budget: {}", b).into()),
+        },
+        _ => DEFAULT_WATCH_POLL_BUDGET,
+    };
+
+    // get the patterns of the paths ignored by the file watcher
+    let mut watch_ignore = Vec::new();
+    for p in args.get_all(ENV_ARG_WATCH_IGNORE_KEY) {
+        match p.trim() {
+            "" => return Err(format!("Invalid watch ignore pattern: {}", p).into()),
+            p => watch_ignore.push(p.to_string()),
+        }
+    }
+
+    // get the min size of memory mapped files, by default those too large to be cached
+    let mmap = match args.get(ENV_ARG_MMAP_KEY) {
+        Some(Some(m)) => match m.parse::<usize>() {
+            Ok(m) => Some(m * 1024),
+            Err(e) => return Err(format!("Invalid mmap min size: {}", e).into()),
+        },
+        Some(None) => Some(max_cacheable_size + 1),
+        None => None,
+    };
+
     // get log level
     let log_level = match args.get(ENV_ARG_LOG_LEVEL_KEY) {
-        Some(Some(l)) => LogLevel::from(l),
-        _ => DEFAULT_LOG_LEVEL,
+        Some(Some(l)) => match LogFilter::parse(l) {
+            Some(l) => l,
+            None => return Err(format!("Invalid log level: {}", l).into()),
+        },
+        _ => DEFAULT_LOG_LEVEL.into(),
+    };
+
+    // get log format
+    let log_format = match args.get(ENV_ARG_LOG_FORMAT_KEY) {
+        Some(Some(f)) => match LogFormat::parse(f) {
+            Some(f) => f,
+            None => return Err(format!("Invalid log format: {}", f).into()),
+        },
+        _ => LogFormat::default(),
+    };
+
+    // get log queue size and whether lines are dropped when it is full
+    let log_queue_size = match args.get(ENV_ARG_LOG_QUEUE_KEY) {
+        Some(Some(q)) => match q.parse::<usize>() {
+            Ok(q) if q > 0 => q,
+            _ => return Err(format!("Invalid log queue size: {}", q).into()),
+        },
+        _ => DEFAULT_LOG_QUEUE_SIZE,
+    };
+    let log_overflow = match args.contains_key(ENV_ARG_LOG_DROP_KEY) {
+        true => LogOverflow::Drop,
+        false => LogOverflow::Block,
+    };
+
+    // get whether levels are colored on a terminal, the NO_COLOR convention turns them off as well
+    let log_color = !args.contains_key(ENV_ARG_NO_COLOR_KEY)
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+
+    // get log file with the size to rotate it at, never rotated by default
+    let log_max_size = match args.get(ENV_ARG_LOG_MAX_SIZE_KEY) {
+        Some(Some(m)) => match m.parse::<u64>() {
+            Ok(m) => m * 1024,
+            Err(e) => return Err(format!("Invalid log max size: {}", e).into()),
+        },
+        _ => 0,
+    };
+    let log_keep = match args.get(ENV_ARG_LOG_KEEP_KEY) {
+        Some(Some(k)) => match k.parse::<usize>() {
+            Ok(k) => k,
+            Err(e) => return Err(format!("Invalid log keep count: {}", e).into()),
+        },
+        _ => DEFAULT_LOG_KEEP,
+    };
+    let log_file = match args.get(ENV_ARG_LOG_FILE_KEY) {
+        Some(Some(f)) => Some(LogFile {
+            path: PathBuf::from(f),
+            max_size: log_max_size,
+            keep: log_keep,
+        }),
+        _ => None,
+    };
+
+    // get debug endpoints flag
+    let debug_endpoints = args.contains_key(ENV_ARG_DEBUG_ENDPOINTS_KEY);
+
+    // get directory listing flag
+    let dir_listing = args.contains_key(ENV_ARG_DIR_LISTING_KEY);
+
+    // get the files served for folders, the first existing one is used
+    let index_files = match args.get(ENV_ARG_INDEX_KEY) {
+        Some(Some(i)) => {
+            let files = i
+                .split(',')
+                .map(|f| f.trim())
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>();
+            if files.iter().any(|f| f.contains(['/', '\\'])) {
+                return Err(format!("Invalid index files: {}", i).into());
+            }
+            files
+        }
+        Some(None) => return Err("--index requires a list of file names".into()),
+        None => DEFAULT_INDEX_FILES.map(String::from).to_vec(),
+    };
+
+    // get dev mode flag
+    let dev = args.contains_key(ENV_ARG_DEV_KEY);
+
+    // get keep-alive idle timeout
+    let idle_timeout = get_duration(&args, ENV_ARG_IDLE_TIMEOUT_KEY, "idle timeout")?
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+
+    // get request read timeouts
+    let header_timeout = get_duration(&args, ENV_ARG_HEADER_TIMEOUT_KEY, "header timeout")?
+        .unwrap_or(DEFAULT_HEADER_TIMEOUT);
+    let body_timeout = get_duration(&args, ENV_ARG_BODY_TIMEOUT_KEY, "body timeout")?
+        .unwrap_or(DEFAULT_BODY_TIMEOUT);
+    let request_timeout = get_duration(&args, ENV_ARG_REQUEST_TIMEOUT_KEY, "request timeout")?
+        .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+
+    // get request header limits
+    let max_header_size = match args.get(ENV_ARG_MAX_HEADER_SIZE_KEY) {
+        Some(Some(x)) => match x.parse::<usize>() {
+            Ok(x) => x * 1024,
+            Err(e) => return Err(format!("Invalid max header size: {}", e).into()),
+        },
+        _ => DEFAULT_MAX_HEADER_SIZE,
+    };
+    let max_header_count = match args.get(ENV_ARG_MAX_HEADER_COUNT_KEY) {
+        Some(Some(x)) => match x.parse::<usize>() {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Invalid max header count: {}", e).into()),
+        },
+        _ => DEFAULT_MAX_HEADER_COUNT,
+    };
+
+    // get request body limit
+    let max_body_size = match args.get(ENV_ARG_MAX_BODY_SIZE_KEY) {
+        Some(Some(b)) => match b.parse::<usize>() {
+            Ok(b) => b * 1024,
+            Err(e) => return Err(format!("Invalid max body size: {}", e).into()),
+        },
+        _ => DEFAULT_MAX_BODY_SIZE,
+    };
+
+    // get metrics endpoint
+    let metrics = match args.get(ENV_ARG_METRICS_KEY) {
+        Some(Some(m)) => match MetricsEndpoint::parse(m) {
+            Some(m) => Some(m),
+            None => return Err(format!("Invalid metrics path or port: {}", m).into()),
+        },
+        _ => None,
+    };
+
+    // get https redirect listener and the webroot of its ACME challenges
+    let mut redirect = match args.get(ENV_ARG_REDIRECT_KEY) {
+        Some(Some(r)) => match Redirect::parse(r) {
+            Some(r) => Some(r),
+            None => return Err(format!("Invalid redirect port: {}", r).into()),
+        },
+        _ => None,
+    };
+    if let Some(Some(w)) = args.get(ENV_ARG_ACME_WEBROOT_KEY) {
+        match &mut redirect {
+            Some(r) => r.acme_webroot = Some(PathBuf::from(w)),
+            None => return Err("ACME webroot requires a redirect port".into()),
+        }
+    }
+
+    // get admin API address
+    let admin = match args.get(ENV_ARG_ADMIN_KEY) {
+        Some(Some(a)) => match parse_admin_addr(a) {
+            Some(a) => Some(a),
+            None => return Err(format!("Invalid admin address: {}", a).into()),
+        },
+        _ => None,
+    };
+
+    // get upload folder with its limits
+    let mut upload = match args.get(ENV_ARG_UPLOAD_KEY) {
+        Some(Some(u)) => match Upload::new(u) {
+            Some(u) => Some(u),
+            None => return Err(format!("Invalid upload folder: {}", u).into()),
+        },
+        _ => None,
+    };
+    if let Some(Some(m)) = args.get(ENV_ARG_UPLOAD_MAX_SIZE_KEY) {
+        let max_size = match m.parse::<usize>() {
+            Ok(m) => m * 1024,
+            Err(e) => return Err(format!("Invalid upload max size: {}", e).into()),
+        };
+        match &mut upload {
+            Some(u) => u.max_size = Some(max_size),
+            None => return Err("Upload max size requires an upload folder".into()),
+        }
+    }
+    if let Some(Some(e)) = args.get(ENV_ARG_UPLOAD_EXT_KEY) {
+        match &mut upload {
+            Some(u) => u.extensions = Upload::parse_extensions(e),
+            None => return Err("Upload extensions require an upload folder".into()),
+        }
+    }
+
+    // get markdown rendering with its page template and stylesheet
+    let mut markdown = args.get(ENV_ARG_MARKDOWN_KEY).map(|t| Markdown {
+        template: t.as_ref().map(PathBuf::from),
+        stylesheet: None,
+    });
+    if let Some(Some(c)) = args.get(ENV_ARG_MARKDOWN_CSS_KEY) {
+        match &mut markdown {
+            Some(m) => m.stylesheet = Some(c.to_string()),
+            None => return Err("Markdown stylesheet requires markdown rendering".into()),
+        }
+    }
+
+    // get server side includes flag
+    let ssi = args.contains_key(ENV_ARG_SSI_KEY);
+    let precompressed = args.contains_key(ENV_ARG_PRECOMPRESSED_KEY);
+
+    // get security headers, headers given on their own change the preset and enable it
+    let mut security_headers = args
+        .contains_key(ENV_ARG_SECURITY_HEADERS_KEY)
+        .then(SecurityHeaders::default);
+    for h in args.get_all(ENV_ARG_SECURITY_HEADER_KEY) {
+        let headers = security_headers.get_or_insert_with(SecurityHeaders::default);
+        if headers.set(h).is_none() {
+            return Err(format!("Invalid security header: {}", h).into());
+        }
+    }
+
+    // get cache control rules, in the order given
+    let mut cache_rules = CacheRules::default();
+    for r in args.get_all(ENV_ARG_CACHE_CONTROL_KEY) {
+        match CacheRule::parse(r) {
+            Some(r) => cache_rules.rules.push(r),
+            None => return Err(format!("Invalid cache control rule: {}", r).into()),
+        }
+    }
+
+    // get tenants file
+    let tenants_file = match args.get(ENV_ARG_TENANTS_FILE_KEY) {
+        Some(Some(t)) => Some(PathBuf::from(t)),
+        _ => None,
+    };
+
+    // get access log file
+    let access_log = match args.get(ENV_ARG_ACCESS_LOG_KEY) {
+        Some(Some(a)) => Some(PathBuf::from(a)),
+        _ => None,
+    };
+
+    // get cache statistics report interval, disabled by default
+    let cache_stats_interval = get_duration(
+        &args,
+        ENV_ARG_CACHE_STATS_INTERVAL_KEY,
+        "cache stats interval",
+    )?
+    .unwrap_or(Duration::ZERO);
+
+    // get the threshold of the slow request log, zero disables it
+    let slow_request = get_duration(&args, ENV_ARG_SLOW_REQUEST_KEY, "slow request threshold")?
+        .unwrap_or(Duration::ZERO);
+
+    // get the sampling and status filter of the request log
+    let log_sample = match args.get(ENV_ARG_LOG_SAMPLE_KEY) {
+        Some(Some(n)) => match n.parse::<u64>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("Invalid log sample: {}", n).into()),
+        },
+        _ => 1,
+    };
+    let log_status = match args.get(ENV_ARG_LOG_STATUS_KEY) {
+        Some(Some(s)) => match StatusFilter::parse(s) {
+            Some(f) => Some(f),
+            None => return Err(format!("Invalid log status filter: {}", s).into()),
+        },
+        _ => None,
+    };
+
+    // get preload, walking the res root unless a list file is given
+    let preload = match args.get(ENV_ARG_PRELOAD_KEY) {
+        Some(Some(p)) => Some(Preload::List(PathBuf::from(p))),
+        Some(None) => Some(Preload::Walk),
+        None => None,
+    };
+
+    // get the folder of the saved hot sets of the file caches
+    let cache_persist = match args.get(ENV_ARG_CACHE_PERSIST_KEY) {
+        Some(Some(d)) => Some(PathBuf::from(d)),
+        _ => None,
+    };
+
+    // get dump map flag
+    let dump_map = args.contains_key(ENV_ARG_DUMP_MAP_KEY);
+
+    // get check flag
+    let check = args.contains_key(ENV_ARG_CHECK_KEY);
+
+    // get ready file
+    let ready_file = match args.get(ENV_ARG_READY_FILE_KEY) {
+        Some(Some(r)) => Some(PathBuf::from(r)),
+        _ => None,
     };
 
     Ok(Config {
         file_root,
-        port,
+        res_dir,
+        listen,
+        bind_ip,
+        accept_workers,
+        io_uring,
+        user,
+        group,
+        chroot,
+        max_connections,
+        accept_rate,
+        ip_filter,
+        drop_refused,
+        trusted_proxies,
+        proxy_protocol,
         file_cache_size,
+        max_cacheable_size,
+        cache_admission,
+        cache_ttl,
+        cache_exclude,
+        watch_debounce,
+        watch_poll,
+        watch_poll_budget,
+        watch_ignore,
+        mmap,
         log_level,
+        log_format,
+        log_queue_size,
+        log_overflow,
+        log_color,
+        log_file,
+        debug_endpoints,
+        dir_listing,
+        index_files,
+        dev,
+        idle_timeout,
+        header_timeout,
+        body_timeout,
+        request_timeout,
+        max_header_size,
+        max_header_count,
+        max_body_size,
+        metrics,
+        redirect,
+        admin,
+        upload,
+        markdown,
+        ssi,
+        precompressed,
+        security_headers,
+        cache_rules,
+        tenants_file,
+        access_log,
+        cache_stats_interval,
+        slow_request,
+        log_sample,
+        log_status,
+        preload,
+        cache_persist,
+        ready_file,
+        config_file,
+        dump_map,
+        check,
     })
 }
 
 async fn _main() -> Result<(), Box<dyn std::error::Error>> {
+    // Print the help or the version and exit if requested
+    let args = getopt(OPTIONS)?;
+    if args.contains_key(ENV_ARG_HELP_KEY) {
+        print!("{}", getopt::help(USAGE, OPTIONS));
+        return Ok(());
+    }
+    if args.contains_key(ENV_ARG_VERSION_KEY) {
+        println!("{}", version());
+        return Ok(());
+    }
+
     // Get config
     let config = get_config()?;
 
     // Set log level
-    log::set_log_level(config.log_level)?;
+    log::set_log_filter(config.log_level.clone())?;
+    log::set_log_format(config.log_format)?;
+    log::set_log_color(config.log_color);
+    log::start_logger(
+        config.log_queue_size,
+        config.log_overflow,
+        config.log_file.clone(),
+    )?;
+
+    // Print the canonical request map and exit if requested
+    if config.dump_map {
+        let map_str = read_to_string(config.file_root.join(REQ_MAP_FILE)).await?;
+        print!("{}", RequestMap::parse_str(&map_str)?);
+        return Ok(());
+    }
+
+    // Check the config without binding and exit if requested, every problem is printed
+    if config.check {
+        let mut problems = server_builder(&config, &config.file_root).check().await;
+        if let Err(e) = Ids::resolve(config.user.as_deref(), config.group.as_deref()) {
+            problems.push(e.to_string());
+        }
+        if problems.is_empty() {
+            println!("Config OK");
+            return Ok(());
+        }
+        for p in &problems {
+            eprintln!("{}", p);
+        }
+        return Err(format!("Config check failed with {} problems", problems.len()).into());
+    }
 
     // Log config
     info!(
-        "Config:\nport -> {}\nfile root -> {}\nfile cache size -> {}\nlog level -> {}",
-        config.port,
+        "Config:\nlisten -> {}\nbind address -> {}\naccept workers -> {}\nio_uring -> {}\nuser -> {}\ngroup -> {}\nchroot -> {}\nmax connections -> {}\naccept rate -> {}\nip filter -> {}\nrefused connections -> {}\ntrusted proxies -> {}\nproxy protocol -> {}\nfile root -> {}\nres dir -> {}\nfile cache size -> {}\nmax cacheable file size -> {}\ncache admission -> {}\ncache ttl -> {}\ncache exclude -> {}\nwatch debounce -> {}ms\nwatch poll -> {}\nwatch ignore -> {}\nmmap -> {}\nlog level -> {}\nlog format -> {}\nlog queue -> {} lines ({} when full)\nlog color -> {}\nlog file -> {}\ndebug endpoints -> {}\ndirectory listing -> {}\nindex files -> {}\ndev mode -> {}\nidle timeout -> {}ms\nheader timeout -> {}ms\nbody timeout -> {}ms\nrequest timeout -> {}ms\nmax header size -> {}\nmax header count -> {}\nmax body size -> {}\nmetrics -> {}\nredirect -> {}\nadmin -> {}\nupload -> {}\nmarkdown -> {}\nserver side includes -> {}\nprecompressed files -> {}\nsecurity headers -> {}\ncache control -> {}\ntenants file -> {}\naccess log -> {}\ncache stats interval -> {}\nslow request log -> {}\nrequest log -> {}\npreload -> {}\ncache persist -> {}",
+        config
+            .listen
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        config.bind_ip,
+        config.accept_workers,
+        config.io_uring,
+        config.user.as_deref().unwrap_or("unchanged"),
+        config.group.as_deref().unwrap_or("unchanged"),
+        config.chroot,
+        match config.max_connections {
+            0 => "unlimited".to_string(),
+            m => m.to_string(),
+        },
+        match config.accept_rate {
+            0 => "unlimited".to_string(),
+            r => format!("{}/s", r),
+        },
+        config.ip_filter,
+        match config.drop_refused {
+            true => "dropped",
+            false => "answered 403",
+        },
+        match config.trusted_proxies.is_empty() {
+            true => "none".to_string(),
+            false => IpFilter::fmt_list(&config.trusted_proxies),
+        },
+        config.proxy_protocol,
         config.file_root.display(),
+        match &config.res_dir {
+            Some(r) => r.display().to_string(),
+            None if cfg!(feature = "embed") => "embedded".to_string(),
+            None => RES_ROOT_FOLDER.to_string(),
+        },
         fmt_size(config.file_cache_size),
-        config.log_level
+        fmt_size(config.max_cacheable_size),
+        config.cache_admission,
+        match config.cache_ttl.is_empty() {
+            true => "none".to_string(),
+            false => config.cache_ttl.to_string(),
+        },
+        match config.cache_exclude.is_empty() {
+            true => "none".to_string(),
+            false => config.cache_exclude.join(", "),
+        },
+        config.watch_debounce.as_millis(),
+        match config.watch_poll {
+            Some(p) => format!("every {}ms, {} files", p.as_millis(), config.watch_poll_budget),
+            None => format!("auto, {} files", config.watch_poll_budget),
+        },
+        match config.watch_ignore.is_empty() {
+            true => "none".to_string(),
+            false => config.watch_ignore.join(", "),
+        },
+        match config.mmap {
+            Some(m) => format!("files of {} and more", fmt_size(m)),
+            None => "off".to_string(),
+        },
+        config.log_level,
+        config.log_format,
+        config.log_queue_size,
+        config.log_overflow,
+        match config.log_color {
+            true => "on terminals",
+            false => "off",
+        },
+        match &config.log_file {
+            Some(f) if f.max_size > 0 => format!(
+                "{} (rotated at {}, keeping {})",
+                f.path.display(),
+                fmt_size(f.max_size as usize),
+                f.keep
+            ),
+            Some(f) => f.path.display().to_string(),
+            None => "none".to_string(),
+        },
+        config.debug_endpoints,
+        config.dir_listing,
+        config.index_files.join(", "),
+        match config.dev {
+            true => "live reload",
+            false => "disabled",
+        },
+        config.idle_timeout.as_millis(),
+        config.header_timeout.as_millis(),
+        config.body_timeout.as_millis(),
+        config.request_timeout.as_millis(),
+        fmt_size(config.max_header_size),
+        config.max_header_count,
+        fmt_size(config.max_body_size),
+        match &config.metrics {
+            Some(m) => m.to_string(),
+            None => "disabled".to_string(),
+        },
+        match &config.redirect {
+            Some(r) => match &r.acme_webroot {
+                Some(w) => format!("{} (ACME webroot {})", r, w.display()),
+                None => r.to_string(),
+            },
+            None => "disabled".to_string(),
+        },
+        match &config.admin {
+            Some(a) => a.to_string(),
+            None => "disabled".to_string(),
+        },
+        match &config.upload {
+            Some(u) => format!(
+                "{} (max {}, {})",
+                u,
+                fmt_size(u.max_size.unwrap_or(config.max_body_size)),
+                match u.extensions.is_empty() {
+                    true => "any extension".to_string(),
+                    false => u.extensions.join(", "),
+                }
+            ),
+            None => "disabled".to_string(),
+        },
+        match &config.markdown {
+            Some(m) => m.to_string(),
+            None => "disabled".to_string(),
+        },
+        match config.ssi {
+            true => "*.shtml",
+            false => "disabled",
+        },
+        match config.precompressed {
+            true => "*.br, *.gz",
+            false => "disabled",
+        },
+        match &config.security_headers {
+            Some(h) => h.to_string(),
+            None => "disabled".to_string(),
+        },
+        match config.cache_rules.rules.is_empty() {
+            true => "none".to_string(),
+            false => config
+                .cache_rules
+                .rules
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        },
+        match &config.tenants_file {
+            Some(f) => f.display().to_string(),
+            None => "none".to_string(),
+        },
+        match &config.access_log {
+            Some(f) => f.display().to_string(),
+            None => "none".to_string(),
+        },
+        match config.cache_stats_interval.is_zero() {
+            true => "disabled".to_string(),
+            false => format!("{}ms", config.cache_stats_interval.as_millis()),
+        },
+        match config.slow_request.is_zero() {
+            true => "disabled".to_string(),
+            false => format!("over {}ms", config.slow_request.as_millis()),
+        },
+        match &config.log_status {
+            Some(s) => format!("1 in {} successful, status {}", config.log_sample, s),
+            None => format!("1 in {} successful", config.log_sample),
+        },
+        match &config.preload {
+            Some(p) => p.to_string(),
+            None => "disabled".to_string(),
+        },
+        match &config.cache_persist {
+            Some(d) => d.display().to_string(),
+            None => "disabled".to_string(),
+        }
     );
 
-    // Construct file cache
-    let file_cache = FileCache::new(Some(config.file_cache_size));
+    // Resolve the ids while the user database is reachable, they are switched to once the sockets are bound
+    let ids = Ids::resolve(config.user.as_deref(), config.group.as_deref())?;
+    let file_root = match config.chroot {
+        true => {
+            // Keep reaching systemd, whose socket is outside of the new root
+            sdnotify::connect()?;
+            privdrop::chroot(&config.file_root)?;
+            if config.user.is_none() {
+                warn!(
+                    "{}",
+                    "Running as root inside the chroot, which does not confine root"
+                );
+            }
+            PathBuf::from("/")
+        }
+        false => config.file_root.clone(),
+    };
 
-    // Derive res root folder
-    let res_root = config.file_root.join(RES_ROOT_FOLDER).canonicalize()?;
+    let server = server_builder(&config, &file_root).bind().await?;
+    if let Some(ids) = ids {
+        ids.switch()?;
+    }
+    let stop = stop_signal()?;
 
-    // Construct request map if exists
-    let request_map = match read_to_string(REQ_MAP_FILE).await {
-        Ok(map_file) => {
-            let map = RequestMap::parse_str(&map_file)?;
-            info!("Map loaded\n{}", &map);
-            Some(map)
-        }
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::NotFound => {
-                info!("No map file found. Starting without request map...");
-                None
+    // Signal readiness with a structured startup record
+    let startup_record = format!(
+        "{{\"event\":\"ready\",\"pid\":{},\"addresses\":[{}],\"config\":{}}}",
+        std::process::id(),
+        server
+            .local_addrs()?
+            .iter()
+            .map(|a| json_escape(&a.to_string()))
+            .collect::<Vec<_>>()
+            .join(","),
+        config.to_json()
+    );
+    info!("{}", &startup_record);
+    if let Some(ready_file) = &config.ready_file {
+        write(ready_file, format!("{}\n", startup_record)).await?;
+    }
+    sdnotify::notify(&format!("READY=1\nMAINPID={}", std::process::id()))?;
+
+    // Apply the changed options on SIGHUP, the server reloads its maps and res folders on its own
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sighup = signal(SignalKind::hangup())?;
+        let state = server.state().clone();
+        tokio::spawn(async move {
+            let mut config = config;
+            while sighup.recv().await.is_some() {
+                reload_config(&mut config, &state).await;
             }
-            _ => return Err(e.into()),
-        },
+        });
+    }
+
+    // Stop accepting once asked to, systemd keeps queueing connections on inherited sockets until restarted
+    let state = server.state().clone();
+    select! {
+        res = server.serve() => res.map_err(|e| e as _),
+        res = stop => {
+            res?;
+            info!("{}", "Stop requested, shutting down");
+            sdnotify::notify("STOPPING=1")?;
+            state.save_hot_sets().await;
+            Ok(())
+        }
+    }
+}
+
+// Resolves once the server is asked to stop by SIGTERM or Ctrl+C, the handlers are registered right away
+fn stop_signal() -> std::io::Result<impl std::future::Future<Output = std::io::Result<()>>> {
+    #[cfg(unix)]
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    Ok(async move {
+        #[cfg(unix)]
+        select! {
+            _ = sigterm.recv() => Ok(()),
+            res = tokio::signal::ctrl_c() => res,
+        }
+        #[cfg(not(unix))]
+        tokio::signal::ctrl_c().await
+    })
+}
+
+// Read the options again and apply those which do not need a restart, changes are logged
+async fn reload_config(config: &mut Config, state: &ServerState) {
+    let new_config = match get_config() {
+        Ok(c) => c,
+        Err(e) => {
+            error!(
+                "Failed to reload config, keeping the current options: {}",
+                e
+            );
+            return;
+        }
     };
+    let old_fields = config.fields();
+    let new_fields = new_config.fields();
+    let changes = old_fields
+        .iter()
+        .zip(&new_fields)
+        .filter(|((_, old), (_, new))| old != new)
+        .map(|((key, old), (_, new))| (*key, old, new))
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        info!("Config reloaded, no options changed");
+        return;
+    }
 
-    // Construct socket
-    let sockaddr = format!("0.0.0.0:{}", config.port);
-    let listener = TcpListener::bind(&sockaddr).await?;
-    info!("socket binded @{}", &sockaddr);
+    let mut applied = String::new();
+    for (key, old, new) in changes {
+        if !RELOADABLE_OPTIONS.contains(&key) {
+            warn!(
+                "{} changed from {} to {}, requires a restart",
+                key, old, new
+            );
+            continue;
+        }
+        applied.push_str(&format!("\n{}: {} -> {}", key, old, new));
+    }
+    if applied.is_empty() {
+        return;
+    }
 
-    // Construct context for main loop
-    let ctx = Arc::new((file_cache, request_map, res_root));
+    // Apply the reloadable options
+    log::update_log_filter(new_config.log_level.clone());
+    config.log_level = new_config.log_level;
+    config.file_cache_size = new_config.file_cache_size;
+    config.max_cacheable_size = new_config.max_cacheable_size;
+    config.security_headers = new_config.security_headers;
+    config.cache_rules = new_config.cache_rules;
+    state.set_security_headers(config.security_headers.clone());
+    state.set_cache_rules(config.cache_rules.clone());
+    state
+        .default_site
+        .file_cache
+        .resize(
+            Some(config.file_cache_size),
+            Some(config.max_cacheable_size),
+        )
+        .await;
+    resize_tenant_caches(config, state).await;
+    info!("Config reloaded{}", applied);
+}
 
-    // Watcher event
-    let watcher_handle = setup_fs_watcher(ctx.clone())?;
-    tokio::pin!(watcher_handle); // pin handle in order for main loop to poll it
+// Resize the file caches of the tenants by the tenants file read again, tenants without a cache directive follow -c
+// Tenants added to or removed from the file take effect on restart
+async fn resize_tenant_caches(config: &Config, state: &ServerState) {
+    let Some(file) = &config.tenants_file else {
+        return;
+    };
+    let tenants = match read_to_string(file)
+        .await
+        .map(|s| parse_tenants(&s, config.file_cache_size).map_err(|e| e.to_string()))
+    {
+        Ok(Ok(t)) => t,
+        Ok(Err(e)) => {
+            error!(
+                "Failed to parse {}, tenant caches are kept: {}",
+                file.display(),
+                e
+            );
+            return;
+        }
+        Err(e) => {
+            error!(
+                "Failed to read {}, tenant caches are kept: {}",
+                file.display(),
+                e
+            );
+            return;
+        }
+    };
+    for tenant in tenants {
+        if let Some(site) = state.tenants.get(&tenant.host) {
+            site.file_cache
+                .resize(Some(tenant.cache_size), Some(config.max_cacheable_size))
+                .await;
+        }
+    }
+}
 
-    // Main loop
-    loop {
-        // Select between watcher error and listener connection
-        let conn = select! {
-            res = &mut watcher_handle => Err(res?.unwrap_err()),
-            conn = listener.accept() => Ok(conn),
-        }?;
+// Configure the server from the command line
+fn server_builder(config: &Config, file_root: &Path) -> ServerBuilder {
+    let mut builder = Server::builder()
+        .root(file_root)
+        .bind_ip(config.bind_ip)
+        .accept_workers(config.accept_workers)
+        .io_uring(config.io_uring)
+        .socket_activation(true)
+        .max_connections(config.max_connections)
+        .accept_rate(config.accept_rate)
+        .ip_filter(config.ip_filter.clone())
+        .drop_refused(config.drop_refused)
+        .trusted_proxies(config.trusted_proxies.clone())
+        .proxy_protocol(config.proxy_protocol)
+        .file_cache_size(config.file_cache_size)
+        .max_cacheable_size(config.max_cacheable_size)
+        .cache_admission(config.cache_admission)
+        .cache_ttl(config.cache_ttl.clone())
+        .cache_exclude(config.cache_exclude.clone())
+        .watch_debounce(config.watch_debounce)
+        .watch_poll_budget(config.watch_poll_budget)
+        .watch_ignore(config.watch_ignore.clone())
+        .mmap(config.mmap)
+        .debug_endpoints(config.debug_endpoints)
+        .dir_listing(config.dir_listing)
+        .index_files(config.index_files.clone())
+        .live_reload(config.dev)
+        .ssi(config.ssi)
+        .precompressed(config.precompressed)
+        .cache_rules(config.cache_rules.clone())
+        .idle_timeout(config.idle_timeout)
+        .header_timeout(config.header_timeout)
+        .body_timeout(config.body_timeout)
+        .request_timeout(config.request_timeout)
+        .max_header_size(config.max_header_size)
+        .max_header_count(config.max_header_count)
+        .max_body_size(config.max_body_size)
+        .cache_stats_interval(config.cache_stats_interval)
+        .slow_request(config.slow_request)
+        .request_log_sample(config.log_sample);
+    for addr in &config.listen {
+        builder = builder.listen(*addr);
+    }
+    if let Some(h) = &config.security_headers {
+        builder = builder.security_headers(h.clone());
+    }
+    if let Some(r) = &config.res_dir {
+        builder = builder.res_dir(r);
+    }
+    if let Some(p) = config.watch_poll {
+        builder = builder.watch_poll(p);
+    }
+    if let Some(m) = &config.metrics {
+        builder = builder.metrics(m.clone());
+    }
+    if let Some(r) = &config.redirect {
+        builder = builder.redirect(r.clone());
+    }
+    if let Some(a) = config.admin {
+        builder = builder.admin(a);
+    }
+    if let Some(u) = &config.upload {
+        builder = builder.upload(u.clone());
+    }
+    if let Some(m) = &config.markdown {
+        builder = builder.markdown(m.clone());
+    }
+    if let Some(t) = &config.tenants_file {
+        builder = builder.tenants_file(t);
+    }
+    if let Some(a) = &config.access_log {
+        builder = builder.access_log(a);
+    }
+    if let Some(p) = &config.preload {
+        builder = builder.preload(p.clone());
+    }
+    if let Some(d) = &config.cache_persist {
+        builder = builder.cache_persist(d);
+    }
+    if let Some(s) = &config.log_status {
+        builder = builder.request_log_status(s.clone());
+    }
+    builder
+}
 
-        // Accept connection
-        let (stream, addr) = match conn {
-            Err(e) => {
-                error!("Client connection error: {}", e);
-                continue;
-            }
-            Ok(s) => s,
-        };
-        debug!("connection from: {}", &addr);
-        let ctx: Arc<(FileCache, Option<RequestMap>, PathBuf)> = ctx.clone();
-        task::spawn(async move {
-            let (f_cache, req_map, res_root) = &*ctx;
-            let req_map = req_map.as_ref();
-            if let Err(e) = handle_connection(&addr, stream, res_root, f_cache, req_map).await {
-                error!("Error: {}, {}", &addr, e);
-            }
-            debug!("connection closed for {}", &addr);
-        });
+// Name and version of the program with the optional features it was built with
+fn version() -> String {
+    let features = [
+        ("embed", cfg!(feature = "embed")),
+        ("normalize", cfg!(feature = "normalize")),
+        ("brotli", cfg!(feature = "brotli")),
+        ("uring", cfg!(feature = "uring")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(name, _)| *name)
+    .collect::<Vec<_>>();
+    match features.is_empty() {
+        true => format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        false => format!(
+            "{} {} ({})",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            features.join(", ")
+        ),
     }
 }
 
@@ -166,5 +2024,8 @@ async fn _main() -> Result<(), Box<dyn std::error::Error>> {
 async fn main() {
     if let Err(e) = _main().await {
         error!("{}", e);
+        log::flush();
+        std::process::exit(1);
     }
+    log::flush();
 }
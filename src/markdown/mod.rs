@@ -0,0 +1,139 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use pulldown_cmark::{html, Options, Parser};
+use tokio::io::{self, AsyncReadExt};
+
+use crate::{
+    filecache::AbstractFile,
+    http::{HttpResponse, ResponseBody},
+    log_ctx,
+    state::Site,
+    trace, warn,
+};
+
+// Placeholders of the page template, the title is the text of the first level one heading
+// Sample: <html><head><title>{{title}}</title></head><body>{{content}}</body></html>
+const MARKDOWN_TITLE: &str = "{{title}}";
+const MARKDOWN_CONTENT: &str = "{{content}}";
+const MARKDOWN_STYLESHEET: &str = "{{stylesheet}}";
+const MARKDOWN_EXT: &str = "md";
+const MARKDOWN_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{{title}}</title>\n{{stylesheet}}</head>\n<body>\n{{content}}</body>\n</html>\n";
+
+// Name of the rendered HTML among the variants of the cached file
+const MARKDOWN_VARIANT: &str = "markdown";
+
+log_ctx!("Markdown");
+
+#[derive(Debug, Clone, Default)]
+pub struct Markdown {
+    pub template: Option<PathBuf>, // Relative to the res folder, a plain page if not given
+    pub stylesheet: Option<String>, // Linked from the page, e.g. /css/markdown.css
+}
+
+impl Markdown {
+    pub fn is_markdown(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case(MARKDOWN_EXT))
+    }
+}
+
+impl Display for Markdown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.template {
+            Some(t) => write!(f, "*.{} with {}", MARKDOWN_EXT, t.display())?,
+            None => write!(f, "*.{}", MARKDOWN_EXT)?,
+        }
+        if let Some(s) = &self.stylesheet {
+            write!(f, " ({})", s)?;
+        }
+        Ok(())
+    }
+}
+
+fn to_html(src: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let mut res = String::with_capacity(src.len() * 3 / 2);
+    html::push_html(&mut res, Parser::new_ext(src, options));
+    res
+}
+
+// Text of the first <h1> of the rendered HTML without its inner tags
+fn title(html: &str) -> Option<String> {
+    let start = html.find("<h1")?;
+    let start = start + html[start..].find('>')? + 1;
+    let end = start + html[start..].find("</h1>")?;
+    let mut title = String::with_capacity(end - start);
+    let mut in_tag = false;
+    for c in html[start..end].chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => title.push(c),
+            _ => {}
+        }
+    }
+    Some(title)
+}
+
+// Render the file into a page, the rendered content is kept with the cached file until it changes
+pub async fn render(
+    mut file: AbstractFile,
+    config: Option<&Markdown>,
+    site: &Site,
+) -> io::Result<HttpResponse> {
+    let variants = file.variants();
+    let content = match variants.as_ref().and_then(|v| v.get(MARKDOWN_VARIANT)) {
+        Some(c) => {
+            trace!("Using cached rendering");
+            String::from_utf8_lossy(&c).into_owned()
+        }
+        None => {
+            let mut src = Vec::with_capacity(file.len());
+            file.read_to_end(&mut src).await?;
+            let content = to_html(&String::from_utf8_lossy(&src));
+            trace!("Rendered {} bytes to {} bytes", src.len(), content.len());
+            if let Some(v) = &variants {
+                v.insert(MARKDOWN_VARIANT, &Arc::from(content.as_bytes()));
+            }
+            content
+        }
+    };
+
+    // The template is read on every request, so that changes to it apply right away
+    let template = match config.and_then(|c| c.template.as_ref()) {
+        Some(t) => match site.open(t).await {
+            Ok(mut f) => {
+                let mut buf = Vec::with_capacity(f.len());
+                f.read_to_end(&mut buf).await?;
+                String::from_utf8_lossy(&buf).into_owned()
+            }
+            Err(e) => {
+                warn!("Failed to open template {}: {}", t.display(), e);
+                DEFAULT_TEMPLATE.to_string()
+            }
+        },
+        None => DEFAULT_TEMPLATE.to_string(),
+    };
+    let stylesheet = match config.and_then(|c| c.stylesheet.as_ref()) {
+        Some(s) => format!(
+            "<link rel=\"stylesheet\" href=\"{}\">\n",
+            s.replace('"', "&quot;")
+        ),
+        None => String::new(),
+    };
+    let page = template
+        .replace(MARKDOWN_TITLE, &title(&content).unwrap_or_default())
+        .replace(MARKDOWN_STYLESHEET, &stylesheet)
+        .replace(MARKDOWN_CONTENT, &content);
+    Ok(HttpResponse::new(200)
+        .with_header("Content-Type", MARKDOWN_CONTENT_TYPE)
+        .with_body(ResponseBody::Bytes(page.into_bytes())))
+}
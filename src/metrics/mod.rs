@@ -0,0 +1,333 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{self, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    task,
+};
+
+use crate::{
+    debug, error,
+    filecache::CacheStats,
+    http::{read_headers_buff, read_timeout, HttpResponse},
+    info, log_ctx,
+    state::ServerState,
+};
+
+// Prometheus text exposition format
+// Sample: http_requests_total{status="200"} 42
+pub const METRICS_DEFAULT_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const METRICS_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 4;
+
+// Upper bounds of the latency histogram buckets in microseconds
+const LATENCY_BUCKETS_US: [u64; 10] = [
+    500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000, 10_000_000,
+];
+
+// Name, help, type and value of the file cache metrics
+type CacheMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&CacheStats) -> f64,
+);
+const CACHE_METRICS: [CacheMetric; 7] = [
+    (
+        "file_cache_hits_total",
+        "Number of files served from the cache.",
+        "counter",
+        |s| s.hits as f64,
+    ),
+    (
+        "file_cache_misses_total",
+        "Number of files not found in the cache.",
+        "counter",
+        |s| s.misses as f64,
+    ),
+    (
+        "file_cache_hit_ratio",
+        "Ratio of cache hits to lookups.",
+        "gauge",
+        |s| s.hit_ratio(),
+    ),
+    (
+        "file_cache_evictions_total",
+        "Number of entries evicted to make room for new ones.",
+        "counter",
+        |s| s.evictions as f64,
+    ),
+    (
+        "file_cache_rejections_total",
+        "Number of files not admitted as they are requested less often than the entries they would evict.",
+        "counter",
+        |s| s.rejections as f64,
+    ),
+    (
+        "file_cache_size_bytes",
+        "Total size of the cached files.",
+        "gauge",
+        |s| s.size as f64,
+    ),
+    (
+        "file_cache_entries",
+        "Number of cached files.",
+        "gauge",
+        |s| s.entries as f64,
+    ),
+];
+
+log_ctx!("Metrics");
+
+// Where the metrics are served, on a path of the public listener or on a separate port
+#[derive(Debug, Clone)]
+pub enum MetricsEndpoint {
+    Path(String),
+    Port(u16),
+}
+
+impl MetricsEndpoint {
+    // Values starting with a slash are paths, otherwise ports
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.starts_with('/') {
+            true => Some(Self::Path(s.to_string())),
+            false => s.parse::<u16>().ok().map(Self::Port),
+        }
+    }
+}
+
+impl std::fmt::Display for MetricsEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricsEndpoint::Path(p) => write!(f, "{}", p),
+            MetricsEndpoint::Port(p) => write!(f, "port {}", p),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<u16, u64>>,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_US.len()],
+    latency_sum_us: AtomicU64,
+    latency_count: AtomicU64,
+    open_connections: AtomicI64,
+    connection_limit_reached: AtomicU64, // Accepts which waited for a free connection slot
+    refused_connections: AtomicU64,      // Connections from addresses refused by the IP filter
+    connections: Mutex<HashMap<u64, OpenConnection>>, // Open client connections by id
+    next_connection_id: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OpenConnection {
+    pub id: u64,
+    pub remote_addr: SocketAddr,
+    pub opened: SystemTime,
+}
+
+// Counts the connection as open until dropped
+pub struct ConnectionGuard<'a>(&'a Metrics, u64);
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+        self.0.connections.lock().unwrap().remove(&self.1);
+    }
+}
+
+impl Metrics {
+    pub fn open_connection(&self, remote_addr: &SocketAddr) -> ConnectionGuard<'_> {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let conn = OpenConnection {
+            id,
+            remote_addr: *remote_addr,
+            opened: SystemTime::now(),
+        };
+        self.connections.lock().unwrap().insert(id, conn);
+        ConnectionGuard(self, id)
+    }
+
+    pub fn record_connection_limit(&self) {
+        self.connection_limit_reached
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_refused_connection(&self) {
+        self.refused_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Open connections from the oldest
+    pub fn connections(&self) -> Vec<OpenConnection> {
+        let mut conns = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        conns.sort_by_key(|c| c.id);
+        conns
+    }
+
+    pub fn record_request(&self, status: u16, latency: Duration) {
+        *self.requests.lock().unwrap().entry(status).or_default() += 1;
+
+        // Latencies above the last bucket are only counted in +Inf
+        let us = latency.as_micros() as u64;
+        if let Some(i) = LATENCY_BUCKETS_US.iter().position(|b| us <= *b) {
+            self.latency_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_sum_us.fetch_add(us, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub async fn render(state: &ServerState) -> String {
+    let metrics = &state.metrics;
+    let mut res = String::with_capacity(METRICS_BUFF_INIT_SIZE);
+
+    // Requests by status
+    res.push_str("# HELP http_requests_total Number of served requests by status code.\n");
+    res.push_str("# TYPE http_requests_total counter\n");
+    for (status, count) in metrics.requests.lock().unwrap().iter() {
+        let _ = writeln!(
+            res,
+            "http_requests_total{{status=\"{}\"}} {}",
+            status, count
+        );
+    }
+
+    // Latency histogram with cumulative buckets
+    res.push_str("# HELP http_request_duration_seconds Time to serve a request.\n");
+    res.push_str("# TYPE http_request_duration_seconds histogram\n");
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS_US.iter().zip(&metrics.latency_buckets) {
+        cumulative += count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            res,
+            "http_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            *bound as f64 / 1e6,
+            cumulative
+        );
+    }
+    let count = metrics.latency_count.load(Ordering::Relaxed);
+    let sum = metrics.latency_sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(
+        res,
+        "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        count
+    );
+    let _ = writeln!(res, "http_request_duration_seconds_sum {}", sum);
+    let _ = writeln!(res, "http_request_duration_seconds_count {}", count);
+
+    // Open connections
+    res.push_str("# HELP http_open_connections Number of open client connections.\n");
+    res.push_str("# TYPE http_open_connections gauge\n");
+    let _ = writeln!(
+        res,
+        "http_open_connections {}",
+        metrics.open_connections.load(Ordering::Relaxed)
+    );
+    if let Some(max) = state.connection_limit.max() {
+        res.push_str("# HELP http_max_connections Limit of open client connections.\n");
+        res.push_str("# TYPE http_max_connections gauge\n");
+        let _ = writeln!(res, "http_max_connections {}", max);
+    }
+    res.push_str("# HELP http_connection_limit_reached_total Number of accepts which waited for a connection to close.\n");
+    res.push_str("# TYPE http_connection_limit_reached_total counter\n");
+    let _ = writeln!(
+        res,
+        "http_connection_limit_reached_total {}",
+        metrics.connection_limit_reached.load(Ordering::Relaxed)
+    );
+    res.push_str(
+        "# HELP http_refused_connections_total Number of connections refused by the IP filter.\n",
+    );
+    res.push_str("# TYPE http_refused_connections_total counter\n");
+    let _ = writeln!(
+        res,
+        "http_refused_connections_total {}",
+        metrics.refused_connections.load(Ordering::Relaxed)
+    );
+
+    // File cache effectiveness and occupancy per site
+    let mut caches = Vec::new();
+    for site in state.sites() {
+        let stats = site.file_cache.stats().await;
+        caches.push((escape_label(site.display_name()), stats));
+    }
+    for (name, help, kind, value) in CACHE_METRICS {
+        let _ = writeln!(res, "# HELP {} {}", name, help);
+        let _ = writeln!(res, "# TYPE {} {}", name, kind);
+        for (site, stats) in &caches {
+            let _ = writeln!(res, "{}{{site=\"{}\"}} {}", name, site, value(stats));
+        }
+    }
+    res
+}
+
+pub async fn response(state: &ServerState) -> HttpResponse {
+    HttpResponse::new(200).with_text(METRICS_CONTENT_TYPE, render(state).await)
+}
+
+async fn handle_connection(mut stream: TcpStream, state: &ServerState) -> io::Result<()> {
+    let (r_stream, mut w_stream) = stream.split();
+    let mut r_stream = BufReader::new(r_stream);
+
+    // Only the default path is served on the separate port
+    let header_buff = read_timeout(
+        state.header_timeout,
+        None,
+        read_headers_buff(&mut r_stream, state.max_header_size),
+    )
+    .await?;
+    let header = String::from_utf8_lossy(&header_buff);
+    let path = header.split(' ').nth(1).unwrap_or_default();
+    let mut res = match path.split('?').next() == Some(METRICS_DEFAULT_PATH) {
+        true => response(state).await,
+        false => HttpResponse::not_found(),
+    };
+    res.write_to(&mut w_stream, false).await?;
+    stream.shutdown().await
+}
+
+// Serve the metrics on a separate listener, apart from the public accept loop
+pub async fn listen(addr: SocketAddr, state: std::sync::Arc<ServerState>) -> io::Result<()> {
+    let listener = crate::server::bind(addr, false)?;
+    info!("metrics socket binded @{}", listener.local_addr()?);
+    task::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Metrics connection error: {}", e);
+                    continue;
+                }
+            };
+            debug!("metrics connection from: {}", &addr);
+            let state = state.clone();
+            task::spawn(async move {
+                if let Err(e) = handle_connection(stream, &state).await {
+                    error!("Error: {}, {}", &addr, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
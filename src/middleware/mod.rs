@@ -0,0 +1,68 @@
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use tokio::io;
+
+use crate::{
+    http::{self, HttpRequest, HttpResponse},
+    state::{ServerState, Site},
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// Intercepts requests before the built-in endpoints and the static files
+// A middleware either answers by itself or passes the request, possibly replaced, on to the next one
+// Sample:
+// struct Ping;
+// impl Middleware for Ping {
+//     fn handle<'a>(&'a self, req: Request<'a>, next: Next<'a>) -> BoxFuture<'a, io::Result<HttpResponse>> {
+//         Box::pin(async move {
+//             match req.http.path == "/ping" {
+//                 true => Ok(HttpResponse::new(200).with_text("text/plain", "pong")),
+//                 false => next.run(req).await,
+//             }
+//         })
+//     }
+// }
+pub trait Middleware: Send + Sync {
+    fn handle<'a>(
+        &'a self,
+        req: Request<'a>,
+        next: Next<'a>,
+    ) -> BoxFuture<'a, io::Result<HttpResponse>>;
+}
+
+// A parsed request with the site it is served from
+#[derive(Clone, Copy)]
+pub struct Request<'a> {
+    pub sockaddr: &'a SocketAddr,
+    pub http: &'a HttpRequest<'a>,
+    pub body: Option<&'a [u8]>,
+    pub site: &'a Site,
+}
+
+// The rest of the chain, which ends with the built-in handling
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Middleware>],
+    state: &'a ServerState,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(chain: &'a [Arc<dyn Middleware>], state: &'a ServerState) -> Self {
+        Self { chain, state }
+    }
+
+    pub fn run<'b>(self, req: Request<'b>) -> BoxFuture<'b, io::Result<HttpResponse>>
+    where
+        'a: 'b,
+    {
+        match self.chain.split_first() {
+            Some((m, rest)) => m.handle(req, Next::new(rest, self.state)),
+            None => {
+                let state = self.state;
+                Box::pin(async move {
+                    http::dispatch(req.sockaddr, req.http, req.body, req.site, state).await
+                })
+            }
+        }
+    }
+}
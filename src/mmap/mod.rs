@@ -0,0 +1,63 @@
+use std::{
+    io,
+    os::fd::AsRawFd,
+    ptr::{self, NonNull},
+};
+
+// Read only mapping of the start of a file, unmapped when dropped
+// The pages are those of the kernel page cache, so every mapping of the file shares them
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Safety: the mapping is read only and owned by the value alone
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    // Map the first len bytes of the file, the descriptor may be closed afterwards
+    // Truncating the file on disk while it is mapped makes reading past its new end fault
+    pub fn map(file: &impl AsRawFd, len: usize) -> io::Result<Self> {
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Mapping of an empty file",
+            ));
+        }
+        // Safety: a fresh read only mapping of an open descriptor, checked for failure below
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Responses go through the file in order, so pages are read ahead; failing to advise is harmless
+        // Safety: the range is the mapping just created
+        unsafe { libc::madvise(addr, len, libc::MADV_SEQUENTIAL) };
+        let ptr = NonNull::new(addr as *mut u8).ok_or_else(io::Error::last_os_error)?;
+        Ok(Self { ptr, len })
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        // Safety: the mapping is len bytes long and lives as long as self
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // Safety: the mapping was created by map and is no longer borrowed
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+    }
+}
@@ -0,0 +1,286 @@
+use std::{error::Error, fmt::Display};
+
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+// Sample of multipart body with the boundary given in the Content-Type header:
+// Content-Type: multipart/form-data; boundary=XyZ
+//
+// --XyZ
+// Content-Disposition: form-data; name="file"; filename="a.txt"
+// Content-Type: text/plain
+//
+// hello
+// --XyZ--
+const MULTIPART_FORM_DATA: &str = "multipart/form-data";
+const MULTIPART_BOUNDARY_PARAM: &str = "boundary";
+const MULTIPART_MAX_BOUNDARY_LEN: usize = 70;
+const MULTIPART_MAX_HEADER_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+const MULTIPART_READ_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+
+#[derive(Debug)]
+pub enum MultipartError {
+    Io(io::Error),
+    Malformed(&'static str),
+}
+
+impl Display for MultipartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MultipartError::Io(e) => write!(f, "Failed to read multipart body: {}", e),
+            MultipartError::Malformed(m) => write!(f, "Malformed multipart body: {}", m),
+        }
+    }
+}
+
+impl Error for MultipartError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MultipartError::Io(e) => Some(e),
+            MultipartError::Malformed(_) => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+// Headers of a part, its body is read from the parser until the next part is requested
+#[derive(Debug, Clone, Default)]
+pub struct Part {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble, // Before the first delimiter
+    Body,     // In the body of a part
+    Headers,  // After a delimiter, before the headers of the next part
+    Done,     // After the closing delimiter
+}
+
+// Parser of a multipart body, only the bytes around the current position are kept in memory
+pub struct Multipart<R> {
+    reader: R,
+    delimiter: Vec<u8>, // CRLF, two dashes and the boundary
+    buff: Vec<u8>,
+    state: State,
+    eof: bool,
+}
+
+// Boundary of a multipart/form-data content type, None for other types
+pub fn form_data_boundary(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case(MULTIPART_FORM_DATA) {
+        return None;
+    }
+    let boundary = params.split(';').find_map(|p| {
+        let (k, v) = p.split_once('=')?;
+        k.trim()
+            .eq_ignore_ascii_case(MULTIPART_BOUNDARY_PARAM)
+            .then(|| v.trim().trim_matches('"'))
+    })?;
+    match (1..=MULTIPART_MAX_BOUNDARY_LEN).contains(&boundary.len()) {
+        true => Some(boundary),
+        false => None,
+    }
+}
+
+impl<R: AsyncRead + Unpin> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+        // The first delimiter has no leading line break, so one is put in front of the body
+        Self {
+            reader,
+            delimiter,
+            buff: b"\r\n".to_vec(),
+            state: State::Preamble,
+            eof: false,
+        }
+    }
+
+    // Read more of the body into the buffer, false at the end of the body
+    async fn fill(&mut self) -> Result<bool, MultipartError> {
+        if self.eof {
+            return Ok(false);
+        }
+        self.buff.reserve(MULTIPART_READ_SIZE);
+        let n = (&mut self.reader)
+            .take(MULTIPART_READ_SIZE as u64)
+            .read_buf(&mut self.buff)
+            .await?;
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    // Skip to the next part, the rest of the current one is discarded, None after the last part
+    pub async fn next_part(&mut self) -> Result<Option<Part>, MultipartError> {
+        let mut discard = [0; MULTIPART_READ_SIZE];
+        while matches!(self.state, State::Preamble | State::Body) {
+            self.read_until_delimiter(&mut discard).await?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        // The delimiter is followed by two dashes after the last part, or a line break before the headers
+        while self.buff.len() < 2 {
+            if !self.fill().await? {
+                return Err(MultipartError::Malformed("Unexpected end after delimiter"));
+            }
+        }
+        if self.buff.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let header = loop {
+            if let Some(i) = find(&self.buff, b"\r\n\r\n") {
+                let header = String::from_utf8_lossy(&self.buff[..i]).into_owned();
+                self.buff.drain(..i + 4);
+                break header;
+            }
+            if self.buff.len() > MULTIPART_MAX_HEADER_SIZE {
+                return Err(MultipartError::Malformed("Part headers too large"));
+            }
+            if !self.fill().await? {
+                return Err(MultipartError::Malformed("Unexpected end in part headers"));
+            }
+        };
+        self.state = State::Body;
+        Ok(Some(parse_part(&header)))
+    }
+
+    // Read the body of the current part, 0 at its end
+    pub async fn read(&mut self, out: &mut [u8]) -> Result<usize, MultipartError> {
+        match self.state {
+            State::Body => self.read_until_delimiter(out).await,
+            _ => Ok(0),
+        }
+    }
+
+    // Read the whole body of the current part, failing if it is larger than the limit
+    pub async fn read_to_end(&mut self, limit: usize) -> Result<Option<Vec<u8>>, MultipartError> {
+        let mut data = Vec::new();
+        let mut chunk = [0; MULTIPART_READ_SIZE];
+        loop {
+            let n = self.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(Some(data));
+            }
+            if data.len() + n > limit {
+                return Ok(None);
+            }
+            data.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    async fn read_until_delimiter(&mut self, out: &mut [u8]) -> Result<usize, MultipartError> {
+        loop {
+            // Bytes before the delimiter belong to the part, the delimiter itself ends it
+            if let Some(i) = find(&self.buff, &self.delimiter) {
+                if i == 0 {
+                    self.buff.drain(..self.delimiter.len());
+                    self.state = State::Headers;
+                    return Ok(0);
+                }
+                let n = i.min(out.len());
+                out[..n].copy_from_slice(&self.buff[..n]);
+                self.buff.drain(..n);
+                return Ok(n);
+            }
+
+            // Bytes which cannot be the start of a delimiter are safe to hand out
+            let safe = self.buff.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                let n = safe.min(out.len());
+                out[..n].copy_from_slice(&self.buff[..n]);
+                self.buff.drain(..n);
+                return Ok(n);
+            }
+            if !self.fill().await? {
+                return Err(MultipartError::Malformed("Missing closing delimiter"));
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Split the parameters of a header value, keeping separators inside of quotes
+// Sample: form-data; name="file"; filename="a; b.txt"
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = value;
+    while let Some(i) = rest.find(';') {
+        rest = &rest[i + 1..];
+        let (k, v) = match rest.split_once('=') {
+            Some(kv) => kv,
+            None => break,
+        };
+        let v = v.trim_start();
+        let (val, next) = match v.strip_prefix('"') {
+            Some(q) => {
+                let mut val = String::new();
+                let mut chars = q.char_indices();
+                let mut end = q.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => val.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => val.push(c),
+                    }
+                }
+                (val, &q[end..])
+            }
+            None => match v.find(';') {
+                Some(i) => (v[..i].trim().to_string(), &v[i..]),
+                None => (v.trim().to_string(), ""),
+            },
+        };
+        params.push((k.trim().to_ascii_lowercase(), val));
+        rest = next;
+    }
+    params
+}
+
+fn parse_part(header: &str) -> Part {
+    let mut part = Part::default();
+    for line in header.lines() {
+        let (k, v) = match line.split_once(':') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+        if k.eq_ignore_ascii_case("Content-Disposition") {
+            for (name, val) in header_params(v) {
+                match name.as_str() {
+                    "name" => part.name = Some(val),
+                    "filename" => part.filename = Some(val),
+                    _ => {}
+                }
+            }
+        } else if k.eq_ignore_ascii_case("Content-Type") {
+            part.content_type = Some(v.to_string());
+        }
+        part.headers.push((k.to_string(), v.to_string()));
+    }
+    part
+}
@@ -0,0 +1,260 @@
+use notify::{
+    event::{CreateKind, DataChange, ModifyKind, RemoveKind},
+    Config, Event, EventHandler, EventKind, RecursiveMode, Watcher, WatcherKind,
+};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    log_ctx,
+    server::{DEFAULT_WATCH_POLL_BUDGET, DEFAULT_WATCH_POLL_INTERVAL},
+    warn,
+};
+log_ctx!("PollWatcher");
+
+// Modified time and size of a file at the last scan
+type Stamp = (Option<SystemTime>, u64);
+
+// Tells whether a path relative to its root, and whether it is a folder, is left out of the scans
+type Ignore = Box<dyn Fn(&Path, bool) -> bool + Send>;
+
+struct Snapshot {
+    roots: HashMap<PathBuf, RecursiveMode>,
+    files: HashMap<PathBuf, Stamp>,
+    truncated: bool, // Whether the last scan stopped at the budget
+    ignore: Option<Ignore>,
+}
+
+// Watcher scanning the modified times of the watched files at an interval, for file systems whose changes are not reported
+// At most budget files are looked at per scan, in name order, the files past it are not watched
+pub struct PollWatcher {
+    snapshot: Arc<Mutex<Snapshot>>,
+    budget: usize,
+    stop: Arc<AtomicBool>,
+}
+
+impl PollWatcher {
+    pub fn with_budget<F: EventHandler>(mut handler: F, interval: Duration, budget: usize) -> Self {
+        let snapshot = Arc::new(Mutex::new(Snapshot {
+            roots: HashMap::new(),
+            files: HashMap::new(),
+            truncated: false,
+            ignore: None,
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (scan_snapshot, scan_stop) = (snapshot.clone(), stop.clone());
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if scan_stop.load(Ordering::Relaxed) {
+                return;
+            }
+
+            // Events are sent once the lock is released, the receiver may be the one adding a root
+            for event in poll(&scan_snapshot, budget) {
+                handler.handle_event(event);
+            }
+        });
+        Self {
+            snapshot,
+            budget,
+            stop,
+        }
+    }
+
+    // Leave the paths of recursive roots matching the filter out of the scans, ignored folders are not walked
+    // Ignored files do not count towards the budget
+    pub fn ignore(self, filter: impl Fn(&Path, bool) -> bool + Send + 'static) -> Self {
+        self.snapshot.lock().unwrap().ignore = Some(Box::new(filter));
+        self
+    }
+}
+
+impl Watcher for PollWatcher {
+    fn new<F: EventHandler>(handler: F, config: Config) -> notify::Result<Self> {
+        Ok(Self::with_budget(
+            handler,
+            config
+                .poll_interval_v2()
+                .unwrap_or(DEFAULT_WATCH_POLL_INTERVAL),
+            DEFAULT_WATCH_POLL_BUDGET,
+        ))
+    }
+
+    // The files of the root are scanned right away, so that the next scan only reports what changed since
+    fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+        if !path.exists() {
+            return Err(notify::Error::path_not_found().add_path(path.to_path_buf()));
+        }
+        let mut snapshot = self.snapshot.lock().unwrap();
+        let budget = self.budget.saturating_sub(snapshot.files.len());
+        let mut files = HashMap::new();
+        let root = Root {
+            path,
+            mode: recursive_mode,
+            ignore: snapshot.ignore.as_ref(),
+        };
+        let truncated = scan(&root, path, budget, &mut files).map_err(notify::Error::io)?;
+        if truncated && !snapshot.truncated {
+            warn!(
+                "Polling stopped at the budget of {} files, the files past it are not watched",
+                self.budget
+            );
+        }
+        snapshot.truncated |= truncated;
+        snapshot.files.extend(files);
+        snapshot.roots.insert(path.to_path_buf(), recursive_mode);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if snapshot.roots.remove(path).is_none() {
+            return Err(notify::Error::watch_not_found().add_path(path.to_path_buf()));
+        }
+        let roots = snapshot.roots.clone();
+        snapshot
+            .files
+            .retain(|f, _| roots.iter().any(|(r, m)| is_under(f, r, *m)));
+        Ok(())
+    }
+
+    fn kind() -> WatcherKind {
+        WatcherKind::PollWatcher
+    }
+}
+
+impl Drop for PollWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// Scan the roots again and return the events of the files created, modified and removed since the last scan
+// A root that cannot be read is reported as an error, its files are kept until it can be read again
+fn poll(snapshot: &Mutex<Snapshot>, budget: usize) -> Vec<notify::Result<Event>> {
+    let mut snapshot = snapshot.lock().unwrap();
+    let mut files = HashMap::new();
+    let mut events = Vec::new();
+    let mut truncated = false;
+    let mut roots = snapshot.roots.iter().collect::<Vec<_>>();
+    roots.sort_by(|a, b| a.0.cmp(b.0));
+    for (root, mode) in roots {
+        let left = budget.saturating_sub(files.len());
+        let scanned = Root {
+            path: root,
+            mode: *mode,
+            ignore: snapshot.ignore.as_ref(),
+        };
+        match scan(&scanned, root, left, &mut files) {
+            Ok(t) => truncated |= t,
+            Err(err) => {
+                events.push(Err(notify::Error::io(err).add_path(root.clone())));
+                files.extend(
+                    snapshot
+                        .files
+                        .iter()
+                        .filter(|(f, _)| is_under(f, root, *mode))
+                        .map(|(f, s)| (f.clone(), *s)),
+                );
+            }
+        }
+    }
+
+    if truncated && !snapshot.truncated {
+        warn!(
+            "Polling stopped at the budget of {} files, the files past it are not watched",
+            budget
+        );
+    }
+    snapshot.truncated = truncated;
+
+    for (path, stamp) in &files {
+        let kind = match snapshot.files.get(path) {
+            None => EventKind::Create(CreateKind::File),
+            Some(old) if old != stamp => EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+            Some(_) => continue,
+        };
+        events.push(Ok(Event::new(kind).add_path(path.clone())));
+    }
+    for path in snapshot.files.keys().filter(|p| !files.contains_key(*p)) {
+        events.push(Ok(
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(path.clone())
+        ));
+    }
+    snapshot.files = files;
+    events
+}
+
+struct Root<'a> {
+    path: &'a Path,
+    mode: RecursiveMode,
+    ignore: Option<&'a Ignore>,
+}
+
+impl Root<'_> {
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        match (self.mode, self.ignore, path.strip_prefix(self.path)) {
+            (RecursiveMode::Recursive, Some(ignore), Ok(rel)) => ignore(rel, is_dir),
+            _ => false,
+        }
+    }
+}
+
+// Add the stamps of the files of the folder to files until the budget is used, in name order
+// Returns whether files were left out, only the root must be readable, sub folders failing to read are skipped
+fn scan(
+    root: &Root,
+    dir: &Path,
+    budget: usize,
+    files: &mut HashMap<PathBuf, Stamp>,
+) -> io::Result<bool> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let mut added = 0;
+    for path in entries {
+        if added >= budget {
+            return Ok(true);
+        }
+        let meta = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        if meta.is_dir() {
+            if root.mode == RecursiveMode::Recursive && !root.is_ignored(&path, true) {
+                let before = files.len();
+                let truncated = scan(root, &path, budget - added, files).unwrap_or(false);
+                added += files.len() - before;
+                if truncated {
+                    return Ok(true);
+                }
+            }
+            continue;
+        }
+        if root.is_ignored(&path, false) {
+            continue;
+        }
+        files.insert(path, (meta.modified().ok(), meta.len()));
+        added += 1;
+    }
+    Ok(false)
+}
+
+fn is_under(path: &Path, root: &Path, mode: RecursiveMode) -> bool {
+    match mode {
+        RecursiveMode::Recursive => path.starts_with(root),
+        RecursiveMode::NonRecursive => path.parent() == Some(root),
+    }
+}
@@ -0,0 +1,138 @@
+use std::{io, path::Path};
+
+use crate::{info, log_ctx};
+
+log_ctx!("PrivDrop");
+
+// User and group the process switches to once its sockets are bound
+#[derive(Debug, Clone, Copy)]
+pub struct Ids {
+    pub uid: Option<u32>,
+    pub gid: u32,
+}
+
+impl Ids {
+    // Resolve names or numeric ids, the group defaults to the primary group of the user
+    // Names are looked up in the user database, so resolve before entering a chroot
+    #[cfg(target_os = "linux")]
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> io::Result<Option<Self>> {
+        let user = match user {
+            Some(u) => Some(lookup_user(u)?),
+            None => None,
+        };
+        let gid = match (group, user) {
+            (Some(g), _) => lookup_group(g)?,
+            (None, Some((_, gid))) => gid,
+            (None, None) => return Ok(None),
+        };
+        Ok(Some(Self {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+        }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> io::Result<Option<Self>> {
+        match (user, group) {
+            (None, None) => Ok(None),
+            _ => Err(unsupported()),
+        }
+    }
+
+    // Switch the whole process to the ids, the supplementary groups are dropped
+    // Fails if the ids could be switched back, i.e. root privileges were kept
+    #[cfg(target_os = "linux")]
+    pub fn switch(&self) -> io::Result<()> {
+        let check = |res: libc::c_int| match res {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+
+        // The group goes first as changing it requires the privileges dropped with the user
+        check(unsafe { libc::setgroups(1, &self.gid) })?;
+        check(unsafe { libc::setgid(self.gid) })?;
+        if let Some(uid) = self.uid {
+            check(unsafe { libc::setuid(uid) })?;
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Root privileges were regained after switching the user",
+                ));
+            }
+        }
+        info!(
+            "Switched to uid {} gid {}",
+            unsafe { libc::getuid() },
+            unsafe { libc::getgid() }
+        );
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn switch(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+// Confine the file system of the process to the folder, which becomes its root and working folder
+// Relative paths opened afterwards are resolved inside the folder
+#[cfg(target_os = "linux")]
+pub fn chroot(path: &Path) -> io::Result<()> {
+    std::os::unix::fs::chroot(path)?;
+    std::env::set_current_dir("/")?;
+    info!("Changed root to {}", path.display());
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn chroot(_path: &Path) -> io::Result<()> {
+    Err(unsupported())
+}
+
+// Uid and primary gid of the user given by name or id
+#[cfg(target_os = "linux")]
+fn lookup_user(user: &str) -> io::Result<(u32, u32)> {
+    let entry = match user.parse::<u32>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => {
+            let name = c_name(user)?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+    match unsafe { entry.as_ref() } {
+        Some(e) => Ok((e.pw_uid, e.pw_gid)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown user: {}", user),
+        )),
+    }
+}
+
+// Gid of the group given by name or id, ids are taken as is
+#[cfg(target_os = "linux")]
+fn lookup_group(group: &str) -> io::Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+    let name = c_name(group)?;
+    match unsafe { libc::getgrnam(name.as_ptr()).as_ref() } {
+        Some(e) => Ok(e.gr_gid),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown group: {}", group),
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn c_name(name: &str) -> io::Result<std::ffi::CString> {
+    std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Dropping privileges is not supported on this platform",
+    )
+}
@@ -0,0 +1,1001 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{self, AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::RwLock,
+    task,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    bufpool::PooledBuf,
+    debug,
+    filecache::AbstractFile,
+    http::{
+        copy_chunked, read_chunked, read_headers_buff, status_reason, BodyFraming, HttpRequest,
+        HttpResponse, ResponseBody,
+    },
+    info, log_ctx, slowlog, trace,
+    util::parse_duration,
+    warn,
+};
+
+// Sample of upstream: http://127.0.0.1:8080/api
+const UPSTREAM_SCHEME: &str = "http://";
+const UPSTREAM_DEFAULT_PORT: u16 = 80;
+const UPSTREAM_BUFF_INIT_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
+const UPSTREAM_MAX_HEADER_SIZE: usize = crate::BUFF_INIT_SIZE * 256;
+
+// Headers which are only meaningful for a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+const CONDITIONAL_HEADERS: &[&str] = &["If-None-Match", "If-Modified-Since"];
+
+// Requests streamed to the upstream whatever the entry, their responses last as long as the upstream wants
+const EVENT_STREAM_TYPE: &str = "text/event-stream";
+const GRPC_WEB_TYPE_PREFIX: &str = "application/grpc-web";
+
+// Upstreams failing requests in a row are skipped for a while, then given requests again
+const PASSIVE_MAX_FAILS: u32 = 3;
+const PASSIVE_COOLDOWN: Duration = Duration::from_secs(10);
+
+// Sample of health check: /healthz or /healthz:5s
+const HEALTH_CHECK_INTERVAL_DELIM: char = ':';
+const HEALTH_CHECK_DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+const HEALTH_CHECK_MAX_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Defaults of the policy of the requests to upstreams
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+log_ctx!("Proxy");
+
+// Health of an upstream shared by the requests to it and its checks
+#[derive(Debug)]
+struct Health {
+    check_ok: AtomicBool, // Of the last active check, true without checks
+    fails: AtomicU32,     // Requests failed in a row
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            check_ok: AtomicBool::new(true),
+            fails: AtomicU32::new(0),
+            down_until: Mutex::new(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host: String,
+    port: u16,
+    path: String,
+    health: Arc<Health>,
+}
+
+impl PartialEq for Upstream {
+    fn eq(&self, other: &Self) -> bool {
+        self.host == other.host && self.port == other.port && self.path == other.path
+    }
+}
+
+impl Eq for Upstream {}
+
+impl Upstream {
+    pub fn is_upstream(s: &str) -> bool {
+        s.starts_with(UPSTREAM_SCHEME)
+    }
+
+    pub fn parse(url: &str) -> Option<Self> {
+        // Split into authority and path
+        let url = url.strip_prefix(UPSTREAM_SCHEME)?;
+        let (authority, path) = match url.find('/') {
+            Some(i) => url.split_at(i),
+            None => (url, "/"),
+        };
+
+        // Split into host and port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse::<u16>().ok()?),
+            None => (authority, UPSTREAM_DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            health: Arc::default(),
+        })
+    }
+
+    // Whether the last active check passed and the upstream is not cooling down after failed requests
+    pub fn is_healthy(&self) -> bool {
+        let cooling = match *self.health.down_until.lock().unwrap() {
+            Some(t) => Instant::now() < t,
+            None => false,
+        };
+        self.health.check_ok.load(Ordering::Relaxed) && !cooling
+    }
+
+    // Passive failure detection from the outcome of a request
+    fn record(&self, ok: bool) {
+        if ok {
+            self.health.fails.store(0, Ordering::Relaxed);
+            *self.health.down_until.lock().unwrap() = None;
+            return;
+        }
+        let fails = self.health.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails < PASSIVE_MAX_FAILS {
+            return;
+        }
+
+        // Requests still sent while cooling down, as every upstream is, extend it silently
+        let now = Instant::now();
+        let prev = self
+            .health
+            .down_until
+            .lock()
+            .unwrap()
+            .replace(now + PASSIVE_COOLDOWN);
+        if prev.is_none_or(|t| now >= t) {
+            warn!(
+                "Upstream {} skipped for {}s after {} failed requests",
+                self,
+                PASSIVE_COOLDOWN.as_secs(),
+                fails
+            );
+        }
+    }
+
+    fn host_header(&self) -> String {
+        match self.port {
+            UPSTREAM_DEFAULT_PORT => self.host.clone(),
+            p => format!("{}:{}", self.host, p),
+        }
+    }
+
+    fn target(&self, query: Option<&str>) -> String {
+        match query {
+            Some(q) => format!("{}?{}", self.path, q),
+            None => self.path.clone(),
+        }
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", UPSTREAM_SCHEME, self.host_header(), self.path)
+    }
+}
+
+// Timeouts and retries of the requests to the upstreams of an entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpstreamPolicy {
+    pub connect_timeout: Option<Duration>, // Default to 5s
+    pub timeout: Option<Duration>,         // Of the whole response once connected (Default to 60s)
+    pub retries: u32,                      // On the next upstreams, for idempotent methods only
+}
+
+impl UpstreamPolicy {
+    fn connect_timeout(&self) -> Duration {
+        self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(DEFAULT_RESPONSE_TIMEOUT)
+    }
+
+    // Failed requests are only sent again if sending them twice does no harm
+    fn attempts(&self, method: &str) -> u32 {
+        match IDEMPOTENT_METHODS.contains(&method) {
+            true => self.retries + 1,
+            false => 1,
+        }
+    }
+}
+
+// Active check of the upstreams of an entry, a GET of the path answered with 2xx or 3xx passes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+}
+
+impl HealthCheck {
+    // Sample: /healthz or /healthz:5s
+    pub fn parse(s: &str) -> Option<Self> {
+        let (path, interval) = match s.rsplit_once(HEALTH_CHECK_INTERVAL_DELIM) {
+            Some((p, i)) => match parse_duration(i) {
+                Some(i) if !i.is_zero() => (p, i),
+                _ => (s, HEALTH_CHECK_DEFAULT_INTERVAL),
+            },
+            None => (s, HEALTH_CHECK_DEFAULT_INTERVAL),
+        };
+        if !path.starts_with('/') {
+            return None;
+        }
+        Some(Self {
+            path: path.to_string(),
+            interval,
+        })
+    }
+
+    // Check the upstream every interval until the map holding it is dropped
+    pub fn spawn(&self, upstream: &Upstream) {
+        let check = self.clone();
+        let target = Upstream {
+            path: check.path.clone(),
+            health: Arc::default(),
+            ..upstream.clone()
+        };
+        let health = Arc::downgrade(&upstream.health);
+        task::spawn(async move {
+            let name = upstream_name(&target);
+            loop {
+                if !check_once(&target, &health, &name, check.interval).await {
+                    break;
+                }
+                sleep(check.interval).await;
+            }
+        });
+    }
+}
+
+impl Display for HealthCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}ms",
+            self.path,
+            HEALTH_CHECK_INTERVAL_DELIM,
+            self.interval.as_millis()
+        )
+    }
+}
+
+fn upstream_name(upstream: &Upstream) -> String {
+    format!("{}{}", UPSTREAM_SCHEME, upstream.host_header())
+}
+
+// Returns false once the upstream is no longer in use
+async fn check_once(
+    target: &Upstream,
+    health: &Weak<Health>,
+    name: &str,
+    interval: Duration,
+) -> bool {
+    let limit = interval.min(HEALTH_CHECK_MAX_TIMEOUT);
+    let status = match timeout(limit, request_status(target)).await {
+        Ok(Ok(s)) => Ok(s),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    let health = match health.upgrade() {
+        Some(h) => h,
+        None => return false,
+    };
+    let ok = matches!(status, Ok(200..=399));
+    let was_ok = health.check_ok.swap(ok, Ordering::Relaxed);
+    match (was_ok, ok, status) {
+        (true, false, Ok(s)) => warn!("Upstream {} failed its health check with {}", name, s),
+        (true, false, Err(e)) => warn!("Upstream {} failed its health check: {}", name, e),
+        (false, true, _) => info!("Upstream {} passed its health check again", name),
+        _ => (),
+    }
+    true
+}
+
+// Status of a bodiless GET of the path of the upstream
+async fn request_status(upstream: &Upstream) -> io::Result<u16> {
+    let header = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        upstream.path,
+        upstream.host_header()
+    );
+    let mut stream = TcpStream::connect((upstream.host.as_str(), upstream.port)).await?;
+    stream.write_all(header.as_bytes()).await?;
+    let mut r_stream = BufReader::new(stream);
+    let header_buff = read_headers_buff(&mut r_stream, UPSTREAM_MAX_HEADER_SIZE).await?;
+    String::from_utf8_lossy(&header_buff)
+        .lines()
+        .next()
+        .and_then(|l| l.split(' ').nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid upstream status"))
+}
+
+struct UpstreamResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Arc<[u8]>,
+}
+
+impl UpstreamResponse {
+    fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Value of a directive of Cache-Control, Some("") for directives without value
+    fn cache_directive(&self, name: &str) -> Option<&str> {
+        self.header("Cache-Control")?
+            .split(',')
+            .map(|d| d.trim())
+            .map(|d| d.split_once('=').unwrap_or((d, "")))
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim().trim_matches('"'))
+    }
+
+    // Freshness lifetime of the route if forced, else from s-maxage, which is meant for shared caches, or max-age
+    // Responses without any are revalidated on every request
+    fn max_age(&self, ttl: Option<Duration>) -> Duration {
+        let secs = ["s-maxage", "max-age"]
+            .iter()
+            .find_map(|d| self.cache_directive(d)?.parse::<u64>().ok());
+        ttl.unwrap_or(Duration::from_secs(secs.unwrap_or(0)))
+    }
+
+    // Successful responses are stored if they can be revalidated or stay fresh for a while
+    // Responses for a single user, or to requests with credentials unless made public, are never stored
+    fn is_cacheable(&self, req: &HttpRequest, ttl: Option<Duration>) -> bool {
+        let no_store = ["no-store", "private"]
+            .iter()
+            .any(|d| self.cache_directive(d).is_some());
+        let shared = ["public", "s-maxage"]
+            .iter()
+            .any(|d| self.cache_directive(d).is_some());
+        let credentials = req.header("Authorization").is_some() && !shared;
+        let vary_all = self.header("Vary").is_some_and(|v| v.trim() == "*");
+        let has_validator = self.header("ETag").is_some() || self.header("Last-Modified").is_some();
+        let reusable = has_validator || !self.max_age(ttl).is_zero();
+        self.status == 200 && reusable && !no_store && !credentials && !vary_all
+    }
+
+    // Headers of the request named by Vary with their values, which select the stored variant
+    fn vary(&self, req: &HttpRequest) -> Vec<(String, Option<String>)> {
+        let names = self.header("Vary").unwrap_or_default();
+        names
+            .split(',')
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty())
+            .map(|n| {
+                (
+                    n.to_ascii_lowercase(),
+                    req.header(n).map(|v| v.trim().to_string()),
+                )
+            })
+            .collect()
+    }
+
+    fn to_http_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::new(self.status);
+        for (k, v) in &self.headers {
+            if is_hop_by_hop(k) || k.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            res = res.with_header(k.clone(), v.clone());
+        }
+        res.with_body(ResponseBody::File(AbstractFile::from(self.body.clone())))
+    }
+}
+
+struct CachedResponse {
+    res: UpstreamResponse,
+    vary: Vec<(String, Option<String>)>,
+    stored_at: Instant,
+    max_age: Duration,
+}
+
+impl CachedResponse {
+    fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.max_age
+    }
+
+    fn matches(&self, req: &HttpRequest) -> bool {
+        self.vary
+            .iter()
+            .all(|(k, v)| req.header(k).map(|h| h.trim()) == v.as_deref())
+    }
+
+    // Stale entries without validators cannot be revalidated and are only taking space
+    fn is_expired(&self) -> bool {
+        let has_validator =
+            self.res.header("ETag").is_some() || self.res.header("Last-Modified").is_some();
+        !has_validator && !self.is_fresh()
+    }
+
+    fn to_http_response(&self) -> HttpResponse {
+        let age = self.stored_at.elapsed().as_secs();
+        self.res
+            .to_http_response()
+            .with_header("Age", age.to_string())
+    }
+}
+
+// Responses by URL, with a variant per value of the request headers named by Vary
+struct UpstreamCacheInner {
+    cache: HashMap<String, Vec<Arc<CachedResponse>>>,
+    size_limit: Option<usize>,
+    cur_size: usize,
+}
+
+pub struct UpstreamCache(RwLock<UpstreamCacheInner>);
+
+impl UpstreamCache {
+    pub fn new(size_limit: Option<usize>) -> Self {
+        let inner = UpstreamCacheInner {
+            cache: HashMap::new(),
+            size_limit,
+            cur_size: 0,
+        };
+        Self(RwLock::new(inner))
+    }
+
+    async fn get(&self, key: &str, req: &HttpRequest<'_>) -> Option<Arc<CachedResponse>> {
+        let read_guard = self.0.read().await;
+        let variants = read_guard.cache.get(key)?;
+        variants.iter().find(|c| c.matches(req)).cloned()
+    }
+
+    async fn remove(&self, key: &str, req: &HttpRequest<'_>) {
+        let mut write_guard = self.0.write().await;
+        let inner = &mut *write_guard;
+        if let Some(variants) = inner.cache.get_mut(key) {
+            variants.retain(|c| match c.matches(req) {
+                true => {
+                    inner.cur_size -= c.res.body.len();
+                    false
+                }
+                false => true,
+            });
+            if variants.is_empty() {
+                inner.cache.remove(key);
+            }
+        }
+    }
+
+    async fn insert(&self, key: String, entry: Arc<CachedResponse>) {
+        let mut write_guard = self.0.write().await;
+        let inner = &mut *write_guard;
+        if let Some(variants) = inner.cache.get_mut(&key) {
+            variants.retain(|c| match c.vary == entry.vary {
+                true => {
+                    inner.cur_size -= c.res.body.len();
+                    false
+                }
+                false => true,
+            });
+        }
+
+        // Drop the expired entries before skipping if the cache size limit is reached
+        let size = entry.res.body.len();
+        if let Some(limit) = inner.size_limit {
+            if inner.cur_size + size > limit {
+                for variants in inner.cache.values_mut() {
+                    variants.retain(|c| match c.is_expired() {
+                        true => {
+                            inner.cur_size -= c.res.body.len();
+                            false
+                        }
+                        false => true,
+                    });
+                }
+                inner.cache.retain(|_, v| !v.is_empty());
+            }
+            if inner.cur_size + size > limit {
+                debug!("Upstream cache size limit reached, skipping {}", key);
+                return;
+            }
+        }
+        inner.cur_size += size;
+        inner.cache.entry(key).or_default().push(entry);
+    }
+}
+
+fn is_hop_by_hop(key: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(key))
+}
+
+fn is_conditional(key: &str) -> bool {
+    CONDITIONAL_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(key))
+}
+
+async fn connect(upstream: &Upstream, limit: Duration) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect((upstream.host.as_str(), upstream.port));
+    match timeout(limit, stream).await {
+        Ok(stream) => stream,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Upstream connection timed out",
+        )),
+    }
+}
+
+// Request line and end to end headers of the request to the upstream, the framing headers are left to the caller
+fn request_header(
+    upstream: &Upstream,
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    skip: impl Fn(&str) -> bool,
+) -> String {
+    let mut header = String::with_capacity(UPSTREAM_BUFF_INIT_SIZE);
+    header.push_str(&format!(
+        "{} {} HTTP/1.1\r\n",
+        req.method,
+        upstream.target(req.query)
+    ));
+    header.push_str(&format!("Host: {}\r\n", upstream.host_header()));
+    for (k, v) in &req.headers {
+        let skip_framing =
+            k.eq_ignore_ascii_case("Host") || k.eq_ignore_ascii_case("Content-Length");
+        if is_hop_by_hop(k) || skip_framing || skip(k) {
+            continue;
+        }
+        header.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    header.push_str(&format!("X-Forwarded-For: {}\r\n", sockaddr.ip()));
+    header
+}
+
+async fn send(
+    upstream: &Upstream,
+    policy: &UpstreamPolicy,
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    validators: &[(&str, &str)],
+) -> io::Result<UpstreamResponse> {
+    // Own validators replace the conditional headers of the client
+    let mut header = request_header(upstream, sockaddr, req, |k| {
+        !validators.is_empty() && is_conditional(k)
+    });
+    for (k, v) in validators {
+        header.push_str(&format!("{}: {}\r\n", k, v));
+    }
+
+    // Chunked bodies of the client are forwarded with the length once read
+    if let Some(body) = body {
+        header.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    header.push_str("Connection: close\r\n\r\n");
+
+    // Send request
+    trace!("Forwarding request to {}\n{}", upstream, header);
+    let mut stream = connect(upstream, policy.connect_timeout()).await?;
+    let exchange = async {
+        stream.write_all(header.as_bytes()).await?;
+        if let Some(body) = body {
+            stream.write_all(body).await?;
+        }
+
+        // Read response header
+        let mut r_stream = BufReader::with_capacity(UPSTREAM_BUFF_INIT_SIZE, stream);
+        let header_buff = read_headers_buff(&mut r_stream, UPSTREAM_MAX_HEADER_SIZE).await?;
+        let header_str = String::from_utf8_lossy(&header_buff);
+        let mut lines = header_str.lines();
+        let status = lines
+            .next()
+            .and_then(|l| l.split(' ').nth(1))
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid upstream status"))?;
+        let headers = lines
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect::<Vec<_>>();
+        let mut res = UpstreamResponse {
+            status,
+            headers,
+            body: Arc::new([]),
+        };
+
+        // Read response body
+        let has_body = req.method != "HEAD" && !matches!(status, 100..=199 | 204 | 304);
+        if has_body {
+            let mut buf = Vec::with_capacity(UPSTREAM_BUFF_INIT_SIZE);
+            let chunked = res
+                .header("Transfer-Encoding")
+                .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+            let content_length = res
+                .header("Content-Length")
+                .and_then(|l| l.parse::<usize>().ok());
+            match (chunked, content_length) {
+                (true, _) => buf = read_chunked(&mut r_stream, usize::MAX).await?,
+                (false, Some(len)) => {
+                    buf.resize(len, 0);
+                    r_stream.read_exact(&mut buf).await?;
+                }
+                (false, None) => {
+                    r_stream.read_to_end(&mut buf).await?;
+                }
+            }
+            res.body = buf.into();
+        }
+        Ok(res)
+    };
+    match timeout(policy.timeout(), exchange).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Upstream response timed out",
+        )),
+    }
+}
+
+// Forward an upgrade request and read the response header, the stream is left for the caller to relay
+// Returns the status, the raw response header and the upstream stream
+pub async fn connect_upgrade(
+    upstream: &Upstream,
+    policy: &UpstreamPolicy,
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+) -> io::Result<(u16, PooledBuf, BufReader<TcpStream>)> {
+    // Keep the upgrade headers which are otherwise not forwarded
+    let mut header = String::with_capacity(UPSTREAM_BUFF_INIT_SIZE);
+    header.push_str(&format!(
+        "{} {} HTTP/1.1\r\n",
+        req.method,
+        upstream.target(req.query)
+    ));
+    header.push_str(&format!("Host: {}\r\n", upstream.host_header()));
+    for (k, v) in &req.headers {
+        let is_upgrade = k.eq_ignore_ascii_case("Upgrade");
+        if (is_hop_by_hop(k) && !is_upgrade) || k.eq_ignore_ascii_case("Host") {
+            continue;
+        }
+        header.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    header.push_str(&format!("X-Forwarded-For: {}\r\n", sockaddr.ip()));
+    header.push_str("Connection: Upgrade\r\n\r\n");
+
+    // Send request and read the response header
+    trace!("Forwarding upgrade request to {}\n{}", upstream, header);
+    // Only the response header is timed, the upgraded connection lives as long as both ends want
+    let connected = async {
+        let mut stream = connect(upstream, policy.connect_timeout()).await?;
+        let exchange = async {
+            stream.write_all(header.as_bytes()).await?;
+            let mut r_stream = BufReader::with_capacity(UPSTREAM_BUFF_INIT_SIZE, stream);
+            let header_buff = read_headers_buff(&mut r_stream, UPSTREAM_MAX_HEADER_SIZE).await?;
+            Ok::<_, io::Error>((header_buff, r_stream))
+        };
+        match timeout(policy.timeout(), exchange).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Upstream response timed out",
+            )),
+        }
+    }
+    .await;
+    upstream.record(connected.is_ok());
+    let (header_buff, r_stream) = connected?;
+    let status = String::from_utf8_lossy(&header_buff)
+        .lines()
+        .next()
+        .and_then(|l| l.split(' ').nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid upstream status"))?;
+    Ok((status, header_buff, r_stream))
+}
+
+// Whether the request expects a response which is never complete until the upstream ends it
+pub fn is_streamed(req: &HttpRequest<'_>) -> bool {
+    let accepts_events = req
+        .header("Accept")
+        .is_some_and(|a| a.to_ascii_lowercase().contains(EVENT_STREAM_TYPE));
+    let is_grpc_web = req
+        .header("Content-Type")
+        .is_some_and(|t| t.to_ascii_lowercase().starts_with(GRPC_WEB_TYPE_PREFIX));
+    accepts_events || is_grpc_web
+}
+
+// Relay the request to the upstream as its body is read and the response to the client as it arrives
+// Nothing is buffered or cached, and the connection ends with the response, returns the upstream status
+pub async fn stream<R, W>(
+    upstreams: &[&Upstream],
+    policy: &UpstreamPolicy,
+    (framing, max_body_size): (BodyFraming, usize),
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    r_stream: &mut R,
+    w_stream: &mut W,
+) -> io::Result<HttpResponse>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send,
+{
+    // Nothing is sent before the connection is made, so any method can move on to the next upstream
+    let mut connected = Err(io::ErrorKind::NotConnected.into());
+    let attempts = policy.retries + 1;
+    for (i, u) in upstreams.iter().cycle().take(attempts as usize).enumerate() {
+        connected = connect(u, policy.connect_timeout()).await.map(|s| (*u, s));
+        match &connected {
+            Ok(_) => break,
+            Err(e) => {
+                warn!(
+                    "Upstream stream to {} failed ({}/{}): {}",
+                    u,
+                    i + 1,
+                    attempts,
+                    e
+                );
+                u.record(false);
+            }
+        }
+    }
+    let (upstream, up_stream) = match connected {
+        Ok(c) => c,
+        Err(e) => return write_error(w_stream, &e).await,
+    };
+
+    // Keep the framing of the client so that chunks are relayed as they are read
+    let mut header = request_header(upstream, sockaddr, req, |_| false);
+    match framing {
+        BodyFraming::None => (),
+        BodyFraming::Length(len) => header.push_str(&format!("Content-Length: {}\r\n", len)),
+        BodyFraming::Chunked => header.push_str("Transfer-Encoding: chunked\r\n"),
+    }
+    header.push_str("Connection: close\r\n\r\n");
+    trace!("Streaming request to {}\n{}", upstream, header);
+    let (up_r, mut up_w) = io::split(up_stream);
+    let mut up_r = BufReader::with_capacity(UPSTREAM_BUFF_INIT_SIZE, up_r);
+    let send_body = async {
+        up_w.write_all(header.as_bytes()).await?;
+        match framing {
+            BodyFraming::None => Ok(()),
+            BodyFraming::Length(len) => {
+                let copied =
+                    io::copy_buf(&mut (&mut *r_stream).take(len as u64), &mut up_w).await?;
+                match copied < len as u64 {
+                    true => Err(io::ErrorKind::UnexpectedEof.into()),
+                    false => Ok(()),
+                }
+            }
+            BodyFraming::Chunked => copy_chunked(r_stream, &mut up_w, max_body_size).await,
+        }
+    };
+    tokio::pin!(send_body);
+
+    // Upstreams may answer before the body ends, e.g. to refuse it or to stream both ways
+    let mut body_sent = false;
+    let header_buff = {
+        let read_header = read_headers_buff(&mut up_r, UPSTREAM_MAX_HEADER_SIZE);
+        let read_header = timeout(policy.timeout(), read_header);
+        tokio::pin!(read_header);
+        tokio::select! {
+            res = &mut read_header => res,
+            res = &mut send_body => {
+                if let Err(e) = res {
+                    debug!("Request body to {} not sent: {}", upstream, e);
+                    return match e.kind() {
+                        io::ErrorKind::FileTooLarge => write_status(w_stream, 413).await,
+                        io::ErrorKind::InvalidInput => write_status(w_stream, 400).await,
+                        _ => Err(e),
+                    };
+                }
+                body_sent = true;
+                read_header.await
+            }
+        }
+    };
+    let header_buff = match header_buff {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => {
+            upstream.record(false);
+            warn!("Upstream stream to {} failed: {}", upstream, e);
+            return write_error(w_stream, &e).await;
+        }
+        Err(_) => {
+            upstream.record(false);
+            warn!("Upstream stream to {} timed out", upstream);
+            return write_error(w_stream, &io::ErrorKind::TimedOut.into()).await;
+        }
+    };
+    upstream.record(true);
+
+    // Pass the response header on with the connection closing once the upstream ends the body
+    let header_str = String::from_utf8_lossy(&header_buff);
+    let mut lines = header_str.lines();
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .unwrap_or(502);
+    let mut res_header = format!("{}\r\n", status_line);
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let key = line.split(':').next().unwrap_or_default().trim();
+        if key.eq_ignore_ascii_case("Connection") || key.eq_ignore_ascii_case("Keep-Alive") {
+            continue;
+        }
+        res_header.push_str(&format!("{}\r\n", line));
+    }
+    res_header.push_str("Connection: close\r\n\r\n");
+    w_stream.write_all(res_header.as_bytes()).await?;
+    w_stream.flush().await?;
+
+    // Copy the response as it arrives while the rest of the body is sent, writes wait for the slower end
+    debug!("Streaming response of {} with {}", upstream, status);
+    let copy_res = io::copy_buf(&mut up_r, w_stream);
+    let res = match body_sent {
+        true => copy_res.await.map(|_| ()),
+        false => {
+            tokio::pin!(copy_res);
+            tokio::select! {
+                res = &mut copy_res => res.map(|_| ()),
+                res = send_body => match res {
+                    Ok(_) => copy_res.await.map(|_| ()),
+                    Err(e) => Err(e),
+                },
+            }
+        }
+    };
+    if let Err(e) = res {
+        debug!("Stream of {} ended: {}", upstream, e);
+    }
+    Ok(HttpResponse::new(status))
+}
+
+// Answer the failure of the upstream, 504 if it timed out
+async fn write_error<W: AsyncWrite + Unpin + Send>(
+    w_stream: &mut W,
+    e: &io::Error,
+) -> io::Result<HttpResponse> {
+    match e.kind() {
+        io::ErrorKind::TimedOut => write_status(w_stream, 504).await,
+        _ => write_status(w_stream, 502).await,
+    }
+}
+
+async fn write_status<W: AsyncWrite + Unpin + Send>(
+    w_stream: &mut W,
+    status: u16,
+) -> io::Result<HttpResponse> {
+    let mut res = HttpResponse::new(status).with_text("text/plain", status_reason(status));
+    res.write_to(w_stream, false).await?;
+    Ok(res)
+}
+
+// Forward the request, GET responses are cached for the TTL of the route if given, else as the upstream allows
+pub async fn forward(
+    upstreams: &[&Upstream],
+    cache: &UpstreamCache,
+    policy: &UpstreamPolicy,
+    ttl: Option<Duration>,
+    sockaddr: &SocketAddr,
+    req: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+) -> HttpResponse {
+    let upstream = upstreams[0];
+    let key = format!(
+        "{}{}",
+        upstream,
+        req.query.map(|q| format!("?{}", q)).unwrap_or_default()
+    );
+    let cached = match req.method {
+        "GET" => cache.get(&key, req).await,
+        _ => None,
+    };
+
+    // Serve fresh entries directly
+    if let Some(c) = &cached {
+        if c.is_fresh() {
+            debug!("Upstream cache fresh for {}", &key);
+            return c.to_http_response();
+        }
+    }
+
+    // Revalidate stale entries with their validators
+    let mut validators = Vec::new();
+    if let Some(c) = &cached {
+        if let Some(etag) = c.res.header("ETag") {
+            validators.push(("If-None-Match", etag));
+        }
+        if let Some(lm) = c.res.header("Last-Modified") {
+            validators.push(("If-Modified-Since", lm));
+        }
+        debug!("Upstream cache stale for {}, revalidating...", &key);
+    }
+
+    // Try the upstreams in order, going around again if there are more attempts than upstreams
+    let mut last_err = None;
+    let mut sent_res = None;
+    let attempts = policy.attempts(req.method);
+    for (i, u) in upstreams.iter().cycle().take(attempts as usize).enumerate() {
+        let sent = Instant::now();
+        let res = send(u, policy, sockaddr, req, body, &validators).await;
+        slowlog::record_upstream(sent.elapsed());
+        u.record(res.is_ok());
+        match res {
+            Ok(r) => {
+                sent_res = Some(r);
+                break;
+            }
+            Err(e) => {
+                warn!(
+                    "Upstream request to {} failed ({}/{}): {}",
+                    u,
+                    i + 1,
+                    attempts,
+                    e
+                );
+                last_err = Some(e);
+            }
+        }
+    }
+    let res = match (sent_res, last_err) {
+        (Some(r), _) => r,
+        (None, Some(e)) if e.kind() == io::ErrorKind::TimedOut => {
+            return HttpResponse::new(504).with_text("text/plain", "Gateway Timeout")
+        }
+        (None, _) => return HttpResponse::new(502).with_text("text/plain", "Bad Gateway"),
+    };
+
+    // Refresh the stored entry if not modified
+    if let (Some(c), 304) = (&cached, res.status) {
+        debug!("Upstream cache revalidated for {}", &key);
+        let refreshed = CachedResponse {
+            res: UpstreamResponse {
+                status: c.res.status,
+                headers: c.res.headers.clone(),
+                body: c.res.body.clone(),
+            },
+            vary: c.vary.clone(),
+            stored_at: Instant::now(),
+            max_age: res.max_age(ttl),
+        };
+        let http_res = refreshed.to_http_response();
+        cache.insert(key, Arc::new(refreshed)).await;
+        return http_res;
+    }
+
+    // Store or drop the entry based on the new response
+    let http_res = res.to_http_response();
+    if req.method == "GET" {
+        match res.is_cacheable(req, ttl) {
+            true => {
+                debug!("Upstream response stored for {}", &key);
+                let entry = CachedResponse {
+                    vary: res.vary(req),
+                    max_age: res.max_age(ttl),
+                    stored_at: Instant::now(),
+                    res,
+                };
+                cache.insert(key, Arc::new(entry)).await;
+            }
+            false if cached.is_some() => cache.remove(&key, req).await,
+            false => (),
+        }
+    }
+    http_res
+}
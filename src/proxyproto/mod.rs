@@ -0,0 +1,94 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{self, AsyncBufRead, AsyncReadExt};
+
+// Preambles of the HAProxy PROXY protocol sent by load balancers before the request
+// Sample of v1: PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107; // Including the CRLF
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x2;
+const V2_CMD_LOCAL: u8 = 0x0;
+const V2_CMD_PROXY: u8 = 0x1;
+const V2_FAMILY_INET: u8 = 0x1;
+const V2_FAMILY_INET6: u8 = 0x2;
+const V2_MAX_LEN: usize = 4096; // Of the addresses and TLVs, which are skipped
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Read the preamble of either version, returns the address of the client
+// None if the balancer speaks for itself, e.g. health checks, or the client is not on TCP
+pub async fn read_preamble<R: AsyncBufRead + Unpin>(r: &mut R) -> io::Result<Option<SocketAddr>> {
+    // The shortest preamble of both versions is longer than the v2 signature
+    let mut start = [0u8; 12];
+    r.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        return read_v2(r).await;
+    }
+    if !start.starts_with(V1_PREFIX) {
+        return Err(invalid("Missing PROXY protocol preamble"));
+    }
+
+    // Read the rest of the line of v1
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol preamble too long"));
+        }
+        line.push(r.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("Invalid PROXY protocol preamble"))?;
+    parse_v1(line).ok_or_else(|| invalid("Invalid PROXY protocol preamble"))
+}
+
+// Sample: TCP4 203.0.113.7 10.0.0.1 51234 80 or UNKNOWN
+fn parse_v1(line: &str) -> Option<Option<SocketAddr>> {
+    let mut tokens = line.split(' ');
+    let is_v4 = match tokens.next()? {
+        "TCP4" => true,
+        "TCP6" => false,
+        "UNKNOWN" => return Some(None),
+        _ => return None,
+    };
+    let src = tokens.next()?.parse::<IpAddr>().ok()?;
+    let _dst = tokens.next()?.parse::<IpAddr>().ok()?;
+    let port = tokens.next()?.parse::<u16>().ok()?;
+    let _dst_port = tokens.next()?.parse::<u16>().ok()?;
+    if tokens.next().is_some() || src.is_ipv4() != is_v4 {
+        return None;
+    }
+    Some(Some(SocketAddr::new(src, port)))
+}
+
+async fn read_v2<R: AsyncBufRead + Unpin>(r: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    r.read_exact(&mut header).await?;
+    let (version, cmd, family) = (header[0] >> 4, header[0] & 0xf, header[1] >> 4);
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if version != V2_VERSION || len > V2_MAX_LEN {
+        return Err(invalid("Invalid PROXY protocol preamble"));
+    }
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body).await?;
+    match (cmd, family) {
+        (V2_CMD_LOCAL, _) => Ok(None),
+        (V2_CMD_PROXY, V2_FAMILY_INET) if len >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).unwrap());
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        (V2_CMD_PROXY, V2_FAMILY_INET6) if len >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        (V2_CMD_PROXY, V2_FAMILY_INET | V2_FAMILY_INET6) => {
+            Err(invalid("Invalid PROXY protocol preamble"))
+        }
+        (V2_CMD_PROXY, _) => Ok(None), // Unix sockets or unspecified
+        _ => Err(invalid("Invalid PROXY protocol preamble")),
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    task,
+};
+
+use crate::{
+    debug, error,
+    http::{read_headers_buff, read_timeout, status_reason, HttpResponse},
+    info, log_ctx,
+    state::ServerState,
+    trace,
+};
+
+// Challenges of the ACME HTTP-01 validation are answered from the same path under the webroot
+// Sample: /.well-known/acme-challenge/LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+pub const DEFAULT_HTTPS_PORT: u16 = 443;
+
+log_ctx!("Redirect");
+
+// Plain HTTP listener sending every request to the https equivalent
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub port: u16,
+    pub https_port: u16,
+    pub acme_webroot: Option<PathBuf>,
+}
+
+impl Redirect {
+    // Sample: 80 or 80:8443
+    pub fn parse(s: &str) -> Option<Self> {
+        let (port, https_port) = match s.split_once(':') {
+            Some((p, h)) => (p.parse().ok()?, h.parse().ok()?),
+            None => (s.parse().ok()?, DEFAULT_HTTPS_PORT),
+        };
+        Some(Self {
+            port,
+            https_port,
+            acme_webroot: None,
+        })
+    }
+
+    // Location of the request on the https port, None if the host is missing or invalid
+    fn location(&self, host: &str, target: &str) -> Option<String> {
+        // Drop the port of the plain listener, keeping brackets of IPv6 hosts
+        let host = match host.rsplit_once(':') {
+            Some((h, p)) if p.chars().all(|c| c.is_ascii_digit()) && !h.is_empty() => h,
+            _ => host,
+        };
+        let valid = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']'));
+        if !valid || !target.starts_with('/') {
+            return None;
+        }
+        match self.https_port {
+            DEFAULT_HTTPS_PORT => Some(format!("https://{}{}", host, target)),
+            p => Some(format!("https://{}:{}{}", host, p, target)),
+        }
+    }
+
+    async fn respond(&self, host: Option<&str>, target: &str) -> io::Result<HttpResponse> {
+        // Serve the token of the challenge if the webroot has it, tokens are base64url only
+        if let (Some(webroot), Some(token)) = (
+            &self.acme_webroot,
+            target
+                .split('?')
+                .next()
+                .and_then(|p| p.strip_prefix(ACME_CHALLENGE_PREFIX)),
+        ) {
+            let valid = !token.is_empty()
+                && token
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Ok(HttpResponse::not_found());
+            }
+            let path = webroot.join(&ACME_CHALLENGE_PREFIX[1..]).join(token);
+            return match fs::read(&path).await {
+                Ok(b) => {
+                    trace!("Serving ACME challenge {}", token);
+                    Ok(HttpResponse::new(200).with_text("text/plain", String::from_utf8_lossy(&b)))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HttpResponse::not_found()),
+                Err(e) => Err(e),
+            };
+        }
+        match host.and_then(|h| self.location(h, target)) {
+            Some(l) => Ok(HttpResponse::new(301).with_header("Location", l)),
+            None => Ok(HttpResponse::new(400).with_text("text/plain", status_reason(400))),
+        }
+    }
+}
+
+impl Display for Redirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.port, self.https_port)
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    redirect: &Redirect,
+    state: &ServerState,
+) -> io::Result<()> {
+    let (r_stream, mut w_stream) = stream.split();
+    let mut r_stream = BufReader::new(r_stream);
+
+    // Only the request line and the host are needed, the connection is closed after the response
+    let header_buff = read_timeout(
+        state.header_timeout,
+        None,
+        read_headers_buff(&mut r_stream, state.max_header_size),
+    )
+    .await?;
+    let header = String::from_utf8_lossy(&header_buff);
+    let mut lines = header.lines();
+    let target = lines
+        .next()
+        .and_then(|l| l.split(' ').nth(1))
+        .unwrap_or_default();
+    let host = lines
+        .take_while(|l| !l.is_empty())
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("Host"))
+        .map(|(_, v)| v.trim());
+    let mut res = redirect.respond(host, target).await?;
+    debug!("{} -> {}", target, res.status_line());
+    res.write_to(&mut w_stream, false).await?;
+    stream.shutdown().await
+}
+
+// Serve the redirects on a separate listener, apart from the public accept loop
+pub async fn listen(redirect: Redirect, ip: IpAddr, state: Arc<ServerState>) -> io::Result<()> {
+    let listener = crate::server::bind(SocketAddr::new(ip, redirect.port), false)?;
+    info!(
+        "redirect socket binded @{} -> https port {}",
+        listener.local_addr()?,
+        redirect.https_port
+    );
+    let redirect = Arc::new(redirect);
+    task::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Redirect connection error: {}", e);
+                    continue;
+                }
+            };
+            debug!("redirect connection from: {}", &addr);
+            let redirect = redirect.clone();
+            let state = state.clone();
+            task::spawn(async move {
+                if let Err(e) = handle_connection(stream, &redirect, &state).await {
+                    error!("Error: {}, {}", &addr, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
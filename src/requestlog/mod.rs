@@ -0,0 +1,110 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+// Status codes of the responses whose requests are logged, e.g. "4xx,5xx,304"
+// Classes are given as a digit followed by xx
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusFilter {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl StatusFilter {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut ranges = Vec::new();
+        for code in s.split(',').map(str::trim) {
+            let range = match code.as_bytes() {
+                [c @ b'1'..=b'5', b'x' | b'X', b'x' | b'X'] => {
+                    let class = (c - b'0') as u16 * 100;
+                    (class, class + 99)
+                }
+                _ => match code.parse::<u16>() {
+                    Ok(c) if (100..600).contains(&c) => (c, c),
+                    _ => return None,
+                },
+            };
+            ranges.push(range);
+        }
+        Some(Self { ranges })
+    }
+
+    pub fn matches(&self, status: u16) -> bool {
+        self.ranges
+            .iter()
+            .any(|(from, to)| (*from..=*to).contains(&status))
+    }
+}
+
+impl Display for StatusFilter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let codes = self
+            .ranges
+            .iter()
+            .map(|(from, to)| match from == to {
+                true => from.to_string(),
+                false => format!("{}xx", from / 100),
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", codes.join(","))
+    }
+}
+
+// Decides which requests get the request log line, changeable while the server runs
+// Failed and slow requests are always logged, successful ones 1 in sample, and only the statuses of the filter if any
+#[derive(Debug)]
+pub struct RequestLog {
+    sample: AtomicU64,
+    count: AtomicU64,
+    statuses: RwLock<Option<StatusFilter>>,
+}
+
+impl Default for RequestLog {
+    fn default() -> Self {
+        Self::new(1, None)
+    }
+}
+
+impl RequestLog {
+    pub fn new(sample: u64, statuses: Option<StatusFilter>) -> Self {
+        Self {
+            sample: AtomicU64::new(sample.max(1)),
+            count: AtomicU64::new(0),
+            statuses: RwLock::new(statuses),
+        }
+    }
+
+    pub fn sample(&self) -> u64 {
+        self.sample.load(Ordering::Relaxed)
+    }
+
+    pub fn set_sample(&self, sample: u64) {
+        self.sample.store(sample.max(1), Ordering::Relaxed);
+    }
+
+    pub fn statuses(&self) -> Option<StatusFilter> {
+        self.statuses.read().unwrap().clone()
+    }
+
+    pub fn set_statuses(&self, statuses: Option<StatusFilter>) {
+        *self.statuses.write().unwrap() = statuses;
+    }
+
+    pub fn should_log(&self, status: u16, slow: bool) -> bool {
+        if let Some(filter) = &*self.statuses.read().unwrap() {
+            if !filter.matches(status) {
+                return false;
+            }
+        }
+        if status >= 400 || slow {
+            return true;
+        }
+        match self.sample() {
+            1 => true,
+            n => self.count.fetch_add(1, Ordering::Relaxed).is_multiple_of(n),
+        }
+    }
+}
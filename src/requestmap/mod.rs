@@ -3,20 +3,121 @@ use std::{
     collections::HashMap,
     error::Error,
     fmt::Display,
+    net::IpAddr,
     num::ParseIntError,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use rand::{thread_rng, Rng};
 
-use crate::{debug, log_ctx, timer};
+use crate::{
+    cachecontrol::CachePolicy,
+    debug,
+    fastcgi::FastCgiUpstream,
+    http::HttpRequest,
+    ipfilter::IpFilter,
+    log_ctx,
+    proxy::{HealthCheck, Upstream, UpstreamPolicy},
+    template::{self, RenderCache},
+    timer,
+    transform::Transforms,
+    util::{fnv1a, parse_duration},
+    websocket,
+};
 
 // Define delimiters
 // Sample of single map entry: /path=path/to/file.txt
+// Sample of map entry of a single method: POST /path=path/to/created.json
 // Sample of weighted map entry: /path=path/to/file1.txt'10,path/to/file2.txt'20
+// Sample of proxy map entry: /path=http://127.0.0.1:8080/path
+// Sample of load balanced proxy map entry: /api=http://10.0.0.1:8080/api'3,http://10.0.0.2:8080/api'1
+// Sample of FastCGI map entry: /*.php=fastcgi://127.0.0.1:9000
+// Sample of static map entry: /robots.txt=@200:"User-agent: *\nDisallow:"
 const REQ_MAP_KEY_VAL_DELIM: char = '=';
 const REQ_MAP_VAL_DELIM: char = ',';
 const REQ_MAP_VAL_WEIGHT_DELIM: char = '\'';
+const REQ_MAP_STATIC_PREFIX: char = '@';
+const REQ_MAP_STATIC_BODY_DELIM: char = ':';
+
+// Define directives
+// Sample of map entry with directives: /path=path/to/file.txt delay=200ms jitter=50ms fail=5%:500 template=true download=true
+// Sample of markdown map entry: /readme=README markdown=true
+// Sample of protected map entry: /admin=admin/index.html auth=users.htpasswd
+// Sample of websocket map entry: /ws=index.html websocket=echo
+// Sample of weighted map entry sticking to the client: /ab=a.html'50,b.html'50 affinity=cookie
+// Sample of transformation directives: header=X-Env:test inject=snippet.html rewrite=http://old.local>https://new.local
+// Sample of conditional map entries, tried in order before the entry without condition: /=beta.html if=Cookie~beta=1
+// Sample of header shorthand: /report=report.pdf +Content-Disposition:attachment +Cache-Control:no-store
+// Sample of entry cached by clients: /app.3f9a2c.js=app.3f9a2c.js cache=immutable or /news=news.html cache=10m
+// Sample of entry without the security headers of the server: /embed=widget.html security=false
+// Sample of entry limited to networks: /admin=admin/index.html allow=10.0.0.0/8,192.168.1.0/24 deny=10.0.9.0/24
+// Sample of proxy entry with active health checks: /api=http://10.0.0.1:8080/api'1,http://10.0.0.2:8080/api'1 health=/healthz:5s
+// Sample of proxy entry cached for a fixed time whatever the upstream allows: /news=http://127.0.0.1:8080/news ttl=1m
+// Sample of proxy entry with timeouts and retries: /api=http://10.0.0.1:8080/api'1,http://10.0.0.2:8080/api'1 connect=1s timeout=10s retries=1
+// Sample of proxy entry streamed both ways instead of buffered: /upload=http://127.0.0.1:8080/upload stream=true
+const REQ_MAP_DIRECTIVE_DELIM: char = '=';
+const REQ_MAP_DIRECTIVE_DELAY: &str = "delay";
+const REQ_MAP_DIRECTIVE_JITTER: &str = "jitter";
+const REQ_MAP_DIRECTIVE_FAIL: &str = "fail";
+const REQ_MAP_DIRECTIVE_TEMPLATE: &str = "template";
+const REQ_MAP_DIRECTIVE_DOWNLOAD: &str = "download";
+const REQ_MAP_DIRECTIVE_MARKDOWN: &str = "markdown";
+const REQ_MAP_DIRECTIVE_AUTH: &str = "auth";
+const REQ_MAP_DIRECTIVE_WEBSOCKET: &str = "websocket";
+const REQ_MAP_DIRECTIVE_AFFINITY: &str = "affinity";
+const REQ_MAP_DIRECTIVE_HEADER: &str = "header";
+const REQ_MAP_DIRECTIVE_INJECT: &str = "inject";
+const REQ_MAP_DIRECTIVE_REWRITE: &str = "rewrite";
+const REQ_MAP_DIRECTIVE_IF: &str = "if";
+const REQ_MAP_DIRECTIVE_ALLOW: &str = "allow";
+const REQ_MAP_DIRECTIVE_DENY: &str = "deny";
+const REQ_MAP_DIRECTIVE_SECURITY: &str = "security";
+const REQ_MAP_DIRECTIVE_CACHE: &str = "cache";
+const REQ_MAP_DIRECTIVE_HEALTH: &str = "health";
+const REQ_MAP_DIRECTIVE_TTL: &str = "ttl";
+const REQ_MAP_DIRECTIVE_CONNECT: &str = "connect";
+const REQ_MAP_DIRECTIVE_TIMEOUT: &str = "timeout";
+const REQ_MAP_DIRECTIVE_RETRIES: &str = "retries";
+const REQ_MAP_DIRECTIVE_STREAM: &str = "stream";
+const REQ_MAP_DIRECTIVE_UNCACHED: &str = "uncached";
+const REQ_MAP_FAIL_RATE_DELIM: char = ':';
+const REQ_MAP_HEADER_DELIM: char = ':';
+const REQ_MAP_HEADER_PREFIX: char = '+';
+
+// Sample of template map entry with variables: /hello=tpl:hello.html name=World
+const REQ_MAP_TEMPLATE_PREFIX: &str = "tpl:";
+const REQ_MAP_REWRITE_DELIM: char = '>';
+const REQ_MAP_IF_EQUALS_DELIM: char = ':';
+const REQ_MAP_IF_CONTAINS_DELIM: char = '~';
+
+// Define global entry, only transformation directives are allowed
+// Sample of global entry: * = inject=snippets/analytics.html header=X-Frame-Options:DENY
+const REQ_MAP_GLOBAL_KEY: &str = "*";
+
+// Define fallback entries, keyed by a prefix ending with the wildcard and served to unknown paths under it
+// Sample of fallback entry for a single page app: /app/* = app/index.html
+const REQ_MAP_FALLBACK_SUFFIX: &str = "/*";
+
+// Define extension entries, keyed by a prefix and an extension and used for the paths under it without own entry
+// Sample of extension entry: /app/*.php = fastcgi://127.0.0.1:9000
+const REQ_MAP_EXTENSION_INFIX: &str = "/*.";
+
+// Define deny rules, keyed by a path or by a prefix covering the folder and everything under it
+// Sample of deny rules: /private/** = !403 and /.well-known/** = !allow to lift the refusal of hidden paths
+const REQ_MAP_DENY_PREFIX: char = '!';
+const REQ_MAP_DENY_ALLOW: &str = "allow";
+const REQ_MAP_SUBTREE_SUFFIX: &str = "/**";
+
+// Define CGI entries, executing a program relative to the root folder, which can be followed by directives
+// Sample of CGI entry: /cgi/time = !exec scripts/time.sh
+const REQ_MAP_EXEC: &str = "exec";
+
+// Define error page entries, keyed by an error status code with a single path and no directives
+// Sample of error page entry: 404 = errors/not_found.html
+const REQ_MAP_ERROR_STATUS_MIN: u16 = 400;
+const REQ_MAP_ERROR_STATUS_MAX: u16 = 599;
+const REQ_MAP_FAIL_DEFAULT_STATUS: u16 = 500;
 const STRING_INIT_SIZE: usize = crate::BUFF_INIT_SIZE / 2;
 
 log_ctx!("RequestMap");
@@ -27,15 +128,157 @@ struct RandPath {
     weight: u32,
 }
 
+#[derive(Debug)]
+struct RandUpstream {
+    upstream: Upstream,
+    weight: u32,
+}
+
 #[derive(Debug)]
 enum PathEntry {
     Single(PathBuf),
     Weighted(Vec<RandPath>),
+    Proxy(Upstream),
+    Pool(Vec<RandUpstream>), // Chosen by weight among the healthy upstreams
+    Static(u16, String),
+    Cgi(PathBuf),
+    FastCgi(FastCgiUpstream),
+}
+
+pub enum MapTarget<'a> {
+    File(&'a Path),
+    Proxy(Vec<&'a Upstream>), // In the order to try, the chosen one first
+    Static(u16, &'a str),     // Status and body
+    Cgi(&'a Path),            // Program relative to the root folder
+    FastCgi(&'a FastCgiUpstream),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FailRate {
+    pub percent: f64,
+    pub status: u16,
+}
+
+// How a client is identified to be given the same target of a weighted entry on every request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Ip,     // Address of the client
+    Cookie, // Id in a cookie served to the client
+}
+
+impl Affinity {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ip" => Some(Affinity::Ip),
+            "cookie" => Some(Affinity::Cookie),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Affinity::Ip => "ip",
+            Affinity::Cookie => "cookie",
+        }
+    }
+}
+
+// Identities of the client used by the affinity of the entry, and its request matched by the conditions
+pub struct Client<'a> {
+    pub ip: IpAddr,
+    pub cookie: &'a str,
+    pub request: &'a HttpRequest<'a>,
+}
+
+// Condition on a request header, a missing header never matches
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Equals(String, String),   // Sample: if=Host:beta.example.com
+    Contains(String, String), // Sample: if=Cookie~beta=1
+}
+
+impl Condition {
+    fn parse(s: &str) -> Option<Self> {
+        let i = s.find([REQ_MAP_IF_EQUALS_DELIM, REQ_MAP_IF_CONTAINS_DELIM])?;
+        let (name, val) = (s[..i].to_string(), s[i + 1..].to_string());
+        if name.is_empty() {
+            return None;
+        }
+        match s[i..].starts_with(REQ_MAP_IF_EQUALS_DELIM) {
+            true => Some(Condition::Equals(name, val)),
+            false => Some(Condition::Contains(name, val)),
+        }
+    }
+
+    fn matches(&self, request: &HttpRequest) -> bool {
+        match self {
+            Condition::Equals(k, v) => request.header(k).is_some_and(|h| h.trim() == v),
+            Condition::Contains(k, v) => request.header(k).is_some_and(|h| h.contains(v.as_str())),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Equals(k, v) => write!(f, "{}{}{}", k, REQ_MAP_IF_EQUALS_DELIM, v),
+            Condition::Contains(k, v) => write!(f, "{}{}{}", k, REQ_MAP_IF_CONTAINS_DELIM, v),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EntryOptions {
+    pub delay: Option<Duration>,
+    pub jitter: Option<Duration>,
+    pub fail: Option<FailRate>,
+    pub template: bool,
+    pub variables: Vec<(String, String)>, // Of templates, given as directives of unknown names
+    pub download: bool,
+    pub markdown: bool, // Render the file as markdown whatever its extension
+    pub auth: Option<PathBuf>, // Credential file relative to the root folder
+    pub websocket: Option<websocket::Handler>, // Handler of upgrade requests
+    pub affinity: Option<Affinity>, // Weighted targets are chosen randomly without affinity
+    pub conditions: Vec<Condition>, // All have to match for the entry to be used
+    pub ip_filter: IpFilter, // Networks clients may use the entry from, refused with 403
+    pub security: Option<bool>, // Whether to add the security headers, None follows the server
+    pub cache: Option<CachePolicy>, // Cache-Control of the entry, None follows the rules of the server
+    pub health: Option<HealthCheck>, // Active check of the upstreams of proxy entries
+    pub ttl: Option<Duration>, // Freshness of the cached responses of proxy entries, None follows the upstream
+    pub upstream_policy: UpstreamPolicy, // Timeouts and retries of the requests of proxy entries
+    pub stream: bool, // Relay the bodies of proxy entries as they arrive instead of buffering them
+    pub uncached: bool, // Read the file of the entry from disk on every request instead of caching it
+    pub transforms: Transforms,
+    pub(crate) rendered: RenderCache, // Of templates which do not depend on the request
+}
+
+#[derive(Debug)]
+struct MapEntry {
+    path: PathEntry,
+    options: EntryOptions,
+}
+
+// Entries of a path in file order with their method, the entries without method serve the methods which have none of their own
+#[derive(Debug, Default)]
+struct Route {
+    entries: Vec<(Option<String>, MapEntry)>,
 }
 
 #[derive(Debug)]
 pub struct RequestMap {
-    map: HashMap<String, PathEntry>,
+    map: HashMap<String, Route>,
+    global: Transforms,
+    errors: HashMap<u16, PathBuf>,
+    rules: HashMap<String, Access>,
+    has_extensions: bool, // Whether extension entries have to be looked up
+    has_proxies: bool,    // Whether requests may have to be streamed to an upstream
+}
+
+// Access to a path given by a deny rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    Deny(u16), // Status of the response
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +287,13 @@ enum ErrorKind {
     InvalidWeight(ParseIntError),
     InvalidPath,
     InvalidKey,
+    InvalidDirective(String),
+    InvalidUpstream(String),
+    InvalidGlobal,
+    InvalidErrorPage,
+    InvalidFallback,
+    InvalidStatic,
+    InvalidRule,
 }
 
 #[derive(Debug, Clone)]
@@ -62,6 +312,21 @@ impl Display for RequestMapParseError {
                 ErrorKind::InvalidWeight(e) => Cow::Owned(format!("Invalid weight ({})", e)),
                 ErrorKind::InvalidPath => Cow::Borrowed("Invalid path"),
                 ErrorKind::InvalidKey => Cow::Borrowed("Invalid key"),
+                ErrorKind::InvalidDirective(d) => Cow::Owned(format!("Invalid directive {}", d)),
+                ErrorKind::InvalidUpstream(u) => Cow::Owned(format!("Invalid upstream {}", u)),
+                ErrorKind::InvalidGlobal => {
+                    Cow::Borrowed("Global entry only accepts transformation directives")
+                }
+                ErrorKind::InvalidErrorPage => {
+                    Cow::Borrowed("Error page entry only accepts a single path")
+                }
+                ErrorKind::InvalidFallback => {
+                    Cow::Borrowed("Fallback entry only accepts file paths")
+                }
+                ErrorKind::InvalidStatic => Cow::Borrowed("Invalid static response"),
+                ErrorKind::InvalidRule => {
+                    Cow::Borrowed("Deny rule only accepts an error status or allow for a path")
+                }
             },
             self.line_num
         )
@@ -86,6 +351,9 @@ impl RequestMap {
     pub fn parse_str(map_str: &str) -> Result<Self, RequestMapParseError> {
         // Construct the map
         let mut request_map = HashMap::new();
+        let mut global = Transforms::default();
+        let mut errors = HashMap::new();
+        let mut rules = HashMap::new();
         for (line_num, line) in map_str.lines().enumerate() {
             let line_num = line_num + 1;
 
@@ -96,7 +364,7 @@ impl RequestMap {
                     line_num,
                     kind: ErrorKind::MissingDelim(REQ_MAP_KEY_VAL_DELIM),
                 })?;
-            let k = k.trim();
+            let (method, k) = split_method(k.trim());
             let v = v.trim();
 
             // Return err if v is empty
@@ -115,9 +383,231 @@ impl RequestMap {
                 });
             }
 
+            // Deny rule refuses the path and everything under it before the files are resolved
+            let rule = v.strip_prefix(REQ_MAP_DENY_PREFIX).filter(|r| !is_exec(r));
+            if let Some(rule) = rule {
+                let access = match rule {
+                    REQ_MAP_DENY_ALLOW => Some(Access::Allow),
+                    s => s
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|s| {
+                            (REQ_MAP_ERROR_STATUS_MIN..=REQ_MAP_ERROR_STATUS_MAX).contains(s)
+                        })
+                        .map(Access::Deny),
+                };
+                match access {
+                    Some(a) if method.is_none() && k.starts_with('/') => {
+                        rules.insert(k.to_string(), a);
+                        continue;
+                    }
+                    _ => {
+                        return Err(RequestMapParseError {
+                            line_num,
+                            kind: ErrorKind::InvalidRule,
+                        })
+                    }
+                }
+            }
+
+            // CGI entry executes the program, the rest of the value are directives
+            let (program, v) = match v.strip_prefix(REQ_MAP_DENY_PREFIX) {
+                Some(exec) => {
+                    let mut tokens = exec[REQ_MAP_EXEC.len()..]
+                        .trim_start()
+                        .splitn(2, char::is_whitespace);
+                    let program = tokens
+                        .next()
+                        .filter(|p| !p.is_empty() && !p.contains(REQ_MAP_DIRECTIVE_DELIM));
+                    let program = program.ok_or(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidPath,
+                    })?;
+                    (
+                        Some(PathBuf::from(program)),
+                        tokens.next().unwrap_or_default().trim(),
+                    )
+                }
+                None => (None, v),
+            };
+
+            // Take the static response out first, as its body may contain spaces and delimiters
+            let (fixed, v) = match v.strip_prefix(REQ_MAP_STATIC_PREFIX) {
+                Some(v) => {
+                    let (status, body, v) = parse_static(v).ok_or(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidStatic,
+                    })?;
+                    (Some((status, body)), v)
+                }
+                None => (None, v),
+            };
+
+            // Split directives from paths
+            let (v, options) =
+                parse_directives(v).map_err(|kind| RequestMapParseError { line_num, kind })?;
+
+            // Only proxy entries have upstreams to check and responses to cache
+            let is_proxy = fixed.is_none()
+                && program.is_none()
+                && !v.is_empty()
+                && v.split(REQ_MAP_VAL_DELIM)
+                    .all(|p| Upstream::is_upstream(p.trim()));
+            let proxy_only = [
+                (REQ_MAP_DIRECTIVE_HEALTH, options.health.is_some()),
+                (REQ_MAP_DIRECTIVE_TTL, options.ttl.is_some()),
+                (
+                    REQ_MAP_DIRECTIVE_CONNECT,
+                    options.upstream_policy.connect_timeout.is_some(),
+                ),
+                (
+                    REQ_MAP_DIRECTIVE_TIMEOUT,
+                    options.upstream_policy.timeout.is_some(),
+                ),
+                (
+                    REQ_MAP_DIRECTIVE_RETRIES,
+                    options.upstream_policy.retries > 0,
+                ),
+                (REQ_MAP_DIRECTIVE_STREAM, options.stream),
+            ];
+            if let Some((name, _)) = proxy_only.iter().find(|(_, set)| *set && !is_proxy) {
+                return Err(RequestMapParseError {
+                    line_num,
+                    kind: ErrorKind::InvalidDirective(name.to_string()),
+                });
+            }
+
+            // Only files are read through the file cache
+            if options.uncached && (is_proxy || fixed.is_some() || program.is_some()) {
+                return Err(RequestMapParseError {
+                    line_num,
+                    kind: ErrorKind::InvalidDirective(REQ_MAP_DIRECTIVE_UNCACHED.to_string()),
+                });
+            }
+
+            // Only paths can be limited to a method
+            if method.is_some() && (k == REQ_MAP_GLOBAL_KEY || parse_error_status(k).is_some()) {
+                return Err(RequestMapParseError {
+                    line_num,
+                    kind: ErrorKind::InvalidKey,
+                });
+            }
+
+            // Global entry applies its transformations to every response
+            if k == REQ_MAP_GLOBAL_KEY {
+                if fixed.is_some()
+                    || program.is_some()
+                    || !v.is_empty()
+                    || !options.is_transform_only()
+                {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidGlobal,
+                    });
+                }
+                global = options.transforms;
+                continue;
+            }
+
+            // Error page entry replaces the body of responses with the status
+            if let Some(status) = parse_error_status(k) {
+                let is_single = fixed.is_none()
+                    && program.is_none()
+                    && !v.is_empty()
+                    && !v.contains(REQ_MAP_VAL_DELIM)
+                    && !Upstream::is_upstream(&v)
+                    && !FastCgiUpstream::is_upstream(&v);
+                if !is_single || !options.is_transform_only() || !options.transforms.is_empty() {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidErrorPage,
+                    });
+                }
+                errors.insert(status, PathBuf::from(v));
+                continue;
+            }
+
+            // CGI entry answers with the output of the program
+            if let Some(program) = program {
+                if !v.is_empty() {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidPath,
+                    });
+                }
+                if k.ends_with(REQ_MAP_FALLBACK_SUFFIX) {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidFallback,
+                    });
+                }
+                let path = PathEntry::Cgi(program);
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
+                continue;
+            }
+
+            // Static entry answers with the status and body without touching the files
+            if let Some((status, body)) = fixed {
+                if !v.is_empty() {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidStatic,
+                    });
+                }
+                if k.ends_with(REQ_MAP_FALLBACK_SUFFIX) {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidFallback,
+                    });
+                }
+                let path = PathEntry::Static(status, body);
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
+                continue;
+            }
+            if v.is_empty() {
+                return Err(RequestMapParseError {
+                    line_num,
+                    kind: ErrorKind::InvalidPath,
+                });
+            }
+
             // Split value into paths
             let v = v.split(REQ_MAP_VAL_DELIM).collect::<Vec<&str>>();
-            if v.len() > 1 {
+            if v.len() > 1 && is_proxy {
+                if k.ends_with(REQ_MAP_FALLBACK_SUFFIX) {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidFallback,
+                    });
+                }
+
+                // Weighted upstreams
+                let mut upstreams = Vec::new();
+                for entry in v {
+                    let (url, weight) =
+                        entry
+                            .split_once(REQ_MAP_VAL_WEIGHT_DELIM)
+                            .ok_or(RequestMapParseError {
+                                line_num,
+                                kind: ErrorKind::MissingDelim(REQ_MAP_VAL_WEIGHT_DELIM),
+                            })?;
+                    let upstream = Upstream::parse(url.trim()).ok_or(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidUpstream(url.trim().to_string()),
+                    })?;
+                    let weight =
+                        weight
+                            .trim()
+                            .parse::<u32>()
+                            .map_err(|e| RequestMapParseError {
+                                line_num,
+                                kind: ErrorKind::InvalidWeight(e),
+                            })?;
+                    upstreams.push(RandUpstream { upstream, weight });
+                }
+                let path = PathEntry::Pool(upstreams);
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
+            } else if v.len() > 1 {
                 // Weighted path
                 let mut weighted_paths = Vec::new();
                 for entry in v {
@@ -144,62 +634,790 @@ impl RequestMap {
                         weight,
                     });
                 }
-                request_map.insert(k.to_string(), PathEntry::Weighted(weighted_paths));
+                let path = PathEntry::Weighted(weighted_paths);
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
+            } else if Upstream::is_upstream(v[0]) {
+                if k.ends_with(REQ_MAP_FALLBACK_SUFFIX) {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidFallback,
+                    });
+                }
+
+                // Proxy to upstream
+                let upstream = Upstream::parse(v[0]).ok_or(RequestMapParseError {
+                    line_num,
+                    kind: ErrorKind::InvalidUpstream(v[0].to_string()),
+                })?;
+                let path = PathEntry::Proxy(upstream);
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
+            } else if FastCgiUpstream::is_upstream(v[0]) {
+                if k.ends_with(REQ_MAP_FALLBACK_SUFFIX) {
+                    return Err(RequestMapParseError {
+                        line_num,
+                        kind: ErrorKind::InvalidFallback,
+                    });
+                }
+
+                // Forward to FastCGI application
+                let upstream = FastCgiUpstream::parse(v[0]).ok_or(RequestMapParseError {
+                    line_num,
+                    kind: ErrorKind::InvalidUpstream(v[0].to_string()),
+                })?;
+                let path = PathEntry::FastCgi(upstream);
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
             } else {
                 // Single path
-                request_map.insert(k.to_string(), PathEntry::Single(PathBuf::from(v[0])));
+                let path = PathEntry::Single(PathBuf::from(v[0]));
+                insert_entry(&mut request_map, k, method, MapEntry { path, options });
             }
         }
 
-        Ok(Self { map: request_map })
+        let has_extensions = request_map
+            .keys()
+            .any(|k| k.contains(REQ_MAP_EXTENSION_INFIX));
+        let has_proxies = request_map
+            .values()
+            .flat_map(|r| r.entries.iter())
+            .any(|(_, e)| !e.path.upstreams().is_empty());
+        Ok(Self {
+            map: request_map,
+            global,
+            errors,
+            rules,
+            has_extensions,
+            has_proxies,
+        })
     }
 
-    pub fn get(&self, k: &str) -> Option<&Path> {
+    pub fn get(
+        &self,
+        k: &str,
+        method: &str,
+        client: &Client,
+    ) -> Option<(MapTarget<'_>, &EntryOptions)> {
         timer!("RequestMap::get");
-        self.map.get(k).map(|p| match p {
+        if let Some(e) = self
+            .map
+            .get(k)
+            .and_then(|r| r.entry(method, client.request))
+        {
+            return Some(e.target(k, client));
+        }
+        if !self.has_extensions {
+            return None;
+        }
+
+        // Extension entry of the longest prefix containing the path, e.g. /*.php for /app/index.php
+        let (mut prefix, name) = k.rsplit_once('/')?;
+        let (_, ext) = name.rsplit_once('.')?;
+        loop {
+            let pattern = format!("{}{}{}", prefix, REQ_MAP_EXTENSION_INFIX, ext);
+            if let Some((pattern, r)) = self.map.get_key_value(&pattern) {
+                if let Some(e) = r.entry(method, client.request) {
+                    return Some(e.target(pattern, client));
+                }
+            }
+            prefix = &prefix[..prefix.rfind('/')?];
+        }
+    }
+
+    // Value of the Allow header of a path mapped for other methods only, None if the method is mapped or the path is not
+    pub fn allowed_methods(&self, k: &str, method: &str) -> Option<String> {
+        let route = self.map.get(k)?;
+        if route
+            .entries
+            .iter()
+            .any(|(m, _)| accepts(m.as_deref(), method))
+        {
+            return None;
+        }
+        let mut methods = route
+            .entries
+            .iter()
+            .filter_map(|(m, _)| m.as_deref())
+            .collect::<Vec<_>>();
+        if methods.contains(&"GET") {
+            methods.push("HEAD");
+        }
+        methods.sort();
+        methods.dedup();
+        Some(methods.join(", "))
+    }
+
+    // Entry of the longest fallback prefix containing the path, e.g. /app/* for /app/users/1
+    pub fn fallback(
+        &self,
+        path: &str,
+        method: &str,
+        client: &Client,
+    ) -> Option<(&Path, &EntryOptions)> {
+        timer!("RequestMap::fallback");
+        let mut prefix = path;
+        while let Some(i) = prefix.rfind('/') {
+            prefix = &prefix[..i];
+            let k = format!("{}{}", prefix, REQ_MAP_FALLBACK_SUFFIX);
+            if let Some(e) = self
+                .map
+                .get(&k)
+                .and_then(|r| r.entry(method, client.request))
+            {
+                return match e.target(&k, client) {
+                    (MapTarget::File(p), options) => Some((p, options)),
+                    _ => None,
+                };
+            }
+        }
+        None
+    }
+
+    // Rule of the path, the exact one or the one of the longest prefix, None if no rule covers it
+    pub fn access(&self, path: &str) -> Option<Access> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        if let Some(a) = self.rules.get(path) {
+            return Some(*a);
+        }
+        let mut prefix = path.trim_end_matches('/');
+        loop {
+            let k = format!("{}{}", prefix, REQ_MAP_SUBTREE_SUFFIX);
+            if let Some(a) = self.rules.get(&k) {
+                return Some(*a);
+            }
+            match prefix.rfind('/') {
+                Some(i) => prefix = &prefix[..i],
+                None => return None,
+            }
+        }
+    }
+
+    // Keys which are direct children of the path, deeper keys are represented by their first folder
+    pub fn children(&self, path: &str) -> Vec<(&str, bool)> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        let mut children = self
+            .map
+            .keys()
+            .filter_map(|k| k.strip_prefix(&prefix))
+            .filter(|r| !r.is_empty() && !r.starts_with('*'))
+            .map(|r| match r.split_once('/') {
+                Some((d, _)) => (d, true),
+                None => (r, false),
+            })
+            .collect::<Vec<_>>();
+        children.sort();
+        children.dedup();
+        children
+    }
+
+    // Check the upstreams of the entries with a health check until the map is dropped
+    pub fn start_health_checks(&self) {
+        let entries = self
+            .map
+            .values()
+            .flat_map(|r| r.entries.iter().map(|(_, e)| e));
+        for e in entries {
+            if let Some(h) = &e.options.health {
+                for u in e.path.upstreams() {
+                    h.spawn(u);
+                }
+            }
+        }
+    }
+
+    pub fn has_proxies(&self) -> bool {
+        self.has_proxies
+    }
+
+    pub fn global_transforms(&self) -> &Transforms {
+        &self.global
+    }
+
+    pub fn error_page(&self, status: u16) -> Option<&Path> {
+        self.errors.get(&status).map(|p| p.as_path())
+    }
+}
+
+// Value of a CGI entry after the deny prefix, e.g. exec scripts/time.sh
+fn is_exec(v: &str) -> bool {
+    v.strip_prefix(REQ_MAP_EXEC)
+        .is_some_and(|r| r.starts_with(char::is_whitespace))
+}
+
+// Split the method off a key, the first word is only a method if it is in upper case, e.g. GET /path
+fn split_method(k: &str) -> (Option<&str>, &str) {
+    match k.split_once(char::is_whitespace) {
+        Some((m, p)) if m.bytes().all(|b| b.is_ascii_uppercase()) => (Some(m), p.trim()),
+        _ => (None, k),
+    }
+}
+
+// Later entries without condition of the same path and method replace earlier ones
+fn insert_entry(map: &mut HashMap<String, Route>, k: &str, method: Option<&str>, entry: MapEntry) {
+    let route = map.entry(k.to_string()).or_default();
+    if entry.options.conditions.is_empty() {
+        route
+            .entries
+            .retain(|(m, e)| m.as_deref() != method || !e.options.conditions.is_empty());
+    }
+    route.entries.push((method.map(|m| m.to_string()), entry));
+}
+
+// HEAD requests are served by the GET entries unless mapped on their own
+fn accepts(entry_method: Option<&str>, method: &str) -> bool {
+    match entry_method {
+        Some(m) => m == method || (method == "HEAD" && m == "GET"),
+        None => true,
+    }
+}
+
+impl Route {
+    // Entries of the method come before the ones of GET for HEAD requests and the ones without method
+    // Within each, the first entry whose conditions all match is used, then the one without condition
+    fn entry(&self, method: &str, request: &HttpRequest) -> Option<&MapEntry> {
+        let find = |group: Option<&str>| {
+            let entries = self
+                .entries
+                .iter()
+                .filter(move |(m, _)| m.as_deref() == group)
+                .map(|(_, e)| e);
+            entries
+                .clone()
+                .find(|e| {
+                    let conditions = &e.options.conditions;
+                    !conditions.is_empty() && conditions.iter().all(|c| c.matches(request))
+                })
+                .or_else(|| entries.clone().find(|e| e.options.conditions.is_empty()))
+        };
+        find(Some(method))
+            .or_else(|| match method {
+                "HEAD" => find(Some("GET")),
+                _ => None,
+            })
+            .or_else(|| find(None))
+    }
+}
+
+// Status and optional quoted body of a static entry followed by the rest of the value, e.g. 200:"OK\n"
+// The body is unescaped from \n, \r, \t, \" and \\
+fn parse_static(v: &str) -> Option<(u16, String, &str)> {
+    let end = v.find(|c: char| !c.is_ascii_digit()).unwrap_or(v.len());
+    let status = v[..end].parse::<u16>().ok()?;
+    if !(200..=599).contains(&status) {
+        return None;
+    }
+    let rest = &v[end..];
+    let quoted = match rest.strip_prefix(REQ_MAP_STATIC_BODY_DELIM) {
+        Some(q) => q.strip_prefix('"')?,
+        None if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            return Some((status, String::new(), rest.trim()))
+        }
+        None => return None,
+    };
+    let mut body = String::with_capacity(quoted.len());
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let rest = &quoted[i + 1..];
+                return match rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                    true => Some((status, body, rest.trim())),
+                    false => None,
+                };
+            }
+            '\\' => body.push(match chars.next()?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => body.push(c),
+        }
+    }
+    None
+}
+
+fn escape_static(body: &str) -> String {
+    let mut res = String::with_capacity(body.len() + 2);
+    res.push('"');
+    for c in body.chars() {
+        match c {
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn parse_error_status(k: &str) -> Option<u16> {
+    let status = k.parse::<u16>().ok()?;
+    (REQ_MAP_ERROR_STATUS_MIN..=REQ_MAP_ERROR_STATUS_MAX)
+        .contains(&status)
+        .then_some(status)
+}
+
+impl MapEntry {
+    fn target(&self, k: &str, client: &Client) -> (MapTarget<'_>, &EntryOptions) {
+        // Hash the client with the key so that entries split clients independently
+        let seed = match self.options.affinity {
+            Some(Affinity::Ip) => Some(fnv1a(&[k.as_bytes(), &client.ip.to_string().into_bytes()])),
+            Some(Affinity::Cookie) => Some(fnv1a(&[k.as_bytes(), client.cookie.as_bytes()])),
+            None => None,
+        };
+        (self.path.choose(seed), &self.options)
+    }
+}
+
+impl PathEntry {
+    fn choose(&self, seed: Option<u64>) -> MapTarget<'_> {
+        match self {
             // Return path directly if it is single
-            PathEntry::Single(p) => p.as_path(),
+            PathEntry::Single(p) => MapTarget::File(p.as_path()),
+
+            // Return upstream directly if it is proxy
+            PathEntry::Proxy(u) => MapTarget::Proxy(vec![u]),
+
+            // Return the response directly if it is static
+            PathEntry::Static(s, b) => MapTarget::Static(*s, b),
+
+            // Return program directly if it is CGI
+            PathEntry::Cgi(p) => MapTarget::Cgi(p.as_path()),
+
+            // Return application directly if it is FastCGI
+            PathEntry::FastCgi(u) => MapTarget::FastCgi(u),
 
             // Choose a random path based on weight
             PathEntry::Weighted(p) => {
-                // Calculate total weight
-                let total_weight = p
+                let rp = choose_weighted(p, |rp| rp.weight, seed);
+                MapTarget::File(rp.path.as_path())
+            }
+
+            // Choose a random upstream among the healthy ones, or among all if none is
+            PathEntry::Pool(u) => {
+                let mut candidates = u
                     .iter()
-                    .map(|p| p.weight)
-                    .reduce(|acc, cur| acc + cur)
-                    .unwrap();
-
-                // Generate a random number
-                let mut rand_num = thread_rng().gen_range(0..total_weight);
-                debug!("Random number: {}", rand_num);
-
-                // Choose a path based on random number
-                for rp in p {
-                    if rand_num < rp.weight {
-                        return rp.path.as_path();
+                    .filter(|ru| ru.weight > 0 && ru.upstream.is_healthy())
+                    .collect::<Vec<_>>();
+                if candidates.is_empty() {
+                    debug!("{}", "No healthy upstream, choosing among all");
+                    candidates = u.iter().collect();
+                }
+                let ru = choose_weighted(&candidates, |ru| ru.weight, seed);
+
+                // The healthy upstreams after the chosen one are tried next
+                let i = u.iter().position(|c| std::ptr::eq(c, *ru)).unwrap_or(0);
+                let next = u[i + 1..].iter().chain(&u[..i]);
+                let next = next
+                    .filter(|c| c.upstream.is_healthy())
+                    .map(|c| &c.upstream);
+                MapTarget::Proxy(std::iter::once(&ru.upstream).chain(next).collect())
+            }
+        }
+    }
+
+    fn upstreams(&self) -> Vec<&Upstream> {
+        match self {
+            PathEntry::Proxy(u) => vec![u],
+            PathEntry::Pool(u) => u.iter().map(|ru| &ru.upstream).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Choose an item by weight, the items without weight are only chosen if no item has any
+fn choose_weighted<T>(items: &[T], weight: impl Fn(&T) -> u32, seed: Option<u64>) -> &T {
+    // Calculate total weight
+    let total_weight = items.iter().map(&weight).sum::<u32>();
+    if total_weight == 0 {
+        return &items[0];
+    }
+
+    // Generate a random number, or derive it from the client for affinity
+    let mut rand_num = match seed {
+        Some(s) => (s % total_weight as u64) as u32,
+        None => thread_rng().gen_range(0..total_weight),
+    };
+    debug!("Random number: {}", rand_num);
+
+    // Choose an item based on random number
+    for item in items {
+        if rand_num < weight(item) {
+            return item;
+        }
+        rand_num -= weight(item);
+    }
+    panic!("Random number out of range");
+}
+
+fn parse_directives(v: &str) -> Result<(String, EntryOptions), ErrorKind> {
+    let mut options = EntryOptions::default();
+    let mut paths = String::with_capacity(v.len());
+    let mut unknown = None; // First directive which is not known, a variable if the entry is a template
+    for token in v.split_whitespace() {
+        // Header shorthand is checked first, as header values may contain the directive delimiter
+        if let Some(h) = token.strip_prefix(REQ_MAP_HEADER_PREFIX) {
+            let (k, v) = h
+                .split_once(REQ_MAP_HEADER_DELIM)
+                .filter(|(k, _)| !k.is_empty())
+                .ok_or_else(|| ErrorKind::InvalidDirective(token.to_string()))?;
+            options
+                .transforms
+                .headers
+                .push((k.to_string(), v.to_string()));
+            continue;
+        }
+
+        // Tokens without directive delimiter belong to the paths
+        let (name, val) = match token.split_once(REQ_MAP_DIRECTIVE_DELIM) {
+            Some(d) => d,
+            None => {
+                // The template shorthand marks the paths as template
+                let token = match token.strip_prefix(REQ_MAP_TEMPLATE_PREFIX) {
+                    Some(t) if paths.is_empty() => {
+                        options.template = true;
+                        t
                     }
-                    rand_num -= rp.weight;
+                    _ => token,
+                };
+                if !paths.is_empty() {
+                    paths.push(' ');
                 }
-                panic!("Random number out of range");
+                paths.push_str(token);
+                continue;
             }
-        })
+        };
+
+        // Parse the directive
+        let invalid = || ErrorKind::InvalidDirective(token.to_string());
+        match name {
+            REQ_MAP_DIRECTIVE_DELAY => {
+                options.delay = Some(parse_duration(val).ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_JITTER => {
+                options.jitter = Some(parse_duration(val).ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_FAIL => {
+                // Split into rate and optional status
+                let (rate, status) = match val.split_once(REQ_MAP_FAIL_RATE_DELIM) {
+                    Some((r, s)) => (r, s.parse::<u16>().map_err(|_| invalid())?),
+                    None => (val, REQ_MAP_FAIL_DEFAULT_STATUS),
+                };
+                let percent = rate
+                    .strip_suffix('%')
+                    .and_then(|r| r.parse::<f64>().ok())
+                    .ok_or_else(invalid)?;
+                if !(0. ..=100.).contains(&percent) || !(100..=599).contains(&status) {
+                    return Err(invalid());
+                }
+                options.fail = Some(FailRate { percent, status });
+            }
+            REQ_MAP_DIRECTIVE_TEMPLATE => options.template = val.parse().map_err(|_| invalid())?,
+            REQ_MAP_DIRECTIVE_DOWNLOAD => options.download = val.parse().map_err(|_| invalid())?,
+            REQ_MAP_DIRECTIVE_MARKDOWN => options.markdown = val.parse().map_err(|_| invalid())?,
+            REQ_MAP_DIRECTIVE_AUTH if !val.is_empty() => options.auth = Some(PathBuf::from(val)),
+            REQ_MAP_DIRECTIVE_AFFINITY => {
+                options.affinity = Some(Affinity::parse(val).ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_WEBSOCKET => {
+                options.websocket = Some(websocket::Handler::parse(val).ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_HEADER => {
+                let (k, v) = val.split_once(REQ_MAP_HEADER_DELIM).ok_or_else(invalid)?;
+                if k.is_empty() {
+                    return Err(invalid());
+                }
+                options
+                    .transforms
+                    .headers
+                    .push((k.to_string(), v.to_string()));
+            }
+            REQ_MAP_DIRECTIVE_INJECT if !val.is_empty() => {
+                options.transforms.inject = Some(PathBuf::from(val))
+            }
+            REQ_MAP_DIRECTIVE_CACHE => {
+                options.cache = Some(CachePolicy::parse(val).ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_HEALTH => {
+                options.health = Some(HealthCheck::parse(val).ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_TTL => options.ttl = Some(parse_duration(val).ok_or_else(invalid)?),
+            REQ_MAP_DIRECTIVE_CONNECT => {
+                let t = parse_duration(val).filter(|t| !t.is_zero());
+                options.upstream_policy.connect_timeout = Some(t.ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_TIMEOUT => {
+                let t = parse_duration(val).filter(|t| !t.is_zero());
+                options.upstream_policy.timeout = Some(t.ok_or_else(invalid)?)
+            }
+            REQ_MAP_DIRECTIVE_STREAM => options.stream = val.parse().map_err(|_| invalid())?,
+            REQ_MAP_DIRECTIVE_UNCACHED => options.uncached = val.parse().map_err(|_| invalid())?,
+            REQ_MAP_DIRECTIVE_RETRIES => {
+                options.upstream_policy.retries = val.parse().map_err(|_| invalid())?
+            }
+            REQ_MAP_DIRECTIVE_SECURITY => {
+                options.security = Some(val.parse().map_err(|_| invalid())?)
+            }
+            REQ_MAP_DIRECTIVE_ALLOW => options
+                .ip_filter
+                .allow
+                .extend(IpFilter::parse_list(val).ok_or_else(invalid)?),
+            REQ_MAP_DIRECTIVE_DENY => options
+                .ip_filter
+                .deny
+                .extend(IpFilter::parse_list(val).ok_or_else(invalid)?),
+            REQ_MAP_DIRECTIVE_IF => options
+                .conditions
+                .push(Condition::parse(val).ok_or_else(invalid)?),
+            REQ_MAP_DIRECTIVE_REWRITE => {
+                let (from, to) = val.split_once(REQ_MAP_REWRITE_DELIM).ok_or_else(invalid)?;
+                if from.is_empty() {
+                    return Err(invalid());
+                }
+                options
+                    .transforms
+                    .rewrites
+                    .push((from.to_string(), to.to_string()));
+            }
+            _ if template::is_variable_name(name) => {
+                unknown.get_or_insert_with(|| token.to_string());
+                options.variables.push((name.to_string(), val.to_string()));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    match unknown {
+        Some(token) if !options.template => Err(ErrorKind::InvalidDirective(token)),
+        _ => Ok((paths, options)),
     }
 }
 
+impl EntryOptions {
+    fn is_transform_only(&self) -> bool {
+        self.delay.is_none()
+            && self.jitter.is_none()
+            && self.fail.is_none()
+            && !self.template
+            && !self.download
+            && !self.markdown
+            && self.auth.is_none()
+            && self.websocket.is_none()
+            && self.affinity.is_none()
+            && self.conditions.is_empty()
+            && self.ip_filter.is_empty()
+            && self.security.is_none()
+            && self.cache.is_none()
+            && self.health.is_none()
+            && self.ttl.is_none()
+            && self.upstream_policy == UpstreamPolicy::default()
+            && !self.stream
+            && !self.uncached
+    }
+}
+
+impl Display for Transforms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (k, v) in &self.headers {
+            write!(
+                f,
+                " {}={}{}{}",
+                REQ_MAP_DIRECTIVE_HEADER, k, REQ_MAP_HEADER_DELIM, v
+            )?;
+        }
+        if let Some(p) = &self.inject {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_INJECT, p.display())?;
+        }
+        for (from, to) in &self.rewrites {
+            write!(
+                f,
+                " {}={}{}{}",
+                REQ_MAP_DIRECTIVE_REWRITE, from, REQ_MAP_REWRITE_DELIM, to
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for EntryOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(d) = self.delay {
+            write!(f, " {}={}ms", REQ_MAP_DIRECTIVE_DELAY, d.as_millis())?;
+        }
+        if let Some(j) = self.jitter {
+            write!(f, " {}={}ms", REQ_MAP_DIRECTIVE_JITTER, j.as_millis())?;
+        }
+        if let Some(r) = self.fail {
+            write!(f, " {}={}%:{}", REQ_MAP_DIRECTIVE_FAIL, r.percent, r.status)?;
+        }
+        if self.template {
+            write!(f, " {}=true", REQ_MAP_DIRECTIVE_TEMPLATE)?;
+        }
+        for (k, v) in &self.variables {
+            write!(f, " {}={}", k, v)?;
+        }
+        if self.download {
+            write!(f, " {}=true", REQ_MAP_DIRECTIVE_DOWNLOAD)?;
+        }
+        if self.markdown {
+            write!(f, " {}=true", REQ_MAP_DIRECTIVE_MARKDOWN)?;
+        }
+        if let Some(p) = &self.auth {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_AUTH, p.display())?;
+        }
+        if let Some(a) = self.affinity {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_AFFINITY, a.name())?;
+        }
+        if let Some(h) = self.websocket {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_WEBSOCKET, h.name())?;
+        }
+        for c in &self.conditions {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_IF, c)?;
+        }
+        if let Some(c) = self.cache {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_CACHE, c)?;
+        }
+        if let Some(s) = self.security {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_SECURITY, s)?;
+        }
+        if let Some(h) = &self.health {
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_HEALTH, h)?;
+        }
+        if let Some(t) = self.ttl {
+            write!(f, " {}={}ms", REQ_MAP_DIRECTIVE_TTL, t.as_millis())?;
+        }
+        if let Some(t) = self.upstream_policy.connect_timeout {
+            write!(f, " {}={}ms", REQ_MAP_DIRECTIVE_CONNECT, t.as_millis())?;
+        }
+        if let Some(t) = self.upstream_policy.timeout {
+            write!(f, " {}={}ms", REQ_MAP_DIRECTIVE_TIMEOUT, t.as_millis())?;
+        }
+        if self.upstream_policy.retries > 0 {
+            write!(
+                f,
+                " {}={}",
+                REQ_MAP_DIRECTIVE_RETRIES, self.upstream_policy.retries
+            )?;
+        }
+        if self.stream {
+            write!(f, " {}=true", REQ_MAP_DIRECTIVE_STREAM)?;
+        }
+        if self.uncached {
+            write!(f, " {}=true", REQ_MAP_DIRECTIVE_UNCACHED)?;
+        }
+        if !self.ip_filter.allow.is_empty() {
+            let allow = IpFilter::fmt_list(&self.ip_filter.allow);
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_ALLOW, allow)?;
+        }
+        if !self.ip_filter.deny.is_empty() {
+            let deny = IpFilter::fmt_list(&self.ip_filter.deny);
+            write!(f, " {}={}", REQ_MAP_DIRECTIVE_DENY, deny)?;
+        }
+        write!(f, "{}", self.transforms)
+    }
+}
+
+// Serialize to the canonical map file format with sorted keys, which parses back to the same map
 impl Display for RequestMap {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (k, v) in &self.map {
-            match v {
-                PathEntry::Single(p) => writeln!(f, "{} -> {}", k, p.display())?,
-                PathEntry::Weighted(p) => {
-                    let mut line = String::with_capacity(STRING_INIT_SIZE);
-                    line.push_str(&format!("{} -> ", k));
-                    for rp in p {
-                        line.push_str(&format!("{}'{} ", rp.path.display(), rp.weight));
+        if !self.global.is_empty() {
+            writeln!(
+                f,
+                "{} {}{}",
+                REQ_MAP_GLOBAL_KEY, REQ_MAP_KEY_VAL_DELIM, self.global
+            )?;
+        }
+        let mut statuses = self.errors.keys().collect::<Vec<_>>();
+        statuses.sort();
+        for status in statuses {
+            writeln!(
+                f,
+                "{} {} {}",
+                status,
+                REQ_MAP_KEY_VAL_DELIM,
+                self.errors[status].display()
+            )?;
+        }
+        let mut rules = self.rules.iter().collect::<Vec<_>>();
+        rules.sort_by_key(|(k, _)| *k);
+        for (k, a) in rules {
+            match a {
+                Access::Allow => writeln!(
+                    f,
+                    "{} {} {}{}",
+                    k, REQ_MAP_KEY_VAL_DELIM, REQ_MAP_DENY_PREFIX, REQ_MAP_DENY_ALLOW
+                )?,
+                Access::Deny(s) => writeln!(
+                    f,
+                    "{} {} {}{}",
+                    k, REQ_MAP_KEY_VAL_DELIM, REQ_MAP_DENY_PREFIX, s
+                )?,
+            }
+        }
+        let mut entries = self
+            .map
+            .iter()
+            .flat_map(|(k, r)| {
+                r.entries.iter().map(move |(m, e)| match m {
+                    Some(m) => (format!("{} {}", m, k), e),
+                    None => (k.to_string(), e),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The sort is stable, so conditional entries of a key keep their order
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (k, v) in entries {
+            let mut line = String::with_capacity(STRING_INIT_SIZE);
+            line.push_str(&format!("{} {} ", k, REQ_MAP_KEY_VAL_DELIM));
+            match &v.path {
+                PathEntry::Single(p) => line.push_str(&p.display().to_string()),
+                PathEntry::Proxy(u) => line.push_str(&u.to_string()),
+                PathEntry::FastCgi(u) => line.push_str(&u.to_string()),
+                PathEntry::Cgi(p) => line.push_str(&format!(
+                    "{}{} {}",
+                    REQ_MAP_DENY_PREFIX,
+                    REQ_MAP_EXEC,
+                    p.display()
+                )),
+                PathEntry::Static(status, body) => {
+                    line.push_str(&format!("{}{}", REQ_MAP_STATIC_PREFIX, status));
+                    if !body.is_empty() {
+                        line.push(REQ_MAP_STATIC_BODY_DELIM);
+                        line.push_str(&escape_static(body));
                     }
-                    writeln!(f, "{}", line)?;
+                }
+                PathEntry::Pool(u) => {
+                    let upstreams = u
+                        .iter()
+                        .map(|ru| {
+                            format!("{}{}{}", ru.upstream, REQ_MAP_VAL_WEIGHT_DELIM, ru.weight)
+                        })
+                        .collect::<Vec<_>>();
+                    line.push_str(&upstreams.join(&REQ_MAP_VAL_DELIM.to_string()));
+                }
+                PathEntry::Weighted(p) => {
+                    let paths = p
+                        .iter()
+                        .map(|rp| {
+                            format!(
+                                "{}{}{}",
+                                rp.path.display(),
+                                REQ_MAP_VAL_WEIGHT_DELIM,
+                                rp.weight
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    line.push_str(&paths.join(&REQ_MAP_VAL_DELIM.to_string()));
                 }
             }
+            writeln!(f, "{}{}", line, v.options)?;
         }
         Ok(())
     }
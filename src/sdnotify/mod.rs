@@ -0,0 +1,154 @@
+use std::io;
+
+use crate::{debug, log_ctx};
+
+// Socket path provided by systemd for Type=notify units
+#[cfg(unix)]
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+// Sockets passed by systemd socket units start at this fd, see sd_listen_fds(3)
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: i32 = 3;
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+#[cfg(target_os = "linux")]
+const LISTEN_PID_ENV: &str = "LISTEN_PID";
+#[cfg(target_os = "linux")]
+const LISTEN_FDNAMES_ENV: &str = "LISTEN_FDNAMES";
+log_ctx!("SdNotify");
+
+// Connection to the notify socket, made ahead of time by connect or on the first notify
+#[cfg(unix)]
+static SOCKET: std::sync::OnceLock<Option<(String, std::os::unix::net::UnixDatagram)>> =
+    std::sync::OnceLock::new();
+
+// Connect to the notify socket of systemd, e.g. before a chroot hides its path
+// Returns false if not running under systemd
+#[cfg(unix)]
+pub fn connect() -> io::Result<bool> {
+    if let Some(s) = SOCKET.get() {
+        return Ok(s.is_some());
+    }
+    let socket = open()?;
+    let connected = socket.is_some();
+    let _ = SOCKET.set(socket);
+    Ok(connected)
+}
+
+#[cfg(unix)]
+fn open() -> io::Result<Option<(String, std::os::unix::net::UnixDatagram)>> {
+    use std::{env, os::unix::net::UnixDatagram};
+
+    let path = match env::var(NOTIFY_SOCKET_ENV) {
+        Ok(p) if !p.is_empty() => p,
+        _ => return Ok(None),
+    };
+    let socket = UnixDatagram::unbound()?;
+
+    // Paths starting with @ are in the abstract namespace
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.connect_addr(&addr)?;
+        }
+        _ => {
+            socket.connect(&path)?;
+        }
+    }
+    Ok(Some((path, socket)))
+}
+
+// Send a state string (e.g. READY=1) to systemd, returns false if not running under systemd
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    if !connect()? {
+        return Ok(false);
+    }
+    let Some((path, socket)) = SOCKET.get().and_then(|s| s.as_ref()) else {
+        return Ok(false);
+    };
+    socket.send(state.as_bytes())?;
+    debug!("Notified {}: {}", path, state.replace('\n', " "));
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn connect() -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    debug!("{}", "Notify is not supported on this platform");
+    Ok(false)
+}
+
+// Take the listening sockets passed by systemd socket activation, empty if none were passed
+// The variables are removed so that child processes such as CGI scripts do not take them as well
+#[cfg(target_os = "linux")]
+pub fn listen_fds() -> io::Result<Vec<std::net::TcpListener>> {
+    use std::{env, os::fd::FromRawFd};
+
+    // The sockets are meant for this process only if the pid matches
+    let pid = env::var(LISTEN_PID_ENV).ok();
+    let count = env::var(LISTEN_FDS_ENV).ok();
+    env::remove_var(LISTEN_PID_ENV);
+    env::remove_var(LISTEN_FDS_ENV);
+    env::remove_var(LISTEN_FDNAMES_ENV);
+    if pid.and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count = match count.and_then(|c| c.parse::<i32>().ok()) {
+        Some(c) if c > 0 => c,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut listeners = Vec::with_capacity(count as usize);
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        if !is_tcp_listener(fd) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Inherited fd {} is not a listening TCP socket", fd),
+            ));
+        }
+
+        // Keep the sockets from leaking into child processes
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        debug!("Inherited fd {} @{}", fd, listener.local_addr()?);
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listen_fds() -> io::Result<Vec<std::net::TcpListener>> {
+    Ok(Vec::new())
+}
+
+// Whether the fd is a stream socket of IPv4 or IPv6 which is listening
+#[cfg(target_os = "linux")]
+fn is_tcp_listener(fd: i32) -> bool {
+    let get = |opt| {
+        let mut val: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                &mut val as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (res == 0).then_some(val)
+    };
+    matches!(get(libc::SO_DOMAIN), Some(libc::AF_INET | libc::AF_INET6))
+        && get(libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+        && get(libc::SO_ACCEPTCONN) == Some(1)
+}
@@ -0,0 +1,77 @@
+use std::fmt::Display;
+
+use crate::{headermap, http::HttpResponse};
+
+// Sample of a header of the preset: Content-Security-Policy: default-src 'self' cdn.example.com
+const HEADER_DELIM: char = ':';
+pub const CSP_HEADER: &str = "Content-Security-Policy";
+
+// Preset hardening browsers against downgrades, sniffing, framing, leaked referrers and injected content
+pub const DEFAULT_SECURITY_HEADERS: [(&str, &str); 5] = [
+    (
+        "Strict-Transport-Security",
+        "max-age=31536000; includeSubDomains",
+    ),
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+    (CSP_HEADER, "default-src 'self'"),
+];
+
+// Headers added to every response which does not set them itself, e.g. by a map entry or an upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            headers: DEFAULT_SECURITY_HEADERS
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    // Replace or add a header of the preset, an empty value removes it
+    // Sample: X-Frame-Options: SAMEORIGIN or Content-Security-Policy:
+    pub fn set(&mut self, s: &str) -> Option<()> {
+        let (name, val) = s.split_once(HEADER_DELIM)?;
+        let (name, val) = (name.trim(), val.trim());
+        if !headermap::is_valid_name(name) {
+            return None;
+        }
+        self.remove(name);
+        if !val.is_empty() {
+            self.headers.push((name.to_string(), val.to_string()));
+        }
+        Some(())
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn apply(&self, mut res: HttpResponse) -> HttpResponse {
+        for (k, v) in &self.headers {
+            if res.header(k).is_none() {
+                res = res.with_header(k.clone(), v.clone());
+            }
+        }
+        res
+    }
+}
+
+impl Display for SecurityHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.headers.iter().map(|(k, _)| k.as_str());
+        write!(f, "{}", names.collect::<Vec<_>>().join(", "))
+    }
+}
@@ -0,0 +1,54 @@
+use std::os::fd::AsRawFd;
+
+use tokio::{
+    fs::File,
+    io::{self, Interest},
+    net::TcpStream,
+};
+
+use crate::{log_ctx, trace};
+
+// Bytes handed to the kernel per call, so that a slow client does not hold a single call for long
+const SENDFILE_CHUNK_SIZE: usize = 1024 * 1024;
+
+log_ctx!("Sendfile");
+
+// Send the first len bytes of the file to the socket without copying them through userspace
+// Exactly len bytes are sent, as promised by the Content-Length, a file shrinking meanwhile fails
+pub async fn send(socket: &TcpStream, file: &File, len: usize) -> io::Result<()> {
+    let mut offset: libc::off_t = 0;
+    let mut remaining = len;
+    while remaining > 0 {
+        socket.writable().await?;
+        let sent = socket.try_io(Interest::WRITABLE, || {
+            let count = remaining.min(SENDFILE_CHUNK_SIZE);
+            // Safety: both descriptors stay open for the call and the offset outlives it
+            let n =
+                unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+            match n {
+                n if n < 0 => Err(io::Error::last_os_error()),
+                n => Ok(n as usize),
+            }
+        });
+        match sent {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("File ended {} bytes early", remaining),
+                ))
+            }
+            Ok(n) => remaining -= n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    trace!("Sent {} bytes with sendfile", len);
+    Ok(())
+}
@@ -0,0 +1,1070 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{
+    fs::read_to_string,
+    io,
+    net::{TcpListener, TcpSocket},
+    select,
+    sync::Notify,
+    task::{self, JoinSet},
+};
+
+#[cfg(all(target_os = "linux", feature = "uring"))]
+use crate::uring;
+use crate::{
+    accesslog::AccessLog,
+    admin,
+    cachecontrol::CacheRules,
+    connlimit::ConnectionLimit,
+    debug, error,
+    filecache::{Admission, CacheTtl},
+    fswatcher::{setup_fs_watcher, watch_ignore_pattern},
+    http::{handle_connection, refuse},
+    info,
+    ipfilter::{Cidr, IpFilter},
+    livereload::LiveReload,
+    log_ctx,
+    markdown::Markdown,
+    metrics::{self, Metrics, MetricsEndpoint},
+    middleware::Middleware,
+    redirect::{self, Redirect},
+    requestlog::{RequestLog, StatusFilter},
+    requestmap::RequestMap,
+    sdnotify,
+    security::SecurityHeaders,
+    signal::setup_sighup_handler,
+    state::{load_tenants, parse_tenants, Preload, ServerState, Site},
+    upload::Upload,
+    util, warn,
+};
+
+// Defaults of the builder
+pub const DEFAULT_PORT: u16 = 3006;
+pub const DEFAULT_FILE_CACHE_SIZE: usize = 100 * 1024 * 1024;
+pub const DEFAULT_MAX_CACHEABLE_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_BODY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+pub const DEFAULT_WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+pub const DEFAULT_WATCH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+pub const DEFAULT_WATCH_POLL_BUDGET: usize = 100_000;
+const LISTEN_BACKLOG: u32 = 1024;
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+log_ctx!("Server");
+
+// Configures a server, unset options take the defaults of the command line
+// Sample: Server::builder().root("/srv/www").port(8080).serve().await
+pub struct ServerBuilder {
+    file_root: Option<PathBuf>,
+    res_dir: Option<PathBuf>,
+    addrs: Vec<SocketAddr>,
+    ports: Vec<u16>,
+    bind_ip: IpAddr,
+    accept_workers: usize,
+    io_uring: bool,
+    socket_activation: bool,
+    max_connections: usize,
+    accept_rate: u32,
+    ip_filter: IpFilter,
+    drop_refused: bool,
+    trusted_proxies: Vec<Cidr>,
+    proxy_protocol: bool,
+    file_cache_size: usize,
+    max_cacheable_size: usize,
+    cache_admission: Admission,
+    mmap: Option<usize>,
+    cache_ttl: CacheTtl,
+    cache_exclude: Vec<String>,
+    debug_endpoints: bool,
+    dir_listing: bool,
+    idle_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    request_timeout: Duration,
+    max_header_size: usize,
+    max_header_count: usize,
+    max_body_size: usize,
+    metrics: Option<MetricsEndpoint>,
+    redirect: Option<Redirect>,
+    admin: Option<SocketAddr>,
+    upload: Option<Upload>,
+    markdown: Option<Markdown>,
+    ssi: bool,
+    precompressed: bool,
+    live_reload: bool,
+    security_headers: Option<SecurityHeaders>,
+    cache_rules: CacheRules,
+    tenants_file: Option<PathBuf>,
+    access_log: Option<PathBuf>,
+    cache_stats_interval: Duration,
+    slow_request: Duration,
+    request_log_sample: u64,
+    request_log_status: Option<StatusFilter>,
+    watch_debounce: Duration,
+    watch_poll: Option<Duration>,
+    watch_poll_budget: usize,
+    watch_ignore: Vec<String>,
+    preload: Option<Preload>,
+    cache_persist: Option<PathBuf>,
+    request_map: Option<RequestMap>,
+    index_files: Option<Vec<String>>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+// A bound server, which accepts connections once served
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    state: Arc<ServerState>,
+    cache_stats_interval: Duration,
+    io_uring: bool,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            file_root: None,
+            res_dir: None,
+            addrs: Vec::new(),
+            ports: Vec::new(),
+            bind_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            accept_workers: 1,
+            io_uring: false,
+            socket_activation: false,
+            max_connections: 0,
+            accept_rate: 0,
+            ip_filter: IpFilter::default(),
+            drop_refused: false,
+            trusted_proxies: Vec::new(),
+            proxy_protocol: false,
+            file_cache_size: DEFAULT_FILE_CACHE_SIZE,
+            max_cacheable_size: DEFAULT_MAX_CACHEABLE_SIZE,
+            cache_admission: Admission::default(),
+            mmap: None,
+            cache_ttl: CacheTtl::default(),
+            cache_exclude: Vec::new(),
+            debug_endpoints: false,
+            dir_listing: false,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            body_timeout: DEFAULT_BODY_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            metrics: None,
+            redirect: None,
+            admin: None,
+            upload: None,
+            markdown: None,
+            ssi: false,
+            precompressed: false,
+            live_reload: false,
+            security_headers: None,
+            cache_rules: CacheRules::default(),
+            tenants_file: None,
+            access_log: None,
+            cache_stats_interval: Duration::ZERO,
+            slow_request: Duration::ZERO,
+            request_log_sample: 1,
+            request_log_status: None,
+            watch_debounce: DEFAULT_WATCH_DEBOUNCE,
+            watch_poll: None,
+            watch_poll_budget: DEFAULT_WATCH_POLL_BUDGET,
+            watch_ignore: Vec::new(),
+            preload: None,
+            cache_persist: None,
+            request_map: None,
+            index_files: None,
+            middleware: Vec::new(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    // Folder of the map file and the res folder (Default to the current directory)
+    pub fn root(mut self, path: impl Into<PathBuf>) -> Self {
+        self.file_root = Some(path.into());
+        self
+    }
+
+    // Folder or archive of the files to serve (Default to res in the root folder)
+    pub fn res_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.res_dir = Some(path.into());
+        self
+    }
+
+    // Listen on the port of the bind address, zero binds to a free port, see Server::local_addrs
+    // Listeners add up, the default port is only used if none is given
+    pub fn port(mut self, port: u16) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    // Address of the ports given without one, including the metrics and redirect ports (Default to 0.0.0.0)
+    // Sample: 127.0.0.1 for loopback only or :: for all interfaces of both IPv4 and IPv6
+    pub fn bind_ip(mut self, ip: IpAddr) -> Self {
+        self.bind_ip = ip;
+        self
+    }
+
+    // Listen on the address, e.g. [::]:3006 for all IPv6 interfaces
+    pub fn listen(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    // Number of sockets sharing every address with SO_REUSEPORT, each with its own accept loop
+    pub fn accept_workers(mut self, workers: usize) -> Self {
+        self.accept_workers = workers.max(1);
+        self
+    }
+
+    // Serve the connections of every listener on its own thread with an io_uring runtime, files are read through the ring
+    // Requires the uring feature on Linux
+    pub fn io_uring(mut self, enabled: bool) -> Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    // Serve the sockets passed by systemd socket activation instead of binding the addresses, if any were passed
+    pub fn socket_activation(mut self, enabled: bool) -> Self {
+        self.socket_activation = enabled;
+        self
+    }
+
+    // Connections served at once, further connections wait in the listen backlog (Default to 0, unlimited)
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    // Connections accepted per second over all listeners, bursts of a second pass at once (Default to 0, unlimited)
+    pub fn accept_rate(mut self, rate: u32) -> Self {
+        self.accept_rate = rate;
+        self
+    }
+
+    // Networks clients may connect from, checked before anything is read (Default to all)
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = filter;
+        self
+    }
+
+    // Close connections refused by the IP filter without answering 403 (Default to false)
+    pub fn drop_refused(mut self, enabled: bool) -> Self {
+        self.drop_refused = enabled;
+        self
+    }
+
+    // Peers trusted to name the client in the Forwarded and X-Forwarded-For headers (Default to none)
+    pub fn trusted_proxies(mut self, proxies: Vec<Cidr>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    // Expect a PROXY protocol preamble from a trusted proxy on every connection (Default to false)
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    pub fn file_cache_size(mut self, size: usize) -> Self {
+        self.file_cache_size = size;
+        self
+    }
+
+    pub fn max_cacheable_size(mut self, size: usize) -> Self {
+        self.max_cacheable_size = size;
+        self
+    }
+
+    // Time after which cached files of every site are checked against the disk, by path pattern (Default to never)
+    pub fn cache_ttl(mut self, cache_ttl: CacheTtl) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    // Path patterns of every site read from disk on every request instead of cached (Default to none)
+    pub fn cache_exclude(mut self, patterns: Vec<String>) -> Self {
+        self.cache_exclude = patterns;
+        self
+    }
+
+    // Policy deciding which files enter the full file caches of every site (Default to LRU)
+    pub fn cache_admission(mut self, admission: Admission) -> Self {
+        self.cache_admission = admission;
+        self
+    }
+
+    // Serve files of at least the given size of every site from memory mappings instead of the cache, requires Linux
+    pub fn mmap(mut self, min_size: Option<usize>) -> Self {
+        self.mmap = min_size;
+        self
+    }
+
+    pub fn debug_endpoints(mut self, enabled: bool) -> Self {
+        self.debug_endpoints = enabled;
+        self
+    }
+
+    pub fn dir_listing(mut self, enabled: bool) -> Self {
+        self.dir_listing = enabled;
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.body_timeout = timeout;
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.max_header_size = size;
+        self
+    }
+
+    pub fn max_header_count(mut self, count: usize) -> Self {
+        self.max_header_count = count;
+        self
+    }
+
+    // Larger request bodies are answered with 413
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.max_body_size = size;
+        self
+    }
+
+    pub fn metrics(mut self, endpoint: MetricsEndpoint) -> Self {
+        self.metrics = Some(endpoint);
+        self
+    }
+
+    // Answer plain HTTP on another port with redirects to https, TLS is terminated in front of the server
+    pub fn redirect(mut self, redirect: Redirect) -> Self {
+        self.redirect = Some(redirect);
+        self
+    }
+
+    // Serve the admin API on a separate address, it is unauthenticated so keep it off public interfaces
+    pub fn admin(mut self, addr: SocketAddr) -> Self {
+        self.admin = Some(addr);
+        self
+    }
+
+    // Store the body of PUT requests under the upload folder of the res folder
+    pub fn upload(mut self, upload: Upload) -> Self {
+        self.upload = Some(upload);
+        self
+    }
+
+    // Render markdown files of the res folder into HTML pages
+    pub fn markdown(mut self, markdown: Markdown) -> Self {
+        self.markdown = Some(markdown);
+        self
+    }
+
+    // Process include and echo directives of .shtml pages
+    pub fn ssi(mut self, enabled: bool) -> Self {
+        self.ssi = enabled;
+        self
+    }
+
+    // Serve the precompressed .br or .gz sibling of a file, e.g. app.js.br, to clients accepting its encoding
+    pub fn precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    // Inject a script into HTML pages which reloads them when files of the res folder change
+    pub fn live_reload(mut self, enabled: bool) -> Self {
+        self.live_reload = enabled;
+        self
+    }
+
+    // Add the headers to responses which do not set them, map entries can opt out (Default to none)
+    pub fn security_headers(mut self, headers: SecurityHeaders) -> Self {
+        self.security_headers = Some(headers);
+        self
+    }
+
+    // Cache-Control by path pattern, map entries with a policy of their own take precedence (Default to none)
+    pub fn cache_rules(mut self, rules: CacheRules) -> Self {
+        self.cache_rules = rules;
+        self
+    }
+
+    pub fn tenants_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tenants_file = Some(path.into());
+        self
+    }
+
+    pub fn access_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.access_log = Some(path.into());
+        self
+    }
+
+    // Log the file cache statistics of every site on the interval, zero disables the report
+    pub fn cache_stats_interval(mut self, interval: Duration) -> Self {
+        self.cache_stats_interval = interval;
+        self
+    }
+
+    // Log the requests taking longer than the threshold in full at any log level, zero disables the log
+    pub fn slow_request(mut self, threshold: Duration) -> Self {
+        self.slow_request = threshold;
+        self
+    }
+
+    // Log 1 in n successful requests, failed and slow requests are always logged (Default to 1)
+    pub fn request_log_sample(mut self, n: u64) -> Self {
+        self.request_log_sample = n;
+        self
+    }
+
+    // Only log the requests whose response status matches the filter (Default to every status)
+    pub fn request_log_status(mut self, filter: StatusFilter) -> Self {
+        self.request_log_status = Some(filter);
+        self
+    }
+
+    // Wait for the file watcher to see no event for this long before applying the changes in one batch (Default to 200ms)
+    pub fn watch_debounce(mut self, window: Duration) -> Self {
+        self.watch_debounce = window;
+        self
+    }
+
+    // Scan the modified times of the res files at this interval instead of using the native file watcher (Default to 2s)
+    // Polling is also used when the platform has no native watcher or a res root is on a network file system
+    pub fn watch_poll(mut self, interval: Duration) -> Self {
+        self.watch_poll = Some(interval);
+        self
+    }
+
+    // Look at most at this many files per scan when polling, the files past it are not watched (Default to 100000)
+    pub fn watch_poll_budget(mut self, budget: usize) -> Self {
+        self.watch_poll_budget = budget;
+        self
+    }
+
+    // Path patterns of the res files whose changes the file watcher ignores, e.g. *.tmp or node_modules/** (Default to none)
+    pub fn watch_ignore(mut self, patterns: Vec<String>) -> Self {
+        self.watch_ignore = patterns;
+        self
+    }
+
+    // Warm up the file caches before accepting connections
+    pub fn preload(mut self, preload: Preload) -> Self {
+        self.preload = Some(preload);
+        self
+    }
+
+    // Load the files cached when the server last stopped from the folder, saved back by ServerState::save_hot_sets
+    pub fn cache_persist(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_persist = Some(dir.into());
+        self
+    }
+
+    // Serve the default site with the given map instead of the map file, which is then not reloaded
+    pub fn request_map(mut self, map: RequestMap) -> Self {
+        self.request_map = Some(map);
+        self
+    }
+
+    // Files served for folders of every site, the first existing one is used (Default to index.html, index.htm, index)
+    pub fn index_files(mut self, index_files: Vec<String>) -> Self {
+        self.index_files = Some(index_files);
+        self
+    }
+
+    // Intercept requests before the built-in handling, middleware is walked in the order added
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Problems which would fail the binding or the serving, found without loading the caches or binding
+    // Covers the options, the res folders and map files of every site and the files named by the options
+    pub async fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.io_uring && !cfg!(all(target_os = "linux", feature = "uring")) {
+            problems.push("io_uring requires building with the uring feature on Linux".to_string());
+        }
+        if self.mmap.is_some() && !cfg!(target_os = "linux") {
+            problems.push("mmap requires Linux".to_string());
+        }
+        if self.watch_poll == Some(Duration::ZERO) || self.watch_poll_budget == 0 {
+            problems.push("Watch poll interval and budget must not be zero".to_string());
+        }
+        if self.proxy_protocol && self.trusted_proxies.is_empty() {
+            problems.push("PROXY protocol requires trusted proxies".to_string());
+        }
+        if let Err(e) = check_dual_stack(&self.listen_addrs()) {
+            problems.push(e);
+        }
+        let file_root = match &self.file_root {
+            Some(f) => f.clone(),
+            None => env::current_dir().unwrap_or_default(),
+        };
+        if !file_root.is_dir() {
+            problems.push(format!(
+                "Root folder {} is not a folder",
+                file_root.display()
+            ));
+        }
+
+        // Sites, the map file is not read if a map is given
+        let map_file = self.request_map.is_none();
+        problems.extend(Site::check(None, &file_root, self.res_dir.as_deref(), map_file).await);
+        if let Some(f) = &self.tenants_file {
+            match read_to_string(f).await {
+                Ok(s) => match parse_tenants(&s, self.file_cache_size) {
+                    Ok(tenants) => {
+                        for t in tenants {
+                            let res_dir = t.file_root.join(crate::RES_ROOT_FOLDER);
+                            let site =
+                                Site::check(Some(&t.host), &t.file_root, Some(&res_dir), true);
+                            problems.extend(site.await);
+                        }
+                    }
+                    Err(e) => problems.push(format!("Tenants file {}: {}", f.display(), e)),
+                },
+                Err(e) => problems.push(format!("Tenants file {}: {}", f.display(), e)),
+            }
+        }
+
+        // Files named by the options
+        if let Some(Preload::List(p)) = &self.preload {
+            if let Err(e) = read_to_string(p).await {
+                problems.push(format!("Preload list {}: {}", p.display(), e));
+            }
+        }
+        if let Some(template) = self.markdown.as_ref().and_then(|m| m.template.as_ref()) {
+            // Only checked in a res folder, archives and embedded files are looked up when served
+            let res_dir = match &self.res_dir {
+                Some(d) => d.clone(),
+                None => file_root.join(crate::RES_ROOT_FOLDER),
+            };
+            let path = res_dir.join(template);
+            if res_dir.is_dir() && !path.is_file() {
+                problems.push(format!("Markdown template {} not found", path.display()));
+            }
+        }
+        if let Some(w) = self.redirect.as_ref().and_then(|r| r.acme_webroot.as_ref()) {
+            if !w.is_dir() {
+                problems.push(format!("ACME webroot {} is not a folder", w.display()));
+            }
+        }
+        if let Some(a) = &self.access_log {
+            let dir = a.parent().filter(|d| !d.as_os_str().is_empty());
+            if dir.is_some_and(|d| !d.is_dir()) {
+                problems.push(format!("Access log {}: folder not found", a.display()));
+            }
+        }
+        if let Some(d) = self.cache_persist.as_ref().filter(|d| d.exists()) {
+            if !d.is_dir() {
+                problems.push(format!(
+                    "Cache persist folder {} is not a folder",
+                    d.display()
+                ));
+            }
+        }
+        problems
+    }
+
+    // Addresses followed by the ports on the bind address, the default port is used if none is given
+    fn listen_addrs(&self) -> Vec<SocketAddr> {
+        let ports = self.ports.iter().map(|p| SocketAddr::new(self.bind_ip, *p));
+        let addrs = self.addrs.iter().copied().chain(ports).collect::<Vec<_>>();
+        match addrs.is_empty() {
+            true => vec![SocketAddr::new(self.bind_ip, DEFAULT_PORT)],
+            false => addrs,
+        }
+    }
+
+    // Load the sites and bind the listeners without accepting connections yet
+    pub async fn bind(self) -> Result<Server, Box<dyn Error>> {
+        if self.io_uring && !cfg!(all(target_os = "linux", feature = "uring")) {
+            return Err("io_uring requires building with the uring feature on Linux".into());
+        }
+        if self.mmap.is_some() && !cfg!(target_os = "linux") {
+            return Err("mmap requires Linux".into());
+        }
+        if self.watch_poll == Some(Duration::ZERO) || self.watch_poll_budget == 0 {
+            return Err("Watch poll interval and budget must not be zero".into());
+        }
+        if self.proxy_protocol && self.trusted_proxies.is_empty() {
+            return Err("PROXY protocol requires trusted proxies".into());
+        }
+        let listen_addrs = self.listen_addrs();
+        check_dual_stack(&listen_addrs)?;
+        let file_root = match self.file_root {
+            Some(f) => f,
+            None => env::current_dir()?,
+        };
+
+        // Load default site
+        let mut default_site = Site::load(
+            None,
+            &file_root,
+            self.res_dir.as_deref(),
+            self.file_cache_size,
+            self.max_cacheable_size,
+            None,
+        )
+        .await?;
+        if let Some(map) = self.request_map {
+            default_site = default_site.with_request_map(map);
+        }
+
+        // Load tenant sites if tenants file is provided
+        let mut tenants = match &self.tenants_file {
+            Some(f) => {
+                let tenants_str = read_to_string(f).await?;
+                load_tenants(&tenants_str, self.file_cache_size, self.max_cacheable_size).await?
+            }
+            None => HashMap::new(),
+        };
+        if let Some(index_files) = self.index_files {
+            default_site = default_site.with_index_files(index_files.clone());
+            tenants = tenants
+                .into_iter()
+                .map(|(host, site)| (host, site.with_index_files(index_files.clone())))
+                .collect();
+        }
+        if !self.cache_ttl.is_empty() {
+            default_site = default_site.with_cache_ttl(self.cache_ttl.clone());
+            tenants = tenants
+                .into_iter()
+                .map(|(host, site)| (host, site.with_cache_ttl(self.cache_ttl.clone())))
+                .collect();
+        }
+        if !self.cache_exclude.is_empty() {
+            let patterns = &self.cache_exclude;
+            default_site = default_site.with_cache_exclude(patterns.clone());
+            tenants = tenants
+                .into_iter()
+                .map(|(host, site)| (host, site.with_cache_exclude(patterns.clone())))
+                .collect();
+        }
+        if self.cache_admission != Admission::default() {
+            let admission = self.cache_admission;
+            default_site = default_site.with_cache_admission(admission);
+            tenants = tenants
+                .into_iter()
+                .map(|(host, site)| (host, site.with_cache_admission(admission)))
+                .collect();
+        }
+        if let Some(min_size) = self.mmap {
+            default_site = default_site.with_mmap(Some(min_size));
+            tenants = tenants
+                .into_iter()
+                .map(|(host, site)| (host, site.with_mmap(Some(min_size))))
+                .collect();
+        }
+
+        // Preload the files of every site
+        if let Some(preload) = &self.preload {
+            let list = match preload {
+                Preload::List(p) => Some(Preload::parse_list(&read_to_string(p).await?)),
+                Preload::Walk => None,
+            };
+            for site in std::iter::once(&default_site).chain(tenants.values()) {
+                let (files, bytes) = site.preload(list.as_deref()).await;
+                info!(
+                    "Preloaded {} files ({}) for {}",
+                    files,
+                    util::fmt_size(bytes),
+                    site.display_name()
+                );
+            }
+        }
+
+        // Warm the file caches with the files they held when the server last stopped, most recently used first
+        if let Some(dir) = &self.cache_persist {
+            for site in std::iter::once(&default_site).chain(tenants.values()) {
+                let path = site.hot_set_path(dir);
+                let list = match read_to_string(&path).await {
+                    Ok(s) => Preload::parse_list(&s),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        warn!("Failed to read {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let (files, bytes) = site.preload(Some(&list)).await;
+                info!(
+                    "Restored {} files ({}) for {}",
+                    files,
+                    util::fmt_size(bytes),
+                    site.display_name()
+                );
+            }
+        }
+
+        // Open access log if provided
+        let access_log = match &self.access_log {
+            Some(p) => Some(AccessLog::open(p).await?),
+            None => None,
+        };
+
+        // Construct sockets, inherited sockets replace the given addresses
+        let mut listeners = Vec::new();
+        if self.socket_activation {
+            for listener in sdnotify::listen_fds()? {
+                let listener = TcpListener::from_std(listener)?;
+                info!("socket inherited @{}", listener.local_addr()?);
+                listeners.push(listener);
+            }
+        }
+        let addrs = match listeners.is_empty() {
+            true => listen_addrs,
+            false => Vec::new(),
+        };
+        for addr in addrs {
+            let mut addr = addr;
+            for _ in 0..self.accept_workers {
+                let listener = bind(addr, self.accept_workers > 1)?;
+
+                // Later workers share the port given to the first one when binding to a free port
+                addr = listener.local_addr()?;
+                listeners.push(listener);
+            }
+            info!("socket binded @{}", addr);
+        }
+
+        // Construct context for main loop
+        let state = Arc::new(ServerState {
+            default_site,
+            tenants,
+            debug_endpoints: self.debug_endpoints,
+            dir_listing: self.dir_listing,
+            idle_timeout: self.idle_timeout,
+            header_timeout: self.header_timeout,
+            body_timeout: self.body_timeout,
+            request_timeout: self.request_timeout,
+            slow_request: self.slow_request,
+            watch_debounce: self.watch_debounce,
+            watch_poll: self.watch_poll,
+            watch_poll_budget: self.watch_poll_budget,
+            watch_ignore: self
+                .watch_ignore
+                .iter()
+                .map(|p| watch_ignore_pattern(p))
+                .collect(),
+            max_header_size: self.max_header_size,
+            max_header_count: self.max_header_count,
+            max_body_size: self.max_body_size,
+            metrics: Metrics::default(),
+            connection_limit: ConnectionLimit::new(self.max_connections, self.accept_rate),
+            ip_filter: self.ip_filter,
+            drop_refused: self.drop_refused,
+            trusted_proxies: self.trusted_proxies,
+            proxy_protocol: self.proxy_protocol,
+            metrics_path: match &self.metrics {
+                Some(MetricsEndpoint::Path(p)) => Some(p.clone()),
+                _ => None,
+            },
+            upload: self.upload,
+            markdown: self.markdown,
+            ssi: self.ssi,
+            precompressed: self.precompressed,
+            live_reload: self.live_reload.then(LiveReload::default),
+            security_headers: RwLock::default(),
+            cache_rules: RwLock::new(Arc::new(self.cache_rules)),
+            access_log,
+            request_log: RequestLog::new(self.request_log_sample, self.request_log_status),
+            cache_persist: self.cache_persist.clone(),
+            middleware: self.middleware,
+            res_root_changed: Notify::new(),
+        });
+        state.set_security_headers(self.security_headers);
+
+        // Serve metrics on a separate port if requested
+        if let Some(MetricsEndpoint::Port(port)) = &self.metrics {
+            metrics::listen(SocketAddr::new(self.bind_ip, *port), state.clone()).await?;
+        }
+
+        // Redirect plain HTTP on a separate port if requested
+        if let Some(r) = self.redirect {
+            redirect::listen(r, self.bind_ip, state.clone()).await?;
+        }
+
+        // Serve the admin API on a separate address if requested
+        if let Some(addr) = self.admin {
+            admin::listen(addr, state.clone()).await?;
+        }
+        Ok(Server {
+            listeners,
+            state,
+            cache_stats_interval: self.cache_stats_interval,
+            io_uring: self.io_uring,
+        })
+    }
+
+    pub async fn serve(self) -> Result<(), Box<dyn Error>> {
+        self.bind()
+            .await?
+            .serve()
+            .await
+            .map_err(|e| e as Box<dyn Error>)
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    // Address of the first listener
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    // Addresses of all listeners, once per address regardless of the accept workers
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = Vec::with_capacity(self.listeners.len());
+        for l in &self.listeners {
+            let addr = l.local_addr()?;
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+
+    pub fn state(&self) -> &Arc<ServerState> {
+        &self.state
+    }
+
+    // Accept connections until an accept loop fails, the future can be spawned on the runtime
+    pub async fn serve(self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Self {
+            listeners,
+            state,
+            cache_stats_interval,
+            io_uring,
+        } = self;
+
+        // Reload handler
+        setup_sighup_handler(state.clone())?;
+
+        // Periodic cache report
+        if !cache_stats_interval.is_zero() {
+            task::spawn(report_cache_stats(state.clone(), cache_stats_interval));
+        }
+
+        // Watcher event
+        let watcher_handle = setup_fs_watcher(state.clone());
+        tokio::pin!(watcher_handle); // pin handle in order for main loop to poll it
+
+        // Accept loops of all listeners feed the same state, they are aborted once dropped
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            #[cfg(all(target_os = "linux", feature = "uring"))]
+            if io_uring {
+                // The listener moves to the reactor of the runtime on the new thread
+                let listener = listener.into_std()?;
+                let state = state.clone();
+                accept_loops.spawn_blocking(move || {
+                    uring::run(async move {
+                        let listener = TcpListener::from_std(listener)
+                            .expect("Failed to register the listener with the io_uring runtime");
+                        accept_loop(listener, state).await
+                    })
+                });
+                continue;
+            }
+            accept_loops.spawn(accept_loop(listener, state.clone()));
+        }
+        #[cfg(not(all(target_os = "linux", feature = "uring")))]
+        let _ = io_uring;
+
+        // Keep serving if the watcher gives up, cached files are then no longer refreshed
+        // Accept loops only end by panicking
+        select! {
+            res = &mut watcher_handle => {
+                let reason = match res {
+                    Ok(e) => e.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                error!("File watcher stopped: {}, changed files may be served stale", reason);
+            }
+            Some(res) = accept_loops.join_next() => return Err(res.unwrap_err().into()),
+        }
+        match accept_loops.join_next().await {
+            Some(res) => Err(res.unwrap_err().into()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Port of the bind address or a full address, e.g. 8080 or [::]:3006
+pub fn parse_listen_addr(s: &str, bind_ip: IpAddr) -> Option<SocketAddr> {
+    match s.parse::<u16>() {
+        Ok(port) => Some(SocketAddr::new(bind_ip, port)),
+        Err(_) => s.parse().ok(),
+    }
+}
+
+// The unspecified IPv6 address takes the IPv4 connections of its port as well
+// Binding 0.0.0.0 along with :: on the same port would fail with the address in use
+fn check_dual_stack(addrs: &[SocketAddr]) -> Result<(), String> {
+    let dual = addrs
+        .iter()
+        .filter(|a| a.ip() == Ipv6Addr::UNSPECIFIED && a.port() != 0);
+    for d in dual {
+        let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, d.port()));
+        if cfg!(unix) && addrs.contains(&v4) {
+            return Err(format!(
+                "{} takes the IPv4 connections of {} as well, give only one of them",
+                d, v4
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Reuse the address so that a restarted server binds while old connections linger
+pub(crate) fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    // Dual-stack whatever the system default, e.g. net.ipv6.bindv6only, Windows stays IPv6 only
+    #[cfg(unix)]
+    if addr.ip() == Ipv6Addr::UNSPECIFIED {
+        use std::os::fd::AsRawFd;
+        let off: libc::c_int = 0;
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &off as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    if reuse_port {
+        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+        socket.set_reuseport(true)?;
+        #[cfg(not(all(unix, not(target_os = "solaris"), not(target_os = "illumos"))))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+async fn accept_loop(listener: TcpListener, state: Arc<ServerState>) {
+    let mut accept_backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        // Wait for a free slot and the accept rate first, the slot is held until the connection closes
+        let permit = state.connection_limit.admit(&state.metrics).await;
+
+        // Accept connection
+        let (stream, addr) = match listener.accept().await {
+            // Back off when out of file descriptors instead of spinning on the failing accept
+            Err(e) if util::is_fd_exhausted(&e) => {
+                warn!(
+                    "Out of file descriptors, pausing accept for {}ms: {}",
+                    accept_backoff.as_millis(),
+                    e
+                );
+                tokio::time::sleep(accept_backoff).await;
+                accept_backoff = (accept_backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+            Err(e) => {
+                error!("Client connection error: {}", e);
+                continue;
+            }
+            Ok(s) => s,
+        };
+        accept_backoff = ACCEPT_BACKOFF_MIN;
+
+        // Refuse clients outside the allowed networks before reading the request
+        // Behind the PROXY protocol the client is only known once the preamble is read
+        if !state.proxy_protocol && !state.ip_filter.permits(addr.ip()) {
+            debug!("connection refused for {}", &addr);
+            state.metrics.record_refused_connection();
+            if !state.drop_refused {
+                task::spawn(async move {
+                    let mut stream = stream;
+                    refuse(&mut stream).await;
+                });
+            }
+            continue;
+        }
+        debug!("connection from: {}", &addr);
+        let state = state.clone();
+        task::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = handle_connection(&addr, stream, &state).await {
+                error!("Error: {}, {}", &addr, e);
+            }
+            debug!("connection closed for {}", &addr);
+        });
+    }
+}
+
+async fn report_cache_stats(state: Arc<ServerState>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await; // The first tick completes immediately
+    loop {
+        ticker.tick().await;
+        for site in state.sites() {
+            let stats = site.file_cache.stats().await;
+            info!(
+                "[{}] File cache: {} hits, {} misses ({:.1}% hit ratio), {} evictions, {} rejections, {} entries, {} of {}",
+                site.display_name(),
+                stats.hits,
+                stats.misses,
+                stats.hit_ratio() * 100.,
+                stats.evictions,
+                stats.rejections,
+                stats.entries,
+                util::fmt_size(stats.size),
+                match stats.size_limit {
+                    Some(l) => util::fmt_size(l),
+                    None => "unlimited".to_string(),
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::{io, sync::Arc};
+
+use tokio::task::JoinHandle;
+
+use crate::{info, log, log_ctx, state::ServerState};
+log_ctx!("Signal");
+
+#[cfg(unix)]
+pub fn setup_sighup_handler(ctx: Arc<ServerState>) -> io::Result<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // register handler before spawning so that the signal is not missed
+    let mut sighup = signal(SignalKind::hangup())?;
+    let t = tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("SIGHUP received, reloading...");
+            log::reopen();
+            ctx.reload_res_roots().await;
+            for site in ctx.sites() {
+                site.reload_request_map().await;
+            }
+        }
+    });
+    Ok(t)
+}
+
+#[cfg(not(unix))]
+pub fn setup_sighup_handler(_ctx: Arc<ServerState>) -> io::Result<JoinHandle<()>> {
+    info!("SIGHUP is not supported on this platform, reload is disabled");
+    Ok(tokio::spawn(async {}))
+}
@@ -0,0 +1,59 @@
+use std::{cell::RefCell, future::Future, time::Duration};
+
+// Details gathered while a request is handled, for the slow request log
+// Recording outside of a scope, e.g. while preloading, does nothing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestStats {
+    pub cache_hits: u32,
+    pub cache_misses: u32,
+    pub upstream: Option<Duration>, // Time spent waiting on upstreams
+}
+
+tokio::task_local! {
+    static STATS: RefCell<RequestStats>;
+}
+
+// Gather the details of the request handled by the future
+pub async fn scope<F: Future>(f: F) -> F::Output {
+    STATS.scope(RefCell::new(RequestStats::default()), f).await
+}
+
+// Details gathered so far, None outside of a scope
+pub fn current() -> Option<RequestStats> {
+    STATS.try_with(|s| *s.borrow()).ok()
+}
+
+pub fn record_cache(hit: bool) {
+    let _ = STATS.try_with(|s| {
+        let mut s = s.borrow_mut();
+        match hit {
+            true => s.cache_hits += 1,
+            false => s.cache_misses += 1,
+        }
+    });
+}
+
+pub fn record_upstream(elapsed: Duration) {
+    let _ = STATS.try_with(|s| {
+        let mut s = s.borrow_mut();
+        s.upstream = Some(s.upstream.unwrap_or_default() + elapsed);
+    });
+}
+
+impl RequestStats {
+    // e.g. "hit", "miss" or "2 hits, 1 miss", None if no file was looked up
+    pub fn cache_summary(&self) -> Option<String> {
+        match (self.cache_hits, self.cache_misses) {
+            (0, 0) => None,
+            (1, 0) => Some("hit".to_string()),
+            (0, 1) => Some("miss".to_string()),
+            (h, m) => Some(format!(
+                "{} hit{}, {} miss{}",
+                h,
+                if h == 1 { "" } else { "s" },
+                m,
+                if m == 1 { "" } else { "es" }
+            )),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    future::Future,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    time::SystemTime,
+};
+
+use tokio::io::{self, AsyncReadExt};
+
+use crate::{
+    filecache::{AbstractFile, Validator},
+    http::HttpRequest,
+    log_ctx,
+    state::Site,
+    trace,
+    util::{fmt_http_date, html_escape},
+    warn,
+};
+
+// Sample of directives:
+// <!--#include file="header.html" -->      Relative to the folder of the including file
+// <!--#include virtual="/inc/footer.html" --> Relative to the res folder
+// <!--#echo var="DOCUMENT_URI" -->
+const SSI_EXT: &str = "shtml";
+const SSI_OPEN: &[u8] = b"<!--#";
+const SSI_CLOSE: &[u8] = b"-->";
+const SSI_INCLUDE: &str = "include";
+const SSI_ECHO: &str = "echo";
+const SSI_ERROR: &str = "[an error occurred while processing this directive]";
+const SSI_UNSET: &str = "(none)";
+const SSI_MAX_DEPTH: usize = 16;
+
+log_ctx!("SSI");
+
+pub fn is_ssi(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(SSI_EXT))
+}
+
+// Split the attributes of a directive, values are quoted with double or single quotes
+// Sample: include file="a.html"
+fn parse_directive(s: &str) -> Option<(&str, Vec<(&str, &str)>)> {
+    let s = s.trim();
+    let (command, mut rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+    let mut attrs = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Some((command, attrs));
+        }
+        let (name, val) = rest.split_once('=')?;
+        let val = val.trim_start();
+        let quote = val.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let end = val[1..].find(quote)? + 1;
+        attrs.push((name.trim(), &val[1..end]));
+        rest = &val[end + 1..];
+    }
+}
+
+// Path of an include relative to the res folder, None if it leaves the res folder
+fn resolve(current: &Path, attr: &str, val: &str) -> Option<PathBuf> {
+    let joined = match attr {
+        "file" => current.parent().unwrap_or(Path::new("")).join(val),
+        "virtual" => PathBuf::from(val.trim_start_matches('/')),
+        _ => return None,
+    };
+    let mut path = PathBuf::new();
+    for c in joined.components() {
+        match c {
+            Component::Normal(n) => path.push(n),
+            Component::CurDir => {}
+            Component::ParentDir if path.pop() => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+// Variables of echo, headers are given as HTTP_ followed by the name in upper case
+fn variable(name: &str, path: &Path, req: &HttpRequest<'_>, modified: SystemTime) -> String {
+    let val = match name {
+        "DOCUMENT_NAME" => path.file_name().map(|n| n.to_string_lossy().into_owned()),
+        "DOCUMENT_URI" => Some(req.path.to_string()),
+        "QUERY_STRING" => req.query.map(|q| q.to_string()),
+        "DATE_GMT" => Some(fmt_http_date(SystemTime::now())),
+        "LAST_MODIFIED" => Some(fmt_http_date(modified)),
+        n => n.strip_prefix("HTTP_").and_then(|h| {
+            req.headers
+                .iter()
+                .find(|(k, _)| k.replace('-', "_").eq_ignore_ascii_case(h))
+                .map(|(_, v)| v.to_string())
+        }),
+    };
+    html_escape(&val.unwrap_or_else(|| SSI_UNSET.to_string()))
+}
+
+struct Pass<'a> {
+    req: &'a HttpRequest<'a>,
+    site: &'a Site,
+    page: &'a Path,
+    modified: SystemTime,
+    stack: Vec<PathBuf>,        // Files being processed, to detect include cycles
+    reads: usize,               // Files read for the page
+    validators: Vec<Validator>, // Of the files read, to tell when the page changes
+}
+
+impl Pass<'_> {
+    // Processing is recursive through includes, so the future is boxed
+    fn process<'b>(
+        &'b mut self,
+        src: Vec<u8>,
+        path: PathBuf,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send + 'b>> {
+        Box::pin(async move {
+            let mut res = Vec::with_capacity(src.len());
+            let mut rest = &src[..];
+            while let Some(open) = find(rest, SSI_OPEN) {
+                let close = match find(&rest[open..], SSI_CLOSE) {
+                    Some(i) => open + i,
+                    None => break,
+                };
+                res.extend_from_slice(&rest[..open]);
+                let directive = String::from_utf8_lossy(&rest[open + SSI_OPEN.len()..close]);
+                rest = &rest[close + SSI_CLOSE.len()..];
+                match self.directive(&directive, &path).await? {
+                    Some(out) => res.extend_from_slice(&out),
+                    None => {
+                        warn!(
+                            "Invalid directive in {}: {}",
+                            path.display(),
+                            directive.trim()
+                        );
+                        res.extend_from_slice(SSI_ERROR.as_bytes());
+                    }
+                }
+            }
+            res.extend_from_slice(rest);
+            Ok(res)
+        })
+    }
+
+    // Output of a directive, None if it cannot be processed
+    async fn directive(&mut self, directive: &str, path: &Path) -> io::Result<Option<Vec<u8>>> {
+        let (command, attrs) = match parse_directive(directive) {
+            Some(d) => d,
+            None => return Ok(None),
+        };
+        match (command, attrs.as_slice()) {
+            (SSI_INCLUDE, [(attr, val)]) => {
+                let include = match resolve(path, attr, val) {
+                    Some(p) => p,
+                    None => return Ok(None),
+                };
+                if self.stack.contains(&include) || self.stack.len() >= SSI_MAX_DEPTH {
+                    warn!(
+                        "Include cycle of {} in {}",
+                        include.display(),
+                        path.display()
+                    );
+                    return Ok(None);
+                }
+                let src = match self.site.open(&include).await {
+                    Ok(f) => self.read(f).await?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+                trace!("Including {} in {}", include.display(), path.display());
+                self.stack.push(include.clone());
+                let out = self.process(src, include).await?;
+                self.stack.pop();
+                Ok(Some(out))
+            }
+            (SSI_ECHO, [("var", name)]) => {
+                let val = variable(name, self.page, self.req, self.modified);
+                Ok(Some(val.into_bytes()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn read(&mut self, mut f: AbstractFile) -> io::Result<Vec<u8>> {
+        self.reads += 1;
+        if let Some(v) = f.validator() {
+            self.validators.push(v);
+        }
+        let mut buf = Vec::with_capacity(f.len());
+        f.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Process the directives of the page, returns the body with the combined validator of all files read
+// The validator changes whenever the page or any included file changes, so that clients revalidate
+pub async fn render(
+    file: AbstractFile,
+    path: &Path,
+    req: &HttpRequest<'_>,
+    site: &Site,
+) -> io::Result<(Vec<u8>, Option<Validator>)> {
+    let modified = file
+        .validator()
+        .map_or(SystemTime::UNIX_EPOCH, |v| v.modified);
+    let mut pass = Pass {
+        req,
+        site,
+        page: path,
+        modified,
+        stack: vec![path.to_path_buf()],
+        reads: 0,
+        validators: Vec::new(),
+    };
+    let src = pass.read(file).await?;
+    let body = pass.process(src, path.to_path_buf()).await?;
+
+    // Files without validator, e.g. of an archive, leave the page without one as well
+    let validator = match pass.validators.len() == pass.reads {
+        true => pass.validators.iter().copied().reduce(|a, b| Validator {
+            modified: a.modified.max(b.modified),
+            size: a.size + b.size,
+        }),
+        false => None,
+    };
+    Ok((body, validator))
+}
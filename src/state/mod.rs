@@ -0,0 +1,1040 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{
+    fs::{self, canonicalize, metadata, read_dir, read_to_string, File, OpenOptions},
+    io::{self, AsyncWriteExt},
+    sync::{Mutex, Notify},
+};
+
+use crate::{
+    accesslog::AccessLog,
+    archive::{entry_name, Archive},
+    cachecontrol::{pattern_matches, CacheRules},
+    connlimit::ConnectionLimit,
+    error,
+    filecache::{AbstractFile, Admission, CacheTtl, FileCache},
+    info,
+    ipfilter::{Cidr, IpFilter},
+    listing::{self, DirEntry},
+    livereload::LiveReload,
+    log_ctx,
+    markdown::Markdown,
+    metrics::Metrics,
+    middleware::Middleware,
+    proxy::UpstreamCache,
+    requestlog::RequestLog,
+    requestmap::RequestMap,
+    security::{SecurityHeaders, CSP_HEADER},
+    trace,
+    upload::Upload,
+    util::fmt_size,
+    warn,
+};
+
+// Define tenants file delimiters
+// Sample of tenant entry: example.com=/srv/example cache=1024 log=example.log
+const TENANT_KEY_VAL_DELIM: char = '=';
+const TENANT_DIRECTIVE_DELIM: char = '=';
+const TENANT_DIRECTIVE_CACHE: &str = "cache";
+const TENANT_DIRECTIVE_LOG: &str = "log";
+const EMBEDDED_ROOT: &str = "<embedded>";
+
+log_ctx!("State");
+
+// Files served for a folder, the first existing one is used
+pub const DEFAULT_INDEX_FILES: [&str; 3] = ["index.html", "index.htm", "index"];
+
+pub enum ResSource {
+    Folder,
+    Archive(Archive),
+    Embedded,
+}
+
+pub struct ResRoot {
+    pub path: PathBuf,
+    pub source: ResSource,
+}
+
+impl ResRoot {
+    async fn load(res_dir: Option<&Path>) -> io::Result<Self> {
+        // Serve the embedded files if no res dir is given
+        let res_dir = match res_dir {
+            Some(d) => d,
+            None => {
+                #[cfg(feature = "embed")]
+                info!("Serving {} embedded files", crate::embed::len());
+                return Ok(Self {
+                    path: PathBuf::from(EMBEDDED_ROOT),
+                    source: ResSource::Embedded,
+                });
+            }
+        };
+
+        let path = canonicalize(res_dir).await?;
+        let source = match Archive::is_archive(&path) {
+            true => {
+                let archive = Archive::open(&path).await?;
+                info!("Archive loaded with {} entries", archive.len());
+                ResSource::Archive(archive)
+            }
+            false => ResSource::Folder,
+        };
+        Ok(Self { path, source })
+    }
+
+    pub fn is_folder(&self) -> bool {
+        matches!(self.source, ResSource::Folder)
+    }
+}
+
+// The embedded files are served if no res dir is given and they are compiled in
+fn derive_res_dir(file_root: &Path, res_dir: Option<&Path>) -> Option<PathBuf> {
+    match res_dir {
+        Some(d) => Some(d.to_path_buf()),
+        None if cfg!(feature = "embed") => None,
+        None => Some(file_root.join(crate::RES_ROOT_FOLDER)),
+    }
+}
+
+// Path relative to the res root as matched by the patterns of the options, e.g. /assets/app.js
+fn pattern_path(rel_path: &Path) -> String {
+    format!("/{}", rel_path.to_string_lossy().replace('\\', "/"))
+}
+
+// Only plain relative paths are allowed so that the res root cannot be escaped
+fn check_rel_path(rel_path: &Path) -> io::Result<()> {
+    let is_plain = rel_path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    match is_plain {
+        true => Ok(()),
+        false => {
+            warn!("Rejected path: {}", rel_path.display());
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        }
+    }
+}
+
+async fn load_request_map(
+    map_path: &Path,
+    site_name: &str,
+) -> Result<Option<RequestMap>, Box<dyn Error>> {
+    match read_to_string(map_path).await {
+        Ok(map_file) => Ok(Some(RequestMap::parse_str(&map_file)?)),
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => {
+                info!(
+                    "No map file found for {}. Serving without request map...",
+                    site_name
+                );
+                Ok(None)
+            }
+            _ => Err(e.into()),
+        },
+    }
+}
+
+// Log the entries of the canonical map which were removed and added
+fn log_map_diff(site_name: &str, old: Option<&str>, new: Option<&str>) {
+    let old_lines = old.unwrap_or_default().lines().collect::<Vec<_>>();
+    let new_lines = new.unwrap_or_default().lines().collect::<Vec<_>>();
+    let mut diff = String::new();
+    for l in old_lines.iter().filter(|l| !new_lines.contains(l)) {
+        diff.push_str(&format!("\n- {}", l));
+    }
+    for l in new_lines.iter().filter(|l| !old_lines.contains(l)) {
+        diff.push_str(&format!("\n+ {}", l));
+    }
+    match diff.is_empty() {
+        true => info!("Map reloaded for {}, no entries changed", site_name),
+        false => info!("Map reloaded for {}{}", site_name, diff),
+    }
+}
+
+pub struct Site {
+    pub name: Option<String>,
+    res_dir: Option<PathBuf>,
+    res_root: RwLock<Arc<ResRoot>>,
+    map_path: PathBuf,
+    request_map: RwLock<Option<Arc<RequestMap>>>,
+    map_reload: bool, // Whether the map follows the map file
+    index_files: Vec<String>,
+    cache_ttl: CacheTtl, // Time after which cached files are checked against the disk, in case the watcher missed a change
+    cache_exclude: Vec<String>, // Patterns of the paths read from disk on every request instead of cached
+    pub file_cache: FileCache,
+    pub upstream_cache: UpstreamCache,
+    log: Option<Mutex<File>>,
+}
+
+impl Site {
+    pub async fn load(
+        name: Option<String>,
+        file_root: &Path,
+        res_dir: Option<&Path>,
+        file_cache_size: usize,
+        max_cacheable_size: usize,
+        log_path: Option<&Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let site_name = name.as_deref().unwrap_or("default");
+        // Derive res root folder, the res dir is kept as is so that it can be resolved again on reload
+        let res_dir = derive_res_dir(file_root, res_dir);
+        let res_root = ResRoot::load(res_dir.as_deref()).await?;
+
+        // Construct request map if exists
+        let map_path = file_root.join(crate::REQ_MAP_FILE);
+        let request_map = load_request_map(&map_path, site_name).await?;
+        if let Some(map) = &request_map {
+            info!("Map loaded for {}\n{}", site_name, map);
+            map.start_health_checks();
+        }
+
+        // Open log file if provided
+        let log = match log_path {
+            Some(p) => {
+                let f = OpenOptions::new().create(true).append(true).open(p).await?;
+                Some(Mutex::new(f))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            name,
+            res_dir,
+            res_root: RwLock::new(res_root.into()),
+            map_path,
+            request_map: RwLock::new(request_map.map(Arc::new)),
+            map_reload: true,
+            index_files: DEFAULT_INDEX_FILES.map(String::from).to_vec(),
+            cache_ttl: CacheTtl::default(),
+            cache_exclude: Vec::new(),
+            file_cache: FileCache::new(Some(file_cache_size), Some(max_cacheable_size)),
+            upstream_cache: UpstreamCache::new(Some(file_cache_size)),
+            log,
+        })
+    }
+
+    // Problems of the res root and the map file which would fail the loading, without loading the caches
+    pub async fn check(
+        name: Option<&str>,
+        file_root: &Path,
+        res_dir: Option<&Path>,
+        map_file: bool,
+    ) -> Vec<String> {
+        let site_name = name.unwrap_or("default");
+        let mut problems = Vec::new();
+        let res_dir = derive_res_dir(file_root, res_dir);
+        if let Err(e) = ResRoot::load(res_dir.as_deref()).await {
+            let path = res_dir.unwrap_or_default();
+            problems.push(format!(
+                "Res folder {} of {}: {}",
+                path.display(),
+                site_name,
+                e
+            ));
+        }
+        let map_path = file_root.join(crate::REQ_MAP_FILE);
+        if map_file {
+            if let Err(e) = load_request_map(&map_path, site_name).await {
+                problems.push(format!(
+                    "Map file {} of {}: {}",
+                    map_path.display(),
+                    site_name,
+                    e
+                ));
+            }
+        }
+        problems
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    pub fn res_root(&self) -> Arc<ResRoot> {
+        self.res_root.read().unwrap().clone()
+    }
+
+    pub fn map_path(&self) -> &Path {
+        &self.map_path
+    }
+
+    // Resolve a path given in the map file relative to the root folder, absolute paths are kept as is
+    pub fn root_path(&self, path: &Path) -> PathBuf {
+        match self.map_path.parent() {
+            Some(root) => root.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    // Serve the first of the files existing in a folder instead of the default ones
+    pub fn with_index_files(mut self, index_files: Vec<String>) -> Self {
+        self.index_files = index_files;
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, cache_ttl: CacheTtl) -> Self {
+        self.cache_ttl = cache_ttl;
+        self
+    }
+
+    pub fn with_cache_exclude(mut self, patterns: Vec<String>) -> Self {
+        self.cache_exclude = patterns;
+        self
+    }
+
+    pub fn with_cache_admission(mut self, admission: Admission) -> Self {
+        self.file_cache = self.file_cache.with_admission(admission);
+        self
+    }
+
+    pub fn with_mmap(mut self, min_size: Option<usize>) -> Self {
+        self.file_cache = self.file_cache.with_mmap(min_size);
+        self
+    }
+
+    // Serve the given map instead of the map file, changes of the file are then ignored
+    pub fn with_request_map(mut self, map: RequestMap) -> Self {
+        info!("Map set for {}\n{}", self.display_name(), &map);
+        map.start_health_checks();
+        self.request_map = RwLock::new(Some(Arc::new(map)));
+        self.map_reload = false;
+        self
+    }
+
+    pub fn request_map(&self) -> Option<Arc<RequestMap>> {
+        self.request_map.read().unwrap().clone()
+    }
+
+    // Parse the map file again and swap it in, the current map is kept if the new one is invalid
+    pub async fn reload_request_map(&self) {
+        if !self.map_reload {
+            return;
+        }
+        match load_request_map(&self.map_path, self.display_name()).await {
+            Ok(map) => {
+                let old = self.request_map().map(|m| m.to_string());
+                let new = map.as_ref().map(|m| m.to_string());
+                log_map_diff(self.display_name(), old.as_deref(), new.as_deref());
+                if let Some(map) = &map {
+                    map.start_health_checks();
+                }
+                *self.request_map.write().unwrap() = map.map(Arc::new);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to reload map of {}, keeping the current map: {}",
+                    self.display_name(),
+                    e
+                );
+            }
+        }
+    }
+
+    pub async fn reload_res_root(&self) -> io::Result<bool> {
+        // Resolve the res dir again, return if unchanged
+        // Archives are always reloaded as the content may have changed at the same path
+        let new_root = ResRoot::load(self.res_dir.as_deref()).await?;
+        let old_root = self.res_root();
+        if new_root.path == old_root.path && !matches!(new_root.source, ResSource::Archive(_)) {
+            return Ok(false);
+        }
+
+        // Swap the root and flush the cache as all entries belong to the old root
+        let path_changed = new_root.path != old_root.path;
+        info!(
+            "Res root of {} switched to {}",
+            self.display_name(),
+            new_root.path.display()
+        );
+        *self.res_root.write().unwrap() = Arc::new(new_root);
+        self.file_cache.clear().await;
+        Ok(path_changed)
+    }
+
+    pub async fn open(&self, rel_path: &Path) -> io::Result<AbstractFile> {
+        self.open_as(rel_path, true).await
+    }
+
+    // Open the file from disk without keeping it in the file cache, e.g. for map entries excluded from it
+    pub async fn open_uncached(&self, rel_path: &Path) -> io::Result<AbstractFile> {
+        self.open_as(rel_path, false).await
+    }
+
+    async fn open_as(&self, rel_path: &Path, cached: bool) -> io::Result<AbstractFile> {
+        let res = self.open_source(rel_path, cached).await;
+
+        // Retry with the other normalization forms as names may be stored decomposed (e.g. by macOS)
+        #[cfg(feature = "normalize")]
+        if let (Err(e), Some(s)) = (&res, rel_path.to_str()) {
+            use unicode_normalization::UnicodeNormalization;
+            if e.kind() == io::ErrorKind::NotFound && !s.is_ascii() {
+                for alt in [s.nfc().collect::<String>(), s.nfd().collect::<String>()] {
+                    if alt == s {
+                        continue;
+                    }
+                    trace!("Retrying with normalized name: {}", &alt);
+                    if let Ok(f) = self.open_source(Path::new(&alt), cached).await {
+                        return Ok(f);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    async fn open_source(&self, rel_path: &Path, cached: bool) -> io::Result<AbstractFile> {
+        check_rel_path(rel_path)?;
+        let root = self.res_root();
+        match &root.source {
+            // Read the entry from the archive through the cache
+            ResSource::Archive(archive) => {
+                let name = archive
+                    .resolve(rel_path, &self.index_files)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                trace!("Opening archive entry: {}", &name);
+                let key = root.path.join(&name);
+                let validator = archive.validator(&name);
+                self.file_cache
+                    .open_with(&key, validator, archive.read(&name))
+                    .await
+            }
+
+            // Serve the embedded file directly from memory
+            #[cfg(feature = "embed")]
+            ResSource::Embedded => {
+                trace!("Opening embedded file: {}", rel_path.display());
+                crate::embed::resolve(rel_path, &self.index_files)
+                    .map(AbstractFile::from)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+            }
+            #[cfg(not(feature = "embed"))]
+            ResSource::Embedded => Err(io::Error::from(io::ErrorKind::NotFound)),
+
+            // Check if the path is a directory, if so, use the index file
+            // Paths found missing recently are answered without touching the disk
+            ResSource::Folder => {
+                let path = root.path.join(rel_path);
+                if self.file_cache.is_missing(&path) {
+                    trace!("Known missing: {}", path.display());
+                    return Err(io::Error::from(io::ErrorKind::NotFound));
+                }
+                let res = self.open_file(&root.path, &path, cached).await;
+                if matches!(&res, Err(e) if e.kind() == io::ErrorKind::NotFound) {
+                    self.file_cache.mark_missing(&path);
+                }
+                res
+            }
+        }
+    }
+
+    // Open the sibling of the file with the extension added, e.g. app.js.br, folders use the sibling of their index file
+    // Only folder roots have siblings, missing ones are remembered like missing files
+    pub async fn open_sibling(&self, rel_path: &Path, ext: &str) -> io::Result<AbstractFile> {
+        check_rel_path(rel_path)?;
+        let root = self.res_root();
+        if !matches!(root.source, ResSource::Folder) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        let path = root.path.join(rel_path);
+        let mut path = match path.is_dir() {
+            true => self.find_index(&path).await?,
+            false => path,
+        }
+        .into_os_string();
+        path.push(".");
+        path.push(ext);
+        let path = PathBuf::from(path);
+        if self.file_cache.is_missing(&path) {
+            trace!("Known missing: {}", path.display());
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        let res = self.open_file(&root.path, &path, true).await;
+        if matches!(&res, Err(e) if e.kind() == io::ErrorKind::NotFound) {
+            self.file_cache.mark_missing(&path);
+        }
+        res
+    }
+
+    // Check if the path is a directory, if so, use the first index file found
+    // Files excluded from the cache, by the caller or by pattern, are read from disk
+    async fn open_file(
+        &self,
+        root_path: &Path,
+        path: &Path,
+        cached: bool,
+    ) -> io::Result<AbstractFile> {
+        let file_path = match path.is_dir() {
+            true => self.find_index(path).await?,
+            false => path.to_path_buf(),
+        };
+        trace!("Opening file: {}", file_path.display());
+
+        // Files reached through links must stay under the root, cached files have been checked already
+        if !self.file_cache.contains(&file_path).await {
+            let canonical = canonicalize(&file_path).await?;
+            if !canonical.starts_with(root_path) {
+                warn!("Path escapes res root: {}", canonical.display());
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+        }
+
+        let rel_path = pattern_path(file_path.strip_prefix(root_path).unwrap_or(&file_path));
+        if !cached || self.is_cache_excluded(&rel_path) {
+            return self.file_cache.open_uncached(&file_path).await;
+        }
+
+        // Entries past their TTL are checked against the file first
+        if let Some(ttl) = self.cache_ttl.ttl_for(&rel_path) {
+            self.file_cache.expire(&file_path, ttl).await;
+        }
+        self.file_cache.open(&file_path).await
+    }
+
+    // Whether the path relative to the res root, with a leading slash, matches a pattern excluded from the cache
+    fn is_cache_excluded(&self, rel_path: &str) -> bool {
+        self.cache_exclude
+            .iter()
+            .any(|p| pattern_matches(p, rel_path))
+    }
+
+    async fn find_index(&self, dir: &Path) -> io::Result<PathBuf> {
+        for name in &self.index_files {
+            let path = dir.join(name);
+            if self.file_cache.contains(&path).await {
+                return Ok(path);
+            }
+            if metadata(&path).await.is_ok_and(|m| m.is_file()) {
+                return Ok(path);
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    // Whether the path is a folder of the res root, cached and known missing paths are not checked on disk
+    pub async fn is_dir(&self, rel_path: &Path) -> bool {
+        if check_rel_path(rel_path).is_err() {
+            return false;
+        }
+        let root = self.res_root();
+        match &root.source {
+            ResSource::Archive(archive) => archive.is_dir(rel_path),
+            #[cfg(feature = "embed")]
+            ResSource::Embedded => crate::embed::is_dir(rel_path),
+            #[cfg(not(feature = "embed"))]
+            ResSource::Embedded => false,
+            ResSource::Folder => {
+                let path = root.path.join(rel_path);
+                if self.file_cache.is_missing(&path) || self.file_cache.contains(&path).await {
+                    return false;
+                }
+                metadata(&path).await.is_ok_and(|m| m.is_dir())
+            }
+        }
+    }
+
+    // Write a file of the res folder atomically, true if it did not exist before
+    // The content is written to a temporary file next to it which then replaces the file
+    pub async fn store_file(&self, rel_path: &Path, data: &[u8]) -> io::Result<bool> {
+        check_rel_path(rel_path)?;
+        let root = self.res_root();
+        if !root.is_folder() {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        let path = root.path.join(rel_path);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(p), Some(n)) => (p, n.to_string_lossy()),
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+
+        // Folders reached through links must stay under the root
+        // They are created one level at a time, so that nothing is created outside of it
+        let mut dir = root.path.clone();
+        for part in parent.strip_prefix(&root.path).unwrap_or(Path::new("")) {
+            dir.push(part);
+            match fs::create_dir(&dir).await {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                _ => {}
+            }
+            if !canonicalize(&dir).await?.starts_with(&root.path) {
+                warn!("Path escapes res root: {}", dir.display());
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+        }
+        let created = match metadata(&path).await {
+            Ok(m) if m.is_dir() => return Err(io::Error::from(io::ErrorKind::IsADirectory)),
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+        let tmp_path = parent.join(format!(".{}.{:016x}.tmp", name, rand::random::<u64>()));
+        let res = async {
+            let mut file = File::create(&tmp_path).await?;
+            file.write_all(data).await?;
+            file.sync_all().await?;
+            fs::rename(&tmp_path, &path).await
+        }
+        .await;
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(e);
+        }
+
+        // Serve the new content right away instead of waiting for the watcher
+        self.file_cache.remove(&path).await;
+        self.file_cache.unmark_missing(&path);
+        Ok(created)
+    }
+
+    // List the entries of a folder of the res root, None if the path is not a folder
+    pub async fn list_dir(&self, rel_path: &Path) -> io::Result<Option<Vec<DirEntry>>> {
+        check_rel_path(rel_path)?;
+        let root = self.res_root();
+        match &root.source {
+            ResSource::Archive(archive) => Ok(listing::list_names(
+                archive.entries(),
+                &entry_name(rel_path),
+                archive.modified(),
+            )),
+            #[cfg(feature = "embed")]
+            ResSource::Embedded => Ok(listing::list_names(
+                crate::embed::entries(),
+                &entry_name(rel_path),
+                None,
+            )),
+            #[cfg(not(feature = "embed"))]
+            ResSource::Embedded => Ok(None),
+            ResSource::Folder => listing::list_folder(&root.path.join(rel_path)).await,
+        }
+    }
+
+    // Load files into the cache while it has room, files which do not fit are skipped instead of evicting others
+    // Returns the number of files and bytes loaded
+    pub async fn preload(&self, list: Option<&[PathBuf]>) -> (usize, usize) {
+        let mut loaded = (0, 0);
+        for (rel_path, key, size) in self.preload_candidates(list).await {
+            if self.is_cache_excluded(&pattern_path(&rel_path)) {
+                trace!(
+                    "Not preloading {}, excluded from the cache",
+                    rel_path.display()
+                );
+                continue;
+            }
+            if !self.file_cache.has_room(&key, size).await {
+                trace!("Not preloading {}, no room left", rel_path.display());
+                continue;
+            }
+            match self.open(&rel_path).await {
+                Ok(_) => {
+                    loaded.0 += 1;
+                    loaded.1 += size;
+                }
+                Err(e) => warn!("Failed to preload {}: {}", rel_path.display(), e),
+            }
+        }
+        loaded
+    }
+
+    // File of the persist folder holding the hot set of the site, named after the site
+    pub fn hot_set_path(&self, dir: &Path) -> PathBuf {
+        let name = self
+            .display_name()
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect::<String>();
+        dir.join(format!("{}.list", name))
+    }
+
+    // Write the paths of the cached files relative to the res root, most recently used first, as a preload list
+    // The file is replaced at once, so a crash while writing keeps the previous list
+    pub async fn save_hot_set(&self, dir: &Path) -> io::Result<usize> {
+        let root = self.res_root();
+        let paths = self
+            .file_cache
+            .hot_set()
+            .await
+            .into_iter()
+            .filter_map(|p| {
+                let rel_path = p.strip_prefix(&root.path).ok()?;
+                Some(format!(
+                    "/{}",
+                    rel_path.to_string_lossy().replace('\\', "/")
+                ))
+            })
+            .collect::<Vec<_>>();
+        let path = self.hot_set_path(dir);
+        let tmp_path = path.with_extension("list.tmp");
+        let content = format!(
+            "# Hot set of {}, most recently used first\n{}\n",
+            self.display_name(),
+            paths.join("\n")
+        );
+        fs::create_dir_all(dir).await?;
+        fs::write(&tmp_path, content).await?;
+        fs::rename(&tmp_path, &path).await?;
+        Ok(paths.len())
+    }
+
+    // Files of the res root with their cache keys and sizes, in the listed order or smallest first
+    async fn preload_candidates(&self, list: Option<&[PathBuf]>) -> Vec<(PathBuf, PathBuf, usize)> {
+        let root = self.res_root();
+        let mut candidates = Vec::new();
+        match (&root.source, list) {
+            (ResSource::Folder, Some(list)) => {
+                for rel_path in list.iter().filter(|p| check_rel_path(p).is_ok()) {
+                    let key = root.path.join(rel_path);
+                    match metadata(&key).await {
+                        Ok(m) if m.is_file() => {
+                            candidates.push((rel_path.clone(), key, m.len() as usize))
+                        }
+                        _ => warn!("Not preloading {}, not a file", rel_path.display()),
+                    }
+                }
+            }
+            (ResSource::Folder, None) => {
+                // Links are not followed, so the walk stays under the root
+                let mut dirs = vec![PathBuf::new()];
+                while let Some(dir) = dirs.pop() {
+                    let mut entries = match read_dir(root.path.join(&dir)).await {
+                        Ok(e) => e,
+                        Err(e) => {
+                            warn!("Failed to walk {}: {}", dir.display(), e);
+                            continue;
+                        }
+                    };
+                    while let Ok(Some(entry)) = entries.next_entry().await {
+                        let rel_path = dir.join(entry.file_name());
+                        match entry.metadata().await {
+                            Ok(m) if m.is_dir() => dirs.push(rel_path),
+                            Ok(m) if m.is_file() => {
+                                let key = root.path.join(&rel_path);
+                                candidates.push((rel_path, key, m.len() as usize));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            (ResSource::Archive(archive), Some(list)) => {
+                for rel_path in list {
+                    let name = archive.resolve(rel_path, &self.index_files);
+                    match name.and_then(|n| Some((archive.validator(&n)?, n))) {
+                        Some((v, n)) => {
+                            candidates.push((rel_path.clone(), root.path.join(n), v.size))
+                        }
+                        None => warn!("Not preloading {}, not in the archive", rel_path.display()),
+                    }
+                }
+            }
+            (ResSource::Archive(archive), None) => {
+                candidates.extend(
+                    archive
+                        .entries()
+                        .map(|(n, size)| (PathBuf::from(n), root.path.join(n), size as usize)),
+                );
+            }
+
+            // Embedded files are served from memory already
+            (ResSource::Embedded, _) => {}
+        }
+        if list.is_none() {
+            candidates.sort_by_key(|(_, _, size)| *size);
+        }
+        candidates
+    }
+
+    pub async fn write_log(&self, line: &str) -> io::Result<()> {
+        if let Some(log) = &self.log {
+            let mut log = log.lock().await;
+            log.write_all(line.as_bytes()).await?;
+            log.write_all(b"\n").await?;
+        }
+        Ok(())
+    }
+}
+
+// Files loaded into the cache of every site before the server accepts connections
+#[derive(Debug, Clone)]
+pub enum Preload {
+    Walk,          // Every file of the res root, smallest first
+    List(PathBuf), // Paths relative to the res root listed in the file, in order
+}
+
+impl Preload {
+    // One path per line, blank lines and lines starting with # are skipped
+    pub fn parse_list(s: &str) -> Vec<PathBuf> {
+        s.lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| PathBuf::from(l.trim_start_matches('/')))
+            .collect()
+    }
+}
+
+impl Display for Preload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Preload::Walk => write!(f, "walk"),
+            Preload::List(p) => write!(f, "{}", p.display()),
+        }
+    }
+}
+
+pub struct ServerState {
+    pub default_site: Site,
+    pub tenants: HashMap<String, Site>,
+    pub debug_endpoints: bool,
+    pub dir_listing: bool,
+    pub idle_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub request_timeout: Duration,
+    pub slow_request: Duration, // Requests taking longer are logged in full, zero disables the log
+    pub watch_debounce: Duration, // Quiet time after which the changes seen by the file watcher are applied
+    pub watch_poll: Option<Duration>, // Interval of the scans when the res roots are polled instead of watched
+    pub watch_poll_budget: usize,     // Files looked at per scan when polling
+    pub watch_ignore: Vec<String>, // Patterns of the res paths whose changes are ignored, as matched
+    pub max_header_size: usize,
+    pub max_header_count: usize,
+    pub max_body_size: usize,
+    pub metrics: Metrics,
+    pub connection_limit: ConnectionLimit,
+    pub ip_filter: IpFilter, // Checked on accept, before anything is read from the connection
+    pub drop_refused: bool,  // Close refused connections without answering 403
+    pub trusted_proxies: Vec<Cidr>, // Peers whose forwarding headers name the client
+    pub proxy_protocol: bool, // Connections start with a PROXY protocol preamble
+    pub security_headers: RwLock<Option<Arc<SecurityHeaders>>>, // Added to responses which do not set them
+    pub cache_rules: RwLock<Arc<CacheRules>>, // Cache-Control of the paths served without policy of their map entry
+    pub metrics_path: Option<String>,
+    pub upload: Option<Upload>,
+    pub markdown: Option<Markdown>, // Render markdown files into pages
+    pub ssi: bool,                  // Process the directives of .shtml pages
+    pub precompressed: bool,        // Serve .br and .gz siblings of files to clients accepting them
+    pub live_reload: Option<LiveReload>, // Dev mode, pages reload when files change
+    pub access_log: Option<AccessLog>,
+    pub request_log: RequestLog, // Sampling and status filter of the request log lines
+    pub cache_persist: Option<PathBuf>, // Folder the hot sets of the file caches are saved to on shutdown
+    pub middleware: Vec<Arc<dyn Middleware>>, // Walked in order before the built-in handling
+    pub res_root_changed: Notify,
+}
+
+impl ServerState {
+    pub fn site(&self, host: Option<&str>) -> &Site {
+        // Strip port from host and lookup case insensitively
+        let host = host.map(|h| match h.rsplit_once(':') {
+            Some((h, p)) if p.bytes().all(|b| b.is_ascii_digit()) => h,
+            _ => h,
+        });
+        match host {
+            Some(h) => self
+                .tenants
+                .get(&h.to_ascii_lowercase())
+                .unwrap_or(&self.default_site),
+            None => &self.default_site,
+        }
+    }
+
+    pub fn sites(&self) -> impl Iterator<Item = &Site> {
+        std::iter::once(&self.default_site).chain(self.tenants.values())
+    }
+
+    pub fn security_headers(&self) -> Option<Arc<SecurityHeaders>> {
+        self.security_headers.read().unwrap().clone()
+    }
+
+    // Swap in the security headers, the content security policy is left out in dev mode as it would block the inline script
+    pub fn set_security_headers(&self, mut headers: Option<SecurityHeaders>) {
+        if let Some(h) = headers.as_mut().filter(|_| self.live_reload.is_some()) {
+            if h.headers()
+                .iter()
+                .any(|(k, _)| k.eq_ignore_ascii_case(CSP_HEADER))
+            {
+                info!(
+                    "{} left out of the security headers in dev mode",
+                    CSP_HEADER
+                );
+                h.remove(CSP_HEADER);
+            }
+        }
+        *self.security_headers.write().unwrap() = headers.map(Arc::new);
+    }
+
+    pub fn cache_rules(&self) -> Arc<CacheRules> {
+        self.cache_rules.read().unwrap().clone()
+    }
+
+    pub fn set_cache_rules(&self, rules: CacheRules) {
+        *self.cache_rules.write().unwrap() = Arc::new(rules);
+    }
+
+    // Save the hot sets of every site to the persist folder, to be loaded back on the next start
+    pub async fn save_hot_sets(&self) {
+        let Some(dir) = &self.cache_persist else {
+            return;
+        };
+        for site in self.sites() {
+            match site.save_hot_set(dir).await {
+                Ok(n) => {
+                    info!("Saved {} cached paths of {}", n, site.display_name());
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to save the cached paths of {}: {}",
+                        site.display_name(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+
+    pub async fn reload_res_roots(&self) {
+        let mut changed = false;
+        for site in self.sites() {
+            match site.reload_res_root().await {
+                Ok(c) => changed |= c,
+                Err(e) => {
+                    error!(
+                        "Failed to reload res root of {}: {}",
+                        site.display_name(),
+                        e
+                    );
+                }
+            }
+        }
+
+        // Notify the watcher to watch the new roots
+        if changed {
+            self.res_root_changed.notify_one();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TenantsParseError {
+    line_num: usize,
+    msg: Cow<'static, str>,
+}
+
+impl Display for TenantsParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to parse tenants file. {} at line {}",
+            self.msg, self.line_num
+        )
+    }
+}
+
+impl Error for TenantsParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        self.source()
+    }
+}
+
+// Entry of the tenants file
+pub struct Tenant {
+    pub host: String,
+    pub file_root: PathBuf,
+    pub cache_size: usize,
+    pub log_path: Option<PathBuf>,
+}
+
+pub fn parse_tenants(
+    tenants_str: &str,
+    default_cache_size: usize,
+) -> Result<Vec<Tenant>, TenantsParseError> {
+    let mut tenants = Vec::new();
+    for (line_num, line) in tenants_str.lines().enumerate() {
+        let line_num = line_num + 1;
+        let err = |msg: Cow<'static, str>| TenantsParseError { line_num, msg };
+
+        // Skip empty lines
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // Split into host and value
+        let (host, v) = line
+            .split_once(TENANT_KEY_VAL_DELIM)
+            .ok_or_else(|| err(format!("Missing delimiter {}", TENANT_KEY_VAL_DELIM).into()))?;
+        let host = host.trim().to_ascii_lowercase();
+        if host.is_empty() {
+            return Err(err("Invalid host".into()));
+        }
+
+        // Split into root folder and directives
+        let mut tokens = v.split_whitespace();
+        let file_root = tokens
+            .next()
+            .ok_or_else(|| err("Invalid root folder".into()))?;
+        let mut cache_size = default_cache_size;
+        let mut log_path = None;
+        for token in tokens {
+            match token.split_once(TENANT_DIRECTIVE_DELIM) {
+                Some((TENANT_DIRECTIVE_CACHE, c)) => {
+                    cache_size = c
+                        .parse::<usize>()
+                        .map_err(|e| err(format!("Invalid cache size ({})", e).into()))?
+                        * 1024;
+                }
+                Some((TENANT_DIRECTIVE_LOG, l)) => log_path = Some(PathBuf::from(l)),
+                _ => return Err(err(format!("Invalid directive {}", token).into())),
+            }
+        }
+        tenants.push(Tenant {
+            host,
+            file_root: PathBuf::from(file_root),
+            cache_size,
+            log_path,
+        });
+    }
+    Ok(tenants)
+}
+
+pub async fn load_tenants(
+    tenants_str: &str,
+    default_cache_size: usize,
+    max_cacheable_size: usize,
+) -> Result<HashMap<String, Site>, Box<dyn Error>> {
+    let mut tenants = HashMap::new();
+    for tenant in parse_tenants(tenants_str, default_cache_size)? {
+        info!(
+            "Loading tenant {} @{} with file cache size {}",
+            &tenant.host,
+            tenant.file_root.display(),
+            fmt_size(tenant.cache_size)
+        );
+        let site = Site::load(
+            Some(tenant.host.clone()),
+            &tenant.file_root,
+            Some(&tenant.file_root.join(crate::RES_ROOT_FOLDER)),
+            tenant.cache_size,
+            max_cacheable_size,
+            tenant.log_path.as_deref(),
+        )
+        .await?;
+        tenants.insert(tenant.host, site);
+    }
+    Ok(tenants)
+}
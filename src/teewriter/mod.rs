@@ -1,6 +1,8 @@
 use std::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::bufpool;
+
 const TEE_WRITER_BUFF_SIZE: usize = crate::BUFF_INIT_SIZE * 8;
 
 pub async fn tee_write<R: AsyncReadExt + Unpin, W: AsyncWriteExt + Unpin>(
@@ -8,7 +10,8 @@ pub async fn tee_write<R: AsyncReadExt + Unpin, W: AsyncWriteExt + Unpin>(
     out_list: &mut [W],
 ) -> io::Result<usize> {
     // Define buffer & total bytes read
-    let mut buf = [0u8; TEE_WRITER_BUFF_SIZE];
+    let mut buf = bufpool::take();
+    buf.resize(TEE_WRITER_BUFF_SIZE, 0);
     let mut t_bytes_read = 0usize;
 
     // Pipe data loop
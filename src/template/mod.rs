@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{filecache::Validator, http::HttpRequest};
+
+// Define placeholder syntax
+// Sample: {{path.0}}, {{query.id}}, {{header.x-user}}, {{request.path}}, {{client.ip}}, {{name}}
+const TEMPLATE_OPEN: &[u8] = b"{{";
+const TEMPLATE_CLOSE: &[u8] = b"}}";
+const TEMPLATE_SOURCE_DELIM: char = '.';
+const TEMPLATE_SOURCE_PATH: &str = "path";
+const TEMPLATE_SOURCE_QUERY: &str = "query";
+const TEMPLATE_SOURCE_HEADER: &str = "header";
+const TEMPLATE_SOURCE_REQUEST: &str = "request";
+const TEMPLATE_SOURCE_CLIENT: &str = "client";
+
+// Values a template is rendered with, variables are defined by the map entry
+pub struct Context<'a> {
+    pub req: &'a HttpRequest<'a>,
+    pub ip: IpAddr,
+    pub variables: &'a [(String, String)],
+}
+
+// Renderings of templates which do not depend on the request, kept until the file changes
+type Renderings = HashMap<PathBuf, (Validator, Arc<[u8]>)>;
+
+#[derive(Debug, Default)]
+pub struct RenderCache(Mutex<Renderings>);
+
+impl RenderCache {
+    pub fn get(&self, path: &Path, validator: Validator) -> Option<Arc<[u8]>> {
+        match self.0.lock().unwrap().get(path) {
+            Some((v, data)) if *v == validator => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, path: &Path, validator: Validator, data: &Arc<[u8]>) {
+        let mut cache = self.0.lock().unwrap();
+        cache.insert(path.to_path_buf(), (validator, data.clone()));
+    }
+}
+
+// Variable names are used as placeholders without source
+pub fn is_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Value of the placeholder, and whether it came from the request
+fn resolve(placeholder: &str, ctx: &Context<'_>) -> (Option<String>, bool) {
+    let req = ctx.req;
+    let (source, key) = match placeholder.trim().split_once(TEMPLATE_SOURCE_DELIM) {
+        Some(s) => s,
+        None => {
+            let name = placeholder.trim();
+            let val = ctx.variables.iter().find(|(k, _)| k == name);
+            return (val.map(|(_, v)| v.clone()), false);
+        }
+    };
+    let val = match source {
+        // Path segment by zero based index, empty segments are skipped
+        TEMPLATE_SOURCE_PATH => key
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| req.path.split('/').filter(|s| !s.is_empty()).nth(i))
+            .map(|s| s.to_string()),
+
+        // First query parameter with the given name
+        TEMPLATE_SOURCE_QUERY => req.param(key).map(|s| s.to_string()),
+
+        // Header value with case insensitive name
+        TEMPLATE_SOURCE_HEADER => req.header(key).map(|s| s.to_string()),
+
+        // Parts of the request line
+        TEMPLATE_SOURCE_REQUEST => match key {
+            "path" => Some(req.path.to_string()),
+            "method" => Some(req.method.to_string()),
+            "query" => req.query.map(|q| q.to_string()),
+            _ => None,
+        },
+        TEMPLATE_SOURCE_CLIENT => match key {
+            "ip" => Some(ctx.ip.to_string()),
+            _ => None,
+        },
+        _ => return (None, false),
+    };
+    (val, true)
+}
+
+// Substitute the placeholders, returns whether the result depends on the request
+pub fn render(src: &[u8], ctx: &Context<'_>) -> (Vec<u8>, bool) {
+    let mut res = Vec::with_capacity(src.len());
+    let mut rest = src;
+    let mut per_request = false;
+    // Find the next placeholder
+    while let Some(open) = find(rest, TEMPLATE_OPEN) {
+        let close = match find(&rest[open..], TEMPLATE_CLOSE) {
+            Some(i) => open + i,
+            None => break,
+        };
+
+        // Copy the text before the placeholder and substitute the placeholder
+        // Unknown placeholders are substituted with an empty string
+        res.extend_from_slice(&rest[..open]);
+        let placeholder = String::from_utf8_lossy(&rest[open + TEMPLATE_OPEN.len()..close]);
+        let (val, from_request) = resolve(&placeholder, ctx);
+        if let Some(val) = val {
+            res.extend_from_slice(val.as_bytes());
+        }
+        per_request |= from_request;
+        rest = &rest[close + TEMPLATE_CLOSE.len()..];
+    }
+    res.extend_from_slice(rest);
+    (res, per_request)
+}
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use tokio::io::{self, AsyncReadExt};
+
+use crate::{
+    debug,
+    http::{HttpResponse, ResponseBody},
+    log_ctx,
+    state::Site,
+    trace, warn,
+};
+
+// Bodies are buffered to be transformed so that the content length stays exact, larger bodies are passed through as is
+pub(crate) const TRANSFORM_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+pub(crate) const INJECT_BEFORE_TAG: &[u8] = b"</body>";
+
+log_ctx!("Transform");
+
+#[derive(Debug, Default, Clone)]
+pub struct Transforms {
+    pub headers: Vec<(String, String)>,
+    pub inject: Option<PathBuf>,
+    pub rewrites: Vec<(String, String)>,
+}
+
+impl Transforms {
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && !self.mutates_body()
+    }
+
+    fn mutates_body(&self) -> bool {
+        self.inject.is_some() || !self.rewrites.is_empty()
+    }
+}
+
+fn replace_all(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(haystack.len());
+    let mut i = 0;
+    while i < haystack.len() {
+        match haystack[i..].starts_with(from) {
+            true => {
+                res.extend_from_slice(to);
+                i += from.len();
+            }
+            false => {
+                res.push(haystack[i]);
+                i += 1;
+            }
+        }
+    }
+    res
+}
+
+// Find the last occurrence of the tag case insensitively
+pub(crate) fn rfind_tag(haystack: &[u8], tag: &[u8]) -> Option<usize> {
+    haystack
+        .windows(tag.len())
+        .rposition(|w| w.eq_ignore_ascii_case(tag))
+}
+
+pub async fn apply(
+    mut res: HttpResponse,
+    transforms: &Transforms,
+    site: &Site,
+) -> io::Result<HttpResponse> {
+    // Inject headers
+    for (k, v) in &transforms.headers {
+        res = res.with_header(k.clone(), v.clone());
+    }
+    if !transforms.mutates_body() {
+        return Ok(res);
+    }
+
+    // Skip bodies which are too large to buffer or encoded by the upstream
+    if res.body_len() > TRANSFORM_MAX_BODY_SIZE || res.header("Content-Encoding").is_some() {
+        debug!("Skipping body transformation of {} bytes", res.body_len());
+        return Ok(res);
+    }
+    let mut body = res.take_body().into_bytes().await?;
+
+    // Rewrite urls
+    for (from, to) in &transforms.rewrites {
+        body = replace_all(&body, from.as_bytes(), to.as_bytes());
+    }
+
+    // Inject snippet before the closing body tag
+    if let Some(snippet_path) = &transforms.inject {
+        if let Some(i) = rfind_tag(&body, INJECT_BEFORE_TAG) {
+            match site.open(snippet_path).await {
+                Ok(mut f) => {
+                    let mut snippet = Vec::with_capacity(f.len());
+                    f.read_to_end(&mut snippet).await?;
+                    trace!("Injecting {} bytes of snippet at {}", snippet.len(), i);
+                    body.splice(i..i, snippet);
+                }
+                Err(e) => warn!("Failed to open snippet {}: {}", snippet_path.display(), e),
+            }
+        }
+    }
+
+    Ok(res.with_body(ResponseBody::Bytes(body)))
+}
@@ -0,0 +1,206 @@
+use std::{fmt::Display, path::Path};
+
+use tokio::io;
+
+use crate::{
+    http::{status_reason, HttpRequest, HttpResponse},
+    info, log_ctx,
+    multipart::{self, Multipart},
+    state::Site,
+    warn,
+};
+
+// Files are uploaded with PUT to the request path under the upload folder of the res folder
+// Sample: curl -T photo.jpg http://127.0.0.1:3006/uploads/photo.jpg
+// Forms are posted to a folder under the upload folder, each file is stored with its file name
+// Sample: curl -F file=@photo.jpg http://127.0.0.1:3006/uploads/
+const UPLOAD_EXT_DELIM: char = ',';
+
+log_ctx!("Upload");
+
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub folder: String,          // Relative to the res folder, e.g. uploads
+    pub max_size: Option<usize>, // Limited by the max body size only if not given
+    pub extensions: Vec<String>, // Lower case without dot, every extension is allowed if empty
+}
+
+impl Upload {
+    pub fn new(folder: &str) -> Option<Self> {
+        let folder = folder.trim_matches('/');
+        if folder.is_empty() {
+            return None;
+        }
+        Some(Self {
+            folder: folder.to_string(),
+            max_size: None,
+            extensions: Vec::new(),
+        })
+    }
+
+    // Sample: jpg,png,.pdf
+    pub fn parse_extensions(s: &str) -> Vec<String> {
+        s.split(UPLOAD_EXT_DELIM)
+            .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|e| !e.is_empty())
+            .collect()
+    }
+
+    // Path relative to the res folder of a request under the upload folder
+    fn rel_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rel = path.strip_prefix('/')?;
+        let name = rel.strip_prefix(&self.folder)?.strip_prefix('/')?;
+        match name.is_empty() || name.ends_with('/') {
+            true => None,
+            false => Some(rel),
+        }
+    }
+
+    // Folder relative to the res folder of a request to the upload folder or a folder under it
+    fn rel_folder<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rel = path.strip_prefix('/')?.trim_end_matches('/');
+        match rel.strip_prefix(&self.folder)? {
+            "" => Some(rel),
+            r if r.starts_with('/') => Some(rel),
+            _ => None,
+        }
+    }
+
+    fn is_allowed_ext(&self, path: &str) -> bool {
+        if self.extensions.is_empty() {
+            return true;
+        }
+        match Path::new(path).extension() {
+            Some(e) => self
+                .extensions
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(&e.to_string_lossy())),
+            None => false,
+        }
+    }
+}
+
+impl Display for Upload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/{}/", self.folder)
+    }
+}
+
+fn status(status: u16) -> HttpResponse {
+    HttpResponse::new(status).with_text("text/plain", status_reason(status))
+}
+
+fn store_error(rel_path: &str, e: io::Error) -> HttpResponse {
+    warn!("Failed to store {}: {}", rel_path, e);
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => status(403),
+        io::ErrorKind::IsADirectory | io::ErrorKind::NotADirectory => status(409),
+        io::ErrorKind::Unsupported => status(405),
+        _ => status(500),
+    }
+}
+
+// Store the body of PUT requests and the files of posted forms under the upload folder, None for other requests
+pub(crate) async fn handle(
+    upload: &Upload,
+    http_request: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    site: &Site,
+) -> Option<HttpResponse> {
+    match http_request.method {
+        "PUT" => put(upload, http_request, body, site).await,
+        "POST" => post(upload, http_request, body, site).await,
+        _ => None,
+    }
+}
+
+async fn put(
+    upload: &Upload,
+    http_request: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    site: &Site,
+) -> Option<HttpResponse> {
+    let rel_path = upload.rel_path(&http_request.path)?;
+    let body = body.unwrap_or_default();
+    if upload.max_size.is_some_and(|m| body.len() > m) {
+        return Some(status(413));
+    }
+    if !upload.is_allowed_ext(rel_path) {
+        return Some(status(415));
+    }
+    let res = match site.store_file(Path::new(rel_path), body).await {
+        Ok(true) => HttpResponse::new(201)
+            .with_header("Location", http_request.path.to_string())
+            .with_text("text/plain", status_reason(201)),
+        Ok(false) => HttpResponse::new(204),
+        Err(e) => return Some(store_error(rel_path, e)),
+    };
+    info!("Stored {} bytes at {}", body.len(), rel_path);
+    Some(res)
+}
+
+// Store every file of a multipart form in the requested folder, fields without a file are ignored
+async fn post(
+    upload: &Upload,
+    http_request: &HttpRequest<'_>,
+    body: Option<&[u8]>,
+    site: &Site,
+) -> Option<HttpResponse> {
+    let folder = upload.rel_folder(&http_request.path)?;
+    let boundary = http_request
+        .header("Content-Type")
+        .and_then(multipart::form_data_boundary)?;
+    let mut form = Multipart::new(body.unwrap_or_default(), boundary);
+    let mut stored = Vec::new();
+    loop {
+        let part = match form.next_part().await {
+            Ok(Some(p)) => p,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Invalid form posted to {}: {}", http_request.path, e);
+                return Some(status(400));
+            }
+        };
+
+        // Only the last component of the file name is used, so that a form cannot write elsewhere
+        let name = match part.filename.as_deref() {
+            Some(f) => f.rsplit(['/', '\\']).next().unwrap_or_default(),
+            None => continue,
+        };
+        if name.is_empty() || name.starts_with('.') {
+            continue;
+        }
+        if !upload.is_allowed_ext(name) {
+            return Some(status(415));
+        }
+        let data = match form
+            .read_to_end(upload.max_size.unwrap_or(usize::MAX))
+            .await
+        {
+            Ok(Some(d)) => d,
+            Ok(None) => return Some(status(413)),
+            Err(e) => {
+                warn!("Invalid form posted to {}: {}", http_request.path, e);
+                return Some(status(400));
+            }
+        };
+        let rel_path = format!("{}/{}", folder, name);
+        if let Err(e) = site.store_file(Path::new(&rel_path), &data).await {
+            return Some(store_error(&rel_path, e));
+        }
+        info!("Stored {} bytes at {}", data.len(), rel_path);
+        stored.push(format!("/{}", rel_path));
+    }
+    if stored.is_empty() {
+        return Some(status(400));
+    }
+
+    // The paths of the stored files are listed in the body, one per line
+    let mut body = stored.join("\n");
+    body.push('\n');
+    Some(
+        HttpResponse::new(201)
+            .with_header("Location", stored[0].clone())
+            .with_text("text/plain", body),
+    )
+}
@@ -0,0 +1,119 @@
+use std::{
+    cell::Cell,
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+use tokio::io::{self, AsyncRead, ReadBuf};
+
+use crate::{debug, log_ctx};
+
+// Bytes submitted per read, the buffer is owned by the ring until the read completes
+const URING_READ_SIZE: usize = crate::BUFF_INIT_SIZE * 64;
+
+log_ctx!("Uring");
+
+thread_local! {
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+// Run the future on an io_uring runtime on the current thread, files opened by its tasks are read through the ring
+// Tasks spawned on the runtime never leave the thread
+pub fn run<F: Future>(future: F) -> F::Output {
+    debug!(
+        "Starting io_uring runtime on {:?}",
+        std::thread::current().id()
+    );
+    ACTIVE.with(|a| a.set(true));
+    let res = tokio_uring::start(future);
+    ACTIVE.with(|a| a.set(false));
+    res
+}
+
+// Whether the current thread runs an io_uring runtime
+pub fn is_active() -> bool {
+    ACTIVE.with(|a| a.get())
+}
+
+type PendingRead = Pin<Box<dyn Future<Output = (io::Result<usize>, Vec<u8>)>>>;
+
+// A file read at its own position through the ring of the current thread
+pub struct UringFile {
+    file: Rc<tokio_uring::fs::File>,
+    pos: u64,
+    pending: Option<PendingRead>,
+    leftover: (Vec<u8>, usize), // Bytes read beyond the buffer of the last call, and how many are consumed
+}
+
+// Safety: files are only created on the threads of io_uring runtimes by the tasks of these runtimes,
+// which are spawned on the current thread runtime underneath and never move to another thread
+unsafe impl Send for UringFile {}
+unsafe impl Sync for UringFile {}
+
+impl UringFile {
+    pub fn new(file: std::fs::File) -> Self {
+        Self {
+            file: Rc::new(tokio_uring::fs::File::from_std(file)),
+            pos: 0,
+            pending: None,
+            leftover: (Vec::new(), 0),
+        }
+    }
+
+    // Continue reading at the given position, reads in flight are discarded
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos;
+        self.pending = None;
+        self.leftover = (Vec::new(), 0);
+    }
+}
+
+impl Debug for UringFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringFile").field("pos", &self.pos).finish()
+    }
+}
+
+impl AsyncRead for UringFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // Hand out the bytes of the last read first
+            let (data, consumed) = &mut this.leftover;
+            if *consumed < data.len() {
+                let n = buf.remaining().min(data.len() - *consumed);
+                buf.put_slice(&data[*consumed..*consumed + n]);
+                *consumed += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            // Submit the next read, the ring owns the buffer until it completes
+            let pending = this.pending.get_or_insert_with(|| {
+                let file = this.file.clone();
+                let pos = this.pos;
+                Box::pin(
+                    async move { file.read_at(Vec::with_capacity(URING_READ_SIZE), pos).await },
+                )
+            });
+            let (res, data) = match pending.as_mut().poll(cx) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending = None;
+            match res? {
+                0 => return Poll::Ready(Ok(())), // End of file
+                n => {
+                    this.pos += n as u64;
+                    this.leftover = (data, 0);
+                }
+            }
+        }
+    }
+}
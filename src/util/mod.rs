@@ -1,3 +1,15 @@
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// 1970-01-01 was a Thursday
+const HTTP_DATE_WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const HTTP_DATE_MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
 pub fn fmt_size(u: usize) -> String {
     let mut u = u as f64;
     let mut i = 0;
@@ -8,3 +20,305 @@ pub fn fmt_size(u: usize) -> String {
     }
     format!("{:.2} {}", u, units[i])
 }
+
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    // Split into number and unit, a bare number is treated as milliseconds
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "ms"),
+    };
+    let num = num.parse::<u64>().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(num)),
+        "s" => Some(Duration::from_secs(num)),
+        "m" => Some(Duration::from_secs(num * 60)),
+        "h" => Some(Duration::from_secs(num * 60 * 60)),
+        "d" => Some(Duration::from_secs(num * 60 * 60 * 24)),
+        _ => None,
+    }
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// Convert days since epoch to year, month and day of the proleptic gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m as u32, d as u32)
+}
+
+// Convert year, month and day of the proleptic gregorian calendar to days since epoch
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let (m, d) = (m as i64, d as i64);
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Format as IMF-fixdate, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+pub fn fmt_http_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (y, m, d) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        HTTP_DATE_WEEKDAYS[(days % 7) as usize],
+        d,
+        HTTP_DATE_MONTHS[m as usize - 1],
+        y,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Format as the timestamp of the common log format in UTC, e.g. 10/Oct/2000:13:55:36 +0000
+pub fn fmt_clf_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (y, m, d) = civil_from_days(days as i64);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        d,
+        HTTP_DATE_MONTHS[m as usize - 1],
+        y,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Format as RFC 3339 in UTC with milliseconds, e.g. 2000-10-10T13:55:36.123Z
+pub fn fmt_rfc3339(t: SystemTime) -> String {
+    let since = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (y, m, d) = civil_from_days(days as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+// Parse an IMF-fixdate, the obsolete formats are not supported
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let mut parts = s.split_whitespace().skip(1); // Skip the weekday
+    let d = parts.next()?.parse::<u32>().ok()?;
+    let month = parts.next()?;
+    let m = HTTP_DATE_MONTHS.iter().position(|&n| n == month)? as u32 + 1;
+    let y = parts.next()?.parse::<i64>().ok()?;
+    let hms = parts
+        .next()?
+        .split(':')
+        .map(|n| n.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if hms.len() != 3 || parts.next()? != "GMT" || y < 1970 || !(1..=31).contains(&d) {
+        return None;
+    }
+    let days = days_from_civil(y, m, d) as u64;
+    let secs = days * 86400 + hms[0] * 3600 + hms[1] * 60 + hms[2];
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Decode %XX escapes to UTF-8, invalid escapes or invalid UTF-8 result in None
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                res.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b => {
+                res.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+// Resolve empty, . and .. segments of an absolute path, None if it escapes the root
+pub fn normalize_path(path: &str) -> Option<String> {
+    let mut segments = Vec::new();
+    for seg in path.split('/') {
+        match seg {
+            "" | "." => (),
+            ".." => {
+                segments.pop()?;
+            }
+            s => segments.push(s),
+        }
+    }
+    let mut res = format!("/{}", segments.join("/"));
+    if path.ends_with('/') && !segments.is_empty() {
+        res.push('/');
+    }
+    Some(res)
+}
+
+// Encode all bytes except the attr-char of RFC 5987, e.g. for the filename* parameter
+pub fn percent_encode_attr(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() * 3);
+    for b in s.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => res.push(b as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                res.push(b as char)
+            }
+            b => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Encode as standard base64 with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut res = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => res.push(BASE64_ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char),
+                false => res.push('='),
+            }
+        }
+    }
+    res
+}
+
+// Decode standard base64 with optional padding, invalid input results in None
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut res = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            res.push((acc >> bits) as u8);
+        }
+    }
+    Some(res)
+}
+
+// FNV-1a, stable across runs and builds unlike the hasher of the standard library
+pub fn fnv1a(parts: &[&[u8]]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    parts
+        .iter()
+        .flat_map(|p| p.iter())
+        .fold(FNV_OFFSET, |h, b| (h ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+pub fn html_escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+// Content type of common web files by extension
+pub fn content_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => Some("text/html; charset=utf-8"),
+        "txt" => Some("text/plain; charset=utf-8"),
+        "css" => Some("text/css; charset=utf-8"),
+        "js" | "mjs" => Some("text/javascript; charset=utf-8"),
+        "json" => Some("application/json"),
+        "xml" => Some("application/xml"),
+        "svg" => Some("image/svg+xml"),
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "ico" => Some("image/x-icon"),
+        _ => None,
+    }
+}
+
+// Whether the process (EMFILE) or the system (ENFILE) has run out of file descriptors
+#[cfg(unix)]
+pub fn is_fd_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(23 | 24))
+}
+
+// Whether the socket limit (WSAEMFILE) has been reached
+#[cfg(windows)]
+pub fn is_fd_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(10024))
+}
+
+// Errors which are expected to go away when retried later
+pub fn is_transient(e: &io::Error) -> bool {
+    is_fd_exhausted(e)
+        || matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ResourceBusy
+        )
+}
@@ -0,0 +1,307 @@
+use std::net::SocketAddr;
+
+use sha1::{Digest, Sha1};
+use tokio::io::{self, AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    debug,
+    http::{status_reason, HttpRequest, HttpResponse},
+    log_ctx,
+    proxy::{self, Upstream, UpstreamPolicy},
+    trace, util, warn,
+};
+
+// RFC 6455, the accept key is the base64 sha1 of the client key appended with the GUID
+// Sample of handshake: Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ== -> Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WEBSOCKET_VERSION: &str = "13";
+const WEBSOCKET_MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+// Frame opcodes
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Close codes
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+log_ctx!("WebSocket");
+
+// Handlers of upgraded connections, selected with the websocket directive of the request map
+// Sample: /ws = index.html websocket=echo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Echo, // Send every message back to the client
+}
+
+impl Handler {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "echo" => Some(Handler::Echo),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Handler::Echo => "echo",
+        }
+    }
+
+    // Serve the connection until closed by either side
+    async fn run<R, W>(&self, r_stream: &mut R, w_stream: &mut W) -> io::Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            Handler::Echo => echo(r_stream, w_stream).await,
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// Whether the request asks to switch to the websocket protocol
+pub fn is_upgrade(http_request: &HttpRequest<'_>) -> bool {
+    let has_token = |key: &str, token: &str| {
+        http_request
+            .header(key)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    http_request.method == "GET"
+        && has_token("Upgrade", "websocket")
+        && has_token("Connection", "upgrade")
+}
+
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{}{}", key.trim(), WEBSOCKET_GUID).as_bytes());
+    util::base64_encode(&digest)
+}
+
+// Build the handshake response, which is an error status if the client key or version is not acceptable
+fn handshake(http_request: &HttpRequest<'_>) -> HttpResponse {
+    if http_request
+        .header("Sec-WebSocket-Version")
+        .map(|v| v.trim())
+        != Some(WEBSOCKET_VERSION)
+    {
+        let res = HttpResponse::new(426).with_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
+        return res.with_text("text/plain", status_reason(426));
+    }
+    let key = http_request
+        .header("Sec-WebSocket-Key")
+        .filter(|k| util::base64_decode(k.trim()).is_some_and(|k| k.len() == 16));
+    match key {
+        Some(k) => HttpResponse::new(101)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", accept_key(k)),
+        None => HttpResponse::new(400).with_text("text/plain", status_reason(400)),
+    }
+}
+
+// Complete the handshake and hand the connection over to the handler, returns the written response
+pub async fn accept<R, W>(
+    handler: Handler,
+    http_request: &HttpRequest<'_>,
+    r_stream: &mut R,
+    w_stream: &mut W,
+) -> io::Result<HttpResponse>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut res = handshake(http_request);
+    res.write_to(w_stream, false).await?;
+    if res.status() != 101 {
+        return Ok(res);
+    }
+
+    // Errors after the switch cannot be answered with a status, they only end the connection
+    debug!("Connection upgraded to {} handler", handler.name());
+    if let Err(e) = handler.run(r_stream, w_stream).await {
+        debug!("WebSocket connection ended: {}", e);
+    }
+    Ok(res)
+}
+
+// Relay the upgrade to the upstream and pipe both directions once switched, returns the upstream status
+pub async fn tunnel<R, W>(
+    upstream: &Upstream,
+    policy: &UpstreamPolicy,
+    sockaddr: &SocketAddr,
+    http_request: &HttpRequest<'_>,
+    r_stream: &mut R,
+    w_stream: &mut W,
+) -> io::Result<HttpResponse>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send,
+{
+    let (status, header, upstream_stream) =
+        match proxy::connect_upgrade(upstream, policy, sockaddr, http_request).await {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Upstream upgrade to {} failed: {}", upstream, e);
+                let status = match e.kind() {
+                    io::ErrorKind::TimedOut => 504,
+                    _ => 502,
+                };
+                let mut res =
+                    HttpResponse::new(status).with_text("text/plain", status_reason(status));
+                res.write_to(w_stream, false).await?;
+                return Ok(res);
+            }
+        };
+
+    // Pass the upstream response through as is, the connection only switches on 101
+    w_stream.write_all(&header).await?;
+    if status != 101 {
+        trace!("Upstream {} refused the upgrade with {}", upstream, status);
+        let content_length = String::from_utf8_lossy(&header)
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, v)| v.trim().parse::<u64>().ok());
+        match content_length {
+            Some(len) => io::copy(&mut upstream_stream.take(len), w_stream).await?,
+            None => io::copy_buf(&mut { upstream_stream }, w_stream).await?,
+        };
+        return Ok(HttpResponse::new(status));
+    }
+    debug!("Tunneling upgraded connection to {}", upstream);
+    let (mut up_r, mut up_w) = io::split(upstream_stream);
+    let client_to_upstream = async {
+        io::copy_buf(r_stream, &mut up_w).await?;
+        up_w.shutdown().await
+    };
+    let upstream_to_client = io::copy(&mut up_r, w_stream);
+    tokio::pin!(upstream_to_client);
+
+    // The upstream closing ends the tunnel, the client closing waits for the upstream to finish
+    let res = tokio::select! {
+        res = &mut upstream_to_client => res.map(|_| ()),
+        res = client_to_upstream => match res {
+            Ok(_) => upstream_to_client.await.map(|_| ()),
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = res {
+        debug!("Tunnel to {} ended: {}", upstream, e);
+    }
+    Ok(HttpResponse::new(status))
+}
+
+async fn read_frame<R: AsyncBufRead + Unpin>(r_stream: &mut R) -> io::Result<Frame> {
+    let mut head = [0; 2];
+    r_stream.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+
+    // Extended payload lengths follow the 7 bit length of 126 and 127
+    let len = match head[1] & 0x7F {
+        126 => r_stream.read_u16().await? as usize,
+        127 => r_stream.read_u64().await? as usize,
+        l => l as usize,
+    };
+
+    // Frames of clients must be masked
+    if !masked {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unmasked client frame",
+        ));
+    }
+    if len > WEBSOCKET_MAX_PAYLOAD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("Frame larger than {} bytes", WEBSOCKET_MAX_PAYLOAD_SIZE),
+        ));
+    }
+    let mut mask = [0; 4];
+    r_stream.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len];
+    r_stream.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+// Frames of servers are never masked
+async fn write_frame<W: AsyncWrite + Unpin>(
+    w_stream: &mut W,
+    fin: bool,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut head = Vec::with_capacity(10);
+    head.push(((fin as u8) << 7) | opcode);
+    match payload.len() {
+        l if l < 126 => head.push(l as u8),
+        l if l <= u16::MAX as usize => {
+            head.push(126);
+            head.extend_from_slice(&(l as u16).to_be_bytes());
+        }
+        l => {
+            head.push(127);
+            head.extend_from_slice(&(l as u64).to_be_bytes());
+        }
+    }
+    w_stream.write_all(&head).await?;
+    w_stream.write_all(payload).await?;
+    w_stream.flush().await
+}
+
+async fn write_close<W: AsyncWrite + Unpin>(w_stream: &mut W, code: u16) -> io::Result<()> {
+    write_frame(w_stream, true, OPCODE_CLOSE, &code.to_be_bytes()).await
+}
+
+async fn echo<R, W>(r_stream: &mut R, w_stream: &mut W) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let frame = match read_frame(r_stream).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return write_close(w_stream, CLOSE_PROTOCOL_ERROR).await;
+            }
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                return write_close(w_stream, CLOSE_TOO_BIG).await;
+            }
+            Err(e) => return Err(e),
+        };
+        match frame.opcode {
+            // Fragments are sent back as they arrive
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                trace!("Echoing {} bytes", frame.payload.len());
+                write_frame(w_stream, frame.fin, frame.opcode, &frame.payload).await?
+            }
+            OPCODE_PING => write_frame(w_stream, true, OPCODE_PONG, &frame.payload).await?,
+            OPCODE_PONG => (),
+
+            // Answer the close with the same status and end the connection
+            OPCODE_CLOSE => {
+                let code = &frame.payload[..frame.payload.len().min(2)];
+                return write_frame(w_stream, true, OPCODE_CLOSE, code).await;
+            }
+            _ => return write_close(w_stream, CLOSE_PROTOCOL_ERROR).await,
+        }
+    }
+}